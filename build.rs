@@ -0,0 +1,53 @@
+//! Bundles the IEEE OUI registry (MA-L, MA-M and MA-S) into the binary.
+//!
+//! The registries are downloaded from IEEE at build time. Offline builds bundle a previously
+//! downloaded file, e.g. the one written by `locator oui-refresh`:
+//! `LOCATOR_OUI_BUNDLE=oui.csv cargo build`.
+
+use std::{env, fs, path::PathBuf, process::Command};
+
+const URLS: [&str; 3] = [
+    "https://standards-oui.ieee.org/oui/oui.csv",
+    "https://standards-oui.ieee.org/oui28/mam.csv",
+    "https://standards-oui.ieee.org/oui36/oui36.csv",
+];
+
+fn download(url: &str) -> Result<Vec<u8>, String> {
+    let output = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--location"])
+        .args(["--retry", "3", "--max-time", "300", url])
+        .output()
+        .map_err(|e| format!("curl {url}: {e}"))?;
+    if !output.status.success() {
+        return Err(format!(
+            "curl {url}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(output.stdout)
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=LOCATOR_OUI_BUNDLE");
+
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("oui.csv");
+
+    if let Ok(path) = env::var("LOCATOR_OUI_BUNDLE") {
+        println!("cargo:rerun-if-changed={path}");
+        fs::copy(&path, &out).unwrap_or_else(|e| panic!("OUI registry {path}: {e}"));
+        return;
+    }
+
+    let mut registry = Vec::new();
+    for url in URLS {
+        let data = download(url).unwrap_or_else(|e| {
+            panic!("OUI registry is not downloaded ({e}), set LOCATOR_OUI_BUNDLE to a local copy")
+        });
+        registry.extend_from_slice(&data);
+        if !registry.ends_with(b"\n") {
+            registry.push(b'\n');
+        }
+    }
+    fs::write(&out, registry).unwrap();
+}
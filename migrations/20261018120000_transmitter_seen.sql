@@ -0,0 +1,15 @@
+ALTER TABLE cell
+    ADD COLUMN first_seen TIMESTAMP WITH TIME ZONE,
+    ADD COLUMN last_seen TIMESTAMP WITH TIME ZONE,
+    ADD COLUMN observation_count INTEGER NOT NULL DEFAULT 0,
+    ADD COLUMN stale BOOLEAN NOT NULL DEFAULT false;
+
+ALTER TABLE bluetooth
+    ADD COLUMN first_seen TIMESTAMP WITH TIME ZONE,
+    ADD COLUMN last_seen TIMESTAMP WITH TIME ZONE,
+    ADD COLUMN observation_count INTEGER NOT NULL DEFAULT 0,
+    ADD COLUMN stale BOOLEAN NOT NULL DEFAULT false;
+
+-- retention policy
+CREATE INDEX cell_last_seen ON cell (last_seen);
+CREATE INDEX bluetooth_last_seen ON bluetooth (last_seen);
@@ -31,15 +31,42 @@ pub struct Config {
     /// Yandex LBS
     #[serde(rename = "yandex-lbs")]
     pub yandex_lbs: YandexLBS,
+    /// External LBS providers
+    pub lbs: Lbs,
+    /// Daily budget of the Yandex API keys by the request priority
+    #[serde(rename = "lbs-budget")]
+    pub lbs_budget: LbsBudget,
+    /// Background lookups of the transmitters unknown to Yandex LBS found in the reports
+    pub enrichment: Enrichment,
+    /// Expiry and revalidation of the cached LBS responses
+    #[serde(rename = "lbs-cache")]
+    pub lbs_cache: LbsCache,
+    /// Circuit breakers of the LBS providers and GraphHopper
+    #[serde(rename = "circuit-breaker")]
+    pub circuit_breaker: CircuitBreaker,
     /// AlterGeo LBS
     #[serde(rename = "altergeo-lbs")]
     pub altergeo_lbs: AlterGeoLBS,
+    /// Upstream Ichnaea-compatible geolocation server
+    #[serde(rename = "ichnaea-lbs")]
+    pub ichnaea_lbs: IchnaeaLBS,
     /// Tile38 settings
     pub t38: T38,
     /// Blobasaur settings
     pub blobasaur: Blobasaur,
     /// GraphHopper settings
     pub graphhopper: GraphHopper,
+    /// Deduplication of repeated report submissions
+    pub idempotency: Idempotency,
+    /// SSID rules of ignoring access points
+    pub ssid: Ssid,
+    /// Expiry of the transmitters not seen for a long time
+    pub retention: Retention,
+    /// IEEE OUI registry and the excluded vendors
+    pub oui: Oui,
+    /// Timing Advance and sector constraints of the serving cell
+    #[serde(rename = "cell-geometry")]
+    pub cell_geometry: CellGeometry,
 }
 
 impl Config {
@@ -51,6 +78,13 @@ impl Config {
                 self.locator.http_client
             ));
         }
+        for rule in &self.ssid.rules {
+            if rule.kind == SsidMatch::Regex {
+                regex::Regex::new(&rule.pattern)
+                    .with_context(|| format!("invalid SSID regex: {}", rule.pattern))?;
+            }
+        }
+        crate::services::helper::oui::VendorRules::new(&self.oui)?;
         Ok(())
     }
 }
@@ -74,6 +108,24 @@ pub struct Locator {
     pub max_distance_cell: f64,
     /// filter Locally Administered Addresses
     pub laa_filter: bool,
+    /// the weight of an observation halves every N days, 0 disables the time decay
+    pub aggregation_half_life: f64,
+    /// number of observations in a new location required to consider the transmitter relocated
+    pub relocation_min_observations: u32,
+    /// days since the last observation of the new location after which a distant observation starts another one
+    pub relocation_candidate_ttl: f64,
+    /// case-insensitive SSID substrings of mobile hotspots
+    pub mobile_ssid_patterns: Vec<String>,
+    /// time window of observations at distant positions or cells to consider the access point mobile, seconds
+    pub mobile_window: u64,
+    /// distance between observations within `mobile_window` to consider the access point mobile, meters
+    pub mobile_distance: f64,
+    /// distance between the cells observed together with the access point within `mobile_window`
+    /// to consider it mobile, meters
+    pub mobile_cell_distance: f64,
+    /// number of contradicting observations to consider the access point mobile, as many consistent
+    /// ones clear it
+    pub mobile_observations: u32,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -127,6 +179,81 @@ pub struct YandexLBS {
     pub max_distance_in_cluster: f64,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct Lbs {
+    /// providers in the priority order
+    pub providers: Vec<LbsProviderKind>,
+    /// locate by one request with all access points and cells instead of one per access point
+    pub combined: bool,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct LbsBudget {
+    pub enabled: bool,
+    /// share of the daily limit of a key reserved for the live locate requests
+    pub interactive_share: f64,
+    /// requests the enrichment of the reports may run ahead of the even spending over the day
+    pub burst: u64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct LbsCache {
+    /// days the positions of the access points are cached, 0 - forever
+    pub wifi_ttl: u64,
+    /// days the positions of the cells are cached, 0 - forever
+    pub cell_ttl: u64,
+    /// days the access points and cells unknown to a provider are not requested again, 0 - forever
+    pub missing_ttl: u64,
+    /// re-request the cached Yandex positions before the expiry within the spare quota
+    pub revalidate: bool,
+    /// positions expiring within the days are re-requested
+    pub revalidate_days: u64,
+    /// seconds between the runs of the revalidation
+    pub revalidate_interval: u32,
+    /// positions re-requested per run
+    pub revalidate_batch: usize,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct CircuitBreaker {
+    pub enabled: bool,
+    /// seconds of the window the failure rate is counted in
+    pub window: u64,
+    /// requests in the window before the failure rate is checked
+    pub min_requests: usize,
+    /// share of the failed requests in the window opening the breaker, 0.0..1.0
+    pub failure_rate: f64,
+    /// seconds the open breaker rejects the requests before a trial one
+    pub open_timeout: u64,
+    /// milliseconds of the Yandex LBS request
+    pub yandex_timeout: u64,
+    /// milliseconds of the AlterGeo request
+    pub altergeo_timeout: u64,
+    /// milliseconds of the Ichnaea request
+    pub ichnaea_timeout: u64,
+    /// milliseconds of the GraphHopper map matching request
+    pub graphhopper_timeout: u64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Enrichment {
+    pub enabled: bool,
+    /// seconds between the runs of the worker
+    pub interval: u32,
+    /// access points and cells requested per run
+    pub batch: u64,
+    /// Moscow hours when the queue may spend the whole daily limit left
+    pub off_peak_hours: Vec<u32>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LbsProviderKind {
+    Yandex,
+    AlterGeo,
+    Ichnaea,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct YandexApiKey {
     pub key: String,
@@ -140,6 +267,15 @@ pub struct AlterGeoLBS {
     pub apikey: String,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct IchnaeaLBS {
+    pub enabled: bool,
+    /// geolocate endpoint, e.g. `https://host/api/mls/v1/geolocate` of another locator
+    pub url: String,
+    /// passed as `?key=`, empty if not required
+    pub apikey: String,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct T38 {
     pub pool_size: u16,
@@ -186,6 +322,108 @@ pub struct GraphHopperAdmin {
     pub gc_frequency: Option<u32>,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct Idempotency {
+    pub enabled: bool,
+    /// dedupe window: lifetime of the keys in Tile38, seconds
+    pub window: u64,
+    /// dedupe items by (device_id, timestamp) when `Idempotency-Key` header is absent
+    pub dedupe_items: bool,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Ssid {
+    /// refuse and purge the access points with `_nomap` or `_optout` in the SSID
+    pub opt_out: bool,
+    /// access points matching any rule are not aggregated and not used for locate
+    pub rules: Vec<SsidRule>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SsidRule {
+    pub kind: SsidMatch,
+    pub pattern: String,
+    pub case_insensitive: bool,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SsidMatch {
+    Exact,
+    Prefix,
+    Suffix,
+    Contains,
+    Regex,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Retention {
+    pub enabled: bool,
+    pub wifi: RetentionPolicy,
+    pub cell: RetentionPolicy,
+    pub bluetooth: RetentionPolicy,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct RetentionPolicy {
+    /// transmitters not seen within this number of days are expired
+    pub days: u32,
+    pub action: RetentionAction,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, strum_macros::Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum RetentionAction {
+    /// delete the transmitter
+    Remove,
+    /// keep the transmitter, but do not use it for locate until it is seen again
+    Demote,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Oui {
+    pub enabled: bool,
+    /// local copy of the IEEE registry (CSV), the bundled registry is used without it
+    pub path: String,
+    /// registries downloaded by `locator oui-refresh`
+    pub urls: Vec<String>,
+    /// case-insensitive substrings of the vendor name
+    pub exclude_vendors: Vec<String>,
+    /// MAC address assignments, e.g. "00:1a:2b" or "70:b3:d5:12:3"
+    pub exclude_prefixes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct CellGeometry {
+    pub enabled: bool,
+    /// tolerance of the Timing Advance ring and the sector (the multipath), meters; the accuracy of
+    /// the LBS location is added to it for the cells without a known antenna
+    pub ta_margin: f64,
+    /// numbering of the LTE sectors by the local cell id, where known
+    pub sectors: Vec<SectorNumbering>,
+    /// positions of the antennas, where known; the cells are located by the geometry only with them
+    pub antennas: Vec<Antenna>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Antenna {
+    /// `{radio}:{mcc}:{mnc}:{lac}:{cid}`
+    pub code: String,
+    pub lat: f64,
+    pub lon: f64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SectorNumbering {
+    pub mcc: u16,
+    pub mnc: u16,
+    /// azimuth by the local cell id modulo the number of azimuths, degrees
+    pub azimuths: Vec<f64>,
+    /// horizontal beamwidth of the antenna, degrees
+    pub beamwidth: f64,
+}
+
 pub fn load_config(path: &Path) -> Result<Config> {
     let data = fs::read_to_string(path).context("Failed to read config")?;
     let config: Config = toml::from_str(&data).context("Failed to parse config")?;
@@ -1,3 +1,6 @@
 mod config;
 
-pub use config::{CONFIG, Config, YandexApiKey};
+pub use config::{
+    Antenna, CONFIG, CellGeometry, CircuitBreaker, Config, Enrichment, LbsBudget, LbsCache,
+    LbsProviderKind, Oui, SectorNumbering, Ssid, SsidMatch, SsidRule, YandexApiKey,
+};
@@ -9,7 +9,6 @@ pub const BASE_RSSI: f64 = -30.0;
 // default RSSI
 pub const DEFAULT_RSSI: f64 = -90.0;
 
-pub const WIFI_SSID_IGNORED: &[&str] = &["carcam"];
 pub const MAX_SCOOTER_SPEED: f64 = 25.0; // 25 km/h = 7 m/s
 pub const MAX_DISTANCE: f64 = 60_000.0; // meters
 
@@ -21,6 +20,31 @@ pub const GPS_VALID_DISTANCE_BY_CELL: f64 = 500.0; // meters
 pub const FALLBACK_EPSILON_CLUSTER: f64 = 150.0; // meters
 pub const FALLBACK_LOCATE_DISTANCE: f64 = 2500.0; // meters
 
+// Timing Advance step is the distance of a half of the round trip
+pub const GSM_TA_STEP: f64 = 553.5; // meters, one bit period (48/13 µs)
+pub const GSM_TA_MAX: f64 = 219.0;
+pub const LTE_TA_STEP: f64 = 78.07; // meters, 16 Ts (0.52 µs)
+pub const LTE_TA_MAX: f64 = 1282.0;
+
+// trust of the transmitter location
+pub const TRUST_OBSERVATIONS: f64 = 3.0; // observations to reach 3/4 of the count factor
+pub const TRUST_MIN_CHECKS: u32 = 3; // agreement checks to decide on outliers without LBS requests
+pub const TRUST_MIN_WEIGHT: f64 = 0.05;
+
+// virtual access points of one router differ in the low nibble of the MAC address
+pub const VIRTUAL_AP_MASK: u8 = 0xf0;
+pub const VIRTUAL_AP_RSSI_DELTA: f64 = 10.0; // dBm
+
+// association of the cells with the access points observed together
+pub const CELL_WIFI_MAX_APS: usize = 256; // the least observed access points are dropped
+pub const CELL_WIFI_MIN_REPORTS: f64 = 3.0; // decayed number of reports to locate the cell
+
+// co-occurrence graph of the access points
+pub const COOCCURRENCE_MAX_PEERS: usize = 64; // the weakest edges are dropped
+pub const COOCCURRENCE_MIN_WEIGHT: f32 = 0.05;
+pub const COOCCURRENCE_MIN_KNOWN: usize = 3; // edges of the access point to trust its graph
+pub const COOCCURRENCE_MIN_PEERS: usize = 2; // known access points in the request to flag the unrelated one
+
 pub const HOUR: u64 = 3600; // seconds
 
 pub const PRESAVED_PARTITIONS_COUNT: u16 = 2;
@@ -41,6 +65,9 @@ pub enum Collection {
     Wifi,
     #[strum(serialize = "bluetooth")]
     Bluetooth,
+    // Wi-Fi aggregates rebuilt by reprocessing
+    #[strum(serialize = "wifi:shadow")]
+    WifiShadow,
 
     // Access points from Yandex Locator
     #[strum(serialize = "lbs:yandex:wifi")]
@@ -56,6 +83,42 @@ pub enum Collection {
     #[strum(serialize = "lbs:yandex:cell:missing")]
     LbsYandexCellMissing,
 
+    // Access points from AlterGeo
+    #[strum(serialize = "lbs:altergeo:wifi")]
+    LbsAlterGeoWifi,
+    // Access points unknown to AlterGeo
+    #[strum(serialize = "lbs:altergeo:wifi:missing")]
+    LbsAlterGeoWifiMissing,
+
+    // Access points and cells from the upstream Ichnaea-compatible server
+    #[strum(serialize = "lbs:ichnaea:wifi")]
+    LbsIchnaeaWifi,
+    #[strum(serialize = "lbs:ichnaea:cell")]
+    LbsIchnaeaCell,
+    // Access points and cells unknown to the upstream
+    #[strum(serialize = "lbs:ichnaea:wifi:missing")]
+    LbsIchnaeaWifiMissing,
+    #[strum(serialize = "lbs:ichnaea:cell:missing")]
+    LbsIchnaeaCellMissing,
+
+    // Access points and cells of the reports waiting for the Yandex LBS request
+    #[strum(serialize = "lbs:queue:wifi")]
+    LbsQueueWifi,
+    #[strum(serialize = "lbs:queue:cell")]
+    LbsQueueCell,
+
+    // Wi-Fi access points observed together with the cells
+    #[strum(serialize = "cell:wifi")]
+    CellWifi,
+
+    // Opted-out access points waiting for the sweep of the cell index and the co-occurrence graph
+    #[strum(serialize = "ssid:optout")]
+    SsidOptOut,
+
+    // Idempotency keys of submitted reports
+    #[strum(serialize = "report:idempotency")]
+    ReportIdempotency,
+
     // Whoosh scooters
     #[strum(serialize = "device:whoosh")]
     DeviceWhoosh,
@@ -67,6 +130,12 @@ pub enum Collection {
     BaLbsYandexWifi,
     #[strum(serialize = "lbs_yandex_cell")]
     BaLbsYandexCell,
+    #[strum(serialize = "lbs_altergeo_wifi")]
+    BaLbsAlterGeoWifi,
+    #[strum(serialize = "lbs_ichnaea_wifi")]
+    BaLbsIchnaeaWifi,
+    #[strum(serialize = "lbs_ichnaea_cell")]
+    BaLbsIchnaeaCell,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Display, EnumString, IntoStaticStr, AsRefStr)]
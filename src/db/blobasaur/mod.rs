@@ -44,6 +44,16 @@ pub async fn set_ba_wifi_one(
     exec_cmd(tx_ba_conn, cmd_arg).await
 }
 
+// namespace (hash) = whoosh_wifi
+pub async fn del_ba_wifi_one(
+    tx_ba_conn: flume::Sender<BAConnectionManageMessage>,
+    namespace: &str,
+    mac: &str,
+) -> Result<(), RedisError> {
+    let cmd_arg = redis::cmd("HDEL").arg(namespace).arg(mac).to_owned();
+    exec_cmd(tx_ba_conn, cmd_arg).await
+}
+
 // namespace (hash) = whoosh_wifi
 pub async fn get_ba_wifi_one(
     tx_ba_conn: flume::Sender<BAConnectionManageMessage>,
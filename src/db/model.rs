@@ -22,6 +22,7 @@ pub enum Transmitter {
     #[strum(to_string = "{mac}")]
     Wifi {
         mac: String,
+        ssid: Option<String>,
         signal_strength: Option<f64>,
         age: Option<i64>,
     },
@@ -88,4 +89,11 @@ impl Transmitter {
             Transmitter::Bluetooth { age, .. } => age,
         }
     }
+
+    pub fn ssid(&self) -> Option<&str> {
+        match self {
+            Transmitter::Wifi { ssid, .. } => ssid.as_deref(),
+            _ => None,
+        }
+    }
 }
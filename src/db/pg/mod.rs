@@ -2,6 +2,7 @@
 
 pub mod bulk_insert;
 pub mod pool;
+pub mod query;
 pub mod transmitter;
 
 use anyhow::anyhow;
@@ -245,41 +246,6 @@ pub async fn detach_partition(
     Ok(())
 }
 
-pub async fn get_reports_by_range_id(
-    client: &Client,
-    ids: &[u64],
-) -> Result<Vec<Report>, tokio_postgres::Error> {
-    if ids.len() != 2 {
-        error!("ids array length must be 2");
-        return Ok(vec![]);
-    }
-    if ids[0] > ids[1] {
-        error!("min ID report must be less than max ID");
-        return Ok(vec![]);
-    }
-    let query = format!(
-        "SELECT
-            id,
-            raw,
-            user_agent
-        FROM report
-        WHERE
-            id >= {} AND id <= {}
-        ",
-        ids[0], ids[1]
-    );
-    let statement = client.prepare(&query).await?;
-
-    let reports = client
-        .query(&statement, &[])
-        .await?
-        .iter()
-        .map(|row| Report::from_row_ref(row).unwrap())
-        .collect::<Vec<Report>>();
-
-    Ok(reports)
-}
-
 pub async fn get_required_reports(
     client: &Client,
     ids: &[u64],
@@ -389,6 +355,53 @@ pub async fn get_reports(
     Ok(reports)
 }
 
+/// Page of the processed reports of the partition ordered by id, used for reprocessing
+pub async fn get_processed_reports_by_partition(
+    client: &Client,
+    partition: &str,
+    geo_fence: Option<&GeoFence>,
+    after_id: i64,
+    limit: u32,
+) -> Result<Vec<Report>, tokio_postgres::Error> {
+    let mut geo_fence_condition = String::new();
+    if let Some(gf) = geo_fence
+        && gf.validate()
+    {
+        geo_fence_condition = format!(
+            "latitude >= {} AND
+            latitude <= {} AND
+            longitude >= {} AND
+            longitude <= {} AND",
+            gf.lat_min, gf.lat_max, gf.lon_min, gf.lon_max,
+        );
+    }
+    let query = format!(
+        "SELECT
+            id,
+            raw,
+            user_agent
+        FROM {}
+        WHERE
+            id > {} AND
+            {}
+            processed_at IS NOT NULL AND
+            processing_error IS NULL
+        ORDER BY id
+        LIMIT {}",
+        partition, after_id, geo_fence_condition, limit,
+    );
+    let statement = client.prepare(&query).await?;
+
+    let reports = client
+        .query(&statement, &[])
+        .await?
+        .iter()
+        .map(|row| Report::from_row_ref(row).unwrap())
+        .collect::<Vec<Report>>();
+
+    Ok(reports)
+}
+
 fn presaved_partitions(n: u16, now: &DateTime<Utc>) -> Vec<String> {
     let current_year = now.year();
     let current_month = now.month();
@@ -657,15 +670,18 @@ pub async fn insert_cell(
 ) -> Result<bool, anyhow::Error> {
     let statement = transaction
         .prepare("
-            INSERT INTO cell (radio, country, network, area, cell, unit, min_lat, min_lon, max_lat, max_lon, lat, lon, accuracy, total_weight, min_strength, max_strength)
-            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16)
+            INSERT INTO cell (radio, country, network, area, cell, unit, min_lat, min_lon, max_lat, max_lon, lat, lon, accuracy, total_weight, min_strength, max_strength, first_seen, last_seen, observation_count)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19)
             ON CONFLICT (radio, country, network, area, cell, unit)
             DO UPDATE SET min_lat = EXCLUDED.min_lat, min_lon = EXCLUDED.min_lon, max_lat = EXCLUDED.max_lat, max_lon = EXCLUDED.max_lon, 
-            lat = EXCLUDED.lat, lon = EXCLUDED.lon, accuracy = EXCLUDED.accuracy, total_weight = EXCLUDED.total_weight, min_strength = EXCLUDED.min_strength, max_strength = EXCLUDED.max_strength
+            lat = EXCLUDED.lat, lon = EXCLUDED.lon, accuracy = EXCLUDED.accuracy, total_weight = EXCLUDED.total_weight, min_strength = EXCLUDED.min_strength, max_strength = EXCLUDED.max_strength,
+            first_seen = LEAST(cell.first_seen, EXCLUDED.first_seen), last_seen = GREATEST(cell.last_seen, EXCLUDED.last_seen), observation_count = EXCLUDED.observation_count, stale = false
             ",
         )
         .await?;
 
+    let (first_seen, last_seen) = seen_at(&tl);
+    let observation_count = tl.observation_count as i32;
     let result = transaction
         .execute(
             &statement,
@@ -686,6 +702,9 @@ pub async fn insert_cell(
                 &tl.total_weight,
                 &tl.min_strength,
                 &tl.max_strength,
+                &first_seen,
+                &last_seen,
+                &observation_count,
             ],
         )
         .await?;
@@ -703,14 +722,17 @@ pub async fn insert_bluetooth(
 ) -> Result<bool, anyhow::Error> {
     let statement = transaction
         .prepare("
-            INSERT INTO bluetooth (mac, min_lat, min_lon, max_lat, max_lon, lat, lon, accuracy, total_weight, min_strength, max_strength)
-            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            INSERT INTO bluetooth (mac, min_lat, min_lon, max_lat, max_lon, lat, lon, accuracy, total_weight, min_strength, max_strength, first_seen, last_seen, observation_count)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)
             ON CONFLICT (mac)
             DO UPDATE SET min_lat = EXCLUDED.min_lat, min_lon = EXCLUDED.min_lon, max_lat = EXCLUDED.max_lat, max_lon = EXCLUDED.max_lon,
-            lat = EXCLUDED.lat, lon = EXCLUDED.lon, accuracy = EXCLUDED.accuracy, total_weight = EXCLUDED.total_weight, min_strength = EXCLUDED.min_strength, max_strength = EXCLUDED.max_strength",
+            lat = EXCLUDED.lat, lon = EXCLUDED.lon, accuracy = EXCLUDED.accuracy, total_weight = EXCLUDED.total_weight, min_strength = EXCLUDED.min_strength, max_strength = EXCLUDED.max_strength,
+            first_seen = LEAST(bluetooth.first_seen, EXCLUDED.first_seen), last_seen = GREATEST(bluetooth.last_seen, EXCLUDED.last_seen), observation_count = EXCLUDED.observation_count, stale = false",
         )
         .await?;
 
+    let (first_seen, last_seen) = seen_at(&tl);
+    let observation_count = tl.observation_count as i32;
     let result = transaction
         .execute(
             &statement,
@@ -726,6 +748,9 @@ pub async fn insert_bluetooth(
                 &tl.total_weight,
                 &tl.min_strength,
                 &tl.max_strength,
+                &first_seen,
+                &last_seen,
+                &observation_count,
             ],
         )
         .await?;
@@ -736,6 +761,92 @@ pub async fn insert_bluetooth(
     }
 }
 
+fn seen_at(tl: &TransmitterLocation) -> (Option<DateTime<Utc>>, Option<DateTime<Utc>>) {
+    (
+        tl.first_seen.and_then(DateTime::from_timestamp_millis),
+        tl.last_seen.and_then(DateTime::from_timestamp_millis),
+    )
+}
+
+/// Metadata of the cell or the bluetooth beacon for the admin lookups
+#[derive(Debug, Serialize)]
+pub struct TransmitterInfo {
+    pub lat: f64,
+    pub lon: f64,
+    pub accuracy: f64,
+    pub first_seen: Option<DateTime<Utc>>,
+    pub last_seen: Option<DateTime<Utc>>,
+    pub observation_count: i32,
+    pub stale: bool,
+}
+
+impl From<Row> for TransmitterInfo {
+    fn from(row: Row) -> Self {
+        Self {
+            lat: row.get("lat"),
+            lon: row.get("lon"),
+            accuracy: row.get("accuracy"),
+            first_seen: row.get("first_seen"),
+            last_seen: row.get("last_seen"),
+            observation_count: row.get("observation_count"),
+            stale: row.get("stale"),
+        }
+    }
+}
+
+pub async fn get_cell_info(
+    client: &Client,
+    radio: i16,
+    country: i16,
+    network: i16,
+    area: i32,
+    cell: i64,
+    unit: i16,
+) -> Result<Option<TransmitterInfo>, anyhow::Error> {
+    let row = client
+        .query_opt(
+            "SELECT lat, lon, accuracy, first_seen, last_seen, observation_count, stale FROM cell
+            WHERE radio = $1 AND country = $2 AND network = $3 AND area = $4 AND cell = $5 AND unit = $6",
+            &[&radio, &country, &network, &area, &cell, &unit],
+        )
+        .await?;
+    Ok(row.map(TransmitterInfo::from))
+}
+
+pub async fn get_bluetooth_info(
+    client: &Client,
+    mac: &str,
+) -> Result<Option<TransmitterInfo>, anyhow::Error> {
+    let row = client
+        .query_opt(
+            "SELECT lat, lon, accuracy, first_seen, last_seen, observation_count, stale FROM bluetooth WHERE mac = $1",
+            &[&mac],
+        )
+        .await?;
+    Ok(row.map(TransmitterInfo::from))
+}
+
+/// Remove the cells or the bluetooth beacons not seen since `cutoff`,
+/// transmitters saved before the tracking of observations are kept
+pub async fn remove_stale_transmitters(
+    client: &Client,
+    table: &str,
+    cutoff: DateTime<Utc>,
+) -> Result<u64, anyhow::Error> {
+    let query = format!("DELETE FROM {table} WHERE last_seen < $1");
+    Ok(client.execute(&query, &[&cutoff]).await?)
+}
+
+/// Mark the cells or the bluetooth beacons not seen since `cutoff` as stale
+pub async fn demote_stale_transmitters(
+    client: &Client,
+    table: &str,
+    cutoff: DateTime<Utc>,
+) -> Result<u64, anyhow::Error> {
+    let query = format!("UPDATE {table} SET stale = true WHERE last_seen < $1 AND NOT stale");
+    Ok(client.execute(&query, &[&cutoff]).await?)
+}
+
 pub async fn insert_h3(
     transaction: &Transaction<'_>,
     h3: CellIndex,
@@ -0,0 +1,385 @@
+use std::{fmt::Display, str::FromStr};
+
+use anyhow::anyhow;
+use chrono::{DateTime, Duration, NaiveDate, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio_pg_mapper::FromTokioPostgresRow;
+use tokio_pg_mapper_derive::PostgresMapper;
+use tokio_postgres::{Transaction, types::ToSql};
+
+use super::{AttachedPartition, get_report_attached_partitions};
+use crate::services::submission::report::GeoFence;
+
+pub const DEFAULT_SEARCH_LIMIT: u32 = 1000;
+pub const MAX_SEARCH_LIMIT: u32 = 10000;
+
+// raw report is stored as serialized geosubmit::Report
+const RAW_JSONB: &str = "convert_from(raw, 'UTF8')::jsonb";
+
+type QueryParam = Box<dyn ToSql + Sync + Send>;
+
+/// Filters of the report search, all conditions are combined with AND
+#[derive(Deserialize, Debug, Default)]
+pub struct ReportFilter {
+    pub device_id: Option<String>,
+    /// time of report submission (partition key)
+    pub submitted_from: Option<DateTime<Utc>>,
+    pub submitted_to: Option<DateTime<Utc>>,
+    /// time of measurement on the device
+    pub measured_from: Option<DateTime<Utc>>,
+    pub measured_to: Option<DateTime<Utc>>,
+    pub geo_fence: Option<GeoFence>,
+    pub has_wifi: Option<bool>,
+    pub has_cell: Option<bool>,
+    pub processed: Option<bool>,
+    pub has_error: Option<bool>,
+    /// substring of the processing error
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, PostgresMapper)]
+#[pg_mapper(table = "report")]
+pub struct ReportRecord {
+    pub id: i64,
+    pub submitted_at: DateTime<Utc>,
+    pub processed_at: Option<DateTime<Utc>>,
+    pub processing_error: Option<String>,
+    pub user_agent: Option<String>,
+    pub raw: Vec<u8>,
+}
+
+/// Position of the last returned report: partition and report ID inside it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReportCursor {
+    pub partition: String,
+    pub id: i64,
+}
+
+impl Display for ReportCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.partition, self.id)
+    }
+}
+
+impl FromStr for ReportCursor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (partition, id) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("invalid cursor: {}", s))?;
+        // partition name is substituted into the query text, so only valid names are accepted
+        partition_date(partition)?;
+        let id = id
+            .parse::<i64>()
+            .map_err(|e| anyhow!("invalid cursor id {}: {}", id, e))?;
+        Ok(Self {
+            partition: partition.to_string(),
+            id,
+        })
+    }
+}
+
+fn partition_date(partition: &str) -> Result<DateTime<Utc>, anyhow::Error> {
+    partition
+        .strip_prefix("report_")
+        .and_then(|d| NaiveDate::parse_from_str(d, "%Y_%m_%d").ok())
+        .map(|d| d.and_time(NaiveTime::MIN).and_utc())
+        .ok_or_else(|| anyhow!("invalid partition: {}", partition))
+}
+
+/// Builder of the report search query for a single daily partition
+pub struct ReportQuery<'a> {
+    filter: &'a ReportFilter,
+    limit: u32,
+    conditions: Vec<String>,
+    params: Vec<QueryParam>,
+}
+
+impl<'a> ReportQuery<'a> {
+    pub fn new(filter: &'a ReportFilter) -> Self {
+        Self {
+            filter,
+            limit: DEFAULT_SEARCH_LIMIT,
+            conditions: Vec::new(),
+            params: Vec::new(),
+        }
+    }
+
+    pub fn limit(mut self, limit: u32) -> Self {
+        self.limit = limit.clamp(1, MAX_SEARCH_LIMIT);
+        self
+    }
+
+    fn param(&mut self, condition: &str, value: QueryParam) {
+        self.params.push(value);
+        self.conditions
+            .push(format!("{} ${}", condition, self.params.len()));
+    }
+
+    /// Return query text and its parameters, `after_id` continues the partition after the cursor
+    pub fn build(mut self, partition: &str, after_id: Option<i64>) -> (String, Vec<QueryParam>) {
+        let filter = self.filter;
+
+        if let Some(id) = after_id {
+            self.param("id >", Box::new(id));
+        }
+        if let Some(device_id) = filter.device_id.as_ref() {
+            self.param(
+                &format!("{} ->> 'deviceId' =", RAW_JSONB),
+                Box::new(device_id.clone()),
+            );
+        }
+        if let Some(from) = filter.submitted_from {
+            self.param("submitted_at >=", Box::new(from));
+        }
+        if let Some(to) = filter.submitted_to {
+            self.param("submitted_at <", Box::new(to));
+        }
+        if let Some(from) = filter.measured_from {
+            self.param("timestamp >=", Box::new(from));
+        }
+        if let Some(to) = filter.measured_to {
+            self.param("timestamp <", Box::new(to));
+        }
+        if let Some(gf) = filter.geo_fence.as_ref()
+            && gf.validate()
+        {
+            self.param("latitude >=", Box::new(gf.lat_min));
+            self.param("latitude <=", Box::new(gf.lat_max));
+            self.param("longitude >=", Box::new(gf.lon_min));
+            self.param("longitude <=", Box::new(gf.lon_max));
+        }
+        if let Some(has_wifi) = filter.has_wifi {
+            self.conditions
+                .push(not_empty_array_condition("wifiAccessPoints", has_wifi));
+        }
+        if let Some(has_cell) = filter.has_cell {
+            let condition = format!(
+                "({} OR jsonb_typeof({} -> 'cell') = 'object')",
+                not_empty_array_condition("cellTowers", true),
+                RAW_JSONB
+            );
+            if has_cell {
+                self.conditions.push(condition);
+            } else {
+                self.conditions.push(format!("NOT {}", condition));
+            }
+        }
+        if let Some(processed) = filter.processed {
+            self.conditions.push(if processed {
+                "processed_at IS NOT NULL".to_string()
+            } else {
+                "processed_at IS NULL".to_string()
+            });
+        }
+        if let Some(has_error) = filter.has_error {
+            self.conditions.push(if has_error {
+                "processing_error IS NOT NULL".to_string()
+            } else {
+                "processing_error IS NULL".to_string()
+            });
+        }
+        if let Some(error) = filter.error.as_ref() {
+            self.param("processing_error ILIKE", Box::new(format!("%{}%", error)));
+        }
+
+        let where_clause = if self.conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", self.conditions.join(" AND "))
+        };
+
+        let query = format!(
+            "SELECT
+                id,
+                submitted_at,
+                processed_at,
+                processing_error,
+                user_agent,
+                raw
+            FROM {}
+            {}
+            ORDER BY id
+            LIMIT {}",
+            partition, where_clause, self.limit,
+        );
+
+        (query, self.params)
+    }
+}
+
+fn not_empty_array_condition(field: &str, not_empty: bool) -> String {
+    format!(
+        "(CASE jsonb_typeof({0} -> '{1}') WHEN 'array' THEN jsonb_array_length({0} -> '{1}') ELSE 0 END {2} 0)",
+        RAW_JSONB,
+        field,
+        if not_empty { ">" } else { "=" }
+    )
+}
+
+/// Partitions to scan in ascending order, limited by the submission range and the cursor
+pub fn partitions_to_scan(
+    attached_partitions: &[AttachedPartition],
+    filter: &ReportFilter,
+    cursor: Option<&ReportCursor>,
+) -> Vec<String> {
+    let cursor_date = cursor.and_then(|c| partition_date(&c.partition).ok());
+
+    let mut partitions = attached_partitions
+        .iter()
+        .filter_map(|ap| ap.date().ok().map(|date| (date, ap.child.clone())))
+        .filter(|(date, _)| {
+            // partition contains reports submitted within [date, date + 1 day)
+            if let Some(from) = filter.submitted_from
+                && *date + Duration::days(1) <= from
+            {
+                return false;
+            }
+            if let Some(to) = filter.submitted_to
+                && *date >= to
+            {
+                return false;
+            }
+            if let Some(cd) = cursor_date
+                && *date < cd
+            {
+                return false;
+            }
+            true
+        })
+        .collect::<Vec<_>>();
+
+    partitions.sort_by(|a, b| a.0.cmp(&b.0));
+    partitions.into_iter().map(|(_, p)| p).collect()
+}
+
+/// Search reports across daily partitions, returns found reports and the cursor for the next page
+pub async fn search_reports(
+    transaction: &Transaction<'_>,
+    filter: &ReportFilter,
+    cursor: Option<&ReportCursor>,
+    limit: u32,
+) -> Result<(Vec<ReportRecord>, Option<ReportCursor>), anyhow::Error> {
+    let limit = limit.clamp(1, MAX_SEARCH_LIMIT);
+    let attached_partitions = get_report_attached_partitions(transaction).await?;
+    let partitions = partitions_to_scan(&attached_partitions, filter, cursor);
+
+    let mut records = Vec::new();
+    let mut next_cursor = None;
+
+    for partition in partitions {
+        let remaining = limit - records.len() as u32;
+        let after_id = cursor.filter(|c| c.partition == partition).map(|c| c.id);
+
+        let (query, params) = ReportQuery::new(filter)
+            .limit(remaining)
+            .build(&partition, after_id);
+        let params_ref = params
+            .iter()
+            .map(|p| p.as_ref() as &(dyn ToSql + Sync))
+            .collect::<Vec<_>>();
+
+        let statement = transaction.prepare(&query).await?;
+        let mut rs = transaction
+            .query(&statement, &params_ref)
+            .await?
+            .iter()
+            .map(ReportRecord::from_row_ref)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if let Some(last) = rs.last() {
+            next_cursor = Some(ReportCursor {
+                partition: partition.clone(),
+                id: last.id,
+            });
+        }
+        records.append(&mut rs);
+
+        if records.len() as u32 >= limit {
+            return Ok((records, next_cursor));
+        }
+    }
+
+    // all partitions are scanned
+    Ok((records, None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attached(child: &str) -> AttachedPartition {
+        AttachedPartition {
+            parent_schema: "public".to_string(),
+            parent: "report".to_string(),
+            child_schema: "public".to_string(),
+            child: child.to_string(),
+        }
+    }
+
+    #[test]
+    fn report_cursor_parse() {
+        let cursor = "report_2025_10_18:12345".parse::<ReportCursor>().unwrap();
+        assert_eq!(cursor.partition, "report_2025_10_18");
+        assert_eq!(cursor.id, 12345);
+        assert_eq!(cursor.to_string(), "report_2025_10_18:12345");
+
+        assert!("report_2025_10_18".parse::<ReportCursor>().is_err());
+        assert!("report_2025_10_18:abc".parse::<ReportCursor>().is_err());
+        assert!("wifi; DROP TABLE report:1".parse::<ReportCursor>().is_err());
+    }
+
+    #[test]
+    fn report_query_build() {
+        let filter = ReportFilter {
+            device_id: Some("868172073967398".to_string()),
+            has_wifi: Some(true),
+            processed: Some(false),
+            ..Default::default()
+        };
+        let (query, params) = ReportQuery::new(&filter)
+            .limit(50)
+            .build("report_2025_10_18", Some(10));
+
+        assert_eq!(params.len(), 2);
+        assert!(query.contains("FROM report_2025_10_18"));
+        assert!(query.contains("id > $1"));
+        assert!(query.contains("->> 'deviceId' = $2"));
+        assert!(query.contains("processed_at IS NULL"));
+        assert!(query.contains("LIMIT 50"));
+    }
+
+    #[test]
+    fn partitions_by_range_and_cursor() {
+        let aps = vec![
+            attached("report_2025_10_19"),
+            attached("report_2025_10_17"),
+            attached("report_2025_10_18"),
+            attached("report_2025_10_20"),
+        ];
+
+        let filter = ReportFilter {
+            submitted_from: "2025-10-17T12:00:00Z".parse().ok(),
+            submitted_to: "2025-10-20T00:00:00Z".parse().ok(),
+            ..Default::default()
+        };
+        assert_eq!(
+            partitions_to_scan(&aps, &filter, None),
+            vec![
+                "report_2025_10_17",
+                "report_2025_10_18",
+                "report_2025_10_19"
+            ]
+        );
+
+        let cursor = ReportCursor {
+            partition: "report_2025_10_18".to_string(),
+            id: 100,
+        };
+        assert_eq!(
+            partitions_to_scan(&aps, &filter, Some(&cursor)),
+            vec!["report_2025_10_18", "report_2025_10_19"]
+        );
+    }
+}
@@ -1,10 +1,15 @@
+use std::collections::BTreeMap;
+
 use geo::{Distance, Haversine, Point};
-use log::error;
+use log::{error, info};
 use redis::{FromRedisValue, ParsingError};
 use serde::{Deserialize, Serialize};
-use tokio_pg_mapper_derive::PostgresMapper;
 
-use crate::{CONFIG, db::t38::REDIS_NO_DATA};
+use crate::{
+    CONFIG,
+    db::{model::Transmitter, t38::REDIS_NO_DATA},
+    services::helper::decay,
+};
 
 macro_rules! not_convertible_error {
     ($v:expr, $det:expr) => {
@@ -12,8 +17,7 @@ macro_rules! not_convertible_error {
     };
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, PostgresMapper)]
-#[pg_mapper(table = "wifi")]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TransmitterLocation {
     pub mac: String,
     pub min_lat: f64,
@@ -29,6 +33,233 @@ pub struct TransmitterLocation {
     pub min_strength: f64,
     pub max_strength: f64,
     pub measurements: Option<Vec<u8>>,
+
+    /// time of the last observation in the current location, milliseconds
+    #[serde(default)]
+    pub updated_at: Option<i64>,
+    /// observations far from the current location, possible new location of a relocated transmitter
+    #[serde(default)]
+    pub candidate: Option<LocationCandidate>,
+
+    /// reason to consider the transmitter mobile, mobile transmitters are neither aggregated nor used for locate
+    #[serde(default)]
+    pub mobile: Option<MobileReason>,
+    /// position of the last observation
+    #[serde(default)]
+    pub last_observation: Option<ObservedAt>,
+    /// location of the serving cell in the last observation
+    #[serde(default)]
+    pub last_cell: Option<ObservedAt>,
+    /// contradicting observations not outweighed by the consistent ones, at most `mobile_observations`
+    #[serde(default)]
+    pub mobility: u32,
+
+    /// number of observations, including the ones outside of the current location
+    #[serde(default)]
+    pub observation_count: u32,
+    /// time of the first observation, milliseconds
+    #[serde(default)]
+    pub first_seen: Option<i64>,
+    /// time of the last observation, milliseconds
+    #[serde(default)]
+    pub last_seen: Option<i64>,
+    /// not seen within the retention period, stale transmitters are not used for locate
+    #[serde(default)]
+    pub stale: bool,
+    /// trust of the location, used as a multiplicative weight in locate
+    #[serde(default)]
+    pub trust: Option<Trust>,
+    /// physical access point of the virtual BSSID, see `virtual_ap::group_key`
+    #[serde(default)]
+    pub virtual_group: Option<String>,
+    /// access points observed together with this one, see `cooccurrence::record`
+    #[serde(default)]
+    pub cooccurrence: Option<Cooccurrence>,
+}
+
+/// Agreement of the transmitter location with the LBS location of the access point and the serving cell
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct Trust {
+    pub agreements: u32,
+    pub contradictions: u32,
+    /// product of the observation count, spread and agreement factors, 0..1
+    pub score: f64,
+}
+
+/// Edges of the co-occurrence graph: the time-decayed number of reports with both access points
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Cooccurrence {
+    /// time the weights were decayed to, milliseconds
+    pub updated_at: i64,
+    pub peers: BTreeMap<String, f32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, strum_macros::Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum MobileReason {
+    /// SSID matches the patterns of hotspots
+    Ssid,
+    /// observed at distant GNSS positions within a short time
+    Gnss,
+    /// observed together with distant cells
+    Cell,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct ObservedAt {
+    pub lat: f64,
+    pub lon: f64,
+    /// milliseconds
+    pub timestamp: i64,
+}
+
+impl ObservedAt {
+    pub fn distance(&self, other: &ObservedAt) -> f64 {
+        Haversine::distance(
+            Point::new(self.lon, self.lat),
+            Point::new(other.lon, other.lat),
+        )
+    }
+}
+
+/// Cluster of observations outside of the current location of the transmitter
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LocationCandidate {
+    pub min_lat: f64,
+    pub min_lon: f64,
+    pub max_lat: f64,
+    pub max_lon: f64,
+
+    pub lat: f64,
+    pub lon: f64,
+    pub accuracy: f64,
+    pub total_weight: f64,
+
+    pub min_strength: f64,
+    pub max_strength: f64,
+
+    pub observations: u32,
+    pub first_seen: i64,
+    pub last_seen: i64,
+}
+
+impl LocationCandidate {
+    fn new(lat: f64, lon: f64, accuracy: f64, weight: f64, strength: f64, timestamp: i64) -> Self {
+        Self {
+            min_lat: lat,
+            min_lon: lon,
+            max_lat: lat,
+            max_lon: lon,
+            lat,
+            lon,
+            accuracy,
+            total_weight: weight,
+            min_strength: strength,
+            max_strength: strength,
+            observations: 1,
+            first_seen: timestamp,
+            last_seen: timestamp,
+        }
+    }
+
+    fn update(
+        &mut self,
+        params: &AggregationParams,
+        lat: f64,
+        lon: f64,
+        accuracy: f64,
+        weight: f64,
+        strength: f64,
+        timestamp: i64,
+    ) {
+        let (total_weight, weight) =
+            decay(params, self.total_weight, self.last_seen, weight, timestamp);
+        self.total_weight = total_weight;
+
+        self.min_lat = self.min_lat.min(lat);
+        self.max_lat = self.max_lat.max(lat);
+        self.min_lon = self.min_lon.min(lon);
+        self.max_lon = self.max_lon.max(lon);
+
+        self.lat = ((self.lat * self.total_weight) + (lat * weight)) / (self.total_weight + weight);
+        self.lon = ((self.lon * self.total_weight) + (lon * weight)) / (self.total_weight + weight);
+        self.accuracy = ((self.accuracy * self.total_weight) + (accuracy * weight))
+            / (self.total_weight + weight);
+        self.total_weight += weight;
+
+        self.min_strength = self.min_strength.min(strength);
+        self.max_strength = self.max_strength.max(strength);
+
+        self.observations += 1;
+        self.first_seen = self.first_seen.min(timestamp);
+        self.last_seen = self.last_seen.max(timestamp);
+    }
+
+    fn distance(&self, lat: f64, lon: f64) -> f64 {
+        Haversine::distance(Point::new(self.lon, self.lat), Point::new(lon, lat))
+    }
+
+    /// A distant observation replaces the candidate only if the decayed candidate weighs less
+    /// or it has not been observed for `candidate_ttl` days, a stray point is dropped otherwise
+    fn is_replaced_by(&self, params: &AggregationParams, weight: f64, timestamp: i64) -> bool {
+        let ttl = (params.candidate_ttl * decay::DAY as f64) as i64;
+        if ttl > 0 && timestamp - self.last_seen > ttl {
+            return true;
+        }
+        let (total_weight, weight) =
+            decay(params, self.total_weight, self.last_seen, weight, timestamp);
+        total_weight < weight
+    }
+}
+
+/// Parameters of the aggregation of observations
+#[derive(Debug, Clone, Copy)]
+pub struct AggregationParams {
+    /// observations further than this distance from the location form a candidate location, meters
+    pub radius: f64,
+    /// the weight of an observation halves every `half_life` days, 0 disables the decay
+    pub half_life: f64,
+    /// number of observations required to accept the candidate location
+    pub min_observations: u32,
+    /// days since the last observation of the candidate location to replace it, 0 disables
+    pub candidate_ttl: f64,
+}
+
+impl AggregationParams {
+    pub fn from_config() -> Self {
+        Self {
+            radius: CONFIG.locator.radius_wifi_detection,
+            half_life: CONFIG.locator.aggregation_half_life,
+            min_observations: CONFIG.locator.relocation_min_observations,
+            candidate_ttl: CONFIG.locator.relocation_candidate_ttl,
+        }
+    }
+
+    /// Cells cover kilometres, their observations are never split into a candidate location
+    pub fn for_transmitter(transmitter: &Transmitter) -> Self {
+        match transmitter {
+            Transmitter::Cell { .. } => Self {
+                radius: f64::MAX,
+                ..Self::from_config()
+            },
+            Transmitter::Wifi { .. } | Transmitter::Bluetooth { .. } => Self::from_config(),
+        }
+    }
+}
+
+/// Exponential time decay.
+/// Returns the total weight decayed to the newest of the two timestamps and the weight of the new observation.
+fn decay(
+    params: &AggregationParams,
+    total_weight: f64,
+    updated_at: i64,
+    weight: f64,
+    timestamp: i64,
+) -> (f64, f64) {
+    let half_life = decay::half_life(params.half_life);
+    let (factor, weight) = decay::to_latest(half_life, updated_at, weight, timestamp);
+    (total_weight * factor, weight)
 }
 
 impl FromRedisValue for TransmitterLocation {
@@ -62,7 +293,15 @@ impl FromRedisValue for TransmitterLocation {
 
 impl TransmitterLocation {
     /// Create a new `TransmitterLocation` struct around a single point.
-    pub fn new(mac: &str, lat: f64, lon: f64, accuracy: f64, weight: f64, strength: f64) -> Self {
+    pub fn new(
+        mac: &str,
+        lat: f64,
+        lon: f64,
+        accuracy: f64,
+        weight: f64,
+        strength: f64,
+        timestamp: i64,
+    ) -> Self {
         Self {
             mac: mac.to_string(),
             min_lat: lat,
@@ -78,9 +317,54 @@ impl TransmitterLocation {
             min_strength: strength,
             max_strength: strength,
             measurements: None,
+
+            updated_at: Some(timestamp),
+            candidate: None,
+
+            mobile: None,
+            last_observation: None,
+            last_cell: None,
+            mobility: 0,
+
+            observation_count: 1,
+            first_seen: Some(timestamp),
+            last_seen: Some(timestamp),
+            stale: false,
+            trust: None,
+            virtual_group: None,
+            cooccurrence: None,
         }
     }
 
+    /// Take over the location of another BSSID of the same physical access point
+    pub fn adopt(&mut self, sibling: &TransmitterLocation) {
+        self.min_lat = sibling.min_lat;
+        self.min_lon = sibling.min_lon;
+        self.max_lat = sibling.max_lat;
+        self.max_lon = sibling.max_lon;
+        self.lat = sibling.lat;
+        self.lon = sibling.lon;
+        self.accuracy = sibling.accuracy;
+        self.total_weight = sibling.total_weight;
+        self.min_strength = sibling.min_strength;
+        self.max_strength = sibling.max_strength;
+        self.updated_at = sibling.updated_at;
+        self.candidate = sibling.candidate.clone();
+        self.trust = sibling.trust;
+    }
+
+    /// Register the observation of the transmitter, including mobile ones
+    pub fn seen(&mut self, timestamp: i64) {
+        self.observation_count = self.observation_count.saturating_add(1);
+        self.first_seen = Some(self.first_seen.map_or(timestamp, |t| t.min(timestamp)));
+        self.last_seen = Some(self.last_seen.map_or(timestamp, |t| t.max(timestamp)));
+        self.stale = false;
+    }
+
+    pub fn is_mobile(&self) -> bool {
+        self.mobile.is_some()
+    }
+
     /// Return the bottom left and the top right point of the rectangle.
     pub fn points(&self) -> (Point, Point) {
         let min = Point::new(self.min_lon, self.min_lat);
@@ -88,8 +372,46 @@ impl TransmitterLocation {
         (min, max)
     }
 
-    /// Add new data to the weighted average
-    pub fn update(&mut self, lat: f64, lon: f64, accuracy: f64, weight: f64, strength: f64) {
+    /// Add new data to the time-decayed weighted average
+    pub fn update(
+        &mut self,
+        transmitter: &Transmitter,
+        lat: f64,
+        lon: f64,
+        accuracy: f64,
+        weight: f64,
+        strength: f64,
+        timestamp: i64,
+    ) {
+        let params = AggregationParams::for_transmitter(transmitter);
+        self.update_with(&params, lat, lon, accuracy, weight, strength, timestamp);
+    }
+
+    /// Observations further than `params.radius` from the current location are collected
+    /// into the candidate location, which replaces the current one once it becomes stable.
+    pub fn update_with(
+        &mut self,
+        params: &AggregationParams,
+        lat: f64,
+        lon: f64,
+        accuracy: f64,
+        mut weight: f64,
+        strength: f64,
+        timestamp: i64,
+    ) {
+        let distance = Haversine::distance(Point::new(self.lon, self.lat), Point::new(lon, lat));
+        if distance > params.radius {
+            self.update_candidate(params, lat, lon, accuracy, weight, strength, timestamp);
+            return;
+        }
+
+        // aggregates saved before the time decay have no timestamp
+        if let Some(updated_at) = self.updated_at {
+            (self.total_weight, weight) =
+                decay(params, self.total_weight, updated_at, weight, timestamp);
+        }
+        self.updated_at = Some(self.updated_at.unwrap_or(timestamp).max(timestamp));
+
         if lat < self.min_lat {
             self.min_lat = lat;
         } else if lat > self.max_lat {
@@ -116,27 +438,133 @@ impl TransmitterLocation {
         }
     }
 
+    fn update_candidate(
+        &mut self,
+        params: &AggregationParams,
+        lat: f64,
+        lon: f64,
+        accuracy: f64,
+        weight: f64,
+        strength: f64,
+        timestamp: i64,
+    ) {
+        match self.candidate.as_mut() {
+            Some(c) if c.distance(lat, lon) <= params.radius => {
+                c.update(params, lat, lon, accuracy, weight, strength, timestamp);
+            }
+            Some(c) if !c.is_replaced_by(params, weight, timestamp) => return,
+            // the first observation of a new cluster replaces the previous candidate
+            _ => {
+                self.candidate = Some(LocationCandidate::new(
+                    lat, lon, accuracy, weight, strength, timestamp,
+                ));
+            }
+        }
+
+        if self.is_relocated(params) {
+            self.relocate();
+        }
+    }
+
+    /// The candidate location is stable: it is confirmed by enough observations and either
+    /// the transmitter has not been seen in the current location since the candidate appeared,
+    /// or the candidate outweighs the decayed current location.
+    fn is_relocated(&self, params: &AggregationParams) -> bool {
+        let Some(c) = self.candidate.as_ref() else {
+            return false;
+        };
+        if c.observations < params.min_observations {
+            return false;
+        }
+        let updated_at = self.updated_at.unwrap_or(i64::MIN);
+        let (total_weight, _) = decay(
+            params,
+            self.total_weight,
+            updated_at.max(0),
+            0.0,
+            c.last_seen,
+        );
+        c.first_seen > updated_at || c.total_weight > total_weight
+    }
+
+    /// Reset the aggregate to the candidate location
+    fn relocate(&mut self) {
+        let Some(c) = self.candidate.take() else {
+            return;
+        };
+        info!(
+            "transmitter {} relocated to ({}, {}), {} observations",
+            self.mac, c.lat, c.lon, c.observations
+        );
+        self.min_lat = c.min_lat;
+        self.min_lon = c.min_lon;
+        self.max_lat = c.max_lat;
+        self.max_lon = c.max_lon;
+        self.lat = c.lat;
+        self.lon = c.lon;
+        self.accuracy = c.accuracy;
+        self.total_weight = c.total_weight;
+        self.min_strength = c.min_strength;
+        self.max_strength = c.max_strength;
+        self.updated_at = Some(c.last_seen);
+        // the agreement was assessed for the previous location
+        self.trust = None;
+        self.cooccurrence = None;
+    }
+
     /// Check the validity of the new access point coordinates in terms of GPS signal accuracy (spoofing)
     /// Based on old accuracy algorithm (bounding box) as weighted
     /// average "accuracy" data can't detect moving AP
     pub fn valid(&self) -> bool {
+        self.valid_with(&AggregationParams::from_config())
+    }
+
+    pub fn valid_with(&self, params: &AggregationParams) -> bool {
         let (min, max) = self.points();
         let center = (min + max) / 2.0;
         let distance = Haversine::distance(min, center);
-        (0.0..=CONFIG.locator.radius_wifi_detection).contains(&distance)
+        (0.0..=params.radius).contains(&distance)
+    }
+}
+
+#[cfg(test)]
+impl TransmitterLocation {
+    /// Access point observed once at time 0, the fixture of the tests
+    pub fn fixture_at(lat: f64, lon: f64) -> Self {
+        Self::new("aa:bb:cc:dd:ee:01", lat, lon, 20.0, 1.0, -70.0, 0)
+    }
+
+    pub fn fixture() -> Self {
+        Self::fixture_at(55.75, 37.62)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::services::helper::decay::DAY;
+
+    // no decay and no relocation
+    const PLAIN: AggregationParams = AggregationParams {
+        radius: f64::MAX,
+        half_life: 0.0,
+        min_observations: u32::MAX,
+        candidate_ttl: 0.0,
+    };
+
+    const DECAYED: AggregationParams = AggregationParams {
+        radius: 150.0,
+        half_life: 30.0,
+        min_observations: 3,
+        candidate_ttl: 7.0,
+    };
 
     #[test]
     fn test_transmitter_location_update() {
         // Values were chosen so all floats are rounds, to be easier to test
         let mut location =
-            TransmitterLocation::new("11::22::33::44::55::66", 0.0, 0.0, 20.0, 1.0, -72.0);
-        location.update(1.8, 0.9, 5.0, 2.0, -56.0);
+            TransmitterLocation::new("11::22::33::44::55::66", 0.0, 0.0, 20.0, 1.0, -72.0, 0);
+        location.update_with(&PLAIN, 1.8, 0.9, 5.0, 2.0, -56.0, 0);
 
         assert_eq!(location.max_lat, 1.8);
         assert_eq!(location.max_lon, 0.9);
@@ -147,7 +575,7 @@ mod tests {
         assert_eq!(location.accuracy, 10.0);
         assert_eq!(location.total_weight, 3.0);
 
-        location.update(-7.2, -4.5, 5.0, 2.0, -76.0);
+        location.update_with(&PLAIN, -7.2, -4.5, 5.0, 2.0, -76.0, 0);
 
         assert_eq!(location.max_lat, 1.8);
         assert_eq!(location.max_lon, 0.9);
@@ -158,4 +586,67 @@ mod tests {
         assert_eq!(location.accuracy, 8.0);
         assert_eq!(location.total_weight, 5.0);
     }
+
+    #[test]
+    fn test_transmitter_location_decay() {
+        let mut location = TransmitterLocation::new("1", 0.0, 0.0, 20.0, 1.0, -72.0, 0);
+        // one half-life later the old weight is halved
+        location.update_with(&DECAYED, 0.0, 0.001, 20.0, 0.5, -72.0, 30 * DAY);
+
+        assert_eq!(location.total_weight, 1.0);
+        assert_eq!(location.lon, 0.0005);
+        assert_eq!(location.updated_at, Some(30 * DAY));
+
+        // an old observation is decayed to the time of the aggregate
+        location.update_with(&DECAYED, 0.0, 0.0, 20.0, 2.0, -72.0, 0);
+        assert_eq!(location.total_weight, 2.0);
+        assert_eq!(location.updated_at, Some(30 * DAY));
+    }
+
+    #[test]
+    fn test_transmitter_location_relocation() {
+        let mut location = TransmitterLocation::new("1", 55.75, 37.62, 20.0, 1.0, -72.0, 0);
+        location.update_with(&DECAYED, 55.7501, 37.62, 20.0, 1.0, -72.0, DAY);
+        assert!(location.valid_with(&DECAYED));
+
+        // single outlier does not affect the location
+        let lat = location.lat;
+        location.update_with(&DECAYED, 56.0, 38.0, 20.0, 1.0, -72.0, 2 * DAY);
+        assert_eq!(location.lat, lat);
+        assert!(location.candidate.is_some());
+
+        // the access point was moved ~1 km away
+        for i in 0..3 {
+            location.update_with(&DECAYED, 55.76, 37.62, 20.0, 1.0, -72.0, (10 + i) * DAY);
+        }
+
+        assert!(location.candidate.is_none());
+        assert!((location.lat - 55.76).abs() < 1e-9);
+        assert_eq!(location.max_lat, 55.76);
+        assert_eq!(location.min_lat, 55.76);
+        assert_eq!(location.updated_at, Some(12 * DAY));
+        assert!(location.valid_with(&DECAYED));
+    }
+
+    #[test]
+    fn test_transmitter_location_relocation_stray() {
+        let mut location = TransmitterLocation::new("1", 55.75, 37.62, 20.0, 1.0, -72.0, 0);
+
+        // the access point was moved ~1 km away, one report in the middle is far from both
+        location.update_with(&DECAYED, 55.76, 37.62, 20.0, 1.0, -72.0, 10 * DAY);
+        location.update_with(&DECAYED, 55.76, 37.62, 20.0, 1.0, -72.0, 11 * DAY);
+        location.update_with(&DECAYED, 56.0, 38.0, 20.0, 1.0, -72.0, 11 * DAY + DAY / 2);
+        let candidate = location.candidate.as_ref().unwrap();
+        assert_eq!(candidate.observations, 2);
+        assert!((candidate.lat - 55.76).abs() < 1e-9);
+
+        location.update_with(&DECAYED, 55.76, 37.62, 20.0, 1.0, -72.0, 12 * DAY);
+        assert!(location.candidate.is_none());
+        assert!((location.lat - 55.76).abs() < 1e-9);
+
+        // the candidate not observed for longer than `candidate_ttl` is replaced
+        location.update_with(&DECAYED, 55.75, 37.62, 20.0, 3.0, -72.0, 13 * DAY);
+        location.update_with(&DECAYED, 56.0, 38.0, 20.0, 1.0, -72.0, 21 * DAY);
+        assert!((location.candidate.as_ref().unwrap().lat - 56.0).abs() < 1e-9);
+    }
 }
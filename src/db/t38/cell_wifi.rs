@@ -0,0 +1,86 @@
+use std::collections::BTreeMap;
+
+use log::error;
+use redis::RedisError;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::t38::{
+        ERROR_ID_NOT_FOUND, ERROR_KEY_NOT_FOUND,
+        cmd::{exec_cmd, query_cmd},
+    },
+    tasks::t38::T38ConnectionManageMessage,
+};
+
+/// Access points observed together with the cell
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CellWifi {
+    /// `{radio}:{mcc}:{mnc}:{lac}:{cid}`
+    pub code: String,
+    /// centroid of the positions the access points were observed at
+    pub lat: f64,
+    pub lon: f64,
+    /// mean distance of the observations from the centroid, meters
+    pub accuracy: f64,
+    /// time-decayed number of the reports
+    pub weight: f64,
+    /// milliseconds
+    pub updated_at: i64,
+    /// time-decayed number of the reports by MAC address
+    pub aps: BTreeMap<String, f32>,
+}
+
+// collection = "cell:wifi"
+pub async fn set_cell_wifi_one(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    collection: &str,
+    cell_wifi: &CellWifi,
+) -> Result<(), RedisError> {
+    let cell_wifi_bytes = serde_json::to_vec(cell_wifi).unwrap();
+    let cmd_arg = redis::cmd("JSET")
+        .arg(collection)
+        .arg(&cell_wifi.code)
+        .arg("data")
+        .arg(cell_wifi_bytes)
+        .to_owned();
+    exec_cmd(tx_t38_conn, cmd_arg).await
+}
+
+// collection = "cell:wifi"
+pub async fn get_cell_wifi_one(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    collection: &str,
+    cell_code: &str,
+) -> Result<Option<CellWifi>, RedisError> {
+    let cmd_arg = redis::cmd("JGET")
+        .arg(collection)
+        .arg(cell_code)
+        .arg("data")
+        .to_owned();
+    match query_cmd(tx_t38_conn, cmd_arg).await {
+        Err(e) => {
+            let e_str = e.to_string();
+            if e_str.contains(ERROR_ID_NOT_FOUND) || e_str.contains(ERROR_KEY_NOT_FOUND) {
+                return Ok(None);
+            }
+            error!("get cell '{}' wifi data: {}", cell_code, e);
+            Err(e)
+        }
+        Ok(value) => {
+            // crud.go, row 972, return empty bulk-string:
+            // return resp.StringValue(""), nil
+            // alternative for ID_NOT_FOUND_ERROR
+            if value.is_empty() {
+                return Ok(None);
+            }
+
+            match serde_json::from_slice::<CellWifi>(&value) {
+                Err(e) => {
+                    error!("deserialize cell '{}' wifi data: {}", cell_code, e);
+                    Ok(None)
+                }
+                Ok(cell_wifi) => Ok(Some(cell_wifi)),
+            }
+        }
+    }
+}
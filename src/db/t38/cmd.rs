@@ -1,7 +1,7 @@
 use std::{io, time::Duration};
 
 use log::error;
-use redis::{FromRedisValue, RedisError};
+use redis::{FromRedisValue, RedisError, aio::MultiplexedConnection};
 
 use super::{ERROR_ID_NOT_FOUND, ERROR_KEY_NOT_FOUND, REDIS_NO_DATA};
 use crate::tasks::t38::{T38ConnectionManageMessage, get_connection, get_connection_service};
@@ -246,6 +246,27 @@ pub async fn exec_pipeline(
     Ok(())
 }
 
+/// Tile38 connection of the pipeline
+#[derive(Debug, Clone, Copy)]
+enum Connection {
+    Master,
+    Service,
+}
+
+impl Connection {
+    async fn get(
+        self,
+        tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+        error: Option<String>,
+    ) -> Result<MultiplexedConnection, RedisError> {
+        let connection = match self {
+            Connection::Master => get_connection(tx_t38_conn, error).await,
+            Connection::Service => get_connection_service(tx_t38_conn, error).await,
+        };
+        connection.map_err(|e| io::Error::new(io::ErrorKind::NotConnected, e).into())
+    }
+}
+
 pub async fn query_pipeline<T>(
     tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
     pipeline: redis::Pipeline,
@@ -253,7 +274,28 @@ pub async fn query_pipeline<T>(
 where
     T: serde::de::DeserializeOwned + FromRedisValue,
 {
-    let mut connection = get_connection(tx_t38_conn.clone(), None).await.unwrap();
+    query_pipeline_with(Connection::Master, tx_t38_conn, pipeline).await
+}
+
+pub async fn query_pipeline_service<T>(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    pipeline: redis::Pipeline,
+) -> Result<Vec<Option<T>>, RedisError>
+where
+    T: serde::de::DeserializeOwned + FromRedisValue,
+{
+    query_pipeline_with(Connection::Service, tx_t38_conn, pipeline).await
+}
+
+async fn query_pipeline_with<T>(
+    conn: Connection,
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    pipeline: redis::Pipeline,
+) -> Result<Vec<Option<T>>, RedisError>
+where
+    T: serde::de::DeserializeOwned + FromRedisValue,
+{
+    let mut connection = conn.get(tx_t38_conn.clone(), None).await?;
     let mut i: u16 = 0;
 
     loop {
@@ -288,10 +330,9 @@ where
 
                 tokio::time::sleep(Duration::from_secs(TIMEOUT)).await;
 
-                match get_connection(tx_t38_conn.clone(), Some(e.to_string())).await {
+                match conn.get(tx_t38_conn.clone(), Some(e.to_string())).await {
                     Err(e) => {
                         error!("get_connecton: {}", e);
-                        continue;
                     }
                     Ok(c) => {
                         connection = c;
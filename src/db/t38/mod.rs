@@ -1,23 +1,33 @@
+pub mod cell_wifi;
 pub mod cmd;
+pub mod queue;
 pub mod scan;
 pub mod track;
 
-use std::time::Duration;
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
 
 use log::error;
 use redis::{
-    AsyncConnectionConfig, Client, FromRedisValue, RedisError, aio::MultiplexedConnection,
+    AsyncConnectionConfig, Client, FromRedisValue, ParsingError, RedisError,
+    aio::MultiplexedConnection,
 };
+use serde::{Deserialize, de::DeserializeOwned};
 
 use crate::{
     db::{
         pg::transmitter::TransmitterLocation,
-        t38::cmd::{exec_cmd_service, query_cmd_service},
+        t38::cmd::{exec_cmd_service, query_cmd_service, query_pipeline_service},
+    },
+    lbs::{
+        cache,
+        yandex::wifi::{YandexData, YandexLbsResponse, YandexWifiMissing},
     },
-    lbs::yandex::wifi::{YandexLbsResponse, YandexWifiMissing},
     tasks::t38::T38ConnectionManageMessage,
 };
-use cmd::{exec_cmd, query_cmd, query_pipeline};
+use cmd::{exec_cmd, exec_pipeline, query_cmd, query_pipeline};
 
 const TIMEOUT: u64 = 1;
 // loading the big AOF file into memory may take a long time
@@ -36,6 +46,33 @@ pub struct T38Node {
     pub client: Client,
 }
 
+/// Object saved by `JSET {collection} {id} data {json}` and read by `GETPIPE`:
+/// `{"data":"{json}"}`, or `{"data":{json}}` if saved as RAW
+#[derive(Debug, Clone, Deserialize)]
+pub struct JsonData<T>(pub T);
+
+impl<T: DeserializeOwned> FromRedisValue for JsonData<T> {
+    fn from_redis_value(v: redis::Value) -> Result<JsonData<T>, ParsingError> {
+        let redis::Value::BulkString(ref bulk_string) = v else {
+            return Err(ParsingError::from(REDIS_NO_DATA));
+        };
+        // Tile38 pipe sends an empty string when there is no data
+        if bulk_string.is_empty() {
+            return Err(ParsingError::from(REDIS_NO_DATA));
+        }
+
+        let object: serde_json::Value = serde_json::from_slice(bulk_string)
+            .map_err(|e| ParsingError::from(format!("{} (response was {:?})", e, v)))?;
+        let data = match object.get("data") {
+            Some(serde_json::Value::String(data)) => serde_json::from_str::<T>(data),
+            Some(data) => serde_json::from_value::<T>(data.clone()),
+            None => return Err(ParsingError::from(REDIS_NO_DATA)),
+        };
+        data.map(JsonData)
+            .map_err(|e| ParsingError::from(format!("{} (response was {:?})", e, v)))
+    }
+}
+
 pub fn t38_client(host: &str, port: u16) -> Result<redis::Client, RedisError> {
     let host = format!("redis://{}:{}/", host, port);
     redis::Client::open(host)
@@ -157,6 +194,33 @@ pub async fn set_wifi_one(
     exec_cmd(tx_t38_conn, cmd_arg).await
 }
 
+// collection = wifi
+pub async fn set_wifi_many(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    collection: &str,
+    tls: &[TransmitterLocation],
+) -> Result<(), RedisError> {
+    let mut pipeline = redis::pipe();
+    for tl in tls {
+        let tl_bytes = serde_json::to_vec(&tl).unwrap();
+        pipeline
+            .cmd("SET")
+            .arg(collection)
+            .arg(&tl.mac)
+            .arg("field")
+            .arg("mac")
+            .arg(&tl.mac)
+            .arg("field")
+            .arg("data")
+            .arg(tl_bytes)
+            .arg("POINT")
+            .arg(tl.lon) // longitude
+            .arg(tl.lat) // latitude
+            .ignore();
+    }
+    exec_pipeline(tx_t38_conn, pipeline).await
+}
+
 pub async fn fget_wifi_many_from_pipeline<T>(
     tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
     collection: &str,
@@ -276,7 +340,7 @@ pub async fn set_yandex_lbs_wifi_one(
     mac: &str,
 ) -> Result<(), RedisError> {
     let ylr_bytes = serde_json::to_vec(yandex_lbs_response).unwrap();
-    let cmd_arg = redis::cmd("SET")
+    let mut cmd_arg = redis::cmd("SET")
         .arg(collection)
         .arg(mac)
         .arg("field")
@@ -285,10 +349,14 @@ pub async fn set_yandex_lbs_wifi_one(
         .arg("field")
         .arg("data")
         .arg(ylr_bytes)
+        .to_owned();
+    if let Some(ttl) = cache::ttl(collection) {
+        cmd_arg.arg("EX").arg(ttl);
+    }
+    cmd_arg
         .arg("POINT")
         .arg(yandex_lbs_response.location.point.lon) // longitude
-        .arg(yandex_lbs_response.location.point.lat) // latitude
-        .to_owned();
+        .arg(yandex_lbs_response.location.point.lat); // latitude
     exec_cmd(tx_t38_conn, cmd_arg).await
 }
 
@@ -327,6 +395,21 @@ pub async fn get_yandex_lbs_wifi_one(
     }
 }
 
+// collection = "lbs:yandex:wifi"
+// one round-trip for all access points, the missing in the cache are absent in the result
+pub async fn get_yandex_lbs_wifi_many(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    collection: &str,
+    macs: &[&str],
+) -> Result<HashMap<String, YandexLbsResponse>, RedisError> {
+    if macs.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let yandex_data_vec =
+        get_wifi_many_from_pipeline::<YandexData>(tx_t38_conn, collection, macs).await?;
+    Ok(YandexData::batch(yandex_data_vec))
+}
+
 // collection = "lbs:yandex:cell"
 pub async fn set_yandex_lbs_cell_one(
     tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
@@ -341,7 +424,17 @@ pub async fn set_yandex_lbs_cell_one(
         .arg("data")
         .arg(ylr_bytes)
         .to_owned();
-    exec_cmd(tx_t38_conn, cmd_arg).await
+    exec_cmd(tx_t38_conn.clone(), cmd_arg).await?;
+    // JSET has no EX option
+    if let Some(ttl) = cache::ttl(collection) {
+        let cmd_arg = redis::cmd("EXPIRE")
+            .arg(collection)
+            .arg(cell_code)
+            .arg(ttl)
+            .to_owned();
+        exec_cmd(tx_t38_conn, cmd_arg).await?;
+    }
+    Ok(())
 }
 
 // collection = "lbs:yandex:wifi"
@@ -399,7 +492,39 @@ pub async fn get_yandex_lbs_cell_one(
     }
 }
 
-// collection = "lbs:yandex:wifi:missing"
+// collection = "lbs:yandex:cell"
+// one round-trip for all cells, the missing in the cache are absent in the result
+pub async fn get_yandex_lbs_cell_many(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    collection: &str,
+    cell_codes: &[&str],
+) -> Result<HashMap<String, YandexLbsResponse>, RedisError> {
+    if cell_codes.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let mut pipeline = redis::pipe();
+    for cell_code in cell_codes {
+        pipeline.cmd("GETPIPE").arg(collection).arg(cell_code);
+    }
+    match query_pipeline::<JsonData<YandexLbsResponse>>(tx_t38_conn, pipeline).await {
+        Err(e) => {
+            let e_str = e.to_string();
+            if e_str.contains(ERROR_ID_NOT_FOUND) || e_str.contains(ERROR_KEY_NOT_FOUND) {
+                return Ok(HashMap::new());
+            }
+            error!("get Yandex LBS cells from pipeline: {}", e);
+            Err(e)
+        }
+        // the replies are in the order of the commands
+        Ok(objects) => Ok(cell_codes
+            .iter()
+            .zip(objects)
+            .filter_map(|(cell_code, data)| data.map(|d| (cell_code.to_string(), d.0)))
+            .collect()),
+    }
+}
+
+// collection = "lbs:yandex:wifi:missing", "lbs:yandex:cell:missing"
 pub async fn set_yandex_lbs_wifi_missing_one(
     tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
     collection: &str,
@@ -412,10 +537,20 @@ pub async fn set_yandex_lbs_wifi_missing_one(
         .arg("data")
         .arg(ylr_bytes)
         .to_owned();
-    exec_cmd_service(tx_t38_conn, cmd_arg).await
+    exec_cmd_service(tx_t38_conn.clone(), cmd_arg).await?;
+    // JSET has no EX option
+    if let Some(ttl) = cache::ttl(collection) {
+        let cmd_arg = redis::cmd("EXPIRE")
+            .arg(collection)
+            .arg(&ywm.mac)
+            .arg(ttl)
+            .to_owned();
+        exec_cmd_service(tx_t38_conn, cmd_arg).await?;
+    }
+    Ok(())
 }
 
-// collection = "lbs:yandex:wifi:missing"
+// collection = "lbs:yandex:wifi:missing", "lbs:yandex:cell:missing"
 pub async fn get_yandex_lbs_wifi_missing_one(
     tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
     collection: &str,
@@ -445,11 +580,152 @@ pub async fn get_yandex_lbs_wifi_missing_one(
 
             let ywm_str = String::from_utf8(value).unwrap();
             let ywm: YandexWifiMissing = serde_json::from_str(&ywm_str).unwrap();
+            // saved before the TTL was configured
+            if cache::is_missing_expired(&ywm.ts) {
+                return Ok(None);
+            }
             Ok(Some(ywm))
         }
     }
 }
 
+// collection = "lbs:yandex:wifi:missing", "lbs:yandex:cell:missing"
+// one round-trip for all access points or cells, returns the ones previously missing in Yandex
+pub async fn get_yandex_lbs_wifi_missing_many(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    collection: &str,
+    macs: &[&str],
+) -> Result<HashSet<String>, RedisError> {
+    if macs.is_empty() {
+        return Ok(HashSet::new());
+    }
+    let mut pipeline = redis::pipe();
+    for mac in macs {
+        pipeline.cmd("GETPIPE").arg(collection).arg(mac);
+    }
+    match query_pipeline_service::<JsonData<YandexWifiMissing>>(tx_t38_conn, pipeline).await {
+        Err(e) => {
+            let e_str = e.to_string();
+            if e_str.contains(ERROR_ID_NOT_FOUND) || e_str.contains(ERROR_KEY_NOT_FOUND) {
+                return Ok(HashSet::new());
+            }
+            error!("get Yandex LBS missing data from service pipeline: {}", e);
+            Err(e)
+        }
+        Ok(objects) => Ok(objects
+            .into_iter()
+            .flatten()
+            .filter(|d| !cache::is_missing_expired(&d.0.ts))
+            .map(|d| d.0.mac)
+            .collect()),
+    }
+}
+
+// remaining time to live of the objects in seconds, -1 if the object does not expire
+pub async fn get_ttl_many(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    collection: &str,
+    ids: &[&str],
+) -> Result<Vec<Option<f64>>, RedisError> {
+    if ids.is_empty() {
+        return Ok(vec![]);
+    }
+    let mut pipeline = redis::pipe();
+    for id in ids {
+        pipeline.cmd("TTL").arg(collection).arg(id);
+    }
+    match query_pipeline::<f64>(tx_t38_conn, pipeline).await {
+        Err(e) => {
+            error!("get TTL from pipeline: {}", e);
+            Err(e)
+        }
+        Ok(ttls) => Ok(ttls),
+    }
+}
+
+// collection = "report:idempotency"
+// registered keys within the dedupe window, GETPIPE returns an empty string for the missing ones
+pub async fn get_idempotency_keys(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    collection: &str,
+    keys: &[String],
+) -> Result<Vec<bool>, RedisError> {
+    if keys.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut pipeline = redis::pipe();
+    for key in keys {
+        pipeline.cmd("GETPIPE").arg(collection).arg(key);
+    }
+    let values = query_pipeline::<String>(tx_t38_conn, pipeline).await?;
+    Ok(values
+        .into_iter()
+        .map(|value| value.is_some_and(|v| !v.is_empty()))
+        .collect())
+}
+
+// collection = "report:idempotency"
+// the keys already registered keep their expiration
+pub async fn set_idempotency_keys(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    collection: &str,
+    keys: &[String],
+    ttl: u64,
+) -> Result<(), RedisError> {
+    if keys.is_empty() {
+        return Ok(());
+    }
+    let mut pipeline = redis::pipe();
+    for key in keys {
+        pipeline
+            .cmd("SET")
+            .arg(collection)
+            .arg(key)
+            .arg("EX")
+            .arg(ttl)
+            .arg("NX")
+            .arg("STRING")
+            .arg("1");
+    }
+    exec_pipeline(tx_t38_conn, pipeline).await
+}
+
+// collection = "ssid:optout"
+pub async fn set_opt_out_one(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    collection: &str,
+    mac: &str,
+) -> Result<(), RedisError> {
+    let cmd_arg = redis::cmd("SET")
+        .arg(collection)
+        .arg(mac)
+        .arg("STRING")
+        .arg("1")
+        .to_owned();
+    exec_cmd(tx_t38_conn, cmd_arg).await
+}
+
+pub async fn drop_collection(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    collection: &str,
+) -> Result<(), RedisError> {
+    let cmd_arg = redis::cmd("DROP").arg(collection).to_owned();
+    exec_cmd(tx_t38_conn, cmd_arg).await
+}
+
+// replaces the destination collection atomically
+pub async fn rename_collection(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    collection: &str,
+    new_collection: &str,
+) -> Result<(), RedisError> {
+    let cmd_arg = redis::cmd("RENAME")
+        .arg(collection)
+        .arg(new_collection)
+        .to_owned();
+    exec_cmd(tx_t38_conn, cmd_arg).await
+}
+
 // very slow responses from Tile38
 pub async fn _get_wifi_many(
     mut connection: MultiplexedConnection,
@@ -533,3 +809,33 @@ pub async fn _get_wifi_many(
 
     Ok(tls)
 }
+
+#[cfg(test)]
+mod tests {
+    use redis::{FromRedisValue, Value};
+
+    use super::JsonData;
+    use crate::lbs::yandex::wifi::{YandexLbsResponse, YandexWifiMissing};
+
+    #[test]
+    fn json_data_from_pipe() {
+        let value = Value::BulkString(
+            br#"{"data":"{\"location\":{\"point\":{\"lat\":55.75,\"lon\":37.62},\"accuracy\":50.0}}"}"#
+                .to_vec(),
+        );
+        let data = JsonData::<YandexLbsResponse>::from_redis_value(value).unwrap();
+        assert_eq!(data.0.location.point.lat, 55.75);
+
+        // saved as RAW
+        let value = Value::BulkString(
+            br#"{"data":{"mac":"00:02:6f:aa:a3:97","ts":"01-06-2025 12:00"}}"#.to_vec(),
+        );
+        let data = JsonData::<YandexWifiMissing>::from_redis_value(value).unwrap();
+        assert_eq!(data.0.mac, "00:02:6f:aa:a3:97");
+
+        // Tile38 pipe sends an empty string when there is no data
+        assert!(
+            JsonData::<YandexWifiMissing>::from_redis_value(Value::BulkString(vec![])).is_err()
+        );
+    }
+}
@@ -0,0 +1,88 @@
+use log::error;
+use redis::RedisError;
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{
+    db::t38::{
+        ERROR_ID_NOT_FOUND, ERROR_KEY_NOT_FOUND,
+        cmd::{exec_cmd, query_cmd},
+    },
+    tasks::t38::T38ConnectionManageMessage,
+};
+
+// collection = "lbs:queue:wifi", "lbs:queue:cell"
+// the transmitter is queued once: the repeated push replaces the measurement
+pub async fn push_queue_one<T: Serialize>(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    collection: &str,
+    id: &str,
+    item: &T,
+) -> Result<(), RedisError> {
+    let item_bytes = serde_json::to_vec(item).unwrap();
+    let cmd_arg = redis::cmd("JSET")
+        .arg(collection)
+        .arg(id)
+        .arg("data")
+        .arg(item_bytes)
+        .to_owned();
+    exec_cmd(tx_t38_conn, cmd_arg).await
+}
+
+// collection = "lbs:queue:wifi", "lbs:queue:cell"
+pub async fn get_queue_one<T: DeserializeOwned>(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    collection: &str,
+    id: &str,
+) -> Result<Option<T>, RedisError> {
+    let cmd_arg = redis::cmd("JGET")
+        .arg(collection)
+        .arg(id)
+        .arg("data")
+        .to_owned();
+    match query_cmd(tx_t38_conn, cmd_arg).await {
+        Err(e) => {
+            let e_str = e.to_string();
+            if e_str.contains(ERROR_ID_NOT_FOUND) || e_str.contains(ERROR_KEY_NOT_FOUND) {
+                return Ok(None);
+            }
+            error!("get '{}' from queue '{}': {}", id, collection, e);
+            Err(e)
+        }
+        Ok(value) => {
+            // crud.go, row 972, return empty bulk-string:
+            // return resp.StringValue(""), nil
+            // alternative for ID_NOT_FOUND_ERROR
+            if value.is_empty() {
+                return Ok(None);
+            }
+
+            match serde_json::from_slice::<T>(&value) {
+                Err(e) => {
+                    error!("deserialize '{}' from queue '{}': {}", id, collection, e);
+                    Ok(None)
+                }
+                Ok(item) => Ok(Some(item)),
+            }
+        }
+    }
+}
+
+// collection = "lbs:queue:wifi", "lbs:queue:cell"
+pub async fn del_queue_one(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    collection: &str,
+    id: &str,
+) -> Result<(), RedisError> {
+    let cmd_arg = redis::cmd("DEL").arg(collection).arg(id).to_owned();
+    match exec_cmd(tx_t38_conn, cmd_arg).await {
+        Err(e) => {
+            let e_str = e.to_string();
+            if e_str.contains(ERROR_ID_NOT_FOUND) || e_str.contains(ERROR_KEY_NOT_FOUND) {
+                return Ok(());
+            }
+            error!("del '{}' from queue '{}': {}", id, collection, e);
+            Err(e)
+        }
+        Ok(_) => Ok(()),
+    }
+}
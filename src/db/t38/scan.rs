@@ -670,6 +670,40 @@ pub async fn scan_ids(
     }
 }
 
+// page of ids starting from the cursor, returns the next cursor (0 at the end of the collection)
+pub async fn scan_ids_page(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    collection: &str,
+    cursor: u64,
+    limit: u64,
+) -> Result<(u64, Vec<String>), RedisError> {
+    let cmd_arg = redis::cmd("SCAN")
+        .arg(collection)
+        .arg("CURSOR")
+        .arg(cursor)
+        .arg("LIMIT")
+        .arg(limit)
+        .arg("IDS")
+        .to_owned();
+
+    let mut connection = get_connection(tx_t38_conn.clone(), None).await.unwrap();
+    match cmd_arg
+        .query_async::<(u64, Vec<String>)>(&mut connection)
+        .await
+    {
+        Err(e) => {
+            if e.to_string().contains(ERROR_ID_NOT_FOUND)
+                || e.to_string().contains(ERROR_KEY_NOT_FOUND)
+            {
+                // empty collection
+                return Ok((0, vec![]));
+            }
+            Err(e)
+        }
+        Ok(page) => Ok(page),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{
@@ -36,6 +36,8 @@ pub enum ApiError {
     LbsError(u16),
     #[error("LBS request error")]
     LbsRequestError(),
+    #[error("{0} is unavailable")]
+    UpstreamUnavailable(&'static str),
 }
 
 impl ApiError {
@@ -52,6 +54,7 @@ impl ApiError {
             Self::RedisParsingError(_) => "Redis parsing error",
             Self::LbsError(_) => "LBS error",
             Self::LbsRequestError() => "LBS request error",
+            Self::UpstreamUnavailable(_) => "Upstream unavailable",
         }
     }
 }
@@ -65,6 +68,7 @@ impl ResponseError for ApiError {
             Self::Tile38Error(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::TimestampParseFailed(_) => StatusCode::BAD_REQUEST,
             Self::LbsError(code) => StatusCode::from_u16(code).unwrap(),
+            Self::UpstreamUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -1,12 +1,49 @@
-#![allow(unused)]
+//! AlterGeo LBS: one request locates the device by the list of access points. The access points
+//! missing in the cache are requested together, the location of the device is attributed to each
+//! of them with the precision of the response.
+//!
+//! The responses are cached in `lbs:altergeo:wifi` (and Blobasaur `lbs_altergeo_wifi`), the access
+//! points unknown to AlterGeo in `lbs:altergeo:wifi:missing`. After a key or quota error the
+//! provider is not requested until the next day.
 
+use std::sync::Mutex;
+
+use chrono::NaiveDate;
+use log::{error, info, warn};
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 
 use crate::{
     CONFIG,
-    lbs::{http_client::HttpClient, yandex::wifi::WifiMeasurement},
+    constants::Collection,
+    db::{
+        blobasaur::set_ba_lbs_yandex_wifi_one,
+        t38::{
+            get_yandex_lbs_wifi_many, get_yandex_lbs_wifi_missing_many,
+            set_yandex_lbs_wifi_missing_one, set_yandex_lbs_wifi_one,
+        },
+    },
+    error::ApiError,
+    lbs::{
+        http_client::HttpClient,
+        model::CellMeasurement,
+        provider::{LbsContext, LbsProvider, LbsResponses, Quota},
+        yandex::wifi::{
+            WifiMeasurement, YandexLbsResponse, YandexLocation, YandexPoint, YandexWifiMissing,
+        },
+    },
+    services::helper::circuit_breaker::BREAKERS,
 };
 
+// error codes of the getlocation API
+const AG_INVALID_REQUEST: u8 = 1;
+const AG_INVALID_APIKEY: u8 = 2;
+const AG_LIMIT_EXCEEDED: u8 = 3;
+const AG_NOT_FOUND: u8 = 4;
+
+/// Date (Moscow) the key or the quota was rejected on
+static BLOCKED_ON: Lazy<Mutex<Option<NaiveDate>>> = Lazy::new(|| Mutex::new(None));
+
 #[derive(Debug, Clone, Deserialize, Serialize, Default)]
 pub struct AlterGeoLbsResponse {
     pub iamhere: Option<IamHere>,
@@ -26,61 +63,304 @@ pub struct AgError {
     pub message: String,
 }
 
+impl AgError {
+    /// HTTP status of the error, `None` if the location is not found
+    pub fn status(&self) -> Option<u16> {
+        match self.code {
+            AG_NOT_FOUND => None,
+            AG_INVALID_REQUEST => Some(400),
+            AG_INVALID_APIKEY | AG_LIMIT_EXCEEDED => Some(403),
+            _ => Some(502),
+        }
+    }
+}
+
+impl AlterGeoLbsResponse {
+    /// Location of the response, `None` if AlterGeo does not know the access points
+    pub fn location(self) -> Result<Option<YandexLbsResponse>, ApiError> {
+        if let Some(e) = self.error {
+            return match e.status() {
+                None => Ok(None),
+                Some(status) => {
+                    info!("AlterGeo error {}: {}", e.code, e.message);
+                    Err(ApiError::LbsError(status))
+                }
+            };
+        }
+        Ok(self.iamhere.map(|iamhere| YandexLbsResponse {
+            location: YandexLocation {
+                point: YandexPoint {
+                    lat: iamhere.latitude,
+                    lon: iamhere.longitude,
+                },
+                accuracy: iamhere.precision,
+            },
+        }))
+    }
+}
+
+/// `rssi,mac;rssi,mac`, the MAC addresses without separators
+fn wifi_param(wms: &[WifiMeasurement]) -> String {
+    wms.iter()
+        .map(|w| format!("{},{}", w.signal_strength as i64, w.bssid.replace(':', "")))
+        .collect::<Vec<String>>()
+        .join(";")
+}
+
+pub async fn altergeo_request(
+    client: &HttpClient,
+    url: &str,
+    apikey: &str,
+    wms: &[WifiMeasurement],
+) -> Result<AlterGeoLbsResponse, ApiError> {
+    let wifi = wifi_param(wms);
+    let params = [
+        ("version", "3.0"),
+        ("apikey", apikey),
+        ("doctype", "json"),
+        ("wifi", wifi.as_str()),
+    ];
+    client.post_form(url, &params).await
+}
+
 pub async fn altergeo_lbs_request(
     wms: Vec<WifiMeasurement>,
     client: HttpClient,
-) -> Result<AlterGeoLbsResponse, anyhow::Error> {
-    // let mut params: Vec<(&str, &str)> = Vec::new();
-    // params.push(("version", "3.0"));
-    // params.push(("apikey", &CONFIG.altergeo_lbs.apikey));
-    // params.push(("doctype", "json"));
-
-    // let mut wifi = String::new();
-    // wms.into_iter().for_each(|w| {
-    //     let mac = w.bssid.replace(":", "");
-    //     let rssi = w.signal_strength as i64;
-    //     let element = format!("{},{};", rssi, mac);
-    //     wifi.push_str(&element);
-    // });
-    // let wifi_param = wifi.strip_suffix(";").unwrap_or(&wifi);
-    // params.push(("wifi", wifi_param));
-
-    // let ag_lbs_response: AlterGeoLbsResponse = client
-    //     .post(&CONFIG.altergeo_lbs.url)
-    //     .form(&params)
-    //     .send()
-    //     .await?
-    //     .json()
-    //     .await?;
-    // Ok(ag_lbs_response)
-
-    Ok(AlterGeoLbsResponse::default())
+) -> Result<AlterGeoLbsResponse, ApiError> {
+    BREAKERS
+        .altergeo
+        .call(altergeo_request(
+            &client,
+            &CONFIG.altergeo_lbs.url,
+            &CONFIG.altergeo_lbs.apikey,
+            &wms,
+        ))
+        .await
+}
+
+fn today() -> NaiveDate {
+    chrono::Utc::now()
+        .with_timezone(&chrono_tz::Europe::Moscow)
+        .date_naive()
 }
 
+fn is_blocked() -> bool {
+    let blocked_on = BLOCKED_ON.lock().unwrap();
+    blocked_on.is_some_and(|date| date >= today())
+}
+
+fn block() {
+    info!("AlterGeo: the key is rejected until tomorrow");
+    *BLOCKED_ON.lock().unwrap() = Some(today());
+}
+
+async fn cache(ctx: &LbsContext, mac: &str, ylr_opt: Option<&YandexLbsResponse>) {
+    match ylr_opt {
+        Some(ylr) => {
+            let collection = Collection::LbsAlterGeoWifi.as_ref();
+            if let Err(e) =
+                set_yandex_lbs_wifi_one(ctx.tx_t38_conn.clone(), collection, ylr, mac).await
+            {
+                error!("save AlterGeo LBS response: {}", e);
+            }
+            if CONFIG.blobasaur.enabled {
+                let namespace = Collection::BaLbsAlterGeoWifi.as_ref();
+                if let Err(e) =
+                    set_ba_lbs_yandex_wifi_one(ctx.tx_ba_conn.clone(), namespace, ylr.clone(), mac)
+                        .await
+                {
+                    error!("save AlterGeo LBS response in blobasaur: {}", e);
+                }
+            }
+        }
+        None => {
+            let missing = YandexWifiMissing {
+                mac: mac.to_string(),
+                ts: chrono::Utc::now().format("%d-%m-%Y %H:%M").to_string(),
+            };
+            if let Err(e) = set_yandex_lbs_wifi_missing_one(
+                ctx.tx_t38_conn.clone(),
+                Collection::LbsAlterGeoWifiMissing.as_ref(),
+                missing,
+            )
+            .await
+            {
+                error!("set AlterGeo WiFi as missing: {}", e);
+            }
+        }
+    }
+}
+
+pub struct AlterGeoProvider;
+
+impl LbsProvider for AlterGeoProvider {
+    fn name(&self) -> &'static str {
+        "altergeo"
+    }
+
+    fn enabled(&self) -> bool {
+        CONFIG.altergeo_lbs.enabled
+    }
+
+    async fn wifi(
+        &self,
+        ctx: &LbsContext,
+        wms: &[WifiMeasurement],
+    ) -> Result<LbsResponses, ApiError> {
+        let mut lbs_responses = LbsResponses::with_capacity(wms.len());
+        if !CONFIG.altergeo_lbs.enabled {
+            return Ok(lbs_responses);
+        }
+
+        // a failed read of the cache is not a miss, the access points are requested
+        let macs = wms
+            .iter()
+            .map(|wm| wm.bssid.as_str())
+            .collect::<Vec<&str>>();
+        let collection = Collection::LbsAlterGeoWifi.as_ref();
+        match get_yandex_lbs_wifi_many(ctx.tx_t38_conn.clone(), collection, &macs).await {
+            Err(e) => error!("get AlterGeo LBS responses: {}", e),
+            Ok(ylrs) => lbs_responses.extend(ylrs.into_iter().map(|(mac, ylr)| (mac, Some(ylr)))),
+        }
+        let uncached = macs
+            .into_iter()
+            .filter(|mac| !lbs_responses.contains_key(*mac))
+            .collect::<Vec<&str>>();
+        let collection = Collection::LbsAlterGeoWifiMissing.as_ref();
+        match get_yandex_lbs_wifi_missing_many(ctx.tx_t38_conn.clone(), collection, &uncached).await
+        {
+            Err(e) => error!("get AlterGeo WiFi missing: {}", e),
+            Ok(missing) => lbs_responses.extend(missing.into_iter().map(|mac| (mac, None))),
+        }
+        let pending = wms
+            .iter()
+            .filter(|wm| !lbs_responses.contains_key(&wm.bssid))
+            .cloned()
+            .collect::<Vec<WifiMeasurement>>();
+        if pending.is_empty() {
+            return Ok(lbs_responses);
+        }
+
+        // the cached access points are returned even if the request fails
+        let located = if is_blocked() {
+            Err(ApiError::LbsError(403))
+        } else {
+            let response = altergeo_lbs_request(pending.clone(), ctx.client.clone()).await;
+            let location = response.and_then(AlterGeoLbsResponse::location);
+            if let Err(ApiError::LbsError(403)) = location {
+                block();
+            }
+            location
+        };
+        match located {
+            Err(e) if lbs_responses.is_empty() => return Err(e),
+            Err(e) => warn!(
+                "AlterGeo LBS request of {} access points: {}",
+                pending.len(),
+                e
+            ),
+            Ok(ylr_opt) => {
+                for wm in pending {
+                    cache(ctx, &wm.bssid, ylr_opt.as_ref()).await;
+                    lbs_responses.insert(wm.bssid, ylr_opt.clone());
+                }
+            }
+        }
+        Ok(lbs_responses)
+    }
+
+    async fn cell(
+        &self,
+        _ctx: &LbsContext,
+        _cms: &[CellMeasurement],
+    ) -> Result<LbsResponses, ApiError> {
+        // the cells are not requested
+        Ok(LbsResponses::new())
+    }
+
+    async fn combined(
+        &self,
+        ctx: &LbsContext,
+        wms: &[WifiMeasurement],
+        _cms: &[CellMeasurement],
+    ) -> Result<Option<YandexLbsResponse>, ApiError> {
+        if !CONFIG.altergeo_lbs.enabled || wms.is_empty() {
+            return Ok(None);
+        }
+        if is_blocked() {
+            return Err(ApiError::LbsError(403));
+        }
+        let response = altergeo_lbs_request(wms.to_vec(), ctx.client.clone()).await?;
+        let location = response.location();
+        if let Err(ApiError::LbsError(403)) = location {
+            block();
+        }
+        location
+    }
+
+    async fn quota(&self, _ctx: &LbsContext) -> Quota {
+        Quota {
+            available: CONFIG.altergeo_lbs.enabled && !is_blocked(),
+            remaining: None,
+        }
+    }
+}
+
+#[cfg(test)]
 mod tests {
-    use crate::{constants::HC, lbs::http_client::HttpClient};
+    use super::*;
+    use crate::{constants::HC, lbs::mock_server};
 
-    #[tokio::test]
-    async fn test_ag_lbs_request() {
-        let wms = vec![
-            super::WifiMeasurement {
+    fn mock_server(body: &'static str) -> (String, std::thread::JoinHandle<String>) {
+        let (address, handle) = mock_server::serve(200, body);
+        (format!("{}/getlocation", address), handle)
+    }
+
+    fn wms() -> Vec<WifiMeasurement> {
+        vec![
+            WifiMeasurement {
                 bssid: "ae:84:c6:a9:45:d2".to_string(),
                 signal_strength: -62.0,
             },
-            super::WifiMeasurement {
+            WifiMeasurement {
                 bssid: "00:a2:b0:8c:90:e5".to_string(),
                 signal_strength: -57.0,
             },
-        ];
-        let client = HttpClient::new(HC::Surf);
-        let ag_lbs_response = super::altergeo_lbs_request(wms, client).await;
-        match ag_lbs_response {
-            Err(err) => {
-                println!("Error AlterGeo LBS request: {}", err)
-            }
-            Ok(resp) => {
-                println!("AlterGeo LBS location: {:?}", resp)
-            }
-        }
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_ag_lbs_request() {
+        let (url, server) =
+            mock_server(r#"{"iamhere":{"latitude":55.75,"longitude":37.62,"precision":35.0}}"#);
+        let client = HttpClient::new(HC::Reqwest);
+        let response = altergeo_request(&client, &url, "key", &wms())
+            .await
+            .unwrap();
+        let ylr = response.location().unwrap().unwrap();
+        assert_eq!(ylr.location.point.lat, 55.75);
+        assert_eq!(ylr.location.accuracy, 35.0);
+
+        let body = server.join().unwrap();
+        assert!(body.contains("apikey=key"));
+        assert!(body.contains("wifi=-62%2Cae84c6a945d2%3B-57%2C00a2b08c90e5"));
+    }
+
+    #[tokio::test]
+    async fn test_ag_lbs_errors() {
+        let (url, server) = mock_server(r#"{"error":{"code":4,"message":"not found"}}"#);
+        let client = HttpClient::new(HC::Reqwest);
+        let response = altergeo_request(&client, &url, "key", &wms())
+            .await
+            .unwrap();
+        assert!(response.location().unwrap().is_none());
+        server.join().unwrap();
+
+        let (url, server) = mock_server(r#"{"error":{"code":3,"message":"limit exceeded"}}"#);
+        let response = altergeo_request(&client, &url, "key", &wms())
+            .await
+            .unwrap();
+        assert!(matches!(response.location(), Err(ApiError::LbsError(403))));
+        server.join().unwrap();
     }
 }
@@ -0,0 +1,286 @@
+//! Expiry and revalidation of the cached LBS responses.
+//!
+//! The positions in `lbs:{provider}:wifi` and `lbs:{provider}:cell` and the misses in
+//! `lbs:{provider}:*:missing` are saved with the Tile38 TTL of `[lbs-cache]`. The misses saved
+//! before the TTL was configured expire by their timestamp. The revalidation re-requests the
+//! Yandex positions expiring soon with the enrichment priority, so only the spare quota is spent:
+//! the refreshed position gets the full TTL again, the position unknown to Yandex now is removed.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use log::error;
+use once_cell::sync::Lazy;
+
+use crate::{
+    CONFIG,
+    config::LbsCache,
+    constants::{Collection, DEFAULT_RSSI},
+    db::{
+        blobasaur::del_ba_wifi_one,
+        t38::{del_yandex_lbs_wifi_one, get_ttl_many, scan::scan_ids_page},
+    },
+    error::ApiError,
+    lbs::{
+        model::CellMeasurement,
+        provider::{LbsContext, LbsResponses},
+        yandex::{
+            cell::yandex_lbs_revalidate_cell,
+            wifi::{WifiMeasurement, yandex_lbs_revalidate_wifi},
+        },
+    },
+    tasks::{blobasaur::BAConnectionManageMessage, t38::T38ConnectionManageMessage},
+};
+
+/// Format of the time the miss was saved at, UTC
+pub const MISSING_TS_FORMAT: &str = "%d-%m-%Y %H:%M";
+
+// number of the cached positions checked per run at most
+const SCAN_PAGE_SIZE: u64 = 1_000;
+const SCAN_PAGES: usize = 10;
+
+static PARAMS: Lazy<LbsCache> = Lazy::new(|| CONFIG.lbs_cache.clone());
+
+// the revalidation walks the collections from run to run
+static WIFI_CURSOR: AtomicU64 = AtomicU64::new(0);
+static CELL_CURSOR: AtomicU64 = AtomicU64::new(0);
+
+fn days(days: u64) -> Option<u64> {
+    (days > 0).then_some(days * 86_400)
+}
+
+/// TTL of the objects of the collection in seconds, `None` if kept forever
+pub fn ttl_with(params: &LbsCache, collection: &str) -> Option<u64> {
+    if !collection.starts_with("lbs:") {
+        None
+    } else if collection.ends_with(":missing") {
+        days(params.missing_ttl)
+    } else if collection.ends_with(":wifi") {
+        days(params.wifi_ttl)
+    } else if collection.ends_with(":cell") {
+        days(params.cell_ttl)
+    } else {
+        None
+    }
+}
+
+pub fn ttl(collection: &str) -> Option<u64> {
+    ttl_with(&PARAMS, collection)
+}
+
+pub fn is_missing_expired_with(params: &LbsCache, ts: &str, now: DateTime<Utc>) -> bool {
+    let Some(ttl) = days(params.missing_ttl) else {
+        return false;
+    };
+    match NaiveDateTime::parse_from_str(ts, MISSING_TS_FORMAT) {
+        Err(_e) => false,
+        Ok(saved) => (now - saved.and_utc()).num_seconds() > ttl as i64,
+    }
+}
+
+/// The miss saved at `ts` is not valid anymore
+pub fn is_missing_expired(ts: &str) -> bool {
+    is_missing_expired_with(&PARAMS, ts, Utc::now())
+}
+
+/// The position with the remaining TTL is to be re-requested
+fn is_expiring(params: &LbsCache, remaining: f64) -> bool {
+    // -1: the position was saved before the TTL was configured
+    remaining < 0.0 || remaining < (params.revalidate_days * 86_400) as f64
+}
+
+/// Ids of the positions expiring soon, the next pages of the collection are checked each run
+async fn expiring(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    collection: &str,
+    cursor: &AtomicU64,
+) -> Result<Vec<String>, ApiError> {
+    let mut expiring = Vec::with_capacity(PARAMS.revalidate_batch);
+    for _ in 0..SCAN_PAGES {
+        let (next, ids) = scan_ids_page(
+            tx_t38_conn.clone(),
+            collection,
+            cursor.load(Ordering::Relaxed),
+            SCAN_PAGE_SIZE,
+        )
+        .await
+        .map_err(|e| ApiError::Tile38Error(e.to_string()))?;
+        // from the beginning after the last page
+        cursor.store(next, Ordering::Relaxed);
+
+        let id_refs = ids.iter().map(String::as_str).collect::<Vec<&str>>();
+        let ttls = get_ttl_many(tx_t38_conn.clone(), collection, &id_refs)
+            .await
+            .map_err(|e| ApiError::Tile38Error(e.to_string()))?;
+        for (id, remaining) in ids.into_iter().zip(ttls) {
+            if remaining.is_some_and(|remaining| is_expiring(&PARAMS, remaining)) {
+                expiring.push(id);
+            }
+        }
+        if expiring.len() >= PARAMS.revalidate_batch || next == 0 {
+            break;
+        }
+    }
+    expiring.truncate(PARAMS.revalidate_batch);
+    Ok(expiring)
+}
+
+/// Remove the positions Yandex does not know anymore, returns the number of the re-requested
+async fn forget(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    tx_ba_conn: flume::Sender<BAConnectionManageMessage>,
+    collection: Collection,
+    namespace: Collection,
+    responses: &LbsResponses,
+) -> usize {
+    for (key, ylr) in responses {
+        if ylr.is_some() {
+            continue;
+        }
+        if let Err(e) = del_yandex_lbs_wifi_one(tx_t38_conn.clone(), collection.as_ref(), key).await
+        {
+            error!("remove '{}' from '{}': {}", key, collection, e);
+        }
+        if CONFIG.blobasaur.enabled
+            && let Err(e) = del_ba_wifi_one(tx_ba_conn.clone(), namespace.as_ref(), key).await
+        {
+            error!("remove '{}' from blobasaur '{}': {}", key, namespace, e);
+        }
+    }
+    responses.len()
+}
+
+/// Re-request the Yandex positions of the access points expiring soon
+pub async fn revalidate_wifi(ctx: &LbsContext) -> Result<usize, ApiError> {
+    let collection = Collection::LbsYandexWifi;
+    if ttl(collection.as_ref()).is_none() {
+        return Ok(0);
+    }
+    let wms = expiring(ctx.tx_t38_conn.clone(), collection.as_ref(), &WIFI_CURSOR)
+        .await?
+        .into_iter()
+        .map(|bssid| WifiMeasurement {
+            bssid,
+            signal_strength: DEFAULT_RSSI,
+        })
+        .collect::<Vec<WifiMeasurement>>();
+    if wms.is_empty() {
+        return Ok(0);
+    }
+
+    let responses = yandex_lbs_revalidate_wifi(
+        ctx.tx_t38_conn.clone(),
+        ctx.tx_ba_conn.clone(),
+        &wms,
+        ctx.client.clone(),
+        ctx.tx_yandex_api.clone(),
+        ctx.rl_app.clone(),
+        ctx.priority,
+    )
+    .await?;
+    Ok(forget(
+        ctx.tx_t38_conn.clone(),
+        ctx.tx_ba_conn.clone(),
+        collection,
+        Collection::BaLbsYandexWifi,
+        &responses,
+    )
+    .await)
+}
+
+/// Re-request the Yandex positions of the cells expiring soon
+pub async fn revalidate_cell(ctx: &LbsContext) -> Result<usize, ApiError> {
+    let collection = Collection::LbsYandexCell;
+    if ttl(collection.as_ref()).is_none() {
+        return Ok(0);
+    }
+    let cms = expiring(ctx.tx_t38_conn.clone(), collection.as_ref(), &CELL_CURSOR)
+        .await?
+        .iter()
+        .filter_map(|code| CellMeasurement::from_code(code, DEFAULT_RSSI))
+        .collect::<Vec<CellMeasurement>>();
+    if cms.is_empty() {
+        return Ok(0);
+    }
+
+    let responses = yandex_lbs_revalidate_cell(
+        ctx.tx_t38_conn.clone(),
+        ctx.tx_ba_conn.clone(),
+        cms,
+        ctx.client.clone(),
+        ctx.tx_yandex_api.clone(),
+        ctx.rl_app.clone(),
+        ctx.priority,
+    )
+    .await?;
+    Ok(forget(
+        ctx.tx_t38_conn.clone(),
+        ctx.tx_ba_conn.clone(),
+        collection,
+        Collection::BaLbsYandexCell,
+        &responses,
+    )
+    .await)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn params() -> LbsCache {
+        LbsCache {
+            wifi_ttl: 365,
+            cell_ttl: 0,
+            missing_ttl: 30,
+            revalidate: true,
+            revalidate_days: 30,
+            revalidate_interval: 600,
+            revalidate_batch: 100,
+        }
+    }
+
+    #[test]
+    fn lbs_cache_ttl() {
+        let params = params();
+        assert_eq!(ttl_with(&params, "lbs:yandex:wifi"), Some(365 * 86_400));
+        assert_eq!(ttl_with(&params, "lbs:ichnaea:wifi"), Some(365 * 86_400));
+        assert_eq!(ttl_with(&params, "lbs:yandex:cell"), None);
+        assert_eq!(
+            ttl_with(&params, "lbs:yandex:cell:missing"),
+            Some(30 * 86_400)
+        );
+        assert_eq!(ttl_with(&params, "report:idempotency"), None);
+        assert_eq!(ttl_with(&params, "cell:wifi"), None);
+
+        assert!(is_expiring(&params, -1.0));
+        assert!(is_expiring(&params, 86_400.0));
+        assert!(!is_expiring(&params, 90.0 * 86_400.0));
+    }
+
+    #[test]
+    fn lbs_cache_missing_expired() {
+        let params = params();
+        let now = Utc.with_ymd_and_hms(2025, 6, 1, 12, 0, 0).unwrap();
+        assert!(is_missing_expired_with(&params, "01-06-2024 12:00", now));
+        assert!(!is_missing_expired_with(&params, "20-05-2025 08:30", now));
+        // not parsed: kept
+        assert!(!is_missing_expired_with(&params, "2024-06-01", now));
+
+        let forever = LbsCache {
+            missing_ttl: 0,
+            ..params
+        };
+        assert!(!is_missing_expired_with(&forever, "01-06-2024 12:00", now));
+    }
+
+    #[test]
+    fn lbs_cache_cell_code() {
+        let cm = CellMeasurement::from_code("lte:250:1:15016:576267", DEFAULT_RSSI).unwrap();
+        assert_eq!((cm.mcc, cm.mnc, cm.lac, cm.cid), (250, 1, 15016, 576267));
+        assert_eq!(cm.code(), "lte:250:1:15016:576267");
+        assert!(CellMeasurement::from_code("lte:250:1:15016", DEFAULT_RSSI).is_none());
+        assert!(CellMeasurement::from_code("lte:250:1:15016:x", DEFAULT_RSSI).is_none());
+    }
+}
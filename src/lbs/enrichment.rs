@@ -0,0 +1,310 @@
+//! Lazy enrichment of the reports by Yandex LBS.
+//!
+//! The access points and cells of a report missing in the Yandex LBS cache are not requested
+//! inline but queued in `lbs:queue:wifi` and `lbs:queue:cell`, one entry per transmitter. The
+//! background worker requests them in batches with the enrichment priority, so only the spare
+//! quota is spent, and during the off-peak hours with the interactive one to use the limit left
+//! before the daily reset. The responses are cached in `lbs:yandex:*` and Blobasaur as usual and
+//! apply to the following reports of the transmitters. The worker walks the queue from run to run,
+//! so the entries failing permanently are retried once per pass and do not block the others.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use log::error;
+use once_cell::sync::Lazy;
+
+use crate::{
+    CONFIG,
+    config::Enrichment,
+    constants::Collection,
+    db::t38::{
+        get_yandex_lbs_cell_many, get_yandex_lbs_wifi_many, get_yandex_lbs_wifi_missing_many,
+        queue::{del_queue_one, get_queue_one, push_queue_one},
+        scan::scan_ids_page,
+    },
+    error::ApiError,
+    lbs::{
+        model::CellMeasurement,
+        provider::{LbsContext, LbsResponses, Priority},
+        yandex::{
+            cell::{create_yandex_cell, yandex_lbs_request_by_individual_cell},
+            wifi::{WifiMeasurement, yandex_lbs_request_by_individual_wifi},
+        },
+    },
+    tasks::t38::T38ConnectionManageMessage,
+};
+
+static PARAMS: Lazy<Enrichment> = Lazy::new(|| CONFIG.enrichment.clone());
+
+// position of the next batch in the queues
+static WIFI_CURSOR: AtomicU64 = AtomicU64::new(0);
+static CELL_CURSOR: AtomicU64 = AtomicU64::new(0);
+
+/// Priority of the queued lookups at the Moscow hour
+pub fn priority_with(params: &Enrichment, hour: u32) -> Priority {
+    if params.off_peak_hours.contains(&hour) {
+        Priority::Interactive
+    } else {
+        Priority::Enrichment
+    }
+}
+
+pub fn priority(hour: u32) -> Priority {
+    priority_with(&PARAMS, hour)
+}
+
+/// Locations of the access points in the Yandex LBS cache, the unknown ones are queued
+pub async fn request_wifi(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    wms: &[WifiMeasurement],
+) -> LbsResponses {
+    let macs = wms
+        .iter()
+        .map(|wm| wm.bssid.as_str())
+        .collect::<Vec<&str>>();
+    let Ok(ylrs) = get_yandex_lbs_wifi_many(
+        tx_t38_conn.clone(),
+        Collection::LbsYandexWifi.as_ref(),
+        &macs,
+    )
+    .await
+    else {
+        return macs.iter().map(|mac| (mac.to_string(), None)).collect();
+    };
+    // previously requested in Yandex and not available
+    let missing = get_yandex_lbs_wifi_missing_many(
+        tx_t38_conn.clone(),
+        Collection::LbsYandexWifiMissing.as_ref(),
+        &macs,
+    )
+    .await
+    .unwrap_or_default();
+
+    let mut responses = LbsResponses::with_capacity(wms.len());
+    for wm in wms {
+        let mac = wm.bssid.clone();
+        if let Some(ylr) = ylrs.get(&mac) {
+            responses.insert(mac, Some(ylr.clone()));
+            continue;
+        }
+        if !missing.contains(&mac)
+            && let Err(e) = push_queue_one(
+                tx_t38_conn.clone(),
+                Collection::LbsQueueWifi.as_ref(),
+                &mac,
+                wm,
+            )
+            .await
+        {
+            error!("queue access point '{}': {}", mac, e);
+        }
+        responses.insert(mac, None);
+    }
+    responses
+}
+
+/// Locations of the cells in the Yandex LBS cache, the unknown ones are queued
+pub async fn request_cell(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    cms: &[CellMeasurement],
+) -> LbsResponses {
+    let cell_codes = cms
+        .iter()
+        .map(CellMeasurement::code)
+        .collect::<Vec<String>>();
+    let codes = cell_codes.iter().map(String::as_str).collect::<Vec<&str>>();
+    let Ok(ylrs) = get_yandex_lbs_cell_many(
+        tx_t38_conn.clone(),
+        Collection::LbsYandexCell.as_ref(),
+        &codes,
+    )
+    .await
+    else {
+        return cell_codes.into_iter().map(|code| (code, None)).collect();
+    };
+    // previously requested in Yandex and not available
+    let missing = get_yandex_lbs_wifi_missing_many(
+        tx_t38_conn.clone(),
+        Collection::LbsYandexCellMissing.as_ref(),
+        &codes,
+    )
+    .await
+    .unwrap_or_default();
+
+    let mut responses = LbsResponses::with_capacity(cms.len());
+    for (cm, cell_code) in cms.iter().zip(cell_codes) {
+        if let Some(ylr) = ylrs.get(&cell_code) {
+            responses.insert(cell_code, Some(ylr.clone()));
+            continue;
+        }
+
+        // undefined cells and the radio types unsupported by Yandex are never requested
+        let undefined = cm.mcc == 0 && cm.mnc == 0 && cm.lac == 0 && cm.cid == 0;
+        if !undefined
+            && !missing.contains(&cell_code)
+            && create_yandex_cell(cm.clone()).is_some()
+            && let Err(e) = push_queue_one(
+                tx_t38_conn.clone(),
+                Collection::LbsQueueCell.as_ref(),
+                &cell_code,
+                cm,
+            )
+            .await
+        {
+            error!("queue cell '{}': {}", cell_code, e);
+        }
+        responses.insert(cell_code, None);
+    }
+    responses
+}
+
+/// Request the next `limit` queued access points, returns the number of the looked up ones.
+/// The access points not requested because of the budget or failed are left in the queue.
+pub async fn drain_wifi(ctx: &LbsContext, limit: u64) -> Result<usize, ApiError> {
+    let collection = Collection::LbsQueueWifi.as_ref();
+    let wms: Vec<WifiMeasurement> =
+        queued(ctx.tx_t38_conn.clone(), collection, &WIFI_CURSOR, limit).await?;
+    if wms.is_empty() {
+        return Ok(0);
+    }
+
+    let responses = yandex_lbs_request_by_individual_wifi(
+        ctx.tx_t38_conn.clone(),
+        ctx.tx_ba_conn.clone(),
+        &wms,
+        ctx.client.clone(),
+        ctx.tx_yandex_api.clone(),
+        ctx.rl_app.clone(),
+        ctx.priority,
+    )
+    .await?;
+    dequeue(
+        ctx.tx_t38_conn.clone(),
+        collection,
+        &WIFI_CURSOR,
+        &responses,
+    )
+    .await
+}
+
+/// Request the next `limit` queued cells, returns the number of the looked up ones.
+/// The cells not requested because of the budget or failed are left in the queue.
+pub async fn drain_cell(ctx: &LbsContext, limit: u64) -> Result<usize, ApiError> {
+    let collection = Collection::LbsQueueCell.as_ref();
+    let cms: Vec<CellMeasurement> =
+        queued(ctx.tx_t38_conn.clone(), collection, &CELL_CURSOR, limit).await?;
+    if cms.is_empty() {
+        return Ok(0);
+    }
+
+    let responses = yandex_lbs_request_by_individual_cell(
+        ctx.tx_t38_conn.clone(),
+        ctx.tx_ba_conn.clone(),
+        cms,
+        ctx.client.clone(),
+        ctx.tx_yandex_api.clone(),
+        ctx.rl_app.clone(),
+        ctx.priority,
+    )
+    .await?;
+    dequeue(
+        ctx.tx_t38_conn.clone(),
+        collection,
+        &CELL_CURSOR,
+        &responses,
+    )
+    .await
+}
+
+/// Move the cursor back by the entries removed before it, the scan cursor is the offset
+/// in the collection. The cursor of the finished pass stays at the beginning.
+fn rewind(cursor: &AtomicU64, removed: usize) {
+    let _ = cursor.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |c| {
+        (c > 0).then(|| c.saturating_sub(removed as u64))
+    });
+}
+
+async fn queued<T: serde::de::DeserializeOwned>(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    collection: &str,
+    cursor: &AtomicU64,
+    limit: u64,
+) -> Result<Vec<T>, ApiError> {
+    let (next, ids) = scan_ids_page(
+        tx_t38_conn.clone(),
+        collection,
+        cursor.load(Ordering::Relaxed),
+        limit,
+    )
+    .await
+    .map_err(|e| ApiError::Tile38Error(e.to_string()))?;
+    // from the beginning after the last page
+    cursor.store(next, Ordering::Relaxed);
+
+    let mut items = Vec::with_capacity(ids.len());
+    for id in &ids {
+        match get_queue_one::<T>(tx_t38_conn.clone(), collection, id)
+            .await
+            .map_err(|e| ApiError::Tile38Error(e.to_string()))?
+        {
+            Some(item) => items.push(item),
+            // not readable, it is never requested
+            None => {
+                del_queue_one(tx_t38_conn.clone(), collection, id)
+                    .await
+                    .map_err(|e| ApiError::Tile38Error(e.to_string()))?;
+                rewind(cursor, 1);
+            }
+        }
+    }
+    Ok(items)
+}
+
+async fn dequeue(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    collection: &str,
+    cursor: &AtomicU64,
+    responses: &LbsResponses,
+) -> Result<usize, ApiError> {
+    for id in responses.keys() {
+        del_queue_one(tx_t38_conn.clone(), collection, id)
+            .await
+            .map_err(|e| ApiError::Tile38Error(e.to_string()))?;
+        rewind(cursor, 1);
+    }
+    Ok(responses.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn enrichment_priority() {
+        let params = Enrichment {
+            enabled: true,
+            interval: 60,
+            batch: 100,
+            off_peak_hours: vec![22, 23],
+        };
+        assert_eq!(priority_with(&params, 22), Priority::Interactive);
+        assert_eq!(priority_with(&params, 23), Priority::Interactive);
+        assert_eq!(priority_with(&params, 0), Priority::Enrichment);
+        assert_eq!(priority_with(&params, 18), Priority::Enrichment);
+    }
+
+    #[test]
+    fn enrichment_cursor() {
+        // 10 of the batch of 100 failed and stay in the queue before the next batch
+        let cursor = AtomicU64::new(100);
+        rewind(&cursor, 90);
+        assert_eq!(cursor.load(Ordering::Relaxed), 10);
+        rewind(&cursor, 20);
+        assert_eq!(cursor.load(Ordering::Relaxed), 0);
+
+        // the pass is finished, the next one starts from the beginning
+        let cursor = AtomicU64::new(0);
+        rewind(&cursor, 5);
+        assert_eq!(cursor.load(Ordering::Relaxed), 0);
+    }
+}
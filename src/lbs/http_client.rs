@@ -4,7 +4,7 @@ use std::collections::HashMap;
 use std::time::Duration;
 
 use log::{error, info};
-use serde::Serialize;
+use serde::{Serialize, de::DeserializeOwned};
 
 use crate::{
     config::CONFIG,
@@ -18,6 +18,7 @@ use crate::{
         COUNT_ATTEMPTS, TIMEOUT,
         wifi::{YandexLbsResponse, YandexWifiMissing},
     },
+    services::helper::circuit_breaker::BREAKERS,
     tasks::{
         blobasaur::BAConnectionManageMessage,
         t38::T38ConnectionManageMessage,
@@ -25,6 +26,17 @@ use crate::{
     },
 };
 
+/// Collection of the transmitters unknown to Yandex LBS
+fn missing_collection(collection: &str) -> Option<Collection> {
+    if collection == Collection::LbsYandexWifi.as_ref() {
+        Some(Collection::LbsYandexWifiMissing)
+    } else if collection == Collection::LbsYandexCell.as_ref() {
+        Some(Collection::LbsYandexCellMissing)
+    } else {
+        None
+    }
+}
+
 #[derive(Clone)]
 pub enum HttpClient {
     Surf { client: surf::Client },
@@ -67,6 +79,90 @@ impl HttpClient {
         Err(ApiError::LbsRequestError())
     }
 
+    /// POST the form-encoded parameters, the response is JSON
+    pub async fn post_form<T: Serialize, R: DeserializeOwned>(
+        &self,
+        url: &str,
+        form: &T,
+    ) -> Result<R, ApiError> {
+        match self {
+            HttpClient::Surf { client } => {
+                let builder = client
+                    .post(url)
+                    .body_form(form)
+                    .map_err(|_| ApiError::LbsRequestError())?;
+                let mut response = builder.await.map_err(|e| {
+                    error!("LBS request '{}': {}", url, e);
+                    ApiError::LbsRequestError()
+                })?;
+                let status: u16 = response.status().into();
+                if status != 200 {
+                    return Err(ApiError::LbsError(status));
+                }
+                response
+                    .body_json::<R>()
+                    .await
+                    .map_err(|_| ApiError::JsonProcessingError)
+            }
+            HttpClient::Reqwest { client } => {
+                let response = client.post(url).form(form).send().await.map_err(|e| {
+                    error!("LBS request '{}': {}", url, e);
+                    ApiError::LbsRequestError()
+                })?;
+                let status = response.status().as_u16();
+                if status != 200 {
+                    return Err(ApiError::LbsError(status));
+                }
+                response
+                    .json::<R>()
+                    .await
+                    .map_err(|_| ApiError::JsonProcessingError)
+            }
+        }
+    }
+
+    /// POST the JSON body, the response is JSON
+    pub async fn post_json<T: Serialize, R: DeserializeOwned>(
+        &self,
+        url: &str,
+        body: &T,
+    ) -> Result<R, ApiError> {
+        match self {
+            HttpClient::Surf { client } => {
+                let builder = client
+                    .post(url)
+                    .body_json(body)
+                    .map_err(|_| ApiError::LbsRequestError())?;
+                let mut response = builder.await.map_err(|e| {
+                    error!("LBS request '{}': {}", url, e);
+                    ApiError::LbsRequestError()
+                })?;
+                let status: u16 = response.status().into();
+                if status != 200 {
+                    return Err(ApiError::LbsError(status));
+                }
+                response
+                    .body_json::<R>()
+                    .await
+                    .map_err(|_| ApiError::JsonProcessingError)
+            }
+            HttpClient::Reqwest { client } => {
+                let response = client.post(url).json(body).send().await.map_err(|e| {
+                    error!("LBS request '{}': {}", url, e);
+                    ApiError::LbsRequestError()
+                })?;
+                let status = response.status().as_u16();
+                if status != 200 {
+                    return Err(ApiError::LbsError(status));
+                }
+                response
+                    .json::<R>()
+                    .await
+                    .map_err(|_| ApiError::JsonProcessingError)
+            }
+        }
+    }
+
     pub async fn post_for_data<T: Serialize>(
         &self,
         key: &str,
@@ -85,7 +181,13 @@ impl HttpClient {
                 while attempt <= COUNT_ATTEMPTS {
                     attempt += 1;
 
-                    match client.post(url).body_json(body).unwrap().await {
+                    // fails at once while Yandex is down, the retries are stopped too
+                    BREAKERS.yandex.acquire()?;
+                    match BREAKERS
+                        .yandex
+                        .send(client.post(url).body_json(body).unwrap())
+                        .await
+                    {
                         Err(e) => {
                             // undefined request error
                             error!("Yandex LBS request for key '{}': {}", key, e);
@@ -94,6 +196,7 @@ impl HttpClient {
                         Ok(mut response) => {
                             // 400, 403, 429, 500, 504
                             let status = response.status().into();
+                            BREAKERS.yandex.record_status(status);
                             if status != 200 {
                                 // repeat the request in Yandex LBS
                                 if status == 429 || status == 500 || status == 504 {
@@ -142,7 +245,7 @@ impl HttpClient {
                                         // no data available for the requested access point
                                         lbs_responses.insert(key.to_string(), None);
 
-                                        if collection == Collection::LbsYandexWifi.as_ref() {
+                                        if let Some(missing) = missing_collection(collection) {
                                             let now = chrono::Utc::now();
                                             let ywm = YandexWifiMissing {
                                                 mac: key.to_string(),
@@ -150,12 +253,12 @@ impl HttpClient {
                                             };
                                             if let Err(e) = set_yandex_lbs_wifi_missing_one(
                                                 tx_t38_conn.clone(),
-                                                Collection::LbsYandexWifiMissing.as_ref(),
+                                                missing.as_ref(),
                                                 ywm,
                                             )
                                             .await
                                             {
-                                                error!("set Yandex WiFi as missing: {}", e);
+                                                error!("set Yandex {} as missing: {}", key, e);
                                             }
                                         }
 
@@ -245,7 +348,13 @@ impl HttpClient {
                 while attempt <= COUNT_ATTEMPTS {
                     attempt += 1;
 
-                    match client.post(url).json(body).send().await {
+                    // fails at once while Yandex is down, the retries are stopped too
+                    BREAKERS.yandex.acquire()?;
+                    match BREAKERS
+                        .yandex
+                        .send(client.post(url).json(body).send())
+                        .await
+                    {
                         Err(e) => {
                             // undefined request error
                             error!("Yandex LBS request for key '{}': {}", key, e);
@@ -254,6 +363,7 @@ impl HttpClient {
                         Ok(response) => {
                             // 400, 403, 429, 500, 504
                             let status = response.status().as_u16();
+                            BREAKERS.yandex.record_status(status);
                             if status != 200 {
                                 // repeat the request in Yandex LBS
                                 if status == 429 || status == 500 || status == 504 {
@@ -302,7 +412,7 @@ impl HttpClient {
                                         // no data available for the requested access point
                                         lbs_responses.insert(key.to_string(), None);
 
-                                        if collection == Collection::LbsYandexWifi.as_ref() {
+                                        if let Some(missing) = missing_collection(collection) {
                                             let now = chrono::Utc::now();
                                             let ywm = YandexWifiMissing {
                                                 mac: key.to_string(),
@@ -310,12 +420,12 @@ impl HttpClient {
                                             };
                                             if let Err(e) = set_yandex_lbs_wifi_missing_one(
                                                 tx_t38_conn.clone(),
-                                                Collection::LbsYandexWifiMissing.as_ref(),
+                                                missing.as_ref(),
                                                 ywm,
                                             )
                                             .await
                                             {
-                                                error!("set Yandex WiFi as missing: {}", e);
+                                                error!("set Yandex {} as missing: {}", key, e);
                                             }
                                         }
 
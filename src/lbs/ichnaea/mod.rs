@@ -0,0 +1,411 @@
+//! Upstream Ichnaea-compatible server: Mozilla Ichnaea, Google Geolocation API or another
+//! locator's `/api/mls/v1/geolocate`.
+//!
+//! The access points and cells are located by individual requests and cached in
+//! `lbs:ichnaea:wifi` and `lbs:ichnaea:cell` (Blobasaur `lbs_ichnaea_wifi`, `lbs_ichnaea_cell`),
+//! the unknown ones in `lbs:ichnaea:wifi:missing` and `lbs:ichnaea:cell:missing`. Ichnaea itself
+//! does not locate by one access point, such a server is useful for the cells and the combined
+//! request only. The IP and area fallbacks are disabled: the upstream answers by the measurements
+//! or 404.
+
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    CONFIG,
+    constants::Collection,
+    db::{
+        blobasaur::{set_ba_lbs_yandex_cell_one, set_ba_lbs_yandex_wifi_one},
+        t38::{
+            get_yandex_lbs_cell_one, get_yandex_lbs_wifi_missing_one, get_yandex_lbs_wifi_one,
+            set_yandex_lbs_cell_one, set_yandex_lbs_wifi_missing_one, set_yandex_lbs_wifi_one,
+        },
+    },
+    error::ApiError,
+    lbs::{
+        http_client::HttpClient,
+        model::CellMeasurement,
+        provider::{LbsContext, LbsProvider, LbsResponses, Quota},
+        yandex::wifi::{
+            WifiMeasurement, YandexLbsResponse, YandexLocation, YandexPoint, YandexWifiMissing,
+        },
+    },
+    services::helper::circuit_breaker::BREAKERS,
+};
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IchnaeaRequest {
+    pub consider_ip: bool,
+    pub fallbacks: Fallbacks,
+    pub wifi_access_points: Vec<IchnaeaAccessPoint>,
+    pub cell_towers: Vec<IchnaeaCellTower>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Fallbacks {
+    pub lacf: bool,
+    pub ipf: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IchnaeaAccessPoint {
+    pub mac_address: String,
+    pub signal_strength: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IchnaeaCellTower {
+    pub radio_type: String,
+    pub mobile_country_code: u16,
+    pub mobile_network_code: u16,
+    pub location_area_code: u64,
+    pub cell_id: u64,
+    pub signal_strength: i64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct IchnaeaResponse {
+    pub location: IchnaeaLocation,
+    pub accuracy: f64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct IchnaeaLocation {
+    pub lat: f64,
+    pub lng: f64,
+}
+
+impl From<IchnaeaResponse> for YandexLbsResponse {
+    fn from(value: IchnaeaResponse) -> Self {
+        YandexLbsResponse {
+            location: YandexLocation {
+                point: YandexPoint {
+                    lat: value.location.lat,
+                    lon: value.location.lng,
+                },
+                accuracy: value.accuracy,
+            },
+        }
+    }
+}
+
+impl IchnaeaRequest {
+    pub fn new(wms: &[WifiMeasurement], cms: &[CellMeasurement]) -> Self {
+        IchnaeaRequest {
+            consider_ip: false,
+            fallbacks: Fallbacks {
+                lacf: false,
+                ipf: false,
+            },
+            wifi_access_points: wms
+                .iter()
+                .map(|w| IchnaeaAccessPoint {
+                    mac_address: w.bssid.clone(),
+                    signal_strength: w.signal_strength as i64,
+                })
+                .collect(),
+            cell_towers: cms
+                .iter()
+                .map(|c| IchnaeaCellTower {
+                    radio_type: c.radio_type.clone(),
+                    mobile_country_code: c.mcc,
+                    mobile_network_code: c.mnc,
+                    location_area_code: c.lac,
+                    cell_id: c.cid,
+                    signal_strength: c.signal_strength as i64,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Location by the upstream, `None` if the measurements are unknown to it
+pub async fn ichnaea_request(
+    client: &HttpClient,
+    url: &str,
+    apikey: &str,
+    request: &IchnaeaRequest,
+) -> Result<Option<YandexLbsResponse>, ApiError> {
+    let url = if apikey.is_empty() {
+        url.to_string()
+    } else {
+        format!("{}?key={}", url, apikey)
+    };
+    match client.post_json::<_, IchnaeaResponse>(&url, request).await {
+        Ok(response) => Ok(Some(response.into())),
+        Err(ApiError::LbsError(404)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub async fn ichnaea_lbs_request(
+    request: &IchnaeaRequest,
+    client: &HttpClient,
+) -> Result<Option<YandexLbsResponse>, ApiError> {
+    BREAKERS
+        .ichnaea
+        .call(ichnaea_request(
+            client,
+            &CONFIG.ichnaea_lbs.url,
+            &CONFIG.ichnaea_lbs.apikey,
+            request,
+        ))
+        .await
+}
+
+/// Cached location of the access point or the cell, `Some(None)` if unknown to the upstream
+async fn cached(
+    ctx: &LbsContext,
+    is_cell: bool,
+    key: &str,
+) -> Result<Option<Option<YandexLbsResponse>>, ApiError> {
+    let found = if is_cell {
+        get_yandex_lbs_cell_one(
+            ctx.tx_t38_conn.clone(),
+            Collection::LbsIchnaeaCell.as_ref(),
+            key,
+        )
+        .await
+    } else {
+        get_yandex_lbs_wifi_one(
+            ctx.tx_t38_conn.clone(),
+            Collection::LbsIchnaeaWifi.as_ref(),
+            key,
+        )
+        .await
+    }
+    .map_err(|e| ApiError::Tile38Error(e.to_string()))?;
+    if found.is_some() {
+        return Ok(Some(found));
+    }
+
+    let missing_collection = if is_cell {
+        Collection::LbsIchnaeaCellMissing
+    } else {
+        Collection::LbsIchnaeaWifiMissing
+    };
+    match get_yandex_lbs_wifi_missing_one(ctx.tx_t38_conn.clone(), missing_collection.as_ref(), key)
+        .await
+    {
+        Ok(Some(_)) => Ok(Some(None)),
+        _ => Ok(None),
+    }
+}
+
+async fn cache(ctx: &LbsContext, is_cell: bool, key: &str, ylr_opt: Option<&YandexLbsResponse>) {
+    let Some(ylr) = ylr_opt else {
+        let missing = YandexWifiMissing {
+            mac: key.to_string(),
+            ts: chrono::Utc::now().format("%d-%m-%Y %H:%M").to_string(),
+        };
+        let collection = if is_cell {
+            Collection::LbsIchnaeaCellMissing
+        } else {
+            Collection::LbsIchnaeaWifiMissing
+        };
+        if let Err(e) =
+            set_yandex_lbs_wifi_missing_one(ctx.tx_t38_conn.clone(), collection.as_ref(), missing)
+                .await
+        {
+            error!("set as missing in Ichnaea: {}", e);
+        }
+        return;
+    };
+
+    let saved = if is_cell {
+        set_yandex_lbs_cell_one(
+            ctx.tx_t38_conn.clone(),
+            Collection::LbsIchnaeaCell.as_ref(),
+            ylr,
+            key,
+        )
+        .await
+    } else {
+        set_yandex_lbs_wifi_one(
+            ctx.tx_t38_conn.clone(),
+            Collection::LbsIchnaeaWifi.as_ref(),
+            ylr,
+            key,
+        )
+        .await
+    };
+    if let Err(e) = saved {
+        error!("save Ichnaea LBS response: {}", e);
+    }
+
+    if CONFIG.blobasaur.enabled {
+        let saved = if is_cell {
+            set_ba_lbs_yandex_cell_one(
+                ctx.tx_ba_conn.clone(),
+                Collection::BaLbsIchnaeaCell.as_ref(),
+                ylr.clone(),
+                key,
+            )
+            .await
+        } else {
+            set_ba_lbs_yandex_wifi_one(
+                ctx.tx_ba_conn.clone(),
+                Collection::BaLbsIchnaeaWifi.as_ref(),
+                ylr.clone(),
+                key,
+            )
+            .await
+        };
+        if let Err(e) = saved {
+            error!("save Ichnaea LBS response in blobasaur: {}", e);
+        }
+    }
+}
+
+/// Locate the measurements one by one, `key` of the measurement in the responses and the cache
+async fn request_individual(
+    ctx: &LbsContext,
+    is_cell: bool,
+    requests: Vec<(String, IchnaeaRequest)>,
+) -> Result<LbsResponses, ApiError> {
+    let mut lbs_responses = LbsResponses::with_capacity(requests.len());
+    if !CONFIG.ichnaea_lbs.enabled {
+        return Ok(lbs_responses);
+    }
+    for (key, request) in requests {
+        if let Some(ylr_opt) = cached(ctx, is_cell, &key).await? {
+            lbs_responses.insert(key, ylr_opt);
+            continue;
+        }
+        let ylr_opt = ichnaea_lbs_request(&request, &ctx.client).await?;
+        cache(ctx, is_cell, &key, ylr_opt.as_ref()).await;
+        lbs_responses.insert(key, ylr_opt);
+    }
+    Ok(lbs_responses)
+}
+
+pub struct IchnaeaProvider;
+
+impl LbsProvider for IchnaeaProvider {
+    fn name(&self) -> &'static str {
+        "ichnaea"
+    }
+
+    fn enabled(&self) -> bool {
+        CONFIG.ichnaea_lbs.enabled
+    }
+
+    async fn wifi(
+        &self,
+        ctx: &LbsContext,
+        wms: &[WifiMeasurement],
+    ) -> Result<LbsResponses, ApiError> {
+        let requests = wms
+            .iter()
+            .map(|wm| {
+                let request = IchnaeaRequest::new(std::slice::from_ref(wm), &[]);
+                (wm.bssid.clone(), request)
+            })
+            .collect();
+        request_individual(ctx, false, requests).await
+    }
+
+    async fn cell(
+        &self,
+        ctx: &LbsContext,
+        cms: &[CellMeasurement],
+    ) -> Result<LbsResponses, ApiError> {
+        let requests = cms
+            .iter()
+            .map(|cm| {
+                let request = IchnaeaRequest::new(&[], std::slice::from_ref(cm));
+                (cm.code(), request)
+            })
+            .collect();
+        request_individual(ctx, true, requests).await
+    }
+
+    async fn combined(
+        &self,
+        ctx: &LbsContext,
+        wms: &[WifiMeasurement],
+        cms: &[CellMeasurement],
+    ) -> Result<Option<YandexLbsResponse>, ApiError> {
+        if !CONFIG.ichnaea_lbs.enabled || (wms.is_empty() && cms.is_empty()) {
+            return Ok(None);
+        }
+        ichnaea_lbs_request(&IchnaeaRequest::new(wms, cms), &ctx.client).await
+    }
+
+    async fn quota(&self, _ctx: &LbsContext) -> Quota {
+        Quota {
+            available: CONFIG.ichnaea_lbs.enabled,
+            remaining: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{constants::HC, lbs::mock_server};
+
+    fn request() -> IchnaeaRequest {
+        IchnaeaRequest::new(
+            &[WifiMeasurement {
+                bssid: "ae:84:c6:a9:45:d2".to_string(),
+                signal_strength: -62.0,
+            }],
+            &[CellMeasurement {
+                radio_type: "lte".to_string(),
+                mcc: 250,
+                mnc: 1,
+                lac: 15016,
+                cid: 147524353,
+                signal_strength: -90.0,
+            }],
+        )
+    }
+
+    #[tokio::test]
+    async fn test_ichnaea_request() {
+        let (address, server) = mock_server::serve(
+            200,
+            r#"{"location":{"lat":55.75,"lng":37.62},"accuracy":120.0}"#,
+        );
+        let url = format!("{}/api/mls/v1/geolocate", address);
+        let client = HttpClient::new(HC::Reqwest);
+        let ylr = ichnaea_request(&client, &url, "key", &request())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(ylr.location.point.lon, 37.62);
+        assert_eq!(ylr.location.accuracy, 120.0);
+
+        let body: serde_json::Value = serde_json::from_str(&server.join().unwrap()).unwrap();
+        assert_eq!(body["considerIp"], false);
+        assert_eq!(
+            body["wifiAccessPoints"][0]["macAddress"],
+            "ae:84:c6:a9:45:d2"
+        );
+        assert_eq!(body["cellTowers"][0]["radioType"], "lte");
+        assert_eq!(body["cellTowers"][0]["cellId"], 147524353);
+    }
+
+    #[tokio::test]
+    async fn test_ichnaea_not_found() {
+        let (address, server) = mock_server::serve(
+            404,
+            r#"{"error":{"errors":[{"domain":"geolocation","reason":"notFound"}],"code":404}}"#,
+        );
+        let client = HttpClient::new(HC::Reqwest);
+        let ylr_opt = ichnaea_request(&client, &address, "", &request())
+            .await
+            .unwrap();
+        assert!(ylr_opt.is_none());
+        server.join().unwrap();
+
+        let (address, server) = mock_server::serve(400, r#"{"error":{"code":400}}"#);
+        let result = ichnaea_request(&client, &address, "", &request()).await;
+        assert!(matches!(result, Err(ApiError::LbsError(400))));
+        server.join().unwrap();
+    }
+}
@@ -1,4 +1,11 @@
 pub mod altergeo;
+pub mod cache;
+pub mod enrichment;
 pub mod http_client;
+pub mod ichnaea;
+#[cfg(test)]
+pub mod mock_server;
 pub mod model;
+pub mod provider;
+pub mod single_flight;
 pub mod yandex;
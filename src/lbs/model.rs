@@ -24,6 +24,33 @@ pub struct CellMeasurement {
     pub signal_strength: f64,
 }
 
+impl CellMeasurement {
+    /// Key of the cell in the LBS cache and the Wi-Fi association index
+    pub fn code(&self) -> String {
+        format!(
+            "{}:{}:{}:{}:{}",
+            self.radio_type, self.mcc, self.mnc, self.lac, self.cid
+        )
+    }
+
+    /// Cell by the key in the LBS cache, the signal strength is unknown
+    pub fn from_code(code: &str, signal_strength: f64) -> Option<CellMeasurement> {
+        let mut parts = code.split(':');
+        let cm = CellMeasurement {
+            radio_type: parts.next()?.to_string(),
+            mcc: parts.next()?.parse().ok()?,
+            mnc: parts.next()?.parse().ok()?,
+            lac: parts.next()?.parse().ok()?,
+            cid: parts.next()?.parse().ok()?,
+            signal_strength,
+        };
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(cm)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Gsm {
     pub mcc: u16,
@@ -0,0 +1,374 @@
+//! External LBS providers queried in the priority order of `[lbs] providers`.
+//!
+//! A provider caches its responses in its own Tile38 collections `lbs:{name}:wifi` and
+//! `lbs:{name}:cell`. The access points and cells unknown to a provider, or all of them if the
+//! provider failed, are requested from the next one.
+
+use std::collections::HashMap;
+
+use log::warn;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::{
+    CONFIG,
+    config::LbsProviderKind,
+    error::ApiError,
+    lbs::{
+        altergeo::AlterGeoProvider,
+        http_client::HttpClient,
+        ichnaea::IchnaeaProvider,
+        model::CellMeasurement,
+        yandex::{
+            provider::YandexProvider,
+            wifi::{WifiMeasurement, YandexLbsResponse},
+        },
+    },
+    services::rate_limiter::RateLimitersApp,
+    tasks::{
+        blobasaur::BAConnectionManageMessage, t38::T38ConnectionManageMessage,
+        yandex::YandexApiMessage,
+    },
+};
+
+/// Locations by MAC address or cell code, `None` if unknown to the provider
+pub type LbsResponses = HashMap<String, Option<YandexLbsResponse>>;
+
+/// Priority of the lookups for the daily budget of the keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Priority {
+    /// live locate requests
+    #[default]
+    Interactive,
+    /// validation and enrichment of the reports
+    Enrichment,
+}
+
+/// Connections and clients shared by the providers
+#[derive(Clone)]
+pub struct LbsContext {
+    pub tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    pub tx_ba_conn: flume::Sender<BAConnectionManageMessage>,
+    pub client: HttpClient,
+    pub tx_yandex_api: flume::Sender<YandexApiMessage>,
+    pub rl_app: RateLimitersApp,
+    pub priority: Priority,
+}
+
+impl LbsContext {
+    pub fn new(
+        tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+        tx_ba_conn: flume::Sender<BAConnectionManageMessage>,
+        client: HttpClient,
+        tx_yandex_api: flume::Sender<YandexApiMessage>,
+        rl_app: RateLimitersApp,
+    ) -> Self {
+        Self {
+            tx_t38_conn,
+            tx_ba_conn,
+            client,
+            tx_yandex_api,
+            rl_app,
+            priority: Priority::default(),
+        }
+    }
+
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Quota {
+    pub available: bool,
+    /// requests left for today, `None` if unknown or unlimited
+    pub remaining: Option<u64>,
+}
+
+pub trait LbsProvider {
+    fn name(&self) -> &'static str;
+
+    /// Whether the provider is enabled in its config section
+    fn enabled(&self) -> bool;
+
+    /// Locations of the individual access points
+    async fn wifi(
+        &self,
+        ctx: &LbsContext,
+        wms: &[WifiMeasurement],
+    ) -> Result<LbsResponses, ApiError>;
+
+    /// Locations of the individual cells
+    async fn cell(
+        &self,
+        ctx: &LbsContext,
+        cms: &[CellMeasurement],
+    ) -> Result<LbsResponses, ApiError>;
+
+    /// Location of the device by all measurements at once, `None` if not supported
+    async fn combined(
+        &self,
+        _ctx: &LbsContext,
+        _wms: &[WifiMeasurement],
+        _cms: &[CellMeasurement],
+    ) -> Result<Option<YandexLbsResponse>, ApiError> {
+        Ok(None)
+    }
+
+    async fn quota(&self, ctx: &LbsContext) -> Quota;
+}
+
+pub enum Provider {
+    Yandex(YandexProvider),
+    AlterGeo(AlterGeoProvider),
+    Ichnaea(IchnaeaProvider),
+}
+
+impl From<LbsProviderKind> for Provider {
+    fn from(kind: LbsProviderKind) -> Self {
+        match kind {
+            LbsProviderKind::Yandex => Provider::Yandex(YandexProvider),
+            LbsProviderKind::AlterGeo => Provider::AlterGeo(AlterGeoProvider),
+            LbsProviderKind::Ichnaea => Provider::Ichnaea(IchnaeaProvider),
+        }
+    }
+}
+
+impl LbsProvider for Provider {
+    fn name(&self) -> &'static str {
+        match self {
+            Provider::Yandex(p) => p.name(),
+            Provider::AlterGeo(p) => p.name(),
+            Provider::Ichnaea(p) => p.name(),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        match self {
+            Provider::Yandex(p) => p.enabled(),
+            Provider::AlterGeo(p) => p.enabled(),
+            Provider::Ichnaea(p) => p.enabled(),
+        }
+    }
+
+    async fn wifi(
+        &self,
+        ctx: &LbsContext,
+        wms: &[WifiMeasurement],
+    ) -> Result<LbsResponses, ApiError> {
+        match self {
+            Provider::Yandex(p) => p.wifi(ctx, wms).await,
+            Provider::AlterGeo(p) => p.wifi(ctx, wms).await,
+            Provider::Ichnaea(p) => p.wifi(ctx, wms).await,
+        }
+    }
+
+    async fn cell(
+        &self,
+        ctx: &LbsContext,
+        cms: &[CellMeasurement],
+    ) -> Result<LbsResponses, ApiError> {
+        match self {
+            Provider::Yandex(p) => p.cell(ctx, cms).await,
+            Provider::AlterGeo(p) => p.cell(ctx, cms).await,
+            Provider::Ichnaea(p) => p.cell(ctx, cms).await,
+        }
+    }
+
+    async fn combined(
+        &self,
+        ctx: &LbsContext,
+        wms: &[WifiMeasurement],
+        cms: &[CellMeasurement],
+    ) -> Result<Option<YandexLbsResponse>, ApiError> {
+        match self {
+            Provider::Yandex(p) => p.combined(ctx, wms, cms).await,
+            Provider::AlterGeo(p) => p.combined(ctx, wms, cms).await,
+            Provider::Ichnaea(p) => p.combined(ctx, wms, cms).await,
+        }
+    }
+
+    async fn quota(&self, ctx: &LbsContext) -> Quota {
+        match self {
+            Provider::Yandex(p) => p.quota(ctx).await,
+            Provider::AlterGeo(p) => p.quota(ctx).await,
+            Provider::Ichnaea(p) => p.quota(ctx).await,
+        }
+    }
+}
+
+pub static PROVIDERS: Lazy<Vec<Provider>> = Lazy::new(|| {
+    CONFIG
+        .lbs
+        .providers
+        .iter()
+        .map(|&kind| Provider::from(kind))
+        .collect()
+});
+
+/// Whether any configured provider is enabled, otherwise the providers are not queried
+pub fn any_enabled() -> bool {
+    PROVIDERS.iter().any(|provider| provider.enabled())
+}
+
+/// Add the responses of the next provider, the known locations are kept
+fn merge(responses: &mut LbsResponses, found: LbsResponses) {
+    for (key, ylr_opt) in found {
+        match ylr_opt {
+            Some(ylr) => {
+                responses.insert(key, Some(ylr));
+            }
+            None => {
+                responses.entry(key).or_insert(None);
+            }
+        }
+    }
+}
+
+fn is_located(responses: &LbsResponses, key: &str) -> bool {
+    matches!(responses.get(key), Some(Some(_)))
+}
+
+/// Locations of the access points by the providers in the priority order.
+/// The error is returned only if every requested provider failed.
+pub async fn request_wifi(
+    ctx: &LbsContext,
+    wms: &[WifiMeasurement],
+) -> Result<LbsResponses, ApiError> {
+    let mut responses = LbsResponses::with_capacity(wms.len());
+    let mut pending = wms.to_vec();
+    let mut error = None;
+    let mut succeeded = false;
+    for provider in PROVIDERS.iter() {
+        if pending.is_empty() {
+            break;
+        }
+        if !provider.enabled() {
+            continue;
+        }
+        match provider.wifi(ctx, &pending).await {
+            Err(e) => {
+                warn!("{} LBS request by access points: {}", provider.name(), e);
+                error.get_or_insert(e);
+            }
+            Ok(found) => {
+                merge(&mut responses, found);
+                succeeded = true;
+            }
+        }
+        pending.retain(|wm| !is_located(&responses, &wm.bssid));
+    }
+    match error {
+        Some(e) if !succeeded => Err(e),
+        _ => Ok(responses),
+    }
+}
+
+/// Locations of the cells by the providers in the priority order.
+/// The error is returned only if every requested provider failed.
+pub async fn request_cell(
+    ctx: &LbsContext,
+    cms: &[CellMeasurement],
+) -> Result<LbsResponses, ApiError> {
+    let mut responses = LbsResponses::with_capacity(cms.len());
+    let mut pending = cms.to_vec();
+    let mut error = None;
+    let mut succeeded = false;
+    for provider in PROVIDERS.iter() {
+        if pending.is_empty() {
+            break;
+        }
+        if !provider.enabled() {
+            continue;
+        }
+        match provider.cell(ctx, &pending).await {
+            Err(e) => {
+                warn!("{} LBS request by cells: {}", provider.name(), e);
+                error.get_or_insert(e);
+            }
+            Ok(found) => {
+                merge(&mut responses, found);
+                succeeded = true;
+            }
+        }
+        pending.retain(|cm| !is_located(&responses, &cm.code()));
+    }
+    match error {
+        Some(e) if !succeeded => Err(e),
+        _ => Ok(responses),
+    }
+}
+
+/// Location of the device by one request with all measurements to the first provider
+/// supporting it. The error is returned only if every requested provider failed.
+pub async fn request_combined(
+    ctx: &LbsContext,
+    wms: &[WifiMeasurement],
+    cms: &[CellMeasurement],
+) -> Result<Option<YandexLbsResponse>, ApiError> {
+    let mut error = None;
+    let mut succeeded = false;
+    for provider in PROVIDERS.iter().filter(|provider| provider.enabled()) {
+        match provider.combined(ctx, wms, cms).await {
+            Err(e) => {
+                warn!("{} LBS combined request: {}", provider.name(), e);
+                error.get_or_insert(e);
+            }
+            Ok(Some(ylr)) => return Ok(Some(ylr)),
+            Ok(None) => succeeded = true,
+        }
+    }
+    match error {
+        Some(e) if !succeeded => Err(e),
+        _ => Ok(None),
+    }
+}
+
+/// Quotas of the configured providers
+pub async fn quotas(ctx: &LbsContext) -> Vec<(&'static str, Quota)> {
+    let mut quotas = Vec::with_capacity(PROVIDERS.len());
+    for provider in PROVIDERS.iter() {
+        quotas.push((provider.name(), provider.quota(ctx).await));
+    }
+    quotas
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lbs::yandex::wifi::{YandexLocation, YandexPoint};
+
+    fn ylr(lat: f64) -> Option<YandexLbsResponse> {
+        Some(YandexLbsResponse {
+            location: YandexLocation {
+                point: YandexPoint { lat, lon: 37.62 },
+                accuracy: 50.0,
+            },
+        })
+    }
+
+    #[test]
+    fn provider_merge() {
+        let mut responses = LbsResponses::from([
+            ("a0:00:00:00:00:01".to_string(), ylr(55.75)),
+            ("a0:00:00:00:00:02".to_string(), None),
+        ]);
+        let found = LbsResponses::from([
+            ("a0:00:00:00:00:01".to_string(), None),
+            ("a0:00:00:00:00:02".to_string(), ylr(55.76)),
+            ("a0:00:00:00:00:03".to_string(), None),
+        ]);
+        merge(&mut responses, found);
+
+        assert!(is_located(&responses, "a0:00:00:00:00:01"));
+        assert_eq!(
+            responses["a0:00:00:00:00:02"]
+                .as_ref()
+                .map(|ylr| ylr.location.point.lat),
+            Some(55.76)
+        );
+        assert!(!is_located(&responses, "a0:00:00:00:00:03"));
+        assert!(responses.contains_key("a0:00:00:00:00:03"));
+    }
+}
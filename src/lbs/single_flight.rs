@@ -0,0 +1,151 @@
+//! Coalescing of the concurrent lookups of the same transmitter: the first caller requests the
+//! provider, the callers arriving while the request is in flight wait for its result instead of
+//! sending their own requests.
+//!
+//! If the first caller is cancelled (the client of `/locate` disconnected), the waiters run their
+//! own requests.
+
+use std::{collections::HashMap, future::Future, sync::Mutex};
+
+use tokio::sync::oneshot;
+
+pub struct SingleFlight<T> {
+    calls: Mutex<HashMap<String, Vec<oneshot::Sender<T>>>>,
+}
+
+impl<T> Default for SingleFlight<T> {
+    fn default() -> Self {
+        Self {
+            calls: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+/// Removes the call of the first caller even if it is cancelled
+struct Call<'a, T> {
+    flight: &'a SingleFlight<T>,
+    key: &'a str,
+    finished: bool,
+}
+
+impl<T> Call<'_, T> {
+    fn finish(mut self) -> Vec<oneshot::Sender<T>> {
+        self.finished = true;
+        self.flight
+            .calls
+            .lock()
+            .unwrap()
+            .remove(self.key)
+            .unwrap_or_default()
+    }
+}
+
+impl<T> Drop for Call<'_, T> {
+    fn drop(&mut self) {
+        // the senders are dropped, the waiters run their own requests
+        if !self.finished {
+            self.flight.calls.lock().unwrap().remove(self.key);
+        }
+    }
+}
+
+impl<T: Clone> SingleFlight<T> {
+    /// Result of `request`, shared with the concurrent calls by the same key.
+    /// `request` is not polled if the call waits for another one.
+    pub async fn run<F>(&self, key: &str, request: F) -> T
+    where
+        F: Future<Output = T>,
+    {
+        let rx = {
+            let mut calls = self.calls.lock().unwrap();
+            match calls.get_mut(key) {
+                Some(waiters) => {
+                    let (tx, rx) = oneshot::channel();
+                    waiters.push(tx);
+                    Some(rx)
+                }
+                None => {
+                    calls.insert(key.to_string(), Vec::new());
+                    None
+                }
+            }
+        };
+
+        if let Some(rx) = rx {
+            return match rx.await {
+                Ok(value) => value,
+                Err(_) => request.await,
+            };
+        }
+
+        let call = Call {
+            flight: self,
+            key,
+            finished: false,
+        };
+        let value = request.await;
+        for tx in call.finish() {
+            let _ = tx.send(value.clone());
+        }
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn single_flight_shared() {
+        let flight = SingleFlight::<u32>::default();
+        let requests = AtomicUsize::new(0);
+        let (tx_gate, rx_gate) = oneshot::channel::<()>();
+
+        let first = flight.run("ae:84:c6:a9:45:d2", async {
+            requests.fetch_add(1, Ordering::SeqCst);
+            rx_gate.await.unwrap();
+            42
+        });
+        let second = flight.run("ae:84:c6:a9:45:d2", async {
+            requests.fetch_add(1, Ordering::SeqCst);
+            0
+        });
+        let other = flight.run("ae:84:c6:a9:45:d3", async {
+            requests.fetch_add(1, Ordering::SeqCst);
+            7
+        });
+        let open = async {
+            tokio::task::yield_now().await;
+            tx_gate.send(()).unwrap();
+        };
+
+        let (first, second, other, _) = futures::join!(first, second, other, open);
+        assert_eq!((first, second, other), (42, 42, 7));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert!(flight.calls.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn single_flight_cancelled() {
+        let flight = SingleFlight::<u32>::default();
+        let (_tx_gate, rx_gate) = oneshot::channel::<()>();
+
+        let mut first = Box::pin(flight.run("ae:84:c6:a9:45:d2", async {
+            let _ = rx_gate.await;
+            42
+        }));
+        // the first call is in flight
+        assert!(futures::poll!(first.as_mut()).is_pending());
+        assert_eq!(flight.calls.lock().unwrap().len(), 1);
+
+        let second = flight.run("ae:84:c6:a9:45:d2", async { 7 });
+        let cancel = async {
+            drop(first);
+        };
+        let (second, _) = futures::join!(second, cancel);
+        assert_eq!(second, 7);
+        assert!(flight.calls.lock().unwrap().is_empty());
+    }
+}
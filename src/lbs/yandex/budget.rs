@@ -0,0 +1,193 @@
+//! Daily budget of the Yandex API keys.
+//!
+//! The usable limit of a key is `limit - reserve_limiter`. The enrichment of the reports may spend
+//! at most `1 - interactive_share` of it, evenly over the Moscow day with `burst` requests ahead,
+//! so the rest is always left for the live locate requests. The interactive requests are limited
+//! by the usable limit only. Both spend the same Blobasaur limiter of the key.
+
+use chrono::{DateTime, Timelike};
+use chrono_tz::Tz;
+use log::error;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::{
+    CONFIG,
+    config::{LbsBudget, YandexApiKey},
+    db::blobasaur::get_ba_limiter,
+    lbs::provider::Priority,
+    tasks::{blobasaur::BAConnectionManageMessage, yandex::YandexApiMessage},
+};
+
+static PARAMS: Lazy<LbsBudget> = Lazy::new(|| CONFIG.lbs_budget.clone());
+
+/// Spending of the key for today
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct KeyBudget {
+    /// first characters of the key
+    pub key: String,
+    pub limit: u64,
+    pub used: u64,
+    /// requests left for the interactive traffic
+    pub remaining: u64,
+    /// requests the enrichment may spend by now
+    pub enrichment_allowance: u64,
+    /// requests left for the enrichment by now
+    pub enrichment_remaining: u64,
+}
+
+/// Elapsed share of the Moscow day, 0..1
+pub fn day_fraction(now: DateTime<Tz>) -> f64 {
+    now.num_seconds_from_midnight() as f64 / 86_400.0
+}
+
+fn now() -> DateTime<Tz> {
+    chrono::Utc::now().with_timezone(&chrono_tz::Europe::Moscow)
+}
+
+fn usable(limit: u64) -> u64 {
+    limit.saturating_sub(CONFIG.yandex_lbs.reserve_limiter)
+}
+
+/// Requests the enrichment may have spent by the moment of the day
+fn enrichment_allowance(params: &LbsBudget, usable: u64, fraction: f64) -> u64 {
+    let cap = (usable as f64 * (1.0 - params.interactive_share).clamp(0.0, 1.0)).floor() as u64;
+    let paced = (cap as f64 * fraction.clamp(0.0, 1.0)).floor() as u64;
+    (paced + params.burst).min(cap)
+}
+
+pub fn budget_with(
+    params: &LbsBudget,
+    key: &YandexApiKey,
+    usable: u64,
+    used: u64,
+    fraction: f64,
+) -> KeyBudget {
+    let enrichment_allowance = enrichment_allowance(params, usable, fraction);
+    KeyBudget {
+        key: key.key.chars().take(8).collect(),
+        limit: key.limit,
+        used,
+        remaining: usable.saturating_sub(used),
+        enrichment_allowance,
+        enrichment_remaining: enrichment_allowance.saturating_sub(used),
+    }
+}
+
+pub fn allows_with(
+    params: &LbsBudget,
+    usable: u64,
+    used: u64,
+    priority: Priority,
+    fraction: f64,
+) -> bool {
+    if !params.enabled {
+        return true;
+    }
+    match priority {
+        Priority::Interactive => used < usable,
+        Priority::Enrichment => used < enrichment_allowance(params, usable, fraction),
+    }
+}
+
+/// The usable limit of the key is reached, the key is blocked until tomorrow
+pub fn is_exhausted(key: &YandexApiKey, used: u64) -> bool {
+    used >= usable(key.limit)
+}
+
+/// The key may be spent on the request of the priority now
+pub fn allows(key: &YandexApiKey, used: u64, priority: Priority) -> bool {
+    allows_with(
+        &PARAMS,
+        usable(key.limit),
+        used,
+        priority,
+        day_fraction(now()),
+    )
+}
+
+/// Budgets of the active keys
+pub async fn budgets(
+    tx_yandex_api: flume::Sender<YandexApiMessage>,
+    tx_ba_conn: flume::Sender<BAConnectionManageMessage>,
+) -> Vec<KeyBudget> {
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    if let Err(e) = tx_yandex_api
+        .send_async(YandexApiMessage::GetActiveKeys { tx })
+        .await
+    {
+        error!("send yandex api message: {}", e);
+    }
+    let keys: Vec<YandexApiKey> = rx.await.unwrap_or_default();
+
+    let fraction = day_fraction(now());
+    let mut budgets = Vec::with_capacity(keys.len());
+    for key in &keys {
+        // the key is skipped if blobasaur is unavailable
+        if let Ok(used) = get_ba_limiter(tx_ba_conn.clone(), &key.key).await {
+            budgets.push(budget_with(&PARAMS, key, usable(key.limit), used, fraction));
+        }
+    }
+    budgets
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn params() -> LbsBudget {
+        LbsBudget {
+            enabled: true,
+            interactive_share: 0.3,
+            burst: 10,
+        }
+    }
+
+    #[test]
+    fn lbs_budget_pacing() {
+        let params = params();
+        // 700 of 1000 for the enrichment, 350 by noon and 10 ahead
+        assert_eq!(enrichment_allowance(&params, 1000, 0.5), 360);
+        assert_eq!(enrichment_allowance(&params, 1000, 1.0), 700);
+        assert_eq!(enrichment_allowance(&params, 1000, 0.0), 10);
+
+        assert!(allows_with(&params, 1000, 359, Priority::Enrichment, 0.5));
+        assert!(!allows_with(&params, 1000, 360, Priority::Enrichment, 0.5));
+        // the evening traffic is left to the interactive requests
+        assert!(!allows_with(&params, 1000, 700, Priority::Enrichment, 0.99));
+        assert!(allows_with(&params, 1000, 999, Priority::Interactive, 0.01));
+        assert!(!allows_with(
+            &params,
+            1000,
+            1000,
+            Priority::Interactive,
+            0.99
+        ));
+
+        let disabled = LbsBudget {
+            enabled: false,
+            ..params
+        };
+        assert!(allows_with(&disabled, 1000, 900, Priority::Enrichment, 0.0));
+    }
+
+    #[test]
+    fn lbs_budget_key() {
+        let key = YandexApiKey {
+            key: "b9da27e2-8440-4561-8a09-c0c9781410a1".to_string(),
+            limit: 1010,
+        };
+        let noon = chrono_tz::Europe::Moscow
+            .with_ymd_and_hms(2025, 6, 1, 12, 0, 0)
+            .unwrap();
+        assert_eq!(day_fraction(noon), 0.5);
+
+        let budget = budget_with(&params(), &key, 1000, 400, day_fraction(noon));
+        assert_eq!(budget.key, "b9da27e2");
+        assert_eq!(budget.remaining, 600);
+        assert_eq!(budget.enrichment_allowance, 360);
+        assert_eq!(budget.enrichment_remaining, 0);
+    }
+}
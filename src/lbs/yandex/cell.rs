@@ -1,30 +1,42 @@
 use std::collections::HashMap;
 
+use futures::future::join_all;
 use log::{error, info};
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use serde_with::skip_serializing_none;
 
 use crate::{
     config::CONFIG,
     constants::{Collection, RadioType},
-    db::{blobasaur::get_ba_limiter, t38::get_yandex_lbs_cell_one},
+    db::t38::{
+        get_yandex_lbs_cell_many, get_yandex_lbs_cell_one, get_yandex_lbs_wifi_missing_many,
+        get_yandex_lbs_wifi_missing_one,
+    },
     error::ApiError,
     lbs::{
         http_client::HttpClient,
         model::{self, create_cell_measurement},
+        provider::{self, LbsContext, LbsResponses, Priority},
+        single_flight::SingleFlight,
         yandex::{
             cell::model::{Cell, CellMeasurement},
+            get_api_key, merge_responses,
             wifi::YandexLbsResponse,
         },
     },
-    services::rate_limiter::RateLimitersApp,
+    services::{
+        helper::circuit_breaker::BREAKERS, rate_limiter::RateLimitersApp, submission::cell_wifi,
+    },
     tasks::{
-        blobasaur::BAConnectionManageMessage,
-        t38::T38ConnectionManageMessage,
-        yandex::{InvalidApiKey, YandexApiMessage},
+        blobasaur::BAConnectionManageMessage, t38::T38ConnectionManageMessage,
+        yandex::YandexApiMessage,
     },
 };
 
+/// Yandex LBS requests by cell code in flight
+static CELL_FLIGHTS: Lazy<SingleFlight<Option<LbsResponses>>> = Lazy::new(SingleFlight::default);
+
 #[skip_serializing_none]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct YandexLbsRequestCell {
@@ -112,16 +124,18 @@ pub async fn get_cell(
 ) -> Result<Option<HashMap<String, Option<YandexLbsResponse>>>, ApiError> {
     if let Some(cell) = cell_opt {
         let cms = create_cell_measurement(&cell);
-        match yandex_lbs_request_by_individual_cell(
-            tx_t38_conn,
-            tx_ba_conn,
-            cms,
-            yandex_client,
-            tx_yandex_api,
-            rl_app,
+        let ylrs = provider::request_cell(
+            &LbsContext::new(
+                tx_t38_conn.clone(),
+                tx_ba_conn,
+                yandex_client,
+                tx_yandex_api,
+                rl_app,
+            ),
+            &cms,
         )
-        .await
-        {
+        .await;
+        match cell_wifi::complete(tx_t38_conn, &cms, ylrs).await {
             Err(e) => {
                 error!("Yandex LBS request by individual cells: {}", e);
                 Err(e)
@@ -133,6 +147,8 @@ pub async fn get_cell(
     }
 }
 
+/// Locations of the cells, the missing in the cache are requested from Yandex LBS one by one
+/// and concurrently, `rl_app.yandex_lbs` limits the simultaneous requests
 pub async fn yandex_lbs_request_by_individual_cell(
     tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
     tx_ba_conn: flume::Sender<BAConnectionManageMessage>,
@@ -140,122 +156,269 @@ pub async fn yandex_lbs_request_by_individual_cell(
     yandex_client: HttpClient,
     tx_yandex_api: flume::Sender<YandexApiMessage>,
     rl_app: RateLimitersApp,
+    priority: Priority,
 ) -> Result<HashMap<String, Option<YandexLbsResponse>>, ApiError> {
-    let mut lbs_responses: HashMap<String, Option<YandexLbsResponse>> =
-        HashMap::with_capacity(cms.len());
-
     let collection = Collection::LbsYandexCell.as_ref();
-    let tz_moscow = chrono_tz::Europe::Moscow;
-
-    for cm in cms {
-        let mcc = cm.mcc;
-        let mnc = cm.mnc;
-        let lac = cm.lac;
-        let cid = cm.cid;
-
-        if mcc == 0 && mnc == 0 && lac == 0 && cid == 0 {
-            info!("MCC, MNC, LAC, CID is undefined");
-            continue;
+    let cell_codes = cms
+        .iter()
+        .map(CellMeasurement::code)
+        .collect::<Vec<String>>();
+    let codes = cell_codes.iter().map(String::as_str).collect::<Vec<&str>>();
+    let mut lbs_responses: HashMap<String, Option<YandexLbsResponse>> =
+        match get_yandex_lbs_cell_many(tx_t38_conn.clone(), collection, &codes).await {
+            // don`t request the cells in Yandex LBS
+            Err(_e) => return Ok(cell_codes.into_iter().map(|code| (code, None)).collect()),
+            Ok(ylrs) => ylrs
+                .into_iter()
+                .map(|(code, ylr)| (code, Some(ylr)))
+                .collect(),
+        };
+    let uncached = cms
+        .into_iter()
+        .filter(|cm| !lbs_responses.contains_key(&cm.code()))
+        .collect::<Vec<CellMeasurement>>();
+    let uncached_codes = uncached
+        .iter()
+        .map(CellMeasurement::code)
+        .collect::<Vec<String>>();
+    let codes = uncached_codes
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<&str>>();
+    let missing = get_yandex_lbs_wifi_missing_many(
+        tx_t38_conn.clone(),
+        Collection::LbsYandexCellMissing.as_ref(),
+        &codes,
+    )
+    .await
+    .unwrap_or_default();
+
+    let mut pending = Vec::with_capacity(uncached.len());
+    for (cm, cell_code) in uncached.into_iter().zip(uncached_codes) {
+        if missing.contains(&cell_code) {
+            // don`t repeat the request in Yandex LBS
+            lbs_responses.insert(cell_code, None);
+        } else {
+            pending.push(cm);
         }
+    }
 
-        let cell_code = format!("{}:{}:{}:{}:{}", &cm.radio_type, mcc, mnc, lac, cid);
+    let codes = pending
+        .iter()
+        .map(CellMeasurement::code)
+        .collect::<Vec<String>>();
+    let requests = pending.into_iter().map(|cm| {
+        yandex_lbs_request_cell_one(
+            tx_t38_conn.clone(),
+            tx_ba_conn.clone(),
+            cm,
+            &yandex_client,
+            tx_yandex_api.clone(),
+            &rl_app,
+            priority,
+        )
+    });
+    let codes = codes.iter().map(String::as_str);
+    merge_responses(lbs_responses, codes.zip(join_all(requests).await))
+}
 
-        // check whether the specified access point is in the database
-        match get_yandex_lbs_cell_one(tx_t38_conn.clone(), collection, &cell_code).await {
-            Err(_e) => {
-                // don`t repeat the request in Yandex LBS
-                lbs_responses.insert(cell_code, None);
-                continue;
-            }
-            Ok(ylr_opt) => {
-                if ylr_opt.is_some() {
-                    // retrieve a previously saved Yandex response from the database
-                    lbs_responses.insert(cell_code, ylr_opt);
-                    continue;
+/// Locations of the cached cells requested again from Yandex LBS, the cells not requested
+/// because of the budget are absent in the result
+pub async fn yandex_lbs_revalidate_cell(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    tx_ba_conn: flume::Sender<BAConnectionManageMessage>,
+    cms: Vec<CellMeasurement>,
+    yandex_client: HttpClient,
+    tx_yandex_api: flume::Sender<YandexApiMessage>,
+    rl_app: RateLimitersApp,
+    priority: Priority,
+) -> Result<HashMap<String, Option<YandexLbsResponse>>, ApiError> {
+    let codes = cms
+        .iter()
+        .map(CellMeasurement::code)
+        .collect::<Vec<String>>();
+    let requests = cms.into_iter().map(|cm| {
+        request_cell_upstream(
+            tx_t38_conn.clone(),
+            tx_ba_conn.clone(),
+            cm,
+            &yandex_client,
+            tx_yandex_api.clone(),
+            &rl_app,
+            priority,
+        )
+    });
+    let codes = codes.iter().map(String::as_str);
+    merge_responses(
+        HashMap::with_capacity(codes.len()),
+        codes.zip(join_all(requests).await),
+    )
+}
+
+/// Concurrent lookups of the same cell share one request, the priority is a part of the
+/// key as the budget may allow one priority and deny another
+async fn yandex_lbs_request_cell_one(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    tx_ba_conn: flume::Sender<BAConnectionManageMessage>,
+    cm: CellMeasurement,
+    yandex_client: &HttpClient,
+    tx_yandex_api: flume::Sender<YandexApiMessage>,
+    rl_app: &RateLimitersApp,
+    priority: Priority,
+) -> Result<HashMap<String, Option<YandexLbsResponse>>, ApiError> {
+    let key = format!("{:?}:{}", priority, cm.code());
+    let mut error = None;
+    let shared = CELL_FLIGHTS
+        .run(&key, async {
+            match request_cell_one(
+                tx_t38_conn,
+                tx_ba_conn,
+                cm,
+                yandex_client,
+                tx_yandex_api,
+                rl_app,
+                priority,
+            )
+            .await
+            {
+                Err(e) => {
+                    error = Some(e);
+                    None
                 }
-                // in case of None make a request to Yandex LBS
+                Ok(lbs_responses) => Some(lbs_responses),
             }
+        })
+        .await;
+    match (shared, error) {
+        (Some(lbs_responses), _) => Ok(lbs_responses),
+        (None, Some(e)) => Err(e),
+        // the shared request failed, possibly because Yandex is down
+        (None, None) => {
+            BREAKERS.yandex.check()?;
+            Err(ApiError::LbsRequestError())
         }
+    }
+}
 
-        let yandex_cell = match create_yandex_cell(cm) {
-            None => continue,
-            Some(c) => c,
-        };
+async fn request_cell_one(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    tx_ba_conn: flume::Sender<BAConnectionManageMessage>,
+    cm: CellMeasurement,
+    yandex_client: &HttpClient,
+    tx_yandex_api: flume::Sender<YandexApiMessage>,
+    rl_app: &RateLimitersApp,
+    priority: Priority,
+) -> Result<HashMap<String, Option<YandexLbsResponse>>, ApiError> {
+    let mut lbs_responses: HashMap<String, Option<YandexLbsResponse>> = HashMap::with_capacity(1);
 
-        let yandex_lbs_request = YandexLbsRequestCell {
-            cell: vec![yandex_cell],
-        };
+    let collection = Collection::LbsYandexCell.as_ref();
+
+    if cm.mcc == 0 && cm.mnc == 0 && cm.lac == 0 && cm.cid == 0 {
+        info!("MCC, MNC, LAC, CID is undefined");
+        return Ok(lbs_responses);
+    }
 
-        let (tx, rx) = tokio::sync::oneshot::channel();
-        let yandex_api_message = YandexApiMessage::GetApiKey { tx };
+    let cell_code = cm.code();
 
-        if let Err(e) = tx_yandex_api.send_async(yandex_api_message).await {
-            error!("send yandex api message: {}", e);
+    // check whether the specified access point is in the database
+    match get_yandex_lbs_cell_one(tx_t38_conn.clone(), collection, &cell_code).await {
+        Err(_e) => {
+            // don`t repeat the request in Yandex LBS
             lbs_responses.insert(cell_code, None);
-            continue;
+            return Ok(lbs_responses);
         }
-
-        if let Ok(Some(api_key)) = rx.await {
-            if let Ok(limiter) = get_ba_limiter(tx_ba_conn.clone(), &api_key.key.key).await {
-                // DEBUG
-                // TODO: remove after tests by Whoosh
-                info!(
-                    "key {}, limiter: {}, limit: {}",
-                    api_key.key.key, limiter, api_key.key.limit
-                );
-
-                if limiter >= api_key.key.limit - CONFIG.yandex_lbs.reserve_limiter {
-                    // block the current API key
-                    let created_date = chrono::Utc::now().with_timezone(&tz_moscow).date_naive();
-                    let yandex_api_message_invalid_key = YandexApiMessage::InvalidApiKey {
-                        invalid_api_key: InvalidApiKey {
-                            _error: Some(format!("StatusCode {}", 403)),
-                            i: api_key.i,
-                            key: api_key.key,
-                            created_date,
-                        },
-                    };
-                    if let Err(e) = tx_yandex_api
-                        .send_async(yandex_api_message_invalid_key)
-                        .await
-                    {
-                        error!("send yandex api invalid key message: {}", e);
-                    }
-                    return Err(ApiError::LbsError(403));
-                }
+        Ok(ylr_opt) => {
+            if ylr_opt.is_some() {
+                // retrieve a previously saved Yandex response from the database
+                lbs_responses.insert(cell_code, ylr_opt);
+                return Ok(lbs_responses);
             }
-
-            let yandex_lbs_url = format!("{}{}", CONFIG.yandex_lbs.url, &api_key.key.key);
-
-            // Acquire permit before processing request
-            // Permit released automatically when the handler is completed
-            let _permit = rl_app.yandex_lbs.acquire().await;
-
-            if let Err(e) = yandex_client
-                .post_for_data(
-                    &cell_code,
-                    &yandex_lbs_url,
-                    &yandex_lbs_request,
-                    tx_yandex_api.clone(),
-                    api_key,
-                    &mut lbs_responses,
-                    tx_t38_conn.clone(),
-                    tx_ba_conn.clone(),
-                    collection,
-                )
-                .await
-            {
-                return Err(e);
-            }
-        } else {
-            return Err(ApiError::LbsError(403));
+            // in case of None make a request to Yandex LBS
         }
     }
 
+    // if the cell was previously requested in Yandex and is not available, then skip it
+    if let Ok(Some(_ycm)) = get_yandex_lbs_wifi_missing_one(
+        tx_t38_conn.clone(),
+        Collection::LbsYandexCellMissing.as_ref(),
+        &cell_code,
+    )
+    .await
+    {
+        lbs_responses.insert(cell_code, None);
+        // don`t repeat the request in Yandex LBS
+        return Ok(lbs_responses);
+    }
+
+    request_cell_upstream(
+        tx_t38_conn,
+        tx_ba_conn,
+        cm,
+        yandex_client,
+        tx_yandex_api,
+        rl_app,
+        priority,
+    )
+    .await
+}
+
+/// Request the cell from Yandex LBS bypassing the cache, the response is cached
+async fn request_cell_upstream(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    tx_ba_conn: flume::Sender<BAConnectionManageMessage>,
+    cm: CellMeasurement,
+    yandex_client: &HttpClient,
+    tx_yandex_api: flume::Sender<YandexApiMessage>,
+    rl_app: &RateLimitersApp,
+    priority: Priority,
+) -> Result<HashMap<String, Option<YandexLbsResponse>>, ApiError> {
+    let mut lbs_responses: HashMap<String, Option<YandexLbsResponse>> = HashMap::with_capacity(1);
+
+    let collection = Collection::LbsYandexCell.as_ref();
+    let cell_code = cm.code();
+
+    let yandex_cell = match create_yandex_cell(cm) {
+        None => return Ok(lbs_responses),
+        Some(c) => c,
+    };
+
+    let yandex_lbs_request = YandexLbsRequestCell {
+        cell: vec![yandex_cell],
+    };
+
+    // the budget is not spent while Yandex is down
+    BREAKERS.yandex.check()?;
+
+    // not requested: the budget of the priority is spent
+    let Some(api_key) = get_api_key(tx_yandex_api.clone(), tx_ba_conn.clone(), priority).await?
+    else {
+        return Ok(lbs_responses);
+    };
+
+    let yandex_lbs_url = format!("{}{}", CONFIG.yandex_lbs.url, &api_key.key.key);
+
+    // Acquire permit before processing request
+    // Permit released automatically when the request is completed
+    let _permit = rl_app.yandex_lbs.acquire().await;
+
+    yandex_client
+        .post_for_data(
+            &cell_code,
+            &yandex_lbs_url,
+            &yandex_lbs_request,
+            tx_yandex_api,
+            api_key,
+            &mut lbs_responses,
+            tx_t38_conn,
+            tx_ba_conn,
+            collection,
+        )
+        .await?;
+
     Ok(lbs_responses)
 }
 
-fn create_yandex_cell(cm: CellMeasurement) -> Option<YandexCell> {
+pub fn create_yandex_cell(cm: CellMeasurement) -> Option<YandexCell> {
     if cm.radio_type == RadioType::Lte.as_ref() {
         Some(YandexCell {
             lte: Some(CellLte {
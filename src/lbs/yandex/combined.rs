@@ -0,0 +1,140 @@
+//! One request to Yandex LBS with all access points and cells of the device: the position is
+//! estimated by Yandex at the cost of one request of the daily limit instead of one per unknown
+//! transmitter. The response is the position of the device, so it is not cached.
+
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::{
+    CONFIG,
+    error::ApiError,
+    lbs::{
+        http_client::HttpClient,
+        model::CellMeasurement,
+        provider::Priority,
+        yandex::{
+            cell::{YandexCell, create_yandex_cell},
+            get_api_key,
+            wifi::{WifiMeasurement, YandexLbsResponse},
+        },
+    },
+    services::{helper::circuit_breaker::BREAKERS, rate_limiter::RateLimitersApp},
+    tasks::{
+        blobasaur::BAConnectionManageMessage, t38::T38ConnectionManageMessage,
+        yandex::YandexApiMessage,
+    },
+};
+
+/// Key of the response in `post_for_data`
+const COMBINED_KEY: &str = "combined";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct YandexLbsRequestCombined {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub wifi: Vec<WifiMeasurement>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub cell: Vec<YandexCell>,
+}
+
+impl YandexLbsRequestCombined {
+    pub fn new(wms: &[WifiMeasurement], cms: &[CellMeasurement]) -> Self {
+        YandexLbsRequestCombined {
+            wifi: wms.to_vec(),
+            cell: cms.iter().cloned().filter_map(create_yandex_cell).collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.wifi.is_empty() && self.cell.is_empty()
+    }
+}
+
+/// Location of the device, `None` if Yandex knows none of the transmitters
+pub async fn yandex_lbs_request_combined(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    tx_ba_conn: flume::Sender<BAConnectionManageMessage>,
+    wms: &[WifiMeasurement],
+    cms: &[CellMeasurement],
+    yandex_client: HttpClient,
+    tx_yandex_api: flume::Sender<YandexApiMessage>,
+    rl_app: RateLimitersApp,
+    priority: Priority,
+) -> Result<Option<YandexLbsResponse>, ApiError> {
+    let yandex_lbs_request = YandexLbsRequestCombined::new(wms, cms);
+    if !CONFIG.yandex_lbs.enabled || yandex_lbs_request.is_empty() {
+        return Ok(None);
+    }
+
+    // the budget is not spent while Yandex is down
+    BREAKERS.yandex.check()?;
+    let Some(api_key) = get_api_key(tx_yandex_api.clone(), tx_ba_conn.clone(), priority).await?
+    else {
+        return Ok(None);
+    };
+    let yandex_lbs_url = format!("{}{}", CONFIG.yandex_lbs.url, &api_key.key.key);
+
+    // Acquire permit before processing request
+    // Permit released automatically when the request is completed
+    let _permit = rl_app.yandex_lbs.acquire().await;
+
+    let mut lbs_responses: HashMap<String, Option<YandexLbsResponse>> = HashMap::with_capacity(1);
+    // no collection: the position of the device is not cached
+    yandex_client
+        .post_for_data(
+            COMBINED_KEY,
+            &yandex_lbs_url,
+            &yandex_lbs_request,
+            tx_yandex_api,
+            api_key,
+            &mut lbs_responses,
+            tx_t38_conn,
+            tx_ba_conn,
+            "",
+        )
+        .await?;
+
+    Ok(lbs_responses.remove(COMBINED_KEY).flatten())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn yandex_combined_request() {
+        let wms = vec![WifiMeasurement {
+            bssid: "ae:84:c6:a9:45:d2".to_string(),
+            signal_strength: -62.0,
+        }];
+        let cms = vec![
+            CellMeasurement {
+                radio_type: "lte".to_string(),
+                mcc: 250,
+                mnc: 1,
+                lac: 15016,
+                cid: 576267,
+                signal_strength: -53.0,
+            },
+            CellMeasurement {
+                radio_type: "nr".to_string(),
+                mcc: 250,
+                mnc: 1,
+                lac: 15016,
+                cid: 576268,
+                signal_strength: -80.0,
+            },
+        ];
+
+        let request = YandexLbsRequestCombined::new(&wms, &cms);
+        // NR is not supported by Yandex LBS
+        assert_eq!(request.cell.len(), 1);
+        let value = serde_json::to_value(&request).unwrap();
+        assert_eq!(value["wifi"][0]["bssid"], "ae:84:c6:a9:45:d2");
+        assert_eq!(value["cell"][0]["lte"]["ci"], 576267);
+
+        let value = serde_json::to_value(YandexLbsRequestCombined::new(&wms, &[])).unwrap();
+        assert!(value.get("cell").is_none());
+        assert!(YandexLbsRequestCombined::new(&[], &[]).is_empty());
+    }
+}
@@ -1,5 +1,133 @@
+use log::{error, info, warn};
+
+use crate::{
+    CONFIG,
+    db::blobasaur::get_ba_limiter,
+    error::ApiError,
+    lbs::provider::{LbsResponses, Priority},
+    tasks::{
+        blobasaur::BAConnectionManageMessage,
+        yandex::{ApiKey, InvalidApiKey, YandexApiMessage},
+    },
+};
+
+pub mod budget;
 pub mod cell;
+pub mod combined;
+pub mod provider;
 pub mod wifi;
 
-pub static COUNT_ATTEMPTS: u8 = 10;
-pub static TIMEOUT: u64 = 1000; // milliseconds
+// a single short retry in the request path, longer outages are left to the circuit breaker
+pub static COUNT_ATTEMPTS: u8 = 2;
+pub static TIMEOUT: u64 = 200; // milliseconds
+
+/// Add the responses of the requests by transmitter to the cached ones. The failed requests are
+/// logged and skipped, the error is returned only if no transmitter is known, neither cached nor
+/// requested.
+pub fn merge_responses<'a>(
+    mut lbs_responses: LbsResponses,
+    responses: impl IntoIterator<Item = (&'a str, Result<LbsResponses, ApiError>)>,
+) -> Result<LbsResponses, ApiError> {
+    let mut error = None;
+    for (id, response) in responses {
+        match response {
+            Err(e) => {
+                warn!("Yandex LBS request of {}: {}", id, e);
+                error.get_or_insert(e);
+            }
+            Ok(found) => lbs_responses.extend(found),
+        }
+    }
+    match error {
+        Some(e) if lbs_responses.is_empty() => Err(e),
+        _ => Ok(lbs_responses),
+    }
+}
+
+/// Active API key with the daily limit not reached, `None` if the key task is unavailable or
+/// the budget of every key is spent for the priority. The key reaching the limit is blocked until
+/// tomorrow.
+pub async fn get_api_key(
+    tx_yandex_api: flume::Sender<YandexApiMessage>,
+    tx_ba_conn: flume::Sender<BAConnectionManageMessage>,
+    priority: Priority,
+) -> Result<Option<ApiKey>, ApiError> {
+    // the keys are given in turn, the next one may have the budget left
+    let mut exhausted = true;
+    for _ in 0..CONFIG.yandex_lbs.api_keys.len().max(1) {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let yandex_api_message = YandexApiMessage::GetApiKey { tx };
+
+        if let Err(e) = tx_yandex_api.send_async(yandex_api_message).await {
+            error!("send yandex api message: {}", e);
+            return Ok(None);
+        }
+
+        let Ok(Some(api_key)) = rx.await else {
+            return Err(ApiError::LbsError(403));
+        };
+        let Ok(limiter) = get_ba_limiter(tx_ba_conn.clone(), &api_key.key.key).await else {
+            return Ok(Some(api_key));
+        };
+
+        if budget::is_exhausted(&api_key.key, limiter) {
+            // block the current API key
+            let created_date = chrono::Utc::now()
+                .with_timezone(&chrono_tz::Europe::Moscow)
+                .date_naive();
+            let yandex_api_message_invalid_key = YandexApiMessage::InvalidApiKey {
+                invalid_api_key: InvalidApiKey {
+                    _error: Some(format!("StatusCode {}", 403)),
+                    i: api_key.i,
+                    key: api_key.key,
+                    created_date,
+                },
+            };
+            if let Err(e) = tx_yandex_api
+                .send_async(yandex_api_message_invalid_key)
+                .await
+            {
+                error!("send yandex api invalid key message: {}", e);
+            }
+            continue;
+        }
+        exhausted = false;
+
+        if budget::allows(&api_key.key, limiter, priority) {
+            return Ok(Some(api_key));
+        }
+    }
+    if exhausted {
+        return Err(ApiError::LbsError(403));
+    }
+    info!("Yandex LBS budget is spent for {:?} requests", priority);
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_partial_responses() {
+        let found = |id: &str| Ok(LbsResponses::from([(id.to_string(), None)]));
+
+        let merged = merge_responses(
+            LbsResponses::new(),
+            [
+                ("a", found("a")),
+                ("b", Err(ApiError::LbsRequestError())),
+                ("c", found("c")),
+            ],
+        )
+        .unwrap();
+        assert_eq!(merged.len(), 2);
+
+        let failed = [("a", Err(ApiError::LbsRequestError()))];
+        assert!(merge_responses(LbsResponses::new(), failed).is_err());
+
+        let cached = LbsResponses::from([("b".to_string(), None)]);
+        let failed = [("a", Err(ApiError::LbsRequestError()))];
+        assert_eq!(merge_responses(cached, failed).unwrap().len(), 1);
+    }
+}
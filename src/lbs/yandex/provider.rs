@@ -0,0 +1,115 @@
+use log::error;
+
+use crate::{
+    CONFIG,
+    config::YandexApiKey,
+    db::blobasaur::get_ba_limiter,
+    error::ApiError,
+    lbs::{
+        model::CellMeasurement,
+        provider::{LbsContext, LbsProvider, LbsResponses, Quota},
+        yandex::{
+            cell::yandex_lbs_request_by_individual_cell,
+            combined::yandex_lbs_request_combined,
+            wifi::{WifiMeasurement, YandexLbsResponse, yandex_lbs_request_by_individual_wifi},
+        },
+    },
+    tasks::yandex::YandexApiMessage,
+};
+
+/// Yandex Locator, the responses are cached in `lbs:yandex:wifi` and `lbs:yandex:cell`
+pub struct YandexProvider;
+
+impl LbsProvider for YandexProvider {
+    fn name(&self) -> &'static str {
+        "yandex"
+    }
+
+    fn enabled(&self) -> bool {
+        CONFIG.yandex_lbs.enabled
+    }
+
+    async fn wifi(
+        &self,
+        ctx: &LbsContext,
+        wms: &[WifiMeasurement],
+    ) -> Result<LbsResponses, ApiError> {
+        yandex_lbs_request_by_individual_wifi(
+            ctx.tx_t38_conn.clone(),
+            ctx.tx_ba_conn.clone(),
+            wms,
+            ctx.client.clone(),
+            ctx.tx_yandex_api.clone(),
+            ctx.rl_app.clone(),
+            ctx.priority,
+        )
+        .await
+    }
+
+    async fn cell(
+        &self,
+        ctx: &LbsContext,
+        cms: &[CellMeasurement],
+    ) -> Result<LbsResponses, ApiError> {
+        yandex_lbs_request_by_individual_cell(
+            ctx.tx_t38_conn.clone(),
+            ctx.tx_ba_conn.clone(),
+            cms.to_vec(),
+            ctx.client.clone(),
+            ctx.tx_yandex_api.clone(),
+            ctx.rl_app.clone(),
+            ctx.priority,
+        )
+        .await
+    }
+
+    async fn combined(
+        &self,
+        ctx: &LbsContext,
+        wms: &[WifiMeasurement],
+        cms: &[CellMeasurement],
+    ) -> Result<Option<YandexLbsResponse>, ApiError> {
+        yandex_lbs_request_combined(
+            ctx.tx_t38_conn.clone(),
+            ctx.tx_ba_conn.clone(),
+            wms,
+            cms,
+            ctx.client.clone(),
+            ctx.tx_yandex_api.clone(),
+            ctx.rl_app.clone(),
+            ctx.priority,
+        )
+        .await
+    }
+
+    async fn quota(&self, ctx: &LbsContext) -> Quota {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        if let Err(e) = ctx
+            .tx_yandex_api
+            .send_async(YandexApiMessage::GetActiveKeys { tx })
+            .await
+        {
+            error!("send yandex api message: {}", e);
+        }
+        let keys: Vec<YandexApiKey> = rx.await.unwrap_or_default();
+
+        // the limiters are counted by blobasaur
+        let mut remaining = Some(0u64);
+        for key in &keys {
+            match get_ba_limiter(ctx.tx_ba_conn.clone(), &key.key).await {
+                Err(_) => remaining = None,
+                Ok(limiter) => {
+                    let left = key
+                        .limit
+                        .saturating_sub(CONFIG.yandex_lbs.reserve_limiter)
+                        .saturating_sub(limiter);
+                    remaining = remaining.map(|r| r + left);
+                }
+            }
+        }
+        Quota {
+            available: !keys.is_empty(),
+            remaining,
+        }
+    }
+}
@@ -4,6 +4,7 @@ use std::collections::HashMap;
 use std::time::Duration;
 
 use clusters::Proximity;
+use futures::future::join_all;
 use log::{error, info};
 use once_cell::sync::Lazy;
 use redis::{FromRedisValue, ParsingError, Value};
@@ -16,17 +17,20 @@ use crate::{
         Collection, DEFAULT_RSSI, FALLBACK_EPSILON_CLUSTER, FALLBACK_LOCATE_DISTANCE, HOUR,
         MAX_DISTANCE, MAX_SCOOTER_SPEED, SIGNAL_DROP_COEFFICIENT,
     },
-    db::{
-        blobasaur::get_ba_limiter,
-        t38::{
-            REDIS_NO_DATA, get_wifi_many_from_pipeline, get_yandex_lbs_wifi_missing_one,
-            get_yandex_lbs_wifi_one, set_yandex_lbs_wifi_one, track::WifiTrack,
-        },
+    db::t38::{
+        REDIS_NO_DATA, get_wifi_many_from_pipeline, get_yandex_lbs_wifi_many,
+        get_yandex_lbs_wifi_missing_many, get_yandex_lbs_wifi_missing_one, get_yandex_lbs_wifi_one,
+        set_yandex_lbs_wifi_one, track::WifiTrack,
     },
     error::ApiError,
-    lbs::http_client::HttpClient,
+    lbs::{
+        http_client::HttpClient,
+        provider::{LbsResponses, Priority},
+        single_flight::SingleFlight,
+    },
     services::{
         geolocate_public::LocationResponsePublic,
+        helper::circuit_breaker::BREAKERS,
         locate::dbscan::{Algorithm, DBSCAN, Point, create_cell_points, distance_factor_cell},
         rate_limiter::RateLimitersApp,
     },
@@ -37,7 +41,7 @@ use crate::{
     },
 };
 
-use super::{COUNT_ATTEMPTS, TIMEOUT};
+use super::{COUNT_ATTEMPTS, TIMEOUT, get_api_key, merge_responses};
 
 pub static YANDEX_LBS_URL: Lazy<String> = Lazy::new(|| {
     let yandex_lbs_url = format!(
@@ -47,6 +51,9 @@ pub static YANDEX_LBS_URL: Lazy<String> = Lazy::new(|| {
     yandex_lbs_url
 });
 
+/// Yandex LBS requests by MAC address in flight
+static WIFI_FLIGHTS: Lazy<SingleFlight<Option<LbsResponses>>> = Lazy::new(SingleFlight::default);
+
 macro_rules! not_convertible_error {
     ($v:expr, $det:expr) => {
         ParsingError::from(format!("{:?} (response was {:?})", $det, $v))
@@ -64,7 +71,7 @@ pub struct YandexLbsRequest {
     pub wifi: Vec<WifiMeasurement>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct WifiMeasurement {
     pub bssid: String,
     pub signal_strength: f64,
@@ -76,6 +83,18 @@ pub struct YandexData {
     pub mac: String,
 }
 
+impl YandexData {
+    /// Responses of `GETPIPE ... WITHFIELDS` by MAC address, the access points missing in the
+    /// cache or not parsed are skipped
+    pub fn batch(yandex_data_vec: Vec<Option<YandexData>>) -> HashMap<String, YandexLbsResponse> {
+        yandex_data_vec
+            .into_iter()
+            .flatten()
+            .map(|yd| (yd.mac, yd.ylr))
+            .collect()
+    }
+}
+
 impl FromRedisValue for YandexData {
     /*
     [
@@ -241,25 +260,63 @@ pub async fn yandex_lbs_cache_wifi(
     tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
     wms: &[WifiMeasurement],
 ) -> Result<HashMap<String, Option<YandexLbsResponse>>, ApiError> {
-    let mut lbs_cache: HashMap<String, Option<YandexLbsResponse>> =
-        HashMap::with_capacity(wms.len());
     let collection = Collection::LbsYandexWifi.as_ref();
-    for wm in wms {
-        let mac = wm.bssid.clone();
-        match get_yandex_lbs_wifi_one(tx_t38_conn.clone(), collection, &wm.bssid).await {
-            Err(_e) => {
-                lbs_cache.insert(mac.clone(), None);
-            }
-            Ok(ylr_opt) => {
-                if ylr_opt.is_some() {
-                    lbs_cache.insert(mac.clone(), ylr_opt);
-                }
-            }
+    let macs = wms
+        .iter()
+        .map(|wm| wm.bssid.as_str())
+        .collect::<Vec<&str>>();
+    match get_yandex_lbs_wifi_many(tx_t38_conn, collection, &macs).await {
+        // don`t request the access points in Yandex LBS
+        Err(_e) => Ok(macs.iter().map(|mac| (mac.to_string(), None)).collect()),
+        Ok(ylrs) => Ok(ylrs
+            .into_iter()
+            .map(|(mac, ylr)| (mac, Some(ylr)))
+            .collect()),
+    }
+}
+
+/// Responses cached in `lbs:yandex:wifi` and the access points known to be missing in Yandex
+/// by two pipelined reads, the rest is to be requested
+async fn yandex_lbs_cached_wifi(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    wms: &[WifiMeasurement],
+) -> (
+    HashMap<String, Option<YandexLbsResponse>>,
+    Vec<WifiMeasurement>,
+) {
+    let mut lbs_responses = yandex_lbs_cache_wifi(tx_t38_conn.clone(), wms)
+        .await
+        .unwrap_or_default();
+    let uncached = wms
+        .iter()
+        .filter(|wm| !lbs_responses.contains_key(&wm.bssid))
+        .collect::<Vec<&WifiMeasurement>>();
+    let macs = uncached
+        .iter()
+        .map(|wm| wm.bssid.as_str())
+        .collect::<Vec<&str>>();
+    let missing = get_yandex_lbs_wifi_missing_many(
+        tx_t38_conn,
+        Collection::LbsYandexWifiMissing.as_ref(),
+        &macs,
+    )
+    .await
+    .unwrap_or_default();
+
+    let mut pending = Vec::with_capacity(uncached.len());
+    for wm in uncached {
+        if missing.contains(&wm.bssid) {
+            // don`t repeat the request in Yandex LBS
+            lbs_responses.insert(wm.bssid.clone(), None);
+        } else {
+            pending.push(wm.clone());
         }
     }
-    Ok(lbs_cache)
+    (lbs_responses, pending)
 }
 
+/// Locations of the access points, the missing in the cache are requested from Yandex LBS
+/// one by one and concurrently, `rl_app.yandex_lbs` limits the simultaneous requests
 pub async fn yandex_lbs_request_by_individual_wifi(
     tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
     tx_ba_conn: flume::Sender<BAConnectionManageMessage>,
@@ -267,120 +324,202 @@ pub async fn yandex_lbs_request_by_individual_wifi(
     yandex_client: HttpClient,
     tx_yandex_api: flume::Sender<YandexApiMessage>,
     rl_app: RateLimitersApp,
+    priority: Priority,
 ) -> Result<HashMap<String, Option<YandexLbsResponse>>, ApiError> {
-    let mut lbs_responses: HashMap<String, Option<YandexLbsResponse>> =
-        HashMap::with_capacity(wms.len());
+    let (lbs_responses, pending) = yandex_lbs_cached_wifi(tx_t38_conn.clone(), wms).await;
 
-    let collection = Collection::LbsYandexWifi.as_ref();
-    let tz_moscow = chrono_tz::Europe::Moscow;
+    let requests = pending.iter().map(|wm| {
+        yandex_lbs_request_wifi_one(
+            tx_t38_conn.clone(),
+            tx_ba_conn.clone(),
+            wm,
+            &yandex_client,
+            tx_yandex_api.clone(),
+            &rl_app,
+            priority,
+        )
+    });
+    let bssids = pending.iter().map(|wm| wm.bssid.as_str());
+    merge_responses(lbs_responses, bssids.zip(join_all(requests).await))
+}
 
-    for wm in wms {
-        let mac = wm.bssid.clone();
-        // check whether the specified access point is in the database
-        match get_yandex_lbs_wifi_one(tx_t38_conn.clone(), collection, &wm.bssid).await {
-            Err(_e) => {
-                // don`t repeat the request in Yandex LBS
-                lbs_responses.insert(mac.clone(), None);
-                continue;
-            }
-            Ok(ylr_opt) => {
-                if ylr_opt.is_some() {
-                    // retrieve a previously saved Yandex response from the database
-                    lbs_responses.insert(mac.clone(), ylr_opt);
-                    continue;
+/// Locations of the cached access points requested again from Yandex LBS, the access points
+/// not requested because of the budget are absent in the result
+pub async fn yandex_lbs_revalidate_wifi(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    tx_ba_conn: flume::Sender<BAConnectionManageMessage>,
+    wms: &[WifiMeasurement],
+    yandex_client: HttpClient,
+    tx_yandex_api: flume::Sender<YandexApiMessage>,
+    rl_app: RateLimitersApp,
+    priority: Priority,
+) -> Result<HashMap<String, Option<YandexLbsResponse>>, ApiError> {
+    let requests = wms.iter().map(|wm| {
+        request_wifi_upstream(
+            tx_t38_conn.clone(),
+            tx_ba_conn.clone(),
+            wm,
+            &yandex_client,
+            tx_yandex_api.clone(),
+            &rl_app,
+            priority,
+        )
+    });
+    let bssids = wms.iter().map(|wm| wm.bssid.as_str());
+    merge_responses(
+        HashMap::with_capacity(wms.len()),
+        bssids.zip(join_all(requests).await),
+    )
+}
+
+/// Concurrent lookups of the same access point share one request, the priority is a part of the
+/// key as the budget may allow one priority and deny another
+async fn yandex_lbs_request_wifi_one(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    tx_ba_conn: flume::Sender<BAConnectionManageMessage>,
+    wm: &WifiMeasurement,
+    yandex_client: &HttpClient,
+    tx_yandex_api: flume::Sender<YandexApiMessage>,
+    rl_app: &RateLimitersApp,
+    priority: Priority,
+) -> Result<HashMap<String, Option<YandexLbsResponse>>, ApiError> {
+    let key = format!("{:?}:{}", priority, wm.bssid);
+    let mut error = None;
+    let shared = WIFI_FLIGHTS
+        .run(&key, async {
+            match request_wifi_one(
+                tx_t38_conn,
+                tx_ba_conn,
+                wm,
+                yandex_client,
+                tx_yandex_api,
+                rl_app,
+                priority,
+            )
+            .await
+            {
+                Err(e) => {
+                    error = Some(e);
+                    None
                 }
-                // in case of None make a request to Yandex LBS
+                Ok(lbs_responses) => Some(lbs_responses),
             }
+        })
+        .await;
+    match (shared, error) {
+        (Some(lbs_responses), _) => Ok(lbs_responses),
+        (None, Some(e)) => Err(e),
+        // the shared request failed, possibly because Yandex is down
+        (None, None) => {
+            BREAKERS.yandex.check()?;
+            Err(ApiError::LbsRequestError())
         }
+    }
+}
 
-        // if wifi was previously requested in Yandex and is not available, then skip it
-        if let Ok(Some(_ywm)) = get_yandex_lbs_wifi_missing_one(
-            tx_t38_conn.clone(),
-            Collection::LbsYandexWifiMissing.as_ref(),
-            &wm.bssid,
-        )
-        .await
-        {
-            lbs_responses.insert(mac, None);
+async fn request_wifi_one(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    tx_ba_conn: flume::Sender<BAConnectionManageMessage>,
+    wm: &WifiMeasurement,
+    yandex_client: &HttpClient,
+    tx_yandex_api: flume::Sender<YandexApiMessage>,
+    rl_app: &RateLimitersApp,
+    priority: Priority,
+) -> Result<HashMap<String, Option<YandexLbsResponse>>, ApiError> {
+    let mut lbs_responses: HashMap<String, Option<YandexLbsResponse>> = HashMap::with_capacity(1);
+
+    let collection = Collection::LbsYandexWifi.as_ref();
+    let mac = wm.bssid.clone();
+
+    // check whether the specified access point is in the database
+    match get_yandex_lbs_wifi_one(tx_t38_conn.clone(), collection, &wm.bssid).await {
+        Err(_e) => {
             // don`t repeat the request in Yandex LBS
-            continue;
+            lbs_responses.insert(mac, None);
+            return Ok(lbs_responses);
         }
+        Ok(ylr_opt) => {
+            if ylr_opt.is_some() {
+                // retrieve a previously saved Yandex response from the database
+                lbs_responses.insert(mac, ylr_opt);
+                return Ok(lbs_responses);
+            }
+            // in case of None make a request to Yandex LBS
+        }
+    }
 
-        /*
-            // temporarily for Yandex revalidation via AlterGeo
-            lbs_responses.insert(mac, None);
-            continue;
-        */
+    // if wifi was previously requested in Yandex and is not available, then skip it
+    if let Ok(Some(_ywm)) = get_yandex_lbs_wifi_missing_one(
+        tx_t38_conn.clone(),
+        Collection::LbsYandexWifiMissing.as_ref(),
+        &wm.bssid,
+    )
+    .await
+    {
+        lbs_responses.insert(mac, None);
+        // don`t repeat the request in Yandex LBS
+        return Ok(lbs_responses);
+    }
 
-        let yandex_lbs_request = YandexLbsRequest {
-            wifi: vec![wm.clone()],
-        };
+    request_wifi_upstream(
+        tx_t38_conn,
+        tx_ba_conn,
+        wm,
+        yandex_client,
+        tx_yandex_api,
+        rl_app,
+        priority,
+    )
+    .await
+}
 
-        let (tx, rx) = tokio::sync::oneshot::channel();
-        let yandex_api_message = YandexApiMessage::GetApiKey { tx };
+/// Request the access point from Yandex LBS bypassing the cache, the response is cached
+async fn request_wifi_upstream(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    tx_ba_conn: flume::Sender<BAConnectionManageMessage>,
+    wm: &WifiMeasurement,
+    yandex_client: &HttpClient,
+    tx_yandex_api: flume::Sender<YandexApiMessage>,
+    rl_app: &RateLimitersApp,
+    priority: Priority,
+) -> Result<HashMap<String, Option<YandexLbsResponse>>, ApiError> {
+    let mut lbs_responses: HashMap<String, Option<YandexLbsResponse>> = HashMap::with_capacity(1);
 
-        if let Err(e) = tx_yandex_api.send_async(yandex_api_message).await {
-            error!("send yandex api message: {}", e);
-            lbs_responses.insert(mac, None);
-            continue;
-        }
+    let collection = Collection::LbsYandexWifi.as_ref();
+    let mac = wm.bssid.clone();
 
-        if let Ok(Some(api_key)) = rx.await {
-            if let Ok(limiter) = get_ba_limiter(tx_ba_conn.clone(), &api_key.key.key).await {
-                // DEBUG
-                // TODO: remove after tests by Whoosh
-                info!(
-                    "key {}, limiter: {}, limit: {}",
-                    api_key.key.key, limiter, api_key.key.limit
-                );
+    let yandex_lbs_request = YandexLbsRequest {
+        wifi: vec![wm.clone()],
+    };
 
-                if limiter >= api_key.key.limit - CONFIG.yandex_lbs.reserve_limiter {
-                    // block the current API key
-                    let created_date = chrono::Utc::now().with_timezone(&tz_moscow).date_naive();
-                    let yandex_api_message_invalid_key = YandexApiMessage::InvalidApiKey {
-                        invalid_api_key: InvalidApiKey {
-                            _error: Some(format!("StatusCode {}", 403)),
-                            i: api_key.i,
-                            key: api_key.key,
-                            created_date,
-                        },
-                    };
-                    if let Err(e) = tx_yandex_api
-                        .send_async(yandex_api_message_invalid_key)
-                        .await
-                    {
-                        error!("send yandex api invalid key message: {}", e);
-                    }
-                    return Err(ApiError::LbsError(403));
-                }
-            }
+    // the budget is not spent while Yandex is down
+    BREAKERS.yandex.check()?;
 
-            let yandex_lbs_url = format!("{}{}", CONFIG.yandex_lbs.url, &api_key.key.key);
-
-            // Acquire permit before processing request
-            // Permit released automatically when the handler is completed
-            let _permit = rl_app.yandex_lbs.acquire().await;
-
-            if let Err(e) = yandex_client
-                .post_for_data(
-                    &mac,
-                    &yandex_lbs_url,
-                    &yandex_lbs_request,
-                    tx_yandex_api.clone(),
-                    api_key,
-                    &mut lbs_responses,
-                    tx_t38_conn.clone(),
-                    tx_ba_conn.clone(),
-                    collection,
-                )
-                .await
-            {
-                return Err(e);
-            }
-        } else {
-            return Err(ApiError::LbsError(403));
-        }
-    }
+    // not requested: the budget of the priority is spent
+    let Some(api_key) = get_api_key(tx_yandex_api.clone(), tx_ba_conn.clone(), priority).await?
+    else {
+        return Ok(lbs_responses);
+    };
+
+    let yandex_lbs_url = format!("{}{}", CONFIG.yandex_lbs.url, &api_key.key.key);
+
+    // Acquire permit before processing request
+    // Permit released automatically when the request is completed
+    let _permit = rl_app.yandex_lbs.acquire().await;
+
+    yandex_client
+        .post_for_data(
+            &mac,
+            &yandex_lbs_url,
+            &yandex_lbs_request,
+            tx_yandex_api,
+            api_key,
+            &mut lbs_responses,
+            tx_t38_conn,
+            tx_ba_conn,
+            collection,
+        )
+        .await?;
 
     Ok(lbs_responses)
 }
@@ -1071,6 +1210,7 @@ pub fn estimate_location_by_yandex_responses(
 #[cfg(test)]
 mod tests {
     use clusters::Proximity;
+    use futures::future::join_all;
 
     use super::{
         CONFIG, WifiMeasurement, detect_yandex_outliers, estimate_location_by_yandex_responses,
@@ -1692,4 +1832,37 @@ mod tests {
         */
         do_yandex_lbs_requests(wms).await;
     }
+
+    #[test]
+    fn yandex_data_batch() {
+        use redis::{FromRedisValue, Value};
+
+        use super::YandexData;
+
+        let bulk = |s: &str| Value::BulkString(s.as_bytes().to_vec());
+        let value = Value::Array(vec![
+            bulk(r#"{"type":"Point","coordinates":[37.900367606988134,55.68643406911036]}"#),
+            Value::Array(vec![
+                bulk("data"),
+                bulk(
+                    r#"{"location":{"point":{"lat":55.68643406911036,"lon":37.900367606988134},"accuracy":55.69628143310547}}"#,
+                ),
+                bulk("mac"),
+                bulk("00:02:6f:aa:a3:97"),
+            ]),
+        ]);
+        let yandex_data_vec = vec![
+            YandexData::from_redis_value(value).ok(),
+            // the access point is missing in the cache
+            YandexData::from_redis_value(bulk("")).ok(),
+        ];
+        assert!(yandex_data_vec[1].is_none());
+
+        let ylrs = YandexData::batch(yandex_data_vec);
+        assert_eq!(ylrs.len(), 1);
+        assert_eq!(
+            ylrs["00:02:6f:aa:a3:97"].location.point.lat,
+            55.68643406911036
+        );
+    }
 }
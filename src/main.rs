@@ -21,7 +21,11 @@ use crate::{
     config::CONFIG,
     constants::HC,
     lbs::http_client::HttpClient,
-    services::{crate_rate_limiters_app, validator},
+    services::{
+        crate_rate_limiters_app,
+        submission::{import::ImportFormat, reprocess::ReprocessArgs},
+        validator,
+    },
     tasks::{
         blobasaur::{self, BAConnectionManageMessage},
         report::MessageSaveReport,
@@ -45,6 +49,20 @@ struct Cli {
 enum Command {
     /// Serve the Locator geolocate service
     Serve,
+    /// Import stumbler exports (NeoStumbler JSON, Tower Collector CSV, WiGLE CSV)
+    Import {
+        /// format of all files, detected by content if omitted
+        #[arg(long)]
+        format: Option<ImportFormat>,
+        files: Vec<PathBuf>,
+    },
+    /// Rebuild the Wi-Fi and cell aggregates from the retained reports
+    Reprocess(ReprocessArgs),
+    /// Download the IEEE OUI registry (or import the local files) into the configured file
+    OuiRefresh {
+        /// registry files in the IEEE CSV format, downloaded if omitted
+        files: Vec<PathBuf>,
+    },
 }
 
 #[tokio::main]
@@ -150,6 +168,45 @@ async fn main() -> Result<()> {
             let _process_reports_partitions_handle =
                 tasks::report::process_report_partitions_task(pool_tp.clone());
 
+            let mut _retention_handle: Option<JoinHandle<()>> = None;
+            if CONFIG.retention.enabled {
+                _retention_handle = Some(tasks::retention::retention_task(
+                    pool_tp.clone(),
+                    tx_t38_conn.clone(),
+                    tx_ba_conn.clone(),
+                ));
+            }
+
+            let mut _ssid_sweep_handle: Option<JoinHandle<()>> = None;
+            if CONFIG.ssid.opt_out {
+                _ssid_sweep_handle = Some(tasks::ssid::ssid_sweep_task(
+                    tx_t38_conn.clone(),
+                    tx_ba_conn.clone(),
+                ));
+            }
+
+            let mut _enrichment_handle: Option<JoinHandle<()>> = None;
+            if CONFIG.enrichment.enabled {
+                _enrichment_handle = Some(tasks::enrichment::enrichment_task(
+                    tx_t38_conn.clone(),
+                    tx_ba_conn.clone(),
+                    yandex_client.clone(),
+                    tx_yandex_api.clone(),
+                    rl_app.clone(),
+                ));
+            }
+
+            let mut _revalidation_handle: Option<JoinHandle<()>> = None;
+            if CONFIG.yandex_lbs.enabled && CONFIG.lbs_cache.revalidate {
+                _revalidation_handle = Some(tasks::revalidation::revalidation_task(
+                    tx_t38_conn.clone(),
+                    tx_ba_conn.clone(),
+                    yandex_client.clone(),
+                    tx_yandex_api.clone(),
+                    rl_app.clone(),
+                ));
+            }
+
             let mut _gc_t38_handle: Option<JoinHandle<()>> = None;
             if let Some(gc_frequency) = CONFIG.t38.gc_frequency {
                 _gc_t38_handle = Some(tasks::t38::gc_task(tx_t38_conn.clone(), gc_frequency));
@@ -226,16 +283,27 @@ async fn main() -> Result<()> {
                         web::JsonConfig::default()
                             .limit(CONFIG.server.max_payload_mb * 1024 * 1024),
                     )
+                    // limit of the decompressed body for /report and /locate
+                    .app_data(web::PayloadConfig::new(
+                        CONFIG.server.max_payload_mb * 1024 * 1024,
+                    ))
                     // public services
                     .service(
                         web::scope("/api/v1")
                             .service(services::geolocate_public::service)
                             .service(services::submission::geosubmit_public::service)
                             .service(services::submission::report::extract_report)
-                            .service(services::submission::report::scan_reports)
+                            .service(services::submission::report::search_reports)
                             .service(services::submission::report::process_report)
+                            .service(services::submission::import::service)
                             .service(services::submission::cell::service)
                             .service(services::health::service)
+                            .service(services::admin::wifi)
+                            .service(services::admin::cell)
+                            .service(services::admin::bluetooth)
+                            .service(services::admin::vendors)
+                            .service(services::admin::lbs)
+                            .service(services::admin::lbs_budget)
                             .service(services::routing::matching::service)
                             .wrap(HttpAuthentication::bearer(validator))
                             .wrap(logger),
@@ -254,6 +322,36 @@ async fn main() -> Result<()> {
             tokio::spawn(graceful_shutdown(handle, save_report_handle_opt));
             server.await?;
         }
+        Command::Import { format, files } => {
+            let (tx_t38_conn, rx_t38_conn) = flume::unbounded::<T38ConnectionManageMessage>();
+            let _connection_manage_t38_handle =
+                t38::connection_manage_task(rx_t38_conn, tx_t38_conn.clone()).await?;
+
+            let (tx_ba_conn, rx_ba_conn) = flume::unbounded::<BAConnectionManageMessage>();
+            if CONFIG.blobasaur.enabled {
+                let _connection_manage_ba_handle =
+                    blobasaur::manage_blobasaur(rx_ba_conn, tx_ba_conn.clone()).await?;
+            }
+
+            services::submission::import::run(pool_tp, tx_t38_conn, tx_ba_conn, format, files)
+                .await?;
+        }
+        Command::Reprocess(args) => {
+            let (tx_t38_conn, rx_t38_conn) = flume::unbounded::<T38ConnectionManageMessage>();
+            let _connection_manage_t38_handle =
+                t38::connection_manage_task(rx_t38_conn, tx_t38_conn.clone()).await?;
+
+            let (tx_ba_conn, rx_ba_conn) = flume::unbounded::<BAConnectionManageMessage>();
+            if CONFIG.blobasaur.enabled {
+                let _connection_manage_ba_handle =
+                    blobasaur::manage_blobasaur(rx_ba_conn, tx_ba_conn.clone()).await?;
+            }
+
+            services::submission::reprocess::run(pool_tp, tx_t38_conn, tx_ba_conn, args).await?;
+        }
+        Command::OuiRefresh { files } => {
+            services::helper::oui::refresh(files).await?;
+        }
     };
 
     Ok(())
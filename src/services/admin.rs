@@ -0,0 +1,178 @@
+//! Lookups of the aggregated transmitters for the administration.
+
+use actix_web::{HttpResponse, get, web};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    CONFIG,
+    constants::Collection,
+    db::{
+        model::CellRadio,
+        pg::{get_bluetooth_info, get_cell_info},
+        t38::get_wifi_one,
+    },
+    error::{ApiError, create_error_response},
+    lbs::{
+        http_client::HttpClient,
+        provider::{self, LbsContext},
+        yandex::budget,
+    },
+    services::{
+        helper::{custom_deserialize::normalize_mac, oui::vendor_stats},
+        rate_limiter::RateLimitersApp,
+    },
+    tasks::{
+        blobasaur::BAConnectionManageMessage, t38::T38ConnectionManageMessage,
+        yandex::YandexApiMessage,
+    },
+};
+
+// number of vendors in the statistics by default
+const VENDORS_LIMIT: usize = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct VendorsQuery {
+    limit: Option<usize>,
+}
+
+fn not_found(domain: &str) -> HttpResponse {
+    HttpResponse::NotFound().json(json!(
+        {
+            "error": {
+                "domain": domain,
+                "reason": "not found",
+                "message": "transmitter not found",
+                "code": 404,
+            }
+        }
+    ))
+}
+
+#[get("/admin/wifi/{mac}")]
+pub async fn wifi(
+    path: web::Path<String>,
+    tx_t38_conn: web::Data<flume::Sender<T38ConnectionManageMessage>>,
+) -> actix_web::Result<HttpResponse> {
+    let mac = normalize_mac(path.into_inner());
+    let tx_t38c = (*tx_t38_conn.into_inner()).clone();
+    match get_wifi_one(tx_t38c, Collection::Wifi.as_ref(), &mac).await {
+        Err(e) => Ok(create_error_response(
+            ApiError::Tile38Error(e.to_string()),
+            "admin",
+        )),
+        Ok(None) => Ok(not_found("admin")),
+        Ok(Some(mut tl)) => {
+            tl.measurements = None;
+            Ok(HttpResponse::Ok().json(tl))
+        }
+    }
+}
+
+#[get("/admin/cell/{radio}/{country}/{network}/{area}/{cell}/{unit}")]
+pub async fn cell(
+    path: web::Path<(CellRadio, i16, i16, i32, i64, i16)>,
+    pool_tp: web::Data<deadpool_postgres::Pool>,
+) -> actix_web::Result<HttpResponse> {
+    let (radio, country, network, area, cell, unit) = path.into_inner();
+    let manager = pool_tp
+        .get()
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    match get_cell_info(
+        manager.client(),
+        radio as i16,
+        country,
+        network,
+        area,
+        cell,
+        unit,
+    )
+    .await
+    .map_err(actix_web::error::ErrorInternalServerError)?
+    {
+        None => Ok(not_found("admin")),
+        Some(info) => Ok(HttpResponse::Ok().json(info)),
+    }
+}
+
+#[get("/admin/bluetooth/{mac}")]
+pub async fn bluetooth(
+    path: web::Path<String>,
+    pool_tp: web::Data<deadpool_postgres::Pool>,
+) -> actix_web::Result<HttpResponse> {
+    let mac = normalize_mac(path.into_inner());
+    let manager = pool_tp
+        .get()
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    match get_bluetooth_info(manager.client(), &mac)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?
+    {
+        None => Ok(not_found("admin")),
+        Some(info) => Ok(HttpResponse::Ok().json(info)),
+    }
+}
+
+#[get("/admin/vendors")]
+pub async fn vendors(
+    query: web::Query<VendorsQuery>,
+    tx_t38_conn: web::Data<flume::Sender<T38ConnectionManageMessage>>,
+) -> actix_web::Result<HttpResponse> {
+    let tx_t38c = (*tx_t38_conn.into_inner()).clone();
+    match vendor_stats(tx_t38c, query.limit.unwrap_or(VENDORS_LIMIT)).await {
+        Err(e) => Ok(create_error_response(
+            ApiError::Tile38Error(e.to_string()),
+            "admin",
+        )),
+        Ok(stats) => Ok(HttpResponse::Ok().json(stats)),
+    }
+}
+
+/// External LBS providers in the priority order and their quotas
+#[get("/admin/lbs")]
+pub async fn lbs(
+    tx_t38_conn: web::Data<flume::Sender<T38ConnectionManageMessage>>,
+    tx_ba_conn: web::Data<flume::Sender<BAConnectionManageMessage>>,
+    yandex_client_web: web::Data<HttpClient>,
+    tx_yandex_api_web: web::Data<flume::Sender<YandexApiMessage>>,
+    rl_app_web: web::Data<RateLimitersApp>,
+) -> actix_web::Result<HttpResponse> {
+    let ctx = LbsContext::new(
+        (*tx_t38_conn.into_inner()).clone(),
+        (*tx_ba_conn.into_inner()).clone(),
+        (*yandex_client_web.into_inner()).clone(),
+        (*tx_yandex_api_web.into_inner()).clone(),
+        (*rl_app_web.into_inner()).clone(),
+    );
+    let providers = provider::quotas(&ctx)
+        .await
+        .into_iter()
+        .map(|(name, quota)| {
+            json!({
+                "name": name,
+                "available": quota.available,
+                "remaining": quota.remaining,
+            })
+        })
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(json!({ "providers": providers })))
+}
+
+/// Today's spending of the Yandex API keys by the request priority
+#[get("/admin/lbs/budget")]
+pub async fn lbs_budget(
+    tx_ba_conn: web::Data<flume::Sender<BAConnectionManageMessage>>,
+    tx_yandex_api_web: web::Data<flume::Sender<YandexApiMessage>>,
+) -> actix_web::Result<HttpResponse> {
+    let keys = budget::budgets(
+        (*tx_yandex_api_web.into_inner()).clone(),
+        (*tx_ba_conn.into_inner()).clone(),
+    )
+    .await;
+    Ok(HttpResponse::Ok().json(json!({
+        "enabled": CONFIG.lbs_budget.enabled,
+        "keys": keys,
+    })))
+}
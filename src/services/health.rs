@@ -1,11 +1,15 @@
 use actix_web::{HttpRequest, HttpResponse, get};
 use serde_json::json;
 
+use crate::services::helper::circuit_breaker::BREAKERS;
+
 #[get("/health")]
 pub async fn service(_req: HttpRequest) -> actix_web::Result<HttpResponse> {
+    // an open breaker degrades the service, it is still alive
     Ok(HttpResponse::Ok().json(json!(
         {
-            "message": "OK"
+            "message": "OK",
+            "circuit_breakers": BREAKERS.status(),
         }
     )))
 }
@@ -0,0 +1,92 @@
+use actix_web::{HttpRequest, http::header::CONTENT_TYPE};
+use serde::de::DeserializeOwned;
+
+// Content-Encoding (gzip, zstd) is handled by the actix-web body extractor,
+// here only the format of the decompressed body is selected
+const CONTENT_TYPE_JSON: &[&str] = &["application/json"];
+const CONTENT_TYPE_MSGPACK: &[&str] = &[
+    "application/msgpack",
+    "application/x-msgpack",
+    "application/vnd.msgpack",
+];
+const CONTENT_TYPE_PROTOBUF: &[&str] = &["application/x-protobuf", "application/protobuf"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BodyFormat {
+    Json,
+    MessagePack,
+    Protobuf,
+}
+
+impl BodyFormat {
+    /// Format of the request body by Content-Type, JSON if the header is absent
+    pub fn from_request(req: &HttpRequest) -> Option<Self> {
+        let content_type = match req.headers().get(CONTENT_TYPE) {
+            None => return Some(Self::Json),
+            Some(ct) => ct.to_str().ok()?,
+        };
+        let essence = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase();
+
+        if essence.is_empty() || CONTENT_TYPE_JSON.contains(&essence.as_str()) {
+            Some(Self::Json)
+        } else if CONTENT_TYPE_MSGPACK.contains(&essence.as_str()) {
+            Some(Self::MessagePack)
+        } else if CONTENT_TYPE_PROTOBUF.contains(&essence.as_str()) {
+            Some(Self::Protobuf)
+        } else {
+            None
+        }
+    }
+}
+
+/// Decode the body into `T`, protobuf is decoded into message `P` and then converted
+pub fn decode_body<T, P>(format: BodyFormat, body: &[u8]) -> Result<T, anyhow::Error>
+where
+    T: DeserializeOwned + From<P>,
+    P: prost::Message + Default,
+{
+    match format {
+        BodyFormat::Json => Ok(serde_json::from_slice(body)?),
+        BodyFormat::MessagePack => Ok(rmp_serde::from_slice(body)?),
+        BodyFormat::Protobuf => Ok(P::decode(body)?.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn body_format_by_content_type() {
+        let req = actix_web::test::TestRequest::default().to_http_request();
+        assert_eq!(BodyFormat::from_request(&req), Some(BodyFormat::Json));
+
+        let req = actix_web::test::TestRequest::default()
+            .insert_header((CONTENT_TYPE, "application/json; charset=utf-8"))
+            .to_http_request();
+        assert_eq!(BodyFormat::from_request(&req), Some(BodyFormat::Json));
+
+        let req = actix_web::test::TestRequest::default()
+            .insert_header((CONTENT_TYPE, "application/x-msgpack"))
+            .to_http_request();
+        assert_eq!(
+            BodyFormat::from_request(&req),
+            Some(BodyFormat::MessagePack)
+        );
+
+        let req = actix_web::test::TestRequest::default()
+            .insert_header((CONTENT_TYPE, "application/x-protobuf"))
+            .to_http_request();
+        assert_eq!(BodyFormat::from_request(&req), Some(BodyFormat::Protobuf));
+
+        let req = actix_web::test::TestRequest::default()
+            .insert_header((CONTENT_TYPE, "text/plain"))
+            .to_http_request();
+        assert_eq!(BodyFormat::from_request(&req), None);
+    }
+}
@@ -0,0 +1,356 @@
+//! Circuit breakers of the upstreams: Yandex LBS, AlterGeo, Ichnaea and GraphHopper.
+//!
+//! When the share of the failed requests in the window reaches `failure_rate`, the breaker opens
+//! and the requests fail at once with `ApiError::UpstreamUnavailable` instead of waiting for the
+//! timeouts and the retries of the dead upstream. After `open_timeout` one trial request is let
+//! through (half-open): its success closes the breaker, its failure opens it again.
+//!
+//! Only the transport errors, the timeouts and the 5xx responses are failures: the rejected keys
+//! and the unknown transmitters say nothing about the health of the upstream.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Display,
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::{CONFIG, config::CircuitBreaker, error::ApiError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BreakerStatus {
+    pub state: State,
+    /// requests in the window
+    pub requests: usize,
+    /// failed requests in the window
+    pub failures: usize,
+}
+
+struct Inner {
+    state: State,
+    /// when the breaker was opened or the trial request was let through
+    since: Instant,
+    /// outcomes of the requests in the window, `true` if failed
+    outcomes: VecDeque<(Instant, bool)>,
+}
+
+pub struct Breaker {
+    name: &'static str,
+    params: CircuitBreaker,
+    timeout: Duration,
+    inner: Mutex<Inner>,
+}
+
+impl Breaker {
+    pub fn new(name: &'static str, params: CircuitBreaker, timeout_ms: u64) -> Self {
+        Self {
+            name,
+            params,
+            timeout: Duration::from_millis(timeout_ms),
+            inner: Mutex::new(Inner {
+                state: State::Closed,
+                since: Instant::now(),
+                outcomes: VecDeque::new(),
+            }),
+        }
+    }
+
+    fn allows(&self, inner: &Inner, now: Instant) -> bool {
+        match inner.state {
+            State::Closed => true,
+            State::Open => {
+                now.duration_since(inner.since) >= Duration::from_secs(self.params.open_timeout)
+            }
+            // the trial request is in flight, the next one is let through if it timed out
+            State::HalfOpen => now.duration_since(inner.since) >= self.timeout,
+        }
+    }
+
+    /// Fails at once if the breaker is open, the trial request is not taken
+    pub fn check(&self) -> Result<(), ApiError> {
+        let inner = self.inner.lock().unwrap();
+        if !self.params.enabled || self.allows(&inner, Instant::now()) {
+            return Ok(());
+        }
+        Err(ApiError::UpstreamUnavailable(self.name))
+    }
+
+    /// Lets the request through, the first one after `open_timeout` is the trial
+    pub fn acquire(&self) -> Result<(), ApiError> {
+        self.acquire_at(Instant::now())
+    }
+
+    fn acquire_at(&self, now: Instant) -> Result<(), ApiError> {
+        if !self.params.enabled {
+            return Ok(());
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if !self.allows(&inner, now) {
+            return Err(ApiError::UpstreamUnavailable(self.name));
+        }
+        if inner.state != State::Closed {
+            inner.state = State::HalfOpen;
+            inner.since = now;
+        }
+        Ok(())
+    }
+
+    /// Outcome of the request let through by `acquire`
+    pub fn record(&self, failed: bool) {
+        self.record_at(Instant::now(), failed);
+    }
+
+    fn record_at(&self, now: Instant, failed: bool) {
+        if !self.params.enabled {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            State::HalfOpen => {
+                if failed {
+                    warn!("{} circuit breaker is open again", self.name);
+                    inner.state = State::Open;
+                    inner.since = now;
+                } else {
+                    info!("{} circuit breaker is closed", self.name);
+                    inner.state = State::Closed;
+                    inner.outcomes.clear();
+                }
+            }
+            // the request was let through before the breaker opened
+            State::Open => {}
+            State::Closed => {
+                inner.outcomes.push_back((now, failed));
+                let window = Duration::from_secs(self.params.window);
+                while inner
+                    .outcomes
+                    .front()
+                    .is_some_and(|&(at, _)| now.duration_since(at) > window)
+                {
+                    inner.outcomes.pop_front();
+                }
+
+                let requests = inner.outcomes.len();
+                let failures = inner.outcomes.iter().filter(|(_, failed)| *failed).count();
+                if requests >= self.params.min_requests
+                    && failures as f64 >= self.params.failure_rate * requests as f64
+                {
+                    warn!(
+                        "{} circuit breaker is open: {} of {} requests failed",
+                        self.name, failures, requests
+                    );
+                    inner.state = State::Open;
+                    inner.since = now;
+                    inner.outcomes.clear();
+                }
+            }
+        }
+    }
+
+    /// Outcome of the request by the response status, 5xx is a failure
+    pub fn record_status(&self, status: u16) {
+        self.record(status >= 500);
+    }
+
+    /// Sends the request within the timeout, the transport error and the timeout are failures.
+    /// The response is to be recorded by `record_status`.
+    pub async fn send<R, E: Display>(
+        &self,
+        request: impl Future<Output = Result<R, E>>,
+    ) -> Result<R, String> {
+        match tokio::time::timeout(self.timeout, request).await {
+            Err(_) => {
+                self.record(true);
+                Err(format!("timed out after {} ms", self.timeout.as_millis()))
+            }
+            Ok(Err(e)) => {
+                self.record(true);
+                Err(e.to_string())
+            }
+            Ok(Ok(response)) => Ok(response),
+        }
+    }
+
+    /// Runs the whole request through the breaker within the timeout
+    pub async fn call<T>(
+        &self,
+        request: impl Future<Output = Result<T, ApiError>>,
+    ) -> Result<T, ApiError> {
+        self.acquire()?;
+        match tokio::time::timeout(self.timeout, request).await {
+            Err(_) => {
+                self.record(true);
+                warn!(
+                    "{} request timed out after {} ms",
+                    self.name,
+                    self.timeout.as_millis()
+                );
+                Err(ApiError::LbsRequestError())
+            }
+            Ok(result) => {
+                self.record(result.as_ref().is_err_and(is_failure));
+                result
+            }
+        }
+    }
+
+    pub fn status(&self) -> BreakerStatus {
+        let now = Instant::now();
+        let window = Duration::from_secs(self.params.window);
+        let inner = self.inner.lock().unwrap();
+        let recent = inner
+            .outcomes
+            .iter()
+            .filter(|(at, _)| now.duration_since(*at) <= window);
+        BreakerStatus {
+            state: inner.state,
+            requests: recent.clone().count(),
+            failures: recent.filter(|(_, failed)| *failed).count(),
+        }
+    }
+}
+
+fn is_failure(e: &ApiError) -> bool {
+    match e {
+        ApiError::LbsRequestError() => true,
+        ApiError::LbsError(code) => *code >= 500,
+        _ => false,
+    }
+}
+
+pub struct Breakers {
+    pub yandex: Breaker,
+    pub altergeo: Breaker,
+    pub ichnaea: Breaker,
+    pub graphhopper: Breaker,
+}
+
+impl Breakers {
+    /// State of the breakers by the upstream
+    pub fn status(&self) -> BTreeMap<&'static str, BreakerStatus> {
+        [
+            &self.yandex,
+            &self.altergeo,
+            &self.ichnaea,
+            &self.graphhopper,
+        ]
+        .into_iter()
+        .map(|breaker| (breaker.name, breaker.status()))
+        .collect()
+    }
+}
+
+pub static BREAKERS: Lazy<Breakers> = Lazy::new(|| {
+    let params = &CONFIG.circuit_breaker;
+    Breakers {
+        yandex: Breaker::new("yandex", params.clone(), params.yandex_timeout),
+        altergeo: Breaker::new("altergeo", params.clone(), params.altergeo_timeout),
+        ichnaea: Breaker::new("ichnaea", params.clone(), params.ichnaea_timeout),
+        graphhopper: Breaker::new("graphhopper", params.clone(), params.graphhopper_timeout),
+    }
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker() -> Breaker {
+        let params = CircuitBreaker {
+            enabled: true,
+            window: 60,
+            min_requests: 4,
+            failure_rate: 0.5,
+            open_timeout: 30,
+            yandex_timeout: 5000,
+            altergeo_timeout: 5000,
+            ichnaea_timeout: 5000,
+            graphhopper_timeout: 30000,
+        };
+        Breaker::new("yandex", params.clone(), params.yandex_timeout)
+    }
+
+    fn state(breaker: &Breaker) -> State {
+        breaker.inner.lock().unwrap().state
+    }
+
+    #[test]
+    fn circuit_breaker_opens() {
+        let breaker = breaker();
+        let now = Instant::now();
+        breaker.record_at(now, true);
+        breaker.record_at(now, true);
+        breaker.record_at(now, false);
+        // not enough requests in the window
+        assert_eq!(state(&breaker), State::Closed);
+
+        breaker.record_at(now, true);
+        assert_eq!(state(&breaker), State::Open);
+        assert!(matches!(
+            breaker.acquire_at(now + Duration::from_secs(10)),
+            Err(ApiError::UpstreamUnavailable("yandex"))
+        ));
+    }
+
+    #[test]
+    fn circuit_breaker_window() {
+        let breaker = breaker();
+        let now = Instant::now();
+        breaker.record_at(now, true);
+        breaker.record_at(now, true);
+        breaker.record_at(now, true);
+        // the failures are out of the window
+        let later = now + Duration::from_secs(61);
+        breaker.record_at(later, false);
+        breaker.record_at(later, true);
+        breaker.record_at(later, false);
+        breaker.record_at(later, false);
+        assert_eq!(state(&breaker), State::Closed);
+    }
+
+    #[test]
+    fn circuit_breaker_half_open() {
+        let breaker = breaker();
+        let now = Instant::now();
+        for _ in 0..4 {
+            breaker.record_at(now, true);
+        }
+        assert_eq!(state(&breaker), State::Open);
+
+        // the trial request
+        let trial = now + Duration::from_secs(30);
+        assert!(breaker.acquire_at(trial).is_ok());
+        assert_eq!(state(&breaker), State::HalfOpen);
+        assert!(breaker.acquire_at(trial + Duration::from_secs(1)).is_err());
+        breaker.record_at(trial + Duration::from_secs(1), true);
+        assert_eq!(state(&breaker), State::Open);
+
+        let trial = trial + Duration::from_secs(31);
+        assert!(breaker.acquire_at(trial).is_ok());
+        // the trial request timed out, the next one is let through
+        assert!(breaker.acquire_at(trial + Duration::from_secs(5)).is_ok());
+        breaker.record_at(trial + Duration::from_secs(5), false);
+        assert_eq!(state(&breaker), State::Closed);
+        assert!(breaker.acquire_at(trial + Duration::from_secs(5)).is_ok());
+    }
+
+    #[test]
+    fn circuit_breaker_failure() {
+        assert!(is_failure(&ApiError::LbsRequestError()));
+        assert!(is_failure(&ApiError::LbsError(504)));
+        assert!(!is_failure(&ApiError::LbsError(403)));
+        assert!(!is_failure(&ApiError::JsonProcessingError));
+    }
+}
@@ -16,18 +16,16 @@ pub fn date_time_utc_from_str<'de, D>(deserializer: D) -> Result<DateTime<Utc>,
 where
     D: Deserializer<'de>,
 {
-    let threshold: i64 = 1_700_000_000_000;
     let n: i64 = Deserialize::deserialize(deserializer)?;
+    date_time_utc_from_i64(n).map_err(serde::de::Error::custom)
+}
+
+pub fn date_time_utc_from_i64(n: i64) -> Result<DateTime<Utc>, ApiError> {
+    let threshold: i64 = 1_700_000_000_000;
     if n < threshold {
-        let res = DateTime::from_timestamp(n, 0)
-            .ok_or(ApiError::TimestampParseFailed(n.to_string()))
-            .map_err(serde::de::Error::custom)?;
-        return Ok(res);
+        return DateTime::from_timestamp(n, 0).ok_or(ApiError::TimestampParseFailed(n.to_string()));
     }
-    let res = DateTime::from_timestamp_millis(n)
-        .ok_or(ApiError::TimestampParseFailed(n.to_string()))
-        .map_err(serde::de::Error::custom)?;
-    Ok(res)
+    DateTime::from_timestamp_millis(n).ok_or(ApiError::TimestampParseFailed(n.to_string()))
 }
 
 pub fn validate_rssi<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
@@ -35,7 +33,12 @@ where
     D: Deserializer<'de>,
 {
     let r: Option<f64> = Deserialize::deserialize(deserializer)?;
-    Ok(r.map(|v| if v >= 0.0 { DEFAULT_RSSI } else { v }))
+    Ok(rssi_or_default(r))
+}
+
+// non-negative RSSI is invalid
+pub fn rssi_or_default(rssi: Option<f64>) -> Option<f64> {
+    rssi.map(|v| if v >= 0.0 { DEFAULT_RSSI } else { v })
 }
 
 // accept mac-address with or without colons:
@@ -45,11 +48,15 @@ pub fn mac_address<'de, D>(deserializer: D) -> Result<String, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let mut mac: String = Deserialize::deserialize(deserializer)?;
-    if !mac.contains(":") {
-        mac = mac_formatted(&mac, 2);
+    let mac: String = Deserialize::deserialize(deserializer)?;
+    Ok(normalize_mac(mac))
+}
+
+pub fn normalize_mac(mac: String) -> String {
+    if mac.contains(":") {
+        return mac;
     }
-    Ok(mac)
+    mac_formatted(&mac, 2)
 }
 
 // BSSID formatted as xx:xx:xx:xx:xx:xx
@@ -0,0 +1,52 @@
+//! Exponential time decay of the observation weights: the weight halves every half-life.
+//!
+//! The accumulated weights are kept decayed to the latest observation, an observation older than
+//! that adds less instead.
+
+use once_cell::sync::Lazy;
+
+use crate::CONFIG;
+
+/// Milliseconds in a day
+pub const DAY: i64 = 86_400_000;
+
+/// Half-life of `aggregation_half_life`, milliseconds, 0 disables the decay
+pub static HALF_LIFE: Lazy<f64> = Lazy::new(|| half_life(CONFIG.locator.aggregation_half_life));
+
+/// Half-life in days to milliseconds
+pub fn half_life(days: f64) -> f64 {
+    days * DAY as f64
+}
+
+/// Factor of the weight `elapsed` milliseconds old
+pub fn factor(half_life: f64, elapsed: i64) -> f64 {
+    if half_life > 0.0 && elapsed > 0 {
+        0.5_f64.powf(elapsed as f64 / half_life)
+    } else {
+        1.0
+    }
+}
+
+/// Factor of the weights accumulated by `updated_at` and the weight of the observation at
+/// `timestamp`, both decayed to the newer of the two
+pub fn to_latest(half_life: f64, updated_at: i64, weight: f64, timestamp: i64) -> (f64, f64) {
+    if timestamp >= updated_at {
+        (factor(half_life, timestamp - updated_at), weight)
+    } else {
+        (1.0, weight * factor(half_life, updated_at - timestamp))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decay_to_latest() {
+        let half_life = half_life(30.0);
+        assert_eq!(to_latest(half_life, 0, 1.0, 30 * DAY), (0.5, 1.0));
+        assert_eq!(to_latest(half_life, 60 * DAY, 1.0, 0), (1.0, 0.25));
+        // disabled
+        assert_eq!(to_latest(0.0, 0, 1.0, 30 * DAY), (1.0, 1.0));
+    }
+}
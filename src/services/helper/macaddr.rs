@@ -64,6 +64,15 @@ impl MacAddr {
     pub fn octets(&self) -> [u8; 6] {
         [self.0, self.1, self.2, self.3, self.4, self.5]
     }
+
+    /// Returns the upper `bits` of the address, e.g. 24 for the OUI.
+    pub fn prefix(&self, bits: u32) -> u64 {
+        let value = self
+            .octets()
+            .iter()
+            .fold(0u64, |acc, b| (acc << 8) | *b as u64);
+        value >> (48 - bits.min(48))
+    }
 }
 
 impl From<EtherAddr> for MacAddr {
@@ -246,6 +255,15 @@ mod tests {
         }
     }
 
+    #[test]
+    fn prefix_of_addr() {
+        let mac = MacAddr(0x70, 0xb3, 0xd5, 0x12, 0x34, 0x56);
+        assert_eq!(mac.prefix(24), 0x70b3d5);
+        assert_eq!(mac.prefix(28), 0x70b3d51);
+        assert_eq!(mac.prefix(36), 0x70b3d5123);
+        assert_eq!(mac.prefix(48), 0x70b3d5123456);
+    }
+
     #[test]
     fn conversion() {
         let mac = MacAddr(0x12, 0x34, 0x56, 0x78, 0x90, 0xAB);
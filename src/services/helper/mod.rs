@@ -1,7 +1,13 @@
+pub mod body;
+pub mod circuit_breaker;
 pub mod custom_deserialize;
+pub mod decay;
 pub mod macaddr;
+pub mod oui;
 pub mod pool_task;
+pub mod proto;
 pub mod rate_limiter;
+pub mod ssid;
 pub mod validation;
 
 pub fn round(x: f64, decimals: u32) -> f64 {
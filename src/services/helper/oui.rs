@@ -0,0 +1,444 @@
+//! IEEE OUI registry: vendor of the access point by the assignment of its MAC address
+//! (MA-L, MA-M and MA-S blocks).
+//!
+//! The registry is loaded from the local CSV file, `locator oui-refresh` downloads it from IEEE.
+//! Without the file the registry bundled into the binary at build time (see `build.rs`) is used.
+//! Access points of the excluded vendors (in-car Wi-Fi, phone hotspots, dashcams) are not
+//! aggregated and not used for locate.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result, anyhow};
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use redis::RedisError;
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::{
+    CONFIG, config::Oui, constants::Collection, db::t38::scan::scan_ids_page,
+    services::helper::macaddr::MacAddr, tasks::t38::T38ConnectionManageMessage,
+};
+
+// lengths of the MA-S, MA-M and MA-L assignments, bits; the longest one is looked up first
+const ASSIGNMENT_BITS: [u32; 3] = [36, 28, 24];
+
+// number of access points read from Tile38 at a time
+const SCAN_PAGE_SIZE: u64 = 1_000;
+
+// the vendor statistics scan the whole collection, so they are recomputed at most this often
+const STATS_TTL: Duration = Duration::from_secs(60 * 60);
+
+// registry downloaded from IEEE by the build script
+const BUNDLED_REGISTRY: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/oui.csv"));
+
+static OUI_REGISTRY: Lazy<OuiRegistry> = Lazy::new(|| {
+    if !CONFIG.oui.enabled {
+        return OuiRegistry::default();
+    }
+    let registry = OuiRegistry::load_or_bundled(Path::new(&CONFIG.oui.path));
+    info!("OUI registry: {} assignments", registry.len());
+    registry
+});
+
+static VENDOR_STATS: Lazy<Mutex<Option<(Instant, VendorStats)>>> = Lazy::new(|| Mutex::new(None));
+
+static VENDOR_RULES: Lazy<VendorRules> = Lazy::new(|| {
+    // the prefixes are checked during the config validation
+    VendorRules::new(&CONFIG.oui).expect("invalid OUI prefixes")
+});
+
+/// Parse the assignment: hex digits with optional `:` or `-` separators, e.g. `00:1a:2b`
+pub fn parse_assignment(s: &str) -> Option<(u32, u64)> {
+    let hex = s
+        .chars()
+        .filter(|c| *c != ':' && *c != '-')
+        .collect::<String>();
+    let bits = hex.len() as u32 * 4;
+    if !ASSIGNMENT_BITS.contains(&bits) {
+        return None;
+    }
+    u64::from_str_radix(&hex, 16).ok().map(|v| (bits, v))
+}
+
+#[derive(Debug, Default)]
+pub struct OuiRegistry {
+    assignments: HashMap<(u32, u64), String>,
+}
+
+impl OuiRegistry {
+    /// Read the IEEE CSV (`Registry,Assignment,Organization Name,Organization Address`),
+    /// several registries may be concatenated
+    pub fn from_csv(data: impl Read) -> Result<Self> {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(data);
+        let mut assignments = HashMap::new();
+        for record in reader.records() {
+            let record = record?;
+            let (Some(assignment), Some(vendor)) = (record.get(1), record.get(2)) else {
+                continue;
+            };
+            // headers and private assignments are skipped
+            if let Some(key) = parse_assignment(assignment.trim())
+                && !vendor.trim().is_empty()
+            {
+                assignments.insert(key, vendor.trim().to_string());
+            }
+        }
+        Ok(Self { assignments })
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::from_csv(fs::File::open(path)?)
+    }
+
+    /// The local file if it is present and not empty, otherwise the bundled registry
+    pub fn load_or_bundled(path: &Path) -> Self {
+        if path.exists() {
+            match Self::load(path) {
+                Ok(registry) if !registry.is_empty() => return registry,
+                Ok(_) => warn!("OUI registry {} is empty", path.display()),
+                Err(e) => warn!("OUI registry {} is not loaded: {:#}", path.display(), e),
+            }
+        }
+        info!(
+            "OUI registry {} is not used, the bundled one is loaded",
+            path.display()
+        );
+        Self::from_csv(BUNDLED_REGISTRY).unwrap_or_else(|e| {
+            warn!("bundled OUI registry is not loaded: {:#}", e);
+            Self::default()
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.assignments.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.assignments.is_empty()
+    }
+
+    pub fn vendor(&self, mac: &MacAddr) -> Option<&str> {
+        ASSIGNMENT_BITS.iter().find_map(|&bits| {
+            self.assignments
+                .get(&(bits, mac.prefix(bits)))
+                .map(String::as_str)
+        })
+    }
+}
+
+/// Vendors whose access points are almost always mobile
+#[derive(Debug, Default)]
+pub struct VendorRules {
+    /// lowercase substrings of the vendor name
+    vendors: Vec<String>,
+    prefixes: Vec<(u32, u64)>,
+}
+
+impl VendorRules {
+    pub fn new(config: &Oui) -> Result<Self> {
+        Ok(Self {
+            vendors: config
+                .exclude_vendors
+                .iter()
+                .map(|v| v.to_lowercase())
+                .collect(),
+            prefixes: config
+                .exclude_prefixes
+                .iter()
+                .map(|p| parse_assignment(p).ok_or_else(|| anyhow!("invalid OUI prefix: {}", p)))
+                .collect::<Result<Vec<(u32, u64)>>>()?,
+        })
+    }
+
+    pub fn is_excluded(&self, registry: &OuiRegistry, mac: &MacAddr) -> bool {
+        if self
+            .prefixes
+            .iter()
+            .any(|&(bits, prefix)| mac.prefix(bits) == prefix)
+        {
+            return true;
+        }
+        registry.vendor(mac).is_some_and(|vendor| {
+            let vendor = vendor.to_lowercase();
+            self.vendors.iter().any(|v| vendor.contains(v))
+        })
+    }
+}
+
+/// Vendor of the access point in the configured registry
+pub fn vendor(mac: &str) -> Option<&'static str> {
+    let mac = MacAddr::from_str(mac).ok()?;
+    OUI_REGISTRY.vendor(&mac)
+}
+
+/// Access point of the excluded vendor
+pub fn is_excluded(mac: &str) -> bool {
+    MacAddr::from_str(mac).is_ok_and(|mac| VENDOR_RULES.is_excluded(&OUI_REGISTRY, &mac))
+}
+
+/// Download the registries (or read the local files) and replace the configured registry file
+pub async fn refresh(files: Vec<PathBuf>) -> Result<()> {
+    let mut data = Vec::new();
+    if files.is_empty() {
+        for url in &CONFIG.oui.urls {
+            info!("Downloading {}", url);
+            let body = reqwest::get(url)
+                .await?
+                .error_for_status()?
+                .bytes()
+                .await
+                .with_context(|| format!("failed to download {}", url))?;
+            data.extend_from_slice(&body);
+            data.push(b'\n');
+        }
+    } else {
+        for file in &files {
+            data.extend(fs::read(file).with_context(|| format!("failed to read {:?}", file))?);
+            data.push(b'\n');
+        }
+    }
+
+    let registry = OuiRegistry::from_csv(data.as_slice())?;
+    if registry.is_empty() {
+        return Err(anyhow!("no assignments in the OUI registry"));
+    }
+
+    // the running service must not read a partially written file
+    let path = Path::new(&CONFIG.oui.path);
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, &data)?;
+    fs::rename(&tmp, path)?;
+    info!(
+        "OUI registry {}: {} assignments",
+        path.display(),
+        registry.len()
+    );
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VendorCount {
+    pub vendor: String,
+    pub count: u64,
+    pub excluded: bool,
+}
+
+/// Access points of the `wifi` collection by vendor
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct VendorStats {
+    pub total: u64,
+    /// locally administered addresses have no vendor
+    pub local: u64,
+    pub unknown: u64,
+    pub vendors: Vec<VendorCount>,
+}
+
+impl VendorStats {
+    fn collect<'a>(
+        registry: &OuiRegistry,
+        rules: &VendorRules,
+        macs: impl Iterator<Item = &'a str>,
+    ) -> Self {
+        let mut stats = Self::default();
+        let mut counts: HashMap<&str, (u64, bool)> = HashMap::new();
+        for mac in macs {
+            stats.total += 1;
+            let Ok(mac) = MacAddr::from_str(mac) else {
+                stats.unknown += 1;
+                continue;
+            };
+            if mac.is_local() {
+                stats.local += 1;
+                continue;
+            }
+            match registry.vendor(&mac) {
+                None => stats.unknown += 1,
+                Some(vendor) => {
+                    let entry = counts.entry(vendor).or_default();
+                    entry.0 += 1;
+                    entry.1 |= rules.is_excluded(registry, &mac);
+                }
+            }
+        }
+
+        stats.vendors = counts
+            .into_iter()
+            .map(|(vendor, (count, excluded))| VendorCount {
+                vendor: vendor.to_string(),
+                count,
+                excluded,
+            })
+            .collect();
+        stats
+            .vendors
+            .sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.vendor.cmp(&b.vendor)));
+        stats
+    }
+
+    /// The statistics with the `limit` most frequent vendors
+    fn top(&self, limit: usize) -> Self {
+        Self {
+            vendors: self.vendors.iter().take(limit).cloned().collect(),
+            ..*self
+        }
+    }
+}
+
+/// Count the aggregated access points by vendor, the `limit` most frequent vendors are returned.
+/// The counts are cached for `STATS_TTL`, concurrent requests wait for a single scan.
+pub async fn vendor_stats(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    limit: usize,
+) -> Result<VendorStats, RedisError> {
+    let mut cached = VENDOR_STATS.lock().await;
+    if let Some((computed_at, stats)) = cached.as_ref()
+        && computed_at.elapsed() < STATS_TTL
+    {
+        return Ok(stats.top(limit));
+    }
+    let stats = scan_vendor_stats(tx_t38_conn).await?;
+    let top = stats.top(limit);
+    *cached = Some((Instant::now(), stats));
+    Ok(top)
+}
+
+async fn scan_vendor_stats(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+) -> Result<VendorStats, RedisError> {
+    let collection = Collection::Wifi.as_ref();
+    let mut macs = Vec::new();
+    let mut cursor = 0;
+    loop {
+        let (next, ids) =
+            scan_ids_page(tx_t38_conn.clone(), collection, cursor, SCAN_PAGE_SIZE).await?;
+        macs.extend(ids);
+        if next == 0 {
+            break;
+        }
+        cursor = next;
+    }
+    Ok(VendorStats::collect(
+        &OUI_REGISTRY,
+        &VENDOR_RULES,
+        macs.iter().map(String::as_str),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REGISTRY: &str = "\
+Registry,Assignment,Organization Name,Organization Address
+MA-L,001A2B,Acme Networks,Street 1
+MA-L,70B3D5,IEEE Registration Authority,Street 2
+MA-L,A0B1C2,\"Car Media, Inc.\",Street 3
+Registry,Assignment,Organization Name,Organization Address
+MA-S,70B3D5123,Dashcam Labs,Street 4
+MA-M,A0B1C2D,Private,
+";
+
+    fn rules(vendors: &[&str], prefixes: &[&str]) -> VendorRules {
+        VendorRules::new(&Oui {
+            enabled: true,
+            path: String::new(),
+            urls: vec![],
+            exclude_vendors: vendors.iter().map(|v| v.to_string()).collect(),
+            exclude_prefixes: prefixes.iter().map(|p| p.to_string()).collect(),
+        })
+        .unwrap()
+    }
+
+    fn mac(s: &str) -> MacAddr {
+        MacAddr::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn oui_vendor() {
+        let registry = OuiRegistry::from_csv(REGISTRY.as_bytes()).unwrap();
+        assert_eq!(registry.len(), 5);
+        assert_eq!(
+            registry.vendor(&mac("00:1a:2b:00:00:01")),
+            Some("Acme Networks")
+        );
+        // the longest assignment wins
+        assert_eq!(
+            registry.vendor(&mac("70:b3:d5:12:34:56")),
+            Some("Dashcam Labs")
+        );
+        assert_eq!(
+            registry.vendor(&mac("70:b3:d5:ff:34:56")),
+            Some("IEEE Registration Authority")
+        );
+        assert_eq!(
+            registry.vendor(&mac("a0:b1:c2:10:00:00")),
+            Some("Car Media, Inc.")
+        );
+        assert_eq!(registry.vendor(&mac("10:20:30:40:50:60")), None);
+
+        assert_eq!(parse_assignment("00:1a:2b"), Some((24, 0x001a2b)));
+        assert_eq!(parse_assignment("70-B3-D5-12-3"), Some((36, 0x70b3d5123)));
+        assert_eq!(parse_assignment("00:1a"), None);
+        assert_eq!(parse_assignment("Assignment"), None);
+    }
+
+    #[test]
+    fn oui_exclusion() {
+        let registry = OuiRegistry::from_csv(REGISTRY.as_bytes()).unwrap();
+        let rules = rules(&["car media", "dashcam"], &["10:20:30"]);
+        assert!(rules.is_excluded(&registry, &mac("a0:b1:c2:10:00:00")));
+        assert!(rules.is_excluded(&registry, &mac("70:b3:d5:12:34:56")));
+        assert!(rules.is_excluded(&registry, &mac("10:20:30:40:50:60")));
+        assert!(!rules.is_excluded(&registry, &mac("00:1a:2b:00:00:01")));
+        assert!(!rules.is_excluded(&registry, &mac("70:b3:d5:ff:34:56")));
+
+        let macs = [
+            "00:1a:2b:00:00:01",
+            "00:1a:2b:00:00:02",
+            "a0:b1:c2:10:00:00",
+            "12:34:56:78:90:ab",
+            "ac:87:a3:07:32:b8",
+        ];
+        let stats = VendorStats::collect(&registry, &rules, macs.into_iter()).top(1);
+        assert_eq!(stats.total, 5);
+        assert_eq!(stats.local, 1);
+        assert_eq!(stats.unknown, 1);
+        assert_eq!(stats.vendors.len(), 1);
+        assert_eq!(stats.vendors[0].vendor, "Acme Networks");
+        assert_eq!(stats.vendors[0].count, 2);
+        assert!(!stats.vendors[0].excluded);
+    }
+
+    #[test]
+    fn oui_load() {
+        let path = std::env::temp_dir().join(format!("oui-{}.csv", std::process::id()));
+        fs::write(&path, REGISTRY).unwrap();
+        let registry = OuiRegistry::load_or_bundled(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(registry.len(), 5);
+        assert_eq!(
+            registry.vendor(&mac("a0:b1:c2:10:00:00")),
+            Some("Car Media, Inc.")
+        );
+
+        // the bundled registry is the complete IEEE one
+        let registry = OuiRegistry::load_or_bundled(Path::new("missing-oui.csv"));
+        assert!(registry.len() > 30_000);
+        assert!(
+            registry
+                .vendor(&mac("00:00:0c:12:34:56"))
+                .is_some_and(|vendor| vendor.starts_with("Cisco Systems"))
+        );
+    }
+}
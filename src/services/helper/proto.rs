@@ -0,0 +1,330 @@
+//! Protobuf messages of the public API, must be kept in sync with `proto/locator.proto`.
+
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+
+use crate::{
+    lbs::model,
+    services::{
+        helper::custom_deserialize::{date_time_utc_from_i64, default_timestamp_ms},
+        submission::geosubmit::PositionSource,
+    },
+};
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Position {
+    #[prost(double, tag = "1")]
+    pub latitude: f64,
+    #[prost(double, tag = "2")]
+    pub longitude: f64,
+    #[prost(double, optional, tag = "3")]
+    pub accuracy: Option<f64>,
+    #[prost(double, optional, tag = "4")]
+    pub altitude: Option<f64>,
+    #[prost(double, optional, tag = "5")]
+    pub altitude_accuracy: Option<f64>,
+    #[prost(int32, optional, tag = "6")]
+    pub age: Option<i32>,
+    #[prost(double, optional, tag = "7")]
+    pub speed: Option<f64>,
+    #[prost(double, optional, tag = "8")]
+    pub bearing: Option<f64>,
+    #[prost(double, optional, tag = "9")]
+    pub pressure: Option<f64>,
+    #[prost(string, optional, tag = "10")]
+    pub source: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Wifi {
+    #[prost(string, tag = "1")]
+    pub mac: String,
+    #[prost(string, optional, tag = "2")]
+    pub radio_type: Option<String>,
+    #[prost(int32, optional, tag = "3")]
+    pub age: Option<i32>,
+    #[prost(uint32, optional, tag = "4")]
+    pub channel: Option<u32>,
+    #[prost(double, optional, tag = "5")]
+    pub frequency: Option<f64>,
+    #[prost(double, optional, tag = "6")]
+    pub rssi: Option<f64>,
+    #[prost(double, optional, tag = "7")]
+    pub snr: Option<f64>,
+    #[prost(string, optional, tag = "8")]
+    pub ssid: Option<String>,
+    #[prost(double, optional, tag = "9")]
+    pub bandwidth: Option<f64>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Gsm {
+    #[prost(uint32, tag = "1")]
+    pub mcc: u32,
+    #[prost(uint32, tag = "2")]
+    pub mnc: u32,
+    #[prost(uint64, tag = "3")]
+    pub lac: u64,
+    #[prost(uint64, tag = "4")]
+    pub ci: u64,
+    #[prost(double, tag = "5")]
+    pub rxlev: f64,
+    #[prost(int32, optional, tag = "6")]
+    pub age: Option<i32>,
+    #[prost(uint32, optional, tag = "7")]
+    pub bsic: Option<u32>,
+    #[prost(uint32, optional, tag = "8")]
+    pub arfcn: Option<u32>,
+    #[prost(double, optional, tag = "9")]
+    pub ta: Option<f64>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Wcdma {
+    #[prost(uint32, tag = "1")]
+    pub mcc: u32,
+    #[prost(uint32, tag = "2")]
+    pub mnc: u32,
+    #[prost(uint64, tag = "3")]
+    pub lac: u64,
+    #[prost(uint64, tag = "4")]
+    pub ci: u64,
+    #[prost(double, tag = "5")]
+    pub rscp: f64,
+    #[prost(int32, optional, tag = "6")]
+    pub age: Option<i32>,
+    #[prost(uint32, optional, tag = "7")]
+    pub psc: Option<u32>,
+    #[prost(uint32, optional, tag = "8")]
+    pub uarfcn: Option<u32>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Lte {
+    #[prost(uint32, tag = "1")]
+    pub mcc: u32,
+    #[prost(uint32, tag = "2")]
+    pub mnc: u32,
+    #[prost(uint64, tag = "3")]
+    pub tac: u64,
+    #[prost(uint64, tag = "4")]
+    pub eci: u64,
+    #[prost(double, tag = "5")]
+    pub rsrp: f64,
+    #[prost(int32, optional, tag = "6")]
+    pub age: Option<i32>,
+    #[prost(double, optional, tag = "7")]
+    pub rsrq: Option<f64>,
+    #[prost(uint32, optional, tag = "8")]
+    pub pci: Option<u32>,
+    #[prost(uint32, optional, tag = "9")]
+    pub earfcn: Option<u32>,
+    #[prost(double, optional, tag = "10")]
+    pub ta: Option<f64>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Nr {
+    #[prost(uint32, tag = "1")]
+    pub mcc: u32,
+    #[prost(uint32, tag = "2")]
+    pub mnc: u32,
+    #[prost(uint64, tag = "3")]
+    pub tac: u64,
+    #[prost(uint64, tag = "4")]
+    pub nci: u64,
+    #[prost(double, tag = "5")]
+    pub ss_rsrp: f64,
+    #[prost(int32, optional, tag = "6")]
+    pub age: Option<i32>,
+    #[prost(double, optional, tag = "7")]
+    pub rsrq: Option<f64>,
+    #[prost(uint32, optional, tag = "8")]
+    pub pci: Option<u32>,
+    #[prost(uint32, optional, tag = "9")]
+    pub arcfn: Option<u32>,
+    #[prost(uint32, optional, tag = "10")]
+    pub ssbi: Option<u32>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Cell {
+    #[prost(message, repeated, tag = "1")]
+    pub lte: Vec<Lte>,
+    #[prost(message, repeated, tag = "2")]
+    pub wcdma: Vec<Wcdma>,
+    #[prost(message, repeated, tag = "3")]
+    pub gsm: Vec<Gsm>,
+    #[prost(message, repeated, tag = "4")]
+    pub nr: Vec<Nr>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Report {
+    #[prost(int64, tag = "1")]
+    pub timestamp: i64,
+    #[prost(string, optional, tag = "2")]
+    pub device_id: Option<String>,
+    #[prost(message, optional, tag = "3")]
+    pub gnss: Option<Position>,
+    #[prost(message, repeated, tag = "4")]
+    pub wifi: Vec<Wifi>,
+    #[prost(message, optional, tag = "5")]
+    pub cell: Option<Cell>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Submission {
+    #[prost(message, repeated, tag = "1")]
+    pub items: Vec<Report>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct LocationRequest {
+    #[prost(int64, tag = "1")]
+    pub timestamp: i64,
+    #[prost(string, optional, tag = "2")]
+    pub device_id: Option<String>,
+    #[prost(message, optional, tag = "3")]
+    pub gnss: Option<Position>,
+    #[prost(message, repeated, tag = "4")]
+    pub wifi: Vec<Wifi>,
+    #[prost(message, optional, tag = "5")]
+    pub cell: Option<Cell>,
+}
+
+// 0 means the timestamp was not set by the client
+pub fn timestamp(ts: i64) -> DateTime<Utc> {
+    if ts == 0 {
+        return default_timestamp_ms();
+    }
+    date_time_utc_from_i64(ts).unwrap_or_else(|_| default_timestamp_ms())
+}
+
+pub fn source(source: Option<String>) -> Option<PositionSource> {
+    source.and_then(|s| serde_json::from_value(Value::String(s.to_lowercase())).ok())
+}
+
+// out of range values are saturated and rejected by the cell validation
+fn to_u16(v: u32) -> u16 {
+    u16::try_from(v).unwrap_or(u16::MAX)
+}
+
+fn to_u8(v: u32) -> u8 {
+    u8::try_from(v).unwrap_or(u8::MAX)
+}
+
+fn non_empty<T, U: From<T>>(v: Vec<T>) -> Option<Vec<U>> {
+    if v.is_empty() {
+        None
+    } else {
+        Some(v.into_iter().map(U::from).collect())
+    }
+}
+
+impl From<Gsm> for model::Gsm {
+    fn from(g: Gsm) -> Self {
+        Self {
+            mcc: to_u16(g.mcc),
+            mnc: to_u16(g.mnc),
+            lac: g.lac,
+            ci: g.ci,
+            rxlev: g.rxlev,
+            age: g.age,
+            bsic: g.bsic,
+            arfcn: g.arfcn.map(to_u16),
+            ta: g.ta,
+        }
+    }
+}
+
+impl From<Wcdma> for model::Wcdma {
+    fn from(w: Wcdma) -> Self {
+        Self {
+            mcc: to_u16(w.mcc),
+            mnc: to_u16(w.mnc),
+            lac: w.lac,
+            ci: w.ci,
+            rscp: w.rscp,
+            age: w.age,
+            psc: w.psc,
+            uarfcn: w.uarfcn,
+        }
+    }
+}
+
+impl From<Lte> for model::Lte {
+    fn from(l: Lte) -> Self {
+        Self {
+            mcc: to_u16(l.mcc),
+            mnc: to_u16(l.mnc),
+            tac: l.tac,
+            eci: l.eci,
+            rsrp: l.rsrp,
+            age: l.age,
+            rsrq: l.rsrq,
+            pci: l.pci.map(to_u16),
+            earfcn: l.earfcn.map(to_u16),
+            ta: l.ta,
+        }
+    }
+}
+
+impl From<Nr> for model::Nr {
+    fn from(n: Nr) -> Self {
+        Self {
+            mcc: to_u16(n.mcc),
+            mnc: to_u16(n.mnc),
+            tac: n.tac,
+            nci: n.nci,
+            ss_rsrp: n.ss_rsrp,
+            age: n.age,
+            rsrq: n.rsrq,
+            pci: n.pci.map(to_u16),
+            arcfn: n.arcfn,
+            ssbi: n.ssbi.map(to_u8),
+        }
+    }
+}
+
+impl From<Cell> for model::Cell {
+    fn from(c: Cell) -> Self {
+        Self {
+            lte: non_empty(c.lte),
+            wcdma: non_empty(c.wcdma),
+            gsm: non_empty(c.gsm),
+            nr: non_empty(c.nr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+
+    use super::*;
+
+    #[test]
+    fn decode_cell() {
+        let cell = Cell {
+            lte: vec![Lte {
+                mcc: 250,
+                mnc: 2,
+                tac: 5016,
+                eci: 40944044,
+                rsrp: -67.0,
+                pci: Some(70000),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let decoded = Cell::decode(cell.encode_to_vec().as_slice()).unwrap();
+        let mc: model::Cell = decoded.into();
+
+        let lte = mc.lte.unwrap();
+        assert_eq!(lte[0].mcc, 250);
+        assert_eq!(lte[0].eci, 40944044);
+        assert_eq!(lte[0].pci, Some(u16::MAX));
+        assert!(mc.gsm.is_none());
+    }
+}
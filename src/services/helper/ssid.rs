@@ -0,0 +1,416 @@
+//! SSID policy: the configured rules of ignoring and the `_nomap`/`_optout` privacy convention.
+//!
+//! Ignored access points are not aggregated and not used for locate. Opted-out access
+//! points are also not saved in the reports, and their stored data is purged: the entries keyed
+//! by the MAC address at once, the cell index and the co-occurrence graph by the daily sweep.
+
+use std::collections::HashSet;
+
+use log::{error, info};
+use once_cell::sync::Lazy;
+use redis::RedisError;
+use regex::{Regex, RegexBuilder};
+
+use crate::{
+    CONFIG,
+    config::{Ssid, SsidMatch, SsidRule},
+    constants::Collection,
+    db::{
+        blobasaur::{del_ba_wifi_one, set_ba_wifi_one},
+        pg::transmitter::TransmitterLocation,
+        t38::{
+            cell_wifi::{get_cell_wifi_one, set_cell_wifi_one},
+            del_wifi_one, fget_wifi_many_from_pipeline, get_wifi_one,
+            scan::scan_ids_page,
+            set_opt_out_one, set_wifi_one,
+        },
+    },
+    tasks::{blobasaur::BAConnectionManageMessage, t38::T38ConnectionManageMessage},
+};
+
+// Google uses "_nomap", Microsoft uses "_optout"
+const OPT_OUT_MARKERS: &[&str] = &["_nomap", "_optout"];
+
+static SSID_POLICY: Lazy<SsidPolicy> = Lazy::new(|| {
+    // the regular expressions are checked during the config validation
+    SsidPolicy::new(&CONFIG.ssid).expect("invalid SSID rules")
+});
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SsidVerdict {
+    Accept,
+    Ignore,
+    OptOut,
+}
+
+#[derive(Debug)]
+enum Matcher {
+    Exact(String),
+    Prefix(String),
+    Suffix(String),
+    Contains(String),
+    Regex(Regex),
+}
+
+#[derive(Debug)]
+struct Rule {
+    matcher: Matcher,
+    case_insensitive: bool,
+}
+
+impl Rule {
+    fn new(rule: &SsidRule) -> Result<Self, regex::Error> {
+        let pattern = if rule.case_insensitive {
+            rule.pattern.to_lowercase()
+        } else {
+            rule.pattern.clone()
+        };
+        let matcher = match rule.kind {
+            SsidMatch::Exact => Matcher::Exact(pattern),
+            SsidMatch::Prefix => Matcher::Prefix(pattern),
+            SsidMatch::Suffix => Matcher::Suffix(pattern),
+            SsidMatch::Contains => Matcher::Contains(pattern),
+            SsidMatch::Regex => Matcher::Regex(
+                RegexBuilder::new(&rule.pattern)
+                    .case_insensitive(rule.case_insensitive)
+                    .build()?,
+            ),
+        };
+        Ok(Self {
+            matcher,
+            case_insensitive: rule.case_insensitive,
+        })
+    }
+
+    fn matches(&self, ssid: &str, ssid_lower: &str) -> bool {
+        let s = if self.case_insensitive {
+            ssid_lower
+        } else {
+            ssid
+        };
+        match &self.matcher {
+            Matcher::Exact(p) => s == p,
+            Matcher::Prefix(p) => s.starts_with(p.as_str()),
+            Matcher::Suffix(p) => s.ends_with(p.as_str()),
+            Matcher::Contains(p) => s.contains(p.as_str()),
+            Matcher::Regex(re) => re.is_match(ssid),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SsidPolicy {
+    opt_out: bool,
+    rules: Vec<Rule>,
+}
+
+impl SsidPolicy {
+    pub fn new(config: &Ssid) -> Result<Self, regex::Error> {
+        Ok(Self {
+            opt_out: config.opt_out,
+            rules: config
+                .rules
+                .iter()
+                .map(Rule::new)
+                .collect::<Result<Vec<Rule>, regex::Error>>()?,
+        })
+    }
+
+    pub fn verdict(&self, ssid: Option<&str>) -> SsidVerdict {
+        let Some(ssid) = ssid else {
+            return SsidVerdict::Accept;
+        };
+        let ssid_lower = ssid.to_lowercase();
+        if self.opt_out && OPT_OUT_MARKERS.iter().any(|m| ssid_lower.contains(m)) {
+            return SsidVerdict::OptOut;
+        }
+        if self.rules.iter().any(|r| r.matches(ssid, &ssid_lower)) {
+            return SsidVerdict::Ignore;
+        }
+        SsidVerdict::Accept
+    }
+}
+
+/// Verdict of the configured policy
+pub fn verdict(ssid: Option<&str>) -> SsidVerdict {
+    SSID_POLICY.verdict(ssid)
+}
+
+pub fn is_opted_out(ssid: Option<&str>) -> bool {
+    verdict(ssid) == SsidVerdict::OptOut
+}
+
+/// Access point of a report with its SSID
+pub trait AccessPoint {
+    fn mac(&self) -> &str;
+    fn ssid(&self) -> Option<&str>;
+}
+
+/// Remove the opted-out access points from the reports and purge their stored data, before the
+/// reports are saved or used for the LBS requests, on every ingest path
+pub async fn drop_opted_out<'a, T: AccessPoint + 'a>(
+    reports: impl IntoIterator<Item = &'a mut Vec<T>>,
+    tx_t38_conn: &flume::Sender<T38ConnectionManageMessage>,
+    tx_ba_conn: &flume::Sender<BAConnectionManageMessage>,
+) {
+    let mut opted_out = Vec::new();
+    for aps in reports {
+        aps.retain(|ap| {
+            if is_opted_out(ap.ssid()) {
+                opted_out.push(ap.mac().to_string());
+                return false;
+            }
+            true
+        });
+    }
+    opted_out.sort_unstable();
+    opted_out.dedup();
+    for mac in opted_out {
+        purge(tx_t38_conn, tx_ba_conn, &mac).await;
+    }
+}
+
+// Tile38 collections keyed by the MAC address of the access point
+const PURGED_COLLECTIONS: [Collection; 8] = [
+    Collection::Wifi,
+    Collection::LbsYandexWifi,
+    Collection::LbsYandexWifiMissing,
+    Collection::LbsAlterGeoWifi,
+    Collection::LbsAlterGeoWifiMissing,
+    Collection::LbsIchnaeaWifi,
+    Collection::LbsIchnaeaWifiMissing,
+    Collection::LbsQueueWifi,
+];
+
+// Blobasaur namespaces keyed by the MAC address of the access point
+const PURGED_NAMESPACES: [Collection; 4] = [
+    Collection::BaWifi,
+    Collection::BaLbsYandexWifi,
+    Collection::BaLbsAlterGeoWifi,
+    Collection::BaLbsIchnaeaWifi,
+];
+
+// number of entries read from Tile38 at a time by the sweep
+const SCAN_PAGE_SIZE: u64 = 1_000;
+
+/// Delete the stored data of the opted-out access point: the aggregate, the cached LBS
+/// responses, the enrichment queue entry and the edges of its peers in the co-occurrence graph.
+/// The access point is registered for the daily `sweep` of the cell index and of the edges
+/// not known from its own graph.
+pub async fn purge(
+    tx_t38_conn: &flume::Sender<T38ConnectionManageMessage>,
+    tx_ba_conn: &flume::Sender<BAConnectionManageMessage>,
+    mac: &str,
+) {
+    // the peers are known from the aggregate, so it is read before the deletion
+    match get_wifi_one(tx_t38_conn.clone(), Collection::Wifi.as_ref(), mac).await {
+        Err(e) => error!("read opted-out access point {}: {}", mac, e),
+        Ok(None) => {}
+        Ok(Some(tl)) => {
+            for peer in tl.cooccurrence.iter().flat_map(|graph| graph.peers.keys()) {
+                if let Err(e) = unlink(tx_t38_conn, tx_ba_conn, peer, |p| p == mac).await {
+                    error!("unlink opted-out access point {} from {}: {}", mac, peer, e);
+                }
+            }
+        }
+    }
+
+    for collection in PURGED_COLLECTIONS {
+        if let Err(e) = del_wifi_one(tx_t38_conn.clone(), collection.as_ref(), mac).await {
+            error!(
+                "purge opted-out access point {} in {}: {}",
+                mac, collection, e
+            );
+        }
+    }
+    if CONFIG.blobasaur.enabled {
+        for namespace in PURGED_NAMESPACES {
+            if let Err(e) = del_ba_wifi_one(tx_ba_conn.clone(), namespace.as_ref(), mac).await {
+                error!(
+                    "purge opted-out access point {} in blobasaur {}: {}",
+                    mac, namespace, e
+                );
+            }
+        }
+    }
+
+    if let Err(e) = set_opt_out_one(tx_t38_conn.clone(), Collection::SsidOptOut.as_ref(), mac).await
+    {
+        error!("register opted-out access point {}: {}", mac, e);
+    }
+    info!("opted-out access point {} is purged", mac);
+}
+
+/// Remove the peers from the co-occurrence graph of the access point read right before the
+/// change, so that the observations saved by the report processing are not overwritten
+async fn unlink(
+    tx_t38_conn: &flume::Sender<T38ConnectionManageMessage>,
+    tx_ba_conn: &flume::Sender<BAConnectionManageMessage>,
+    mac: &str,
+    is_removed: impl Fn(&str) -> bool,
+) -> Result<(), RedisError> {
+    let Some(mut tl) = get_wifi_one(tx_t38_conn.clone(), Collection::Wifi.as_ref(), mac).await?
+    else {
+        return Ok(());
+    };
+    let Some(graph) = tl.cooccurrence.as_mut() else {
+        return Ok(());
+    };
+    let len = graph.peers.len();
+    graph.peers.retain(|peer, _| !is_removed(peer));
+    if graph.peers.len() == len {
+        return Ok(());
+    }
+    set_wifi_one(tx_t38_conn.clone(), Collection::Wifi.as_ref(), &tl).await?;
+    if CONFIG.blobasaur.enabled {
+        set_ba_wifi_one(tx_ba_conn.clone(), Collection::BaWifi.as_ref(), &tl).await?;
+    }
+    Ok(())
+}
+
+async fn scan_all_ids(
+    tx_t38_conn: &flume::Sender<T38ConnectionManageMessage>,
+    collection: &str,
+) -> Result<Vec<String>, RedisError> {
+    let mut ids = Vec::new();
+    let mut cursor = 0;
+    loop {
+        let (next, page) =
+            scan_ids_page(tx_t38_conn.clone(), collection, cursor, SCAN_PAGE_SIZE).await?;
+        ids.extend(page);
+        if next == 0 {
+            return Ok(ids);
+        }
+        cursor = next;
+    }
+}
+
+/// Remove the registered opted-out access points from the cell index and from the co-occurrence
+/// graph of every access point, returns the number of the changed entries
+pub async fn sweep(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    tx_ba_conn: flume::Sender<BAConnectionManageMessage>,
+) -> Result<usize, RedisError> {
+    let registry = Collection::SsidOptOut.as_ref();
+    let opted_out = scan_all_ids(&tx_t38_conn, registry)
+        .await?
+        .into_iter()
+        .collect::<HashSet<String>>();
+    if opted_out.is_empty() {
+        return Ok(0);
+    }
+    let mut count = 0;
+
+    let collection = Collection::CellWifi.as_ref();
+    for code in scan_all_ids(&tx_t38_conn, collection).await? {
+        let Some(mut cw) = get_cell_wifi_one(tx_t38_conn.clone(), collection, &code).await? else {
+            continue;
+        };
+        let len = cw.aps.len();
+        cw.aps.retain(|mac, _| !opted_out.contains(mac));
+        if cw.aps.len() != len {
+            set_cell_wifi_one(tx_t38_conn.clone(), collection, &cw).await?;
+            count += 1;
+        }
+    }
+
+    let collection = Collection::Wifi.as_ref();
+    let mut linked = Vec::new();
+    let mut cursor = 0;
+    loop {
+        let (next, ids) =
+            scan_ids_page(tx_t38_conn.clone(), collection, cursor, SCAN_PAGE_SIZE).await?;
+        if !ids.is_empty() {
+            let macs = ids.iter().map(String::as_str).collect::<Vec<&str>>();
+            let tls = fget_wifi_many_from_pipeline::<TransmitterLocation>(
+                tx_t38_conn.clone(),
+                collection,
+                &macs,
+            )
+            .await?;
+            linked.extend(
+                tls.into_iter()
+                    .flatten()
+                    .filter(|tl| {
+                        tl.cooccurrence.as_ref().is_some_and(|graph| {
+                            graph.peers.keys().any(|peer| opted_out.contains(peer))
+                        })
+                    })
+                    .map(|tl| tl.mac),
+            );
+        }
+        if next == 0 {
+            break;
+        }
+        cursor = next;
+    }
+    for mac in &linked {
+        unlink(&tx_t38_conn, &tx_ba_conn, mac, |peer| {
+            opted_out.contains(peer)
+        })
+        .await?;
+    }
+    count += linked.len();
+
+    // access points opted out during the sweep stay registered until the next one
+    for mac in &opted_out {
+        del_wifi_one(tx_t38_conn.clone(), registry, mac).await?;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(opt_out: bool, rules: &[(SsidMatch, &str, bool)]) -> SsidPolicy {
+        SsidPolicy::new(&Ssid {
+            opt_out,
+            rules: rules
+                .iter()
+                .map(|(kind, pattern, case_insensitive)| SsidRule {
+                    kind: *kind,
+                    pattern: pattern.to_string(),
+                    case_insensitive: *case_insensitive,
+                })
+                .collect(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn ssid_rules() {
+        let p = policy(
+            false,
+            &[
+                (SsidMatch::Contains, "carcam", true),
+                (SsidMatch::Exact, "FreeWiFi", false),
+                (SsidMatch::Prefix, "DIRECT-", false),
+                (SsidMatch::Suffix, "_bus", true),
+                (SsidMatch::Regex, r"^tram-\d+$", true),
+            ],
+        );
+        assert_eq!(p.verdict(None), SsidVerdict::Accept);
+        assert_eq!(p.verdict(Some("Home")), SsidVerdict::Accept);
+        assert_eq!(p.verdict(Some("my CarCam 70")), SsidVerdict::Ignore);
+        assert_eq!(p.verdict(Some("FreeWiFi")), SsidVerdict::Ignore);
+        assert_eq!(p.verdict(Some("freewifi")), SsidVerdict::Accept);
+        assert_eq!(p.verdict(Some("DIRECT-5a-HP")), SsidVerdict::Ignore);
+        assert_eq!(p.verdict(Some("direct-5a-HP")), SsidVerdict::Accept);
+        assert_eq!(p.verdict(Some("Route7_BUS")), SsidVerdict::Ignore);
+        assert_eq!(p.verdict(Some("TRAM-42")), SsidVerdict::Ignore);
+        assert_eq!(p.verdict(Some("tram-42a")), SsidVerdict::Accept);
+        // opt-out is not honoured
+        assert_eq!(p.verdict(Some("Home_nomap")), SsidVerdict::Accept);
+    }
+
+    #[test]
+    fn ssid_opt_out() {
+        let p = policy(true, &[(SsidMatch::Contains, "carcam", true)]);
+        assert_eq!(p.verdict(Some("Home_nomap")), SsidVerdict::OptOut);
+        assert_eq!(p.verdict(Some("Home_NOMAP")), SsidVerdict::OptOut);
+        assert_eq!(p.verdict(Some("Office_optout_5G")), SsidVerdict::OptOut);
+        assert_eq!(p.verdict(Some("carcam_nomap")), SsidVerdict::OptOut);
+        assert_eq!(p.verdict(Some("nomap")), SsidVerdict::Accept);
+    }
+}
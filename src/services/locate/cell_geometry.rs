@@ -0,0 +1,373 @@
+//! Geometric constraints of the serving cell.
+//!
+//! Timing Advance gives the distance to the antenna quantized by the radio technology: one step
+//! is a bit period of GSM or 16 Ts of LTE of the round trip. The local cell id in the ECI gives the
+//! sector azimuth where the numbering of the operator is configured. The cell location by LBS is
+//! the centroid of the coverage rather than the antenna, so its accuracy is added to `ta_margin`
+//! (the multipath) as the tolerance of the ring and the sector. Such a constraint only validates
+//! positions; a position is estimated by the ring and the sector only around a configured antenna.
+
+use std::collections::HashMap;
+
+use geo::{Bearing, Destination, Distance, Haversine, Point};
+use once_cell::sync::Lazy;
+
+use crate::{
+    CONFIG,
+    config::{CellGeometry, SectorNumbering},
+    constants::{GSM_TA_MAX, GSM_TA_STEP, LTE_TA_MAX, LTE_TA_STEP, RadioType},
+    lbs::{
+        model::{Cell, CellMeasurement},
+        yandex::wifi::YandexLbsResponse,
+    },
+};
+
+static PARAMS: Lazy<CellGeometry> = Lazy::new(|| CONFIG.cell_geometry.clone());
+
+/// Distance ring and sector of the cell derived from the measurement
+#[derive(Debug, Clone, PartialEq)]
+pub struct Geometry {
+    /// `{radio}:{mcc}:{mnc}:{lac}:{cid}`
+    pub code: String,
+    /// distance to the antenna by Timing Advance (min, max), meters
+    pub ring: Option<(f64, f64)>,
+    /// azimuth and beamwidth of the sector, degrees
+    pub sector: Option<(f64, f64)>,
+}
+
+/// Geometry of the located cell
+#[derive(Debug, Clone, PartialEq)]
+pub struct CellConstraint {
+    pub lat: f64,
+    pub lon: f64,
+    /// tolerance of the ring and the sector, meters
+    pub margin: f64,
+    /// the position is the antenna, otherwise the LBS centroid of the coverage
+    pub is_antenna: bool,
+    pub geometry: Geometry,
+}
+
+/// Azimuth and beamwidth of the sector by the local cell id
+fn sector(numbering: &SectorNumbering, eci: u64) -> Option<(f64, f64)> {
+    // ECI = eNodeB id (20 bits) + local cell id (8 bits)
+    let local_id = (eci & 0xff) as usize;
+    let azimuth = numbering
+        .azimuths
+        .get(local_id % numbering.azimuths.len().max(1))?;
+    Some((*azimuth, numbering.beamwidth))
+}
+
+fn ta_ring(ta: Option<f64>, step: f64, max: f64) -> Option<(f64, f64)> {
+    // Android reports an unknown TA as i32::MAX
+    let ta = ta.filter(|ta| (0.0..=max).contains(ta))?.floor();
+    Some((ta * step, (ta + 1.0) * step))
+}
+
+/// Geometries of the cells with a known Timing Advance or sector
+pub fn geometries(cell: &Cell) -> Vec<Geometry> {
+    if PARAMS.enabled {
+        geometries_with(&PARAMS, cell)
+    } else {
+        vec![]
+    }
+}
+
+pub fn geometries_with(params: &CellGeometry, cell: &Cell) -> Vec<Geometry> {
+    let mut geometries = Vec::new();
+    for g in cell.gsm.iter().flatten() {
+        let cm = CellMeasurement {
+            radio_type: RadioType::Gsm.to_string(),
+            mcc: g.mcc,
+            mnc: g.mnc,
+            lac: g.lac,
+            cid: g.ci,
+            signal_strength: g.rxlev,
+        };
+        geometries.push(Geometry {
+            code: cm.code(),
+            ring: ta_ring(g.ta, GSM_TA_STEP, GSM_TA_MAX),
+            sector: None,
+        });
+    }
+    for l in cell.lte.iter().flatten() {
+        let cm = CellMeasurement {
+            radio_type: RadioType::Lte.to_string(),
+            mcc: l.mcc,
+            mnc: l.mnc,
+            lac: l.tac,
+            cid: l.eci,
+            signal_strength: l.rsrp,
+        };
+        let sector = params
+            .sectors
+            .iter()
+            .find(|s| s.mcc == l.mcc && s.mnc == l.mnc)
+            .and_then(|s| sector(s, l.eci));
+        geometries.push(Geometry {
+            code: cm.code(),
+            ring: ta_ring(l.ta, LTE_TA_STEP, LTE_TA_MAX),
+            sector,
+        });
+    }
+    geometries.retain(|g| g.ring.is_some() || g.sector.is_some());
+    geometries
+}
+
+/// Constraints of the cells with a known antenna or located by LBS
+pub fn constraints(
+    geometries: &[Geometry],
+    ylrs_cell: &HashMap<String, Option<YandexLbsResponse>>,
+) -> Vec<CellConstraint> {
+    constraints_with(&PARAMS, geometries, ylrs_cell)
+}
+
+pub fn constraints_with(
+    params: &CellGeometry,
+    geometries: &[Geometry],
+    ylrs_cell: &HashMap<String, Option<YandexLbsResponse>>,
+) -> Vec<CellConstraint> {
+    geometries
+        .iter()
+        .filter_map(|g| {
+            if let Some(antenna) = params.antennas.iter().find(|a| a.code == g.code) {
+                return Some(CellConstraint {
+                    lat: antenna.lat,
+                    lon: antenna.lon,
+                    margin: params.ta_margin,
+                    is_antenna: true,
+                    geometry: g.clone(),
+                });
+            }
+            let ylr = ylrs_cell.get(&g.code)?.as_ref()?;
+            Some(CellConstraint {
+                lat: ylr.location.point.lat,
+                lon: ylr.location.point.lon,
+                // the antenna is somewhere within the accuracy of the centroid
+                margin: params.ta_margin + ylr.location.accuracy.max(0.0),
+                is_antenna: false,
+                geometry: g.clone(),
+            })
+        })
+        .collect()
+}
+
+impl CellConstraint {
+    fn antenna(&self) -> Point {
+        Point::new(self.lon, self.lat)
+    }
+
+    /// The point is inside the ring and the sector, `tolerance` widens both
+    pub fn contains(&self, lat: f64, lon: f64, tolerance: f64) -> bool {
+        let tolerance = self.margin + tolerance;
+        let p = Point::new(lon, lat);
+        let d = Haversine::distance(self.antenna(), p);
+        if let Some((min, max)) = self.geometry.ring
+            && (d < min - tolerance || d > max + tolerance)
+        {
+            return false;
+        }
+        if let Some((azimuth, beamwidth)) = self.geometry.sector
+            && d > tolerance
+        {
+            let bearing = Haversine::bearing(self.antenna(), p);
+            let deviation = (bearing - azimuth + 180.0).rem_euclid(360.0) - 180.0;
+            let slack = (tolerance / d).asin().to_degrees();
+            if deviation.abs() > beamwidth / 2.0 + slack {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Position by the cell only (lat, lon, accuracy): the middle of the ring on the sector
+    /// azimuth, or the antenna if the sector is unknown. Around the LBS centroid the ring is no
+    /// more accurate than the centroid itself, so nothing is estimated without the antenna.
+    pub fn estimate(&self) -> Option<(f64, f64, f64)> {
+        if !self.is_antenna {
+            return None;
+        }
+        let (min, max) = self.geometry.ring?;
+        match self.geometry.sector {
+            Some((azimuth, beamwidth)) => {
+                let r = (min + max) / 2.0;
+                let p = Haversine::destination(self.antenna(), azimuth, r);
+                let radial = (max - min) / 2.0;
+                let lateral = r * (beamwidth / 2.0).to_radians().sin().min(1.0);
+                Some((p.y(), p.x(), radial.max(lateral) + self.margin))
+            }
+            None => Some((self.lat, self.lon, max + self.margin)),
+        }
+    }
+}
+
+/// The point satisfies all constraints
+pub fn satisfies(constraints: &[CellConstraint], lat: f64, lon: f64, tolerance: f64) -> bool {
+    constraints.iter().all(|c| c.contains(lat, lon, tolerance))
+}
+
+/// The most accurate position by the cells only
+pub fn estimate(constraints: &[CellConstraint]) -> Option<(f64, f64, f64)> {
+    constraints
+        .iter()
+        .filter_map(CellConstraint::estimate)
+        .min_by(|a, b| a.2.total_cmp(&b.2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Antenna,
+        lbs::{
+            model::{Gsm, Lte},
+            yandex::wifi::{YandexLocation, YandexPoint},
+        },
+    };
+
+    fn params() -> CellGeometry {
+        CellGeometry {
+            enabled: true,
+            ta_margin: 100.0,
+            sectors: vec![SectorNumbering {
+                mcc: 250,
+                mnc: 1,
+                azimuths: vec![0.0, 120.0, 240.0],
+                beamwidth: 120.0,
+            }],
+            antennas: vec![],
+        }
+    }
+
+    fn cell() -> Cell {
+        Cell {
+            lte: Some(vec![Lte {
+                mcc: 250,
+                mnc: 1,
+                tac: 15016,
+                // local cell id 1
+                eci: (576267 << 8) | 1,
+                rsrp: -90.0,
+                ta: Some(10.0),
+                ..Default::default()
+            }]),
+            gsm: Some(vec![Gsm {
+                mcc: 250,
+                mnc: 2,
+                lac: 7800,
+                ci: 3401,
+                rxlev: -80.0,
+                ta: Some(i32::MAX as f64),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn cell_geometry_measurements() {
+        let geometries = geometries_with(&params(), &cell());
+        // the GSM cell has no TA nor sector
+        assert_eq!(geometries.len(), 1);
+        let (min, max) = geometries[0].ring.unwrap();
+        assert!((min - 10.0 * LTE_TA_STEP).abs() < 1e-9);
+        assert!((max - 11.0 * LTE_TA_STEP).abs() < 1e-9);
+        assert_eq!(geometries[0].sector, Some((120.0, 120.0)));
+    }
+
+    fn located(
+        code: &str,
+        lat: f64,
+        lon: f64,
+        accuracy: f64,
+    ) -> HashMap<String, Option<YandexLbsResponse>> {
+        HashMap::from([(
+            code.to_string(),
+            Some(YandexLbsResponse {
+                location: YandexLocation {
+                    point: YandexPoint { lat, lon },
+                    accuracy,
+                },
+            }),
+        )])
+    }
+
+    #[test]
+    fn cell_geometry_constraint() {
+        let geometries = geometries_with(&params(), &cell());
+        let ylrs_cell = located(&geometries[0].code, 55.75, 37.62, 50.0);
+        let constraints = constraints_with(&params(), &geometries, &ylrs_cell);
+        assert_eq!(constraints.len(), 1);
+        let c = &constraints[0];
+
+        let at = |bearing: f64, distance: f64| {
+            let p = Haversine::destination(Point::new(37.62, 55.75), bearing, distance);
+            (p.y(), p.x())
+        };
+        // inside the ring and the sector
+        let (lat, lon) = at(120.0, 800.0);
+        assert!(c.contains(lat, lon, 0.0));
+        // in the ring, but in the opposite sector
+        let (lat, lon) = at(300.0, 800.0);
+        assert!(!c.contains(lat, lon, 0.0));
+        // in the sector, but too far
+        let (lat, lon) = at(120.0, 2000.0);
+        assert!(!c.contains(lat, lon, 0.0));
+        assert!(c.contains(lat, lon, 1200.0));
+
+        // the centroid is not the antenna
+        assert_eq!(estimate(&constraints), None);
+    }
+
+    #[test]
+    fn cell_geometry_known_antenna() {
+        let geometries = geometries_with(&params(), &cell());
+        let antenna = Point::new(37.62, 55.75);
+        // the device is in the ring of TA 10 and the sector at 120 degrees
+        let device = Haversine::destination(antenna, 100.0, 810.0);
+        // the LBS centroid of the coverage is 400 m north of the antenna
+        let centroid = Haversine::destination(antenna, 0.0, 400.0);
+        let ylrs_cell = located(&geometries[0].code, centroid.y(), centroid.x(), 500.0);
+        let lbs_error = Haversine::distance(centroid, device);
+
+        let params = CellGeometry {
+            antennas: vec![Antenna {
+                code: geometries[0].code.clone(),
+                lat: antenna.y(),
+                lon: antenna.x(),
+            }],
+            ..params()
+        };
+        let constraints = constraints_with(&params, &geometries, &ylrs_cell);
+        assert!(constraints[0].is_antenna);
+        assert!(satisfies(&constraints, device.y(), device.x(), 0.0));
+
+        let (lat, lon, accuracy) = estimate(&constraints).unwrap();
+        let error = Haversine::distance(Point::new(lon, lat), device);
+        assert!(error <= accuracy);
+        // closer than the LBS location of the cell
+        assert!(error < lbs_error);
+    }
+
+    #[test]
+    fn cell_geometry_offset_centroid() {
+        let params = CellGeometry {
+            sectors: vec![],
+            ..params()
+        };
+        let geometries = geometries_with(&params, &cell());
+        let antenna = Point::new(37.62, 55.75);
+        // the LBS centroid of the coverage is 400 m north of the antenna
+        let centroid = Haversine::destination(antenna, 0.0, 400.0);
+        // the fix is in the ring of TA 10 south of the antenna, 1220 m from the centroid
+        let fix = Haversine::destination(antenna, 180.0, 820.0);
+
+        let ylrs_cell = located(&geometries[0].code, centroid.y(), centroid.x(), 500.0);
+        let constraints = constraints_with(&params, &geometries, &ylrs_cell);
+        assert!(satisfies(&constraints, fix.y(), fix.x(), 0.0));
+
+        // the accurate centroid is taken as the antenna
+        let ylrs_cell = located(&geometries[0].code, centroid.y(), centroid.x(), 0.0);
+        let constraints = constraints_with(&params, &geometries, &ylrs_cell);
+        assert!(!satisfies(&constraints, fix.y(), fix.x(), 0.0));
+    }
+}
@@ -1,7 +1,7 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, future::Future};
 
 use clusters::Proximity;
-use log::error;
+use log::{error, warn};
 
 use super::{Algorithm, DBSCAN, Point};
 use crate::{
@@ -10,9 +10,14 @@ use crate::{
     error::ApiError,
     lbs::{
         http_client::HttpClient,
-        yandex::wifi::{WifiMeasurement, YandexLbsResponse, yandex_lbs_request_by_individual_wifi},
+        provider::{self, LbsContext, LbsResponses, Priority},
+        yandex::wifi::{WifiMeasurement, YandexLbsResponse},
+    },
+    services::{
+        locate::cell_geometry::{self, CellConstraint},
+        rate_limiter::RateLimitersApp,
+        submission::trust,
     },
-    services::rate_limiter::RateLimitersApp,
     tasks::{
         blobasaur::BAConnectionManageMessage, t38::T38ConnectionManageMessage,
         yandex::YandexApiMessage,
@@ -40,7 +45,40 @@ pub async fn detect_outliers(
     tx_yandex_api: flume::Sender<YandexApiMessage>,
     rl_app: RateLimitersApp,
     cell_opt: Option<HashMap<String, Option<YandexLbsResponse>>>,
+    constraints: &[CellConstraint],
 ) -> Result<Option<Vec<Outlier<'_>>>, ApiError> {
+    let ctx = LbsContext::new(
+        tx_t38_conn,
+        tx_ba_conn,
+        yandex_client,
+        tx_yandex_api,
+        rl_app,
+    )
+    .with_priority(Priority::Enrichment);
+    detect_outliers_by(
+        tls,
+        cell_opt,
+        constraints,
+        provider::any_enabled(),
+        |wms| async move { provider::request_wifi(&ctx, &wms).await },
+    )
+    .await
+}
+
+/// Outliers among the access points of our database, the LBS providers are queried by
+/// `request_wifi` only if `lbs_enabled`. While the providers are unavailable (the circuit breaker
+/// is open) there is no LBS verdict and only the cell constraints are checked.
+async fn detect_outliers_by<'a, F, Fut>(
+    tls: &'a [Option<TransmitterLocation>],
+    cell_opt: Option<HashMap<String, Option<YandexLbsResponse>>>,
+    constraints: &[CellConstraint],
+    lbs_enabled: bool,
+    request_wifi: F,
+) -> Result<Option<Vec<Outlier<'a>>>, ApiError>
+where
+    F: FnOnce(Vec<WifiMeasurement>) -> Fut,
+    Fut: Future<Output = Result<LbsResponses, ApiError>>,
+{
     let tls_filtered = tls.iter().filter_map(|t| t.as_ref()).collect::<Vec<_>>();
 
     let cap = tls_filtered.len();
@@ -60,9 +98,23 @@ pub async fn detect_outliers(
     let cell = cell_opt.unwrap_or_default();
     let cell_points = create_cell_points(&cell);
     let dfc = distance_factor_cell(&cell);
+    // the access point is heard within its range from the Timing Advance ring and the sector
+    let outside_cell = |p: &Point| {
+        !cell_geometry::satisfies(
+            constraints,
+            p.lat,
+            p.lon,
+            CONFIG.locator.radius_wifi_detection,
+        )
+    };
 
     if points.len() == 1 {
-        if CONFIG.yandex_lbs.enabled {
+        if outside_cell(&points[0]) {
+            return Ok(Some(vec![Outlier {
+                mac: &tls_filtered[0].mac,
+            }]));
+        }
+        if lbs_enabled {
             let mut noise = Vec::new();
 
             // first filter by BS service area
@@ -77,21 +129,27 @@ pub async fn detect_outliers(
                 }
             }
 
+            // the agreement with LBS is already known from the report processing
+            if let Some(contradicts) = trust::is_contradicting(tls_filtered[0]) {
+                if contradicts {
+                    noise.push(Outlier {
+                        mac: &tls_filtered[0].mac,
+                    });
+                    return Ok(Some(noise));
+                }
+                return Ok(None);
+            }
+
             let wms = vec![WifiMeasurement {
                 bssid: tls_filtered[0].mac.clone(),
                 signal_strength: -70.0, // does not affect the result
             }];
 
-            match yandex_lbs_request_by_individual_wifi(
-                tx_t38_conn,
-                tx_ba_conn,
-                &wms,
-                yandex_client.clone(),
-                tx_yandex_api,
-                rl_app,
-            )
-            .await
-            {
+            match request_wifi(wms).await {
+                // our own database is the only source while the providers are down
+                Err(ApiError::UpstreamUnavailable(upstream)) => {
+                    warn!("Yandex LBS request: {upstream} is unavailable");
+                }
                 Err(e) => {
                     error!("Yandex LBS request: {}", e);
                     return Err(e);
@@ -124,7 +182,7 @@ pub async fn detect_outliers(
 
     if points.len() == 2 {
         let d = points[0].distance(&points[1]);
-        if d > CONFIG.locator.max_distance_in_cluster && CONFIG.yandex_lbs.enabled {
+        if d > CONFIG.locator.max_distance_in_cluster && lbs_enabled {
             let mut noise = Vec::new();
 
             let mut wms = Vec::with_capacity(points.len());
@@ -135,16 +193,24 @@ pub async fn detect_outliers(
                 });
             });
 
-            match yandex_lbs_request_by_individual_wifi(
-                tx_t38_conn,
-                tx_ba_conn,
-                &wms,
-                yandex_client.clone(),
-                tx_yandex_api,
-                rl_app,
-            )
-            .await
-            {
+            match request_wifi(wms).await {
+                // no LBS verdict: only the access points out of the cell are outliers
+                Err(ApiError::UpstreamUnavailable(upstream)) => {
+                    warn!("Yandex LBS request: {upstream} is unavailable");
+                    for (i, p) in points.iter().enumerate() {
+                        let out_of_cell = cell_points.first().is_some_and(|cp| {
+                            p.distance(cp) > CONFIG.locator.max_distance_cell * dfc
+                        });
+                        if out_of_cell || outside_cell(p) {
+                            noise.push(Outlier {
+                                mac: &tls_filtered[i].mac,
+                            });
+                        }
+                    }
+                    if !noise.is_empty() {
+                        return Ok(Some(noise));
+                    }
+                }
                 Err(e) => {
                     error!("Yandex LBS request: {}", e);
                     return Err(e);
@@ -167,6 +233,7 @@ pub async fn detect_outliers(
 
                                 if distance > CONFIG.locator.max_distance_in_cluster
                                     || distance_point_cell > CONFIG.locator.max_distance_cell * dfc
+                                    || outside_cell(&points[index_mac])
                                 {
                                     // can't tell which data has an error: ours or Yandex's
                                     // add point to outlier
@@ -208,7 +275,7 @@ pub async fn detect_outliers(
     if !cell_points.is_empty() {
         points.iter().for_each(|p| {
             let distance_point_cell = p.distance(&cell_points[0]);
-            if distance_point_cell > CONFIG.locator.max_distance_cell * dfc {
+            if distance_point_cell > CONFIG.locator.max_distance_cell * dfc || outside_cell(p) {
                 discarded_by_cell_points.push(*p);
             } else {
                 filtered_by_cell_points.push(*p);
@@ -293,3 +360,63 @@ pub fn distance_factor_cell(cell: &HashMap<String, Option<YandexLbsResponse>>) -
     }
     factor
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::CircuitBreaker, services::helper::circuit_breaker::Breaker};
+
+    fn open_breaker() -> Breaker {
+        let params = CircuitBreaker {
+            enabled: true,
+            window: 60,
+            min_requests: 4,
+            failure_rate: 0.5,
+            open_timeout: 30,
+            yandex_timeout: 5000,
+            altergeo_timeout: 5000,
+            ichnaea_timeout: 5000,
+            graphhopper_timeout: 30000,
+        };
+        let breaker = Breaker::new("yandex", params.clone(), params.yandex_timeout);
+        for _ in 0..4 {
+            breaker.record(true);
+        }
+        breaker
+    }
+
+    fn located(tls: &[Option<TransmitterLocation>], outliers: Option<&Vec<Outlier<'_>>>) -> usize {
+        tls.iter()
+            .flatten()
+            .filter(|tl| !check_outlier(outliers, tl) && tl.valid())
+            .count()
+    }
+
+    #[tokio::test]
+    async fn outliers_breaker_open() {
+        let breaker = open_breaker();
+        assert!(breaker.acquire().is_err());
+        let breaker = &breaker;
+        let request_wifi = move |_wms: Vec<WifiMeasurement>| async move {
+            breaker.acquire().map(|_| LbsResponses::new())
+        };
+
+        // two access points too far apart to be checked without LBS
+        let mut second = TransmitterLocation::fixture_at(55.76, 37.62);
+        second.mac = "aa:bb:cc:dd:ee:02".to_string();
+        let tls = vec![Some(TransmitterLocation::fixture()), Some(second)];
+        let outliers = detect_outliers_by(&tls, None, &[], true, request_wifi)
+            .await
+            .unwrap();
+        assert!(outliers.is_none());
+        // locate falls back to our own database
+        assert_eq!(located(&tls, outliers.as_ref()), 2);
+
+        let tls = vec![Some(TransmitterLocation::fixture())];
+        let outliers = detect_outliers_by(&tls, None, &[], true, request_wifi)
+            .await
+            .unwrap();
+        assert!(outliers.is_none());
+        assert_eq!(located(&tls, outliers.as_ref()), 1);
+    }
+}
@@ -3,24 +3,39 @@
 use std::collections::HashSet;
 
 use actix_web::{HttpRequest, HttpResponse, post, web};
-use log::{debug, error};
+use chrono::Utc;
+use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
 use super::dbscan::{check_outlier, detect_outliers};
 use crate::{
     CONFIG,
-    constants::{DEFAULT_RSSI, SIGNAL_DROP_COEFFICIENT},
-    db::{model::CellRadio, t38::fget_wifi_many_from_pipeline},
+    constants::{DEFAULT_RSSI, RadioType, SIGNAL_DROP_COEFFICIENT},
+    db::{
+        model::CellRadio, pg::transmitter::TransmitterLocation, t38::fget_wifi_many_from_pipeline,
+    },
     error::{ApiError, create_error_response},
     lbs::{
         http_client::HttpClient,
+        model::CellMeasurement,
+        provider::{self, LbsContext},
         yandex::wifi::{
             WifiMeasurement, detect_yandex_outliers, estimate_location_by_yandex_responses,
-            yandex_lbs_request_by_individual_wifi,
         },
     },
-    services::{helper::custom_deserialize::mac_address, rate_limiter::RateLimitersApp},
+    services::{
+        helper::{
+            custom_deserialize::mac_address,
+            oui,
+            ssid::{self, SsidVerdict},
+        },
+        rate_limiter::RateLimitersApp,
+        submission::{
+            cooccurrence, trust,
+            virtual_ap::{self, Member},
+        },
+    },
     tasks::{
         blobasaur::BAConnectionManageMessage, t38::T38ConnectionManageMessage,
         yandex::YandexApiMessage,
@@ -58,6 +73,27 @@ struct CellTower {
     location_area_code: i32,
     cell_id: i64,
     psc: Option<i16>,
+    signal_strength: Option<f64>,
+}
+
+impl CellTower {
+    /// Measurement for the LBS providers, `None` if the identifiers are invalid
+    fn measurement(&self) -> Option<CellMeasurement> {
+        let radio_type = match self.radio_type {
+            CellRadio::Gsm => RadioType::Gsm,
+            CellRadio::Wcdma => RadioType::Wcdma,
+            CellRadio::Lte => RadioType::Lte,
+            CellRadio::Nr => RadioType::Nr,
+        };
+        Some(CellMeasurement {
+            radio_type: radio_type.to_string(),
+            mcc: u16::try_from(self.mobile_country_code).ok()?,
+            mnc: u16::try_from(self.mobile_network_code).ok()?,
+            lac: u64::try_from(self.location_area_code).ok()?,
+            cid: u64::try_from(self.cell_id).ok()?,
+            signal_strength: self.signal_strength.unwrap_or(DEFAULT_RSSI),
+        })
+    }
 }
 
 // Serde representation of access points in the client's request
@@ -67,6 +103,7 @@ struct AccessPoint {
     #[serde(deserialize_with = "mac_address")]
     mac_address: String,
     signal_strength: Option<f64>,
+    ssid: Option<String>,
 }
 
 /// Struct for representing the server's response
@@ -117,7 +154,7 @@ pub async fn service(
     tx_yandex_api_web: web::Data<flume::Sender<YandexApiMessage>>,
     _req: HttpRequest,
 ) -> actix_web::Result<HttpResponse> {
-    let data = match data.map(|x| x.into_inner()) {
+    let mut data = match data.map(|x| x.into_inner()) {
         Some(loc_request) => loc_request,
         None => {
             return Ok(HttpResponse::BadRequest().json(json!(
@@ -133,6 +170,12 @@ pub async fn service(
         }
     };
 
+    // ignored, opted-out and excluded by vendor access points are not used for the location
+    data.wifi_access_points.retain(|ap| {
+        ssid::verdict(ap.ssid.as_deref()) == SsidVerdict::Accept
+            && !oui::is_excluded(&ap.mac_address)
+    });
+
     let mut macs_set = HashSet::with_capacity(data.wifi_access_points.len());
     data.wifi_access_points.iter().for_each(|m| {
         macs_set.insert(m.mac_address.as_str());
@@ -146,6 +189,20 @@ pub async fn service(
     let tls = fget_wifi_many_from_pipeline(tx_t38c.clone(), collection, &macs)
         .await
         .map_err(|e| ApiError::Tile38Error(e.to_string()))?;
+    // mobile and stale access points are not used for the location
+    let tls = tls
+        .into_iter()
+        .map(|tl_opt: Option<TransmitterLocation>| tl_opt.filter(|tl| !tl.is_mobile() && !tl.stale))
+        .collect::<Vec<_>>();
+    // access points never observed together with the others are not used for the location
+    let inconsistent = cooccurrence::inconsistent(&tls);
+    if !inconsistent.is_empty() {
+        info!("inconsistent access points: {:?}", inconsistent);
+    }
+    let tls = tls
+        .into_iter()
+        .map(|tl_opt| tl_opt.filter(|tl| !inconsistent.contains(&tl.mac)))
+        .collect::<Vec<_>>();
 
     let mut lat_weight = 0.0;
     let mut lon_weight = 0.0;
@@ -165,6 +222,7 @@ pub async fn service(
         tx_yandex_api.clone(),
         rl_app.clone(),
         None,
+        &[],
     )
     .await
     {
@@ -174,6 +232,9 @@ pub async fn service(
         Ok(o) => o,
     };
 
+    let now = Utc::now().timestamp_millis();
+    let mut located = Vec::new();
+    let mut members = Vec::new();
     for tl in tls.iter().flatten() {
         // skip the outlier TransmitterLocation
         if check_outlier(outliers_opt.as_ref(), &tl) {
@@ -181,23 +242,35 @@ pub async fn service(
         }
 
         if tl.valid() {
-            let wap_signal_strength = data
+            let rssi = data
                 .wifi_access_points
                 .iter()
                 .find(|wap| *wap.mac_address == tl.mac)
-                .and_then(|wap| wap.signal_strength);
+                .and_then(|wap| wap.signal_strength)
+                .unwrap_or(DEFAULT_RSSI);
 
             // At this point, we can use the real coordinates
-            let weight = 10_f64.powf(
-                wap_signal_strength.unwrap_or(DEFAULT_RSSI) / (10.0 * SIGNAL_DROP_COEFFICIENT),
-            );
-            lat_weight = lat_weight + tl.lat * weight;
-            lon_weight = lon_weight + tl.lon * weight;
-            r_weight = r_weight + tl.accuracy * weight;
-            w_weight = w_weight + weight;
-            c = c + 1;
+            let weight =
+                10_f64.powf(rssi / (10.0 * SIGNAL_DROP_COEFFICIENT)) * trust::weight(tl, now);
+            located.push(tl);
+            members.push(Member {
+                mac: &tl.mac,
+                rssi,
+                group: tl.virtual_group.as_deref(),
+                weight,
+            });
         }
     }
+    // virtual access points of one router are counted once
+    virtual_ap::cap_weights(&mut members);
+    for (tl, member) in located.iter().zip(&members) {
+        let weight = member.weight;
+        lat_weight = lat_weight + tl.lat * weight;
+        lon_weight = lon_weight + tl.lon * weight;
+        r_weight = r_weight + tl.accuracy * weight;
+        w_weight = w_weight + weight;
+        c = c + 1;
+    }
 
     if c >= 1 {
         lat_weight = lat_weight / w_weight;
@@ -211,8 +284,8 @@ pub async fn service(
         }
     }
 
-    // localization using our own database didn't work, so we're using Yandex LBS
-    if CONFIG.yandex_lbs.enabled {
+    // localization using our own database didn't work, so we're using the LBS providers
+    if provider::any_enabled() {
         let mut wms = Vec::with_capacity(data.wifi_access_points.len());
         data.wifi_access_points.iter().for_each(|m| {
             wms.push(WifiMeasurement {
@@ -221,16 +294,37 @@ pub async fn service(
             });
         });
 
-        match yandex_lbs_request_by_individual_wifi(
-            tx_t38c,
-            tx_ba_c,
-            &wms,
-            yandex_client,
-            tx_yandex_api,
-            rl_app,
-        )
-        .await
-        {
+        let ctx = LbsContext::new(tx_t38c, tx_ba_c, yandex_client, tx_yandex_api, rl_app);
+        if CONFIG.lbs.combined {
+            let cms = data
+                .cell_towers
+                .iter()
+                .filter_map(CellTower::measurement)
+                .collect::<Vec<_>>();
+            return match provider::request_combined(&ctx, &wms, &cms).await {
+                // our own database is the only source while the providers are down
+                Err(ApiError::UpstreamUnavailable(upstream)) => {
+                    warn!("Yandex LBS combined request: {upstream} is unavailable");
+                    Ok(not_found())
+                }
+                Err(e) => {
+                    error!("Yandex LBS combined request: {e}");
+                    Ok(create_error_response(e, "locate"))
+                }
+                Ok(Some(ylr)) => LocationResponse::new(
+                    ylr.location.point.lat,
+                    ylr.location.point.lon,
+                    ylr.location.accuracy,
+                )
+                .respond(),
+                Ok(None) => Ok(not_found()),
+            };
+        }
+
+        match provider::request_wifi(&ctx, &wms).await {
+            Err(ApiError::UpstreamUnavailable(upstream)) => {
+                warn!("Yandex LBS request by individual access points: {upstream} is unavailable");
+            }
             Err(e) => {
                 error!("Yandex LBS request by individual access points: {e}");
                 return Ok(create_error_response(e, "locate"));
@@ -262,7 +356,11 @@ pub async fn service(
         }
     }
 
-    Ok(HttpResponse::NotFound().json(json!(
+    Ok(not_found())
+}
+
+fn not_found() -> HttpResponse {
+    HttpResponse::NotFound().json(json!(
         {
             "error": {
                 "domain": "locate",
@@ -271,5 +369,5 @@ pub async fn service(
                 "code": 404,
             }
         }
-    )))
+    ))
 }
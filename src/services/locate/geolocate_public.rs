@@ -1,4 +1,4 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 use actix_web::{HttpRequest, HttpResponse, post, web};
 use chrono::{DateTime, Utc};
@@ -17,20 +17,33 @@ use crate::{
     },
     error::{ApiError, create_error_response},
     lbs::{
-        model::{Cell, valid_cell},
+        model::{Cell, create_cell_measurement, valid_cell},
+        provider::{self, LbsContext},
         yandex::cell::get_cell,
         yandex::wifi::{
             WifiMeasurement, detect_yandex_outliers, estimate_location_by_yandex_responses,
-            yandex_lbs_cache_wifi, yandex_lbs_request_by_individual_wifi,
+            yandex_lbs_cache_wifi,
         },
     },
     services::{
-        helper::custom_deserialize::{
-            date_time_utc_from_str, default_timestamp_ms, mac_address, validate_rssi,
+        helper::{
+            body::{BodyFormat, decode_body},
+            custom_deserialize::{
+                date_time_utc_from_str, default_timestamp_ms, mac_address, normalize_mac,
+                rssi_or_default, validate_rssi,
+            },
+            oui, proto,
+            ssid::{self, SsidVerdict},
         },
         locate::dbscan::Point,
         rate_limiter::RateLimitersApp,
-        submission::{geosubmit_public::PositionPublic, report::is_gps_valid_relative_cell},
+        submission::{
+            cooccurrence,
+            geosubmit_public::PositionPublic,
+            report::{is_gps_valid_relative_cell, serving_cell_location},
+            trust,
+            virtual_ap::{self, Member},
+        },
     },
     tasks::{
         blobasaur::BAConnectionManageMessage, t38::T38ConnectionManageMessage,
@@ -38,7 +51,10 @@ use crate::{
     },
 };
 
-use super::dbscan::{check_outlier, detect_outliers};
+use super::{
+    cell_geometry,
+    dbscan::{check_outlier, detect_outliers},
+};
 
 /// Serde representation of the client's request
 #[derive(Debug, Deserialize, Serialize, Default)]
@@ -80,6 +96,34 @@ struct AccessPoint {
     extra: Value,
 }
 
+impl From<proto::LocationRequest> for LocationRequestPublic {
+    fn from(lr: proto::LocationRequest) -> Self {
+        LocationRequestPublic {
+            timestamp: proto::timestamp(lr.timestamp),
+            device_id: lr.device_id,
+            gnss: lr.gnss.map(|p| p.into()),
+            wifi: lr.wifi.into_iter().map(AccessPoint::from).collect(),
+            cell: lr.cell.map(|c| c.into()),
+        }
+    }
+}
+
+impl From<proto::Wifi> for AccessPoint {
+    fn from(w: proto::Wifi) -> Self {
+        AccessPoint {
+            mac: normalize_mac(w.mac),
+            radio_type: w.radio_type,
+            age: w.age,
+            channel: w.channel.and_then(|c| u16::try_from(c).ok()),
+            frequency: w.frequency,
+            rssi: rssi_or_default(w.rssi),
+            snr: w.snr,
+            ssid: w.ssid,
+            extra: Value::Null,
+        }
+    }
+}
+
 /// Struct for representing the server's response
 #[derive(Debug, Serialize)]
 pub struct LocationResponsePublic {
@@ -128,17 +172,33 @@ struct LocationPublic {
 
 #[post("/locate")]
 pub async fn service(
-    data: Option<web::Json<LocationRequestPublic>>,
+    body: web::Bytes,
     tx_t38_conn: web::Data<flume::Sender<T38ConnectionManageMessage>>,
     tx_ba_conn: web::Data<flume::Sender<BAConnectionManageMessage>>,
     rl_app_web: web::Data<RateLimitersApp>,
     yandex_client_web: web::Data<HttpClient>,
     tx_yandex_api_web: web::Data<flume::Sender<YandexApiMessage>>,
-    _req: HttpRequest,
+    req: HttpRequest,
 ) -> actix_web::Result<HttpResponse> {
-    let mut data = match data.map(|x| x.into_inner()) {
-        Some(lr) => lr,
+    let format = match BodyFormat::from_request(&req) {
+        Some(f) => f,
         None => {
+            return Ok(HttpResponse::UnsupportedMediaType().json(json!(
+                {
+                    "error": {
+                        "domain": "locate",
+                        "reason": "invalid request",
+                        "message": "unsupported content type",
+                        "code": 415,
+                    }
+                }
+            )));
+        }
+    };
+    let mut data = match decode_body::<LocationRequestPublic, proto::LocationRequest>(format, &body)
+    {
+        Ok(lr) => lr,
+        Err(_) => {
             return Ok(HttpResponse::BadRequest().json(json!(
                 {
                     "error": {
@@ -165,6 +225,10 @@ pub async fn service(
         )));
     }
 
+    // ignored, opted-out and excluded by vendor access points are not used for the location
+    data.wifi.retain(|ap| {
+        ssid::verdict(ap.ssid.as_deref()) == SsidVerdict::Accept && !oui::is_excluded(&ap.mac)
+    });
     let count_wifi = data.wifi.len();
 
     // DEBUG
@@ -196,6 +260,17 @@ pub async fn service(
     let tx_yandex_api = (*tx_yandex_api_web.into_inner()).clone();
     let rl_app = (*rl_app_web.into_inner()).clone();
 
+    // Timing Advance and sectors are lost with the cell measurements
+    let geometries = data
+        .cell
+        .as_ref()
+        .map(cell_geometry::geometries)
+        .unwrap_or_default();
+    let cms = data
+        .cell
+        .as_ref()
+        .map(create_cell_measurement)
+        .unwrap_or_default();
     let ylrs_cell_opt = match get_cell(
         data.cell.take(),
         tx_t38c.clone(),
@@ -211,6 +286,14 @@ pub async fn service(
         }
         Ok(c) => c,
     };
+    let constraints = ylrs_cell_opt
+        .as_ref()
+        .map(|ylrs_cell| cell_geometry::constraints(&geometries, ylrs_cell))
+        .unwrap_or_default();
+
+    let cell_location = ylrs_cell_opt
+        .as_ref()
+        .and_then(|ylrs_cell| serving_cell_location(&cms, ylrs_cell));
 
     // validate GPS relative Cell
     if let Some(gnss) = &data.gnss {
@@ -219,7 +302,7 @@ pub async fn service(
             lat: gnss.latitude,
             lon: gnss.longitude,
         };
-        if let Some(valid_gps) = is_gps_valid_relative_cell(ylrs_cell_opt.as_ref(), p_gnss)
+        if let Some(valid_gps) = is_gps_valid_relative_cell(cell_location, &constraints, p_gnss)
             && valid_gps
         {
             // accuracy = 0.0
@@ -231,6 +314,20 @@ pub async fn service(
         fget_wifi_many_from_pipeline::<TransmitterLocation>(tx_t38c.clone(), collection, &macs)
             .await
             .map_err(|e| ApiError::Tile38Error(e.to_string()))?;
+    // mobile and stale access points are not used for the location
+    let tls = tls
+        .into_iter()
+        .map(|tl_opt: Option<TransmitterLocation>| tl_opt.filter(|tl| !tl.is_mobile() && !tl.stale))
+        .collect::<Vec<_>>();
+    // access points never observed together with the others are not used for the location
+    let inconsistent = cooccurrence::inconsistent(&tls);
+    if !inconsistent.is_empty() {
+        info!("inconsistent access points: {:?}", inconsistent);
+    }
+    let tls = tls
+        .into_iter()
+        .map(|tl_opt| tl_opt.filter(|tl| !inconsistent.contains(&tl.mac)))
+        .collect::<Vec<_>>();
 
     let mut lat_weight = 0.0;
     let mut lon_weight = 0.0;
@@ -246,6 +343,7 @@ pub async fn service(
         tx_yandex_api.clone(),
         rl_app.clone(),
         ylrs_cell_opt.clone(),
+        &constraints,
     )
     .await
     {
@@ -255,6 +353,9 @@ pub async fn service(
         Ok(o) => o,
     };
 
+    let now = Utc::now().timestamp_millis();
+    let mut located = Vec::new();
+    let mut members = Vec::new();
     for tl in tls.iter().flatten() {
         // skip the outlier TransmitterLocation
         if check_outlier(outliers_opt.as_ref(), &tl) {
@@ -262,22 +363,34 @@ pub async fn service(
         }
 
         if tl.valid() {
-            let wap_signal_strength = data
+            let rssi = data
                 .wifi
                 .iter()
                 .find(|wap| *wap.mac == tl.mac)
-                .and_then(|wap| wap.rssi);
+                .and_then(|wap| wap.rssi)
+                .unwrap_or(DEFAULT_RSSI);
             // At this point, we can use the real coordinates
-            let weight = 10_f64.powf(
-                wap_signal_strength.unwrap_or(DEFAULT_RSSI) / (10.0 * SIGNAL_DROP_COEFFICIENT),
-            );
-            lat_weight = lat_weight + tl.lat * weight;
-            lon_weight = lon_weight + tl.lon * weight;
-            r_weight = r_weight + tl.accuracy * weight;
-            w_weight = w_weight + weight;
-            c = c + 1;
+            let weight =
+                10_f64.powf(rssi / (10.0 * SIGNAL_DROP_COEFFICIENT)) * trust::weight(tl, now);
+            located.push(tl);
+            members.push(Member {
+                mac: &tl.mac,
+                rssi,
+                group: tl.virtual_group.as_deref(),
+                weight,
+            });
         }
     }
+    // virtual access points of one router are counted once
+    virtual_ap::cap_weights(&mut members);
+    for (tl, member) in located.iter().zip(&members) {
+        let weight = member.weight;
+        lat_weight = lat_weight + tl.lat * weight;
+        lon_weight = lon_weight + tl.lon * weight;
+        r_weight = r_weight + tl.accuracy * weight;
+        w_weight = w_weight + weight;
+        c = c + 1;
+    }
 
     let mut wms = Vec::with_capacity(count_wifi);
     data.wifi.iter().for_each(|m| {
@@ -346,30 +459,63 @@ pub async fn service(
         }
     }
 
-    // localization using our own database didn't work, so we're using Yandex LBS
-    if CONFIG.yandex_lbs.enabled {
-        let mut wifi_track = None;
-        if let Some(device_id) = data.device_id {
-            match get_wifi_track_one(tx_t38c.clone(), collection, &device_id).await {
-                Err(e) => {
-                    error!("get wifi track for device id '{}': {}", device_id, e);
-                }
-                Ok(wt) => {
-                    wifi_track = wt;
-                }
+    // localization using our own database didn't work, so we're using the LBS providers
+    let mut wifi_track = None;
+    if provider::any_enabled()
+        && let Some(device_id) = data.device_id
+    {
+        match get_wifi_track_one(tx_t38c.clone(), collection, &device_id).await {
+            Err(e) => {
+                error!("get wifi track for device id '{}': {}", device_id, e);
+            }
+            Ok(wt) => {
+                wifi_track = wt;
             }
         }
+    }
 
-        match yandex_lbs_request_by_individual_wifi(
-            tx_t38c,
-            tx_ba_c,
+    if provider::any_enabled() && CONFIG.lbs.combined {
+        match provider::request_combined(
+            &LbsContext::new(tx_t38c, tx_ba_c, yandex_client, tx_yandex_api, rl_app),
+            &wms,
+            &cms,
+        )
+        .await
+        {
+            // our own database is the only source while the providers are down
+            Err(ApiError::UpstreamUnavailable(upstream)) => {
+                warn!("Yandex LBS combined request: {upstream} is unavailable");
+            }
+            Err(e) => {
+                error!("Yandex LBS combined request: {e}");
+                return Ok(create_error_response(e, "locate"));
+            }
+            Ok(Some(ylr)) => {
+                // the same check by the serving cell and the track as of the access points
+                let ylrs = HashMap::from([("combined".to_string(), Some(ylr.clone()))]);
+                if detect_yandex_outliers(&ylrs, ylrs_cell_opt.clone(), wifi_track).is_none() {
+                    debug!("Estimate by Yandex combined request");
+                    return LocationResponsePublic::new(
+                        ylr.location.point.lat,
+                        ylr.location.point.lon,
+                        ylr.location.accuracy,
+                    )
+                    .respond();
+                }
+                debug!("detect Yandex combined outlier: {:?}", ylr);
+            }
+            Ok(None) => {}
+        }
+    } else if provider::any_enabled() {
+        match provider::request_wifi(
+            &LbsContext::new(tx_t38c, tx_ba_c, yandex_client, tx_yandex_api, rl_app),
             &wms,
-            yandex_client,
-            tx_yandex_api,
-            rl_app,
         )
         .await
         {
+            Err(ApiError::UpstreamUnavailable(upstream)) => {
+                warn!("Yandex LBS request by individual access points: {upstream} is unavailable");
+            }
             Err(e) => {
                 error!("Yandex LBS request by individual access points: {e}");
                 return Ok(create_error_response(e, "locate"));
@@ -417,6 +563,12 @@ pub async fn service(
         }
     }
 
+    // the Timing Advance ring and the sector of the serving cell
+    if let Some((lat, lon, accuracy)) = cell_geometry::estimate(&constraints) {
+        info!("Estimate by Cell geometry");
+        return LocationResponsePublic::new(lat, lon, accuracy).respond();
+    }
+
     Ok(HttpResponse::NotFound().json(json!(
         {
             "error": {
@@ -65,6 +65,11 @@ mod tests {
         (x * y).round() / y
     }
 
+    /// Defaults of the fields the outlier detection doesn't use
+    fn base() -> TransmitterLocation {
+        TransmitterLocation::new("", 0.0, 0.0, 0.0, 0.0, 0.0, 0)
+    }
+
     #[test]
     fn test_detect_outliers_failed() {
         let tls = vec![
@@ -81,6 +86,7 @@ mod tests {
                 min_lat: 0.0,
                 min_lon: 0.0,
                 total_weight: 0.0,
+                ..base()
             }),
             Some(TransmitterLocation {
                 accuracy: 1.1,
@@ -95,6 +101,7 @@ mod tests {
                 min_lat: 0.0,
                 min_lon: 0.0,
                 total_weight: 0.0,
+                ..base()
             }),
             Some(TransmitterLocation {
                 accuracy: 1.1,
@@ -109,6 +116,7 @@ mod tests {
                 min_lat: 0.0,
                 min_lon: 0.0,
                 total_weight: 0.0,
+                ..base()
             }),
             Some(TransmitterLocation {
                 accuracy: 1.1,
@@ -123,6 +131,7 @@ mod tests {
                 min_lat: 0.0,
                 min_lon: 0.0,
                 total_weight: 0.0,
+                ..base()
             }),
         ];
 
@@ -151,6 +160,7 @@ mod tests {
                 min_lat: 0.0,
                 min_lon: 0.0,
                 total_weight: 0.0,
+                ..base()
             }),
             Some(TransmitterLocation {
                 accuracy: 1.1,
@@ -165,6 +175,7 @@ mod tests {
                 min_lat: 0.0,
                 min_lon: 0.0,
                 total_weight: 0.0,
+                ..base()
             }),
             Some(TransmitterLocation {
                 accuracy: 1.1,
@@ -179,6 +190,7 @@ mod tests {
                 min_lat: 0.0,
                 min_lon: 0.0,
                 total_weight: 0.0,
+                ..base()
             }),
         ];
 
@@ -207,6 +219,7 @@ mod tests {
                 min_lat: 0.0,
                 min_lon: 0.0,
                 total_weight: 0.0,
+                ..base()
             }),
             Some(TransmitterLocation {
                 accuracy: 1.1,
@@ -221,6 +234,7 @@ mod tests {
                 min_lat: 0.0,
                 min_lon: 0.0,
                 total_weight: 0.0,
+                ..base()
             }),
             Some(TransmitterLocation {
                 accuracy: 1.1,
@@ -235,6 +249,7 @@ mod tests {
                 min_lat: 0.0,
                 min_lon: 0.0,
                 total_weight: 0.0,
+                ..base()
             }),
         ];
 
@@ -263,6 +278,7 @@ mod tests {
                 min_lat: 0.0,
                 min_lon: 0.0,
                 total_weight: 0.0,
+                ..base()
             }),
             Some(TransmitterLocation {
                 accuracy: 1.1,
@@ -277,6 +293,7 @@ mod tests {
                 min_lat: 0.0,
                 min_lon: 0.0,
                 total_weight: 0.0,
+                ..base()
             }),
             Some(TransmitterLocation {
                 accuracy: 1.1,
@@ -291,6 +308,7 @@ mod tests {
                 min_lat: 0.0,
                 min_lon: 0.0,
                 total_weight: 0.0,
+                ..base()
             }),
             Some(TransmitterLocation {
                 accuracy: 1.1,
@@ -305,6 +323,7 @@ mod tests {
                 min_lat: 0.0,
                 min_lon: 0.0,
                 total_weight: 0.0,
+                ..base()
             }),
         ];
 
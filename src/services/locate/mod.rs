@@ -1,3 +1,4 @@
+pub mod cell_geometry;
 pub mod dbscan;
 pub mod geolocate;
 pub mod geolocate_public;
@@ -4,7 +4,11 @@ use log::{error, warn};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use crate::{CONFIG, services::rate_limiter::RateLimitersApp};
+use crate::{
+    CONFIG,
+    error::ApiError,
+    services::{helper::circuit_breaker::BREAKERS, rate_limiter::RateLimitersApp},
+};
 
 /*
 
@@ -28,6 +32,11 @@ pub async fn service(
     mut payload: actix_web::web::Payload,
     params: web::Query<Params>,
 ) -> actix_web::Result<HttpResponse> {
+    // GraphHopper is down: the request is not queued for the permit
+    if let Err(err) = BREAKERS.graphhopper.check() {
+        return Ok(unavailable(&err));
+    }
+
     // Acquire permit before processing request
     // Permit released automatically when the handler is completed
     let _permit = rl_app.gh_matching.acquire().await;
@@ -58,6 +67,11 @@ pub async fn service(
                 Ok(_) => {
                     let gh_response = match gh_match_request(buffer, params.into_inner()).await {
                         Err(err) => {
+                            if let Some(api_err @ ApiError::UpstreamUnavailable(_)) =
+                                err.downcast_ref::<ApiError>()
+                            {
+                                return Ok(unavailable(api_err));
+                            }
                             return Ok(HttpResponse::InternalServerError().json(json!(
                                 {
                                     "error": {
@@ -171,6 +185,19 @@ pub async fn service(
     }
 }
 
+fn unavailable(err: &ApiError) -> HttpResponse {
+    HttpResponse::ServiceUnavailable().json(json!(
+        {
+            "error": {
+                "domain": "match",
+                "reason": "Matching service unavailable",
+                "message": err.to_string(),
+                "code": 503,
+            }
+        }
+    ))
+}
+
 #[derive(Debug, Deserialize)]
 struct Params {
     profile: Option<String>,
@@ -237,14 +264,22 @@ async fn gh_match_request(
         gh_url.push_str(&lmd);
     }
 
-    let gh_response: GhMatchingResponse = reqwest::Client::new()
-        .post(gh_url)
-        .body(data)
-        .header("Content-Type", "application/gpx+xml")
-        .send()
-        .await?
-        .json()
-        .await?;
+    BREAKERS.graphhopper.acquire()?;
+    let response = BREAKERS
+        .graphhopper
+        .send(
+            reqwest::Client::new()
+                .post(gh_url)
+                .body(data)
+                .header("Content-Type", "application/gpx+xml")
+                .send(),
+        )
+        .await
+        .map_err(anyhow::Error::msg)?;
+    BREAKERS
+        .graphhopper
+        .record_status(response.status().as_u16());
+    let gh_response: GhMatchingResponse = response.json().await?;
 
     Ok(gh_response)
 }
@@ -6,7 +6,7 @@ use crate::{
     lbs::{
         http_client::HttpClient,
         model::{self, create_cell_measurement},
-        yandex::cell::yandex_lbs_request_by_individual_cell,
+        provider::{self, LbsContext},
     },
     services::rate_limiter::RateLimitersApp,
     tasks::{
@@ -98,13 +98,9 @@ pub async fn service(
     let rl_app = (*rl_app_web.into_inner()).clone();
 
     let cms = create_cell_measurement(&data);
-    match yandex_lbs_request_by_individual_cell(
-        tx_t38c,
-        tx_ba_c,
-        cms,
-        yandex_client,
-        tx_yandex_api,
-        rl_app,
+    match provider::request_cell(
+        &LbsContext::new(tx_t38c, tx_ba_c, yandex_client, tx_yandex_api, rl_app),
+        &cms,
     )
     .await
     {
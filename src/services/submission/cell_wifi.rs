@@ -0,0 +1,193 @@
+//! Association index of the cells with the Wi-Fi access points observed together in the reports.
+//!
+//! The cell is located by the centroid of the report positions, so the filtering of the access
+//! points against the serving cell does not depend on Yandex: the cells unknown to Yandex, or all
+//! cells if the request fails (disabled or out of quota), are located by the index.
+
+use std::collections::{BTreeMap, HashMap};
+
+use geo::{Distance, Haversine, Point};
+use log::{error, warn};
+
+use crate::{
+    constants::{CELL_WIFI_MAX_APS, CELL_WIFI_MIN_REPORTS, Collection},
+    db::t38::cell_wifi::{CellWifi, get_cell_wifi_one, set_cell_wifi_one},
+    error::ApiError,
+    lbs::{
+        model::CellMeasurement,
+        yandex::wifi::{YandexLbsResponse, YandexLocation, YandexPoint},
+    },
+    services::{helper::decay, submission::report::Position},
+    tasks::t38::T38ConnectionManageMessage,
+};
+
+/// Add the report to the index entry of the cell
+pub fn observe(
+    half_life: f64,
+    entry: Option<CellWifi>,
+    code: &str,
+    (lat, lon): (f64, f64),
+    timestamp: i64,
+    macs: &[String],
+) -> CellWifi {
+    let mut cw = entry.unwrap_or_else(|| CellWifi {
+        code: code.to_string(),
+        lat,
+        lon,
+        accuracy: 0.0,
+        weight: 0.0,
+        updated_at: timestamp,
+        aps: BTreeMap::new(),
+    });
+
+    let (factor, increment) = decay::to_latest(half_life, cw.updated_at, 1.0, timestamp);
+    cw.weight *= factor;
+    cw.aps.values_mut().for_each(|w| *w *= factor as f32);
+    cw.updated_at = cw.updated_at.max(timestamp);
+
+    let distance = Haversine::distance(Point::new(cw.lon, cw.lat), Point::new(lon, lat));
+    let total = cw.weight + increment;
+    cw.lat = (cw.lat * cw.weight + lat * increment) / total;
+    cw.lon = (cw.lon * cw.weight + lon * increment) / total;
+    cw.accuracy = (cw.accuracy * cw.weight + distance * increment) / total;
+    cw.weight = total;
+
+    for mac in macs {
+        *cw.aps.entry(mac.clone()).or_default() += increment as f32;
+    }
+    if cw.aps.len() > CELL_WIFI_MAX_APS {
+        let mut aps = std::mem::take(&mut cw.aps)
+            .into_iter()
+            .collect::<Vec<(String, f32)>>();
+        aps.sort_by(|a, b| b.1.total_cmp(&a.1));
+        aps.truncate(CELL_WIFI_MAX_APS);
+        cw.aps = aps.into_iter().collect();
+    }
+    cw
+}
+
+/// Location of the cell by the index, `None` if the cell was observed in too few reports
+pub fn location(cw: &CellWifi) -> Option<YandexLbsResponse> {
+    (cw.weight >= CELL_WIFI_MIN_REPORTS).then(|| YandexLbsResponse {
+        location: YandexLocation {
+            point: YandexPoint {
+                lat: cw.lat,
+                lon: cw.lon,
+            },
+            accuracy: cw.accuracy,
+        },
+    })
+}
+
+/// Add the report with the valid access points to the index entries of its serving cells.
+/// Concurrent reports of the same cell may overwrite each other, the index is statistical.
+pub async fn record<'a>(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    codes: impl Iterator<Item = &'a String>,
+    position: &Position,
+    macs: &[String],
+) {
+    if macs.is_empty() {
+        return;
+    }
+    let collection = Collection::CellWifi.as_ref();
+    for code in codes {
+        let entry = match get_cell_wifi_one(tx_t38_conn.clone(), collection, code).await {
+            Err(e) => {
+                error!("get cell '{}' wifi: {}", code, e);
+                continue;
+            }
+            Ok(entry) => entry,
+        };
+        let cw = observe(
+            *decay::HALF_LIFE,
+            entry,
+            code,
+            (position.latitude, position.longitude),
+            position.timestamp,
+            macs,
+        );
+        if let Err(e) = set_cell_wifi_one(tx_t38_conn.clone(), collection, &cw).await {
+            error!("set cell '{}' wifi: {}", code, e);
+        }
+    }
+}
+
+/// Locate the cells unknown to Yandex by the index. If the Yandex request failed,
+/// the error is returned only when some cell is not in the index either.
+pub async fn complete(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    cms: &[CellMeasurement],
+    lbs: Result<HashMap<String, Option<YandexLbsResponse>>, ApiError>,
+) -> Result<HashMap<String, Option<YandexLbsResponse>>, ApiError> {
+    let (mut ylrs, lbs_error) = match lbs {
+        Ok(ylrs) => (ylrs, None),
+        Err(e) => (HashMap::with_capacity(cms.len()), Some(e)),
+    };
+
+    let collection = Collection::CellWifi.as_ref();
+    let mut unlocated = false;
+    for cm in cms {
+        let code = cm.code();
+        if let Some(Some(_)) = ylrs.get(&code) {
+            continue;
+        }
+        let ylr_opt = match get_cell_wifi_one(tx_t38_conn.clone(), collection, &code).await {
+            Err(_) => None,
+            Ok(cw_opt) => cw_opt.as_ref().and_then(location),
+        };
+        match ylr_opt {
+            Some(ylr) => {
+                ylrs.insert(code, Some(ylr));
+            }
+            None if lbs_error.is_some() => {
+                unlocated = true;
+                break;
+            }
+            None => {}
+        }
+    }
+
+    match lbs_error {
+        Some(e) if unlocated => Err(e),
+        Some(e) => {
+            warn!("cells are located by the Wi-Fi association index: {}", e);
+            Ok(ylrs)
+        }
+        None => Ok(ylrs),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::helper::decay::DAY;
+
+    #[test]
+    fn cell_wifi_observe() {
+        let half_life = 30.0 * DAY as f64;
+        let code = "lte:250:1:15016:576267";
+        let macs = vec!["a0:00:00:00:00:01".to_string()];
+
+        let cw = observe(half_life, None, code, (55.75, 37.62), 0, &macs);
+        assert_eq!(cw.weight, 1.0);
+        assert_eq!(cw.accuracy, 0.0);
+        assert!(location(&cw).is_none());
+
+        let cw = observe(half_life, Some(cw), code, (55.76, 37.62), 0, &macs);
+        let cw = observe(half_life, Some(cw), code, (55.77, 37.62), 0, &macs);
+        let ylr = location(&cw).unwrap();
+        assert!((ylr.location.point.lat - 55.76).abs() < 1e-9);
+        assert!(ylr.location.accuracy > 0.0);
+        assert_eq!(cw.aps["a0:00:00:00:00:01"], 3.0);
+
+        // a month later the previous reports weigh a half
+        let macs = vec!["a0:00:00:00:00:02".to_string()];
+        let cw = observe(half_life, Some(cw), code, (55.80, 37.62), 30 * DAY, &macs);
+        assert!((cw.weight - 2.5).abs() < 1e-9);
+        assert!((cw.lat - (55.76 * 1.5 + 55.80) / 2.5).abs() < 1e-9);
+        assert_eq!(cw.aps["a0:00:00:00:00:01"], 1.5);
+        assert_eq!(cw.aps["a0:00:00:00:00:02"], 1.0);
+        assert!(location(&cw).is_none());
+    }
+}
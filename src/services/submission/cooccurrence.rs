@@ -0,0 +1,251 @@
+//! Co-occurrence graph of the access points: every report is a set of access points observed
+//! together.
+//!
+//! The edges are saved in `TransmitterLocation::cooccurrence` with weights decayed by
+//! `aggregation_half_life`, only the strongest `COOCCURRENCE_MAX_PEERS` edges are kept.
+//! In locate the access points that have never been observed together with the others
+//! are flagged, complementing the geometric outlier detection.
+
+use std::collections::{BTreeMap, HashSet};
+
+use crate::{
+    constants::{
+        COOCCURRENCE_MAX_PEERS, COOCCURRENCE_MIN_KNOWN, COOCCURRENCE_MIN_PEERS,
+        COOCCURRENCE_MIN_WEIGHT,
+    },
+    db::{
+        model::Transmitter,
+        pg::transmitter::{Cooccurrence, TransmitterLocation},
+    },
+    services::helper::decay,
+};
+
+/// Access points of the report
+pub fn report_macs(transmitters: &[Transmitter]) -> Vec<String> {
+    transmitters
+        .iter()
+        .filter_map(|t| match t {
+            Transmitter::Wifi { mac, .. } => Some(mac.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Add the edges between the access points of the report, mobile access points are skipped
+pub fn record(
+    aggregates: &mut BTreeMap<String, (Transmitter, TransmitterLocation)>,
+    macs: &[String],
+    timestamp: i64,
+) {
+    record_with(*decay::HALF_LIFE, aggregates, macs, timestamp);
+}
+
+pub fn record_with(
+    half_life: f64,
+    aggregates: &mut BTreeMap<String, (Transmitter, TransmitterLocation)>,
+    macs: &[String],
+    timestamp: i64,
+) {
+    let macs = macs
+        .iter()
+        .filter(|mac| aggregates.get(*mac).is_some_and(|(_, tl)| !tl.is_mobile()))
+        .collect::<HashSet<&String>>();
+    if macs.len() < 2 {
+        return;
+    }
+
+    for mac in &macs {
+        let Some((_, tl)) = aggregates.get_mut(*mac) else {
+            continue;
+        };
+        let graph = tl.cooccurrence.get_or_insert_with(Cooccurrence::default);
+        let peers = macs
+            .iter()
+            .filter(|peer| *peer != mac)
+            .map(|peer| peer.as_str());
+        add(half_life, graph, peers, timestamp);
+    }
+}
+
+fn add<'a>(
+    half_life: f64,
+    graph: &mut Cooccurrence,
+    peers: impl Iterator<Item = &'a str>,
+    timestamp: i64,
+) {
+    let (factor, increment) = decay::to_latest(half_life, graph.updated_at, 1.0, timestamp);
+    graph.peers.values_mut().for_each(|w| *w *= factor as f32);
+    graph.updated_at = graph.updated_at.max(timestamp);
+    for peer in peers {
+        *graph.peers.entry(peer.to_string()).or_default() += increment as f32;
+    }
+
+    graph.peers.retain(|_, w| *w >= COOCCURRENCE_MIN_WEIGHT);
+    if graph.peers.len() > COOCCURRENCE_MAX_PEERS {
+        let mut edges = std::mem::take(&mut graph.peers)
+            .into_iter()
+            .collect::<Vec<(String, f32)>>();
+        edges.sort_by(|a, b| b.1.total_cmp(&a.1));
+        edges.truncate(COOCCURRENCE_MAX_PEERS);
+        graph.peers = edges.into_iter().collect();
+    }
+}
+
+fn is_linked(a: &TransmitterLocation, b: &TransmitterLocation) -> bool {
+    let has = |tl: &TransmitterLocation, mac: &str| {
+        tl.cooccurrence
+            .as_ref()
+            .is_some_and(|graph| graph.peers.contains_key(mac))
+    };
+    has(a, &b.mac) || has(b, &a.mac)
+}
+
+/// Access points of the request never observed together with the other known access points.
+/// Nothing is flagged if the request falls apart into unrelated access points.
+pub fn inconsistent(tls: &[Option<TransmitterLocation>]) -> HashSet<String> {
+    let known = tls
+        .iter()
+        .flatten()
+        .filter(|tl| {
+            tl.cooccurrence
+                .as_ref()
+                .is_some_and(|graph| graph.peers.len() >= COOCCURRENCE_MIN_KNOWN)
+        })
+        .collect::<Vec<&TransmitterLocation>>();
+
+    let mut flagged = HashSet::new();
+    for tl in &known {
+        let peers = known
+            .iter()
+            .filter(|peer| peer.mac != tl.mac)
+            .collect::<Vec<_>>();
+        if peers.len() >= COOCCURRENCE_MIN_PEERS && !peers.iter().any(|peer| is_linked(tl, peer)) {
+            flagged.insert(tl.mac.clone());
+        }
+    }
+
+    if flagged.len() * 2 > known.len() {
+        return HashSet::new();
+    }
+    flagged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::helper::decay::DAY;
+
+    fn wifi(mac: &str) -> (Transmitter, TransmitterLocation) {
+        let transmitter = Transmitter::Wifi {
+            mac: mac.to_string(),
+            ssid: None,
+            signal_strength: None,
+            age: None,
+        };
+        let tl = TransmitterLocation {
+            mac: mac.to_string(),
+            ..TransmitterLocation::fixture()
+        };
+        (transmitter, tl)
+    }
+
+    fn aggregates(macs: &[&str]) -> BTreeMap<String, (Transmitter, TransmitterLocation)> {
+        macs.iter()
+            .map(|mac| (mac.to_string(), wifi(mac)))
+            .collect()
+    }
+
+    fn strings(macs: &[&str]) -> Vec<String> {
+        macs.iter().map(|mac| mac.to_string()).collect()
+    }
+
+    #[test]
+    fn cooccurrence_record() {
+        let macs = [
+            "a0:00:00:00:00:01",
+            "a0:00:00:00:00:02",
+            "a0:00:00:00:00:03",
+        ];
+        let mut aggregates = aggregates(&macs);
+        record_with(30.0 * DAY as f64, &mut aggregates, &strings(&macs), 0);
+        record_with(
+            30.0 * DAY as f64,
+            &mut aggregates,
+            &strings(&macs[..2]),
+            30 * DAY,
+        );
+
+        let graph = aggregates[macs[0]].1.cooccurrence.as_ref().unwrap();
+        assert_eq!(graph.updated_at, 30 * DAY);
+        assert!((graph.peers[macs[1]] - 1.5).abs() < 1e-6);
+        assert!((graph.peers[macs[2]] - 0.5).abs() < 1e-6);
+
+        // an older report adds a decayed weight
+        record_with(30.0 * DAY as f64, &mut aggregates, &strings(&macs), 0);
+        let graph = aggregates[macs[0]].1.cooccurrence.as_ref().unwrap();
+        assert_eq!(graph.updated_at, 30 * DAY);
+        assert!((graph.peers[macs[2]] - 1.0).abs() < 1e-6);
+
+        // the weakest edges are dropped
+        let mut graph = Cooccurrence::default();
+        let peers = (0..COOCCURRENCE_MAX_PEERS + 10)
+            .map(|i| format!("b0:00:00:00:00:{:02x}", i))
+            .collect::<Vec<String>>();
+        add(0.0, &mut graph, peers.iter().map(String::as_str), 0);
+        add(0.0, &mut graph, peers[..5].iter().map(String::as_str), 0);
+        assert_eq!(graph.peers.len(), COOCCURRENCE_MAX_PEERS);
+        assert!(peers[..5].iter().all(|peer| graph.peers[peer] == 2.0));
+    }
+
+    #[test]
+    fn cooccurrence_inconsistent() {
+        let street = [
+            "a0:00:00:00:00:01",
+            "a0:00:00:00:00:02",
+            "a0:00:00:00:00:03",
+            "a0:00:00:00:00:04",
+        ];
+        let other = [
+            "c0:00:00:00:00:01",
+            "c0:00:00:00:00:02",
+            "c0:00:00:00:00:03",
+            "c0:00:00:00:00:04",
+        ];
+        let third = [
+            "e0:00:00:00:00:01",
+            "e0:00:00:00:00:02",
+            "e0:00:00:00:00:03",
+            "e0:00:00:00:00:04",
+        ];
+        let mut all = aggregates(&street);
+        all.extend(aggregates(&other));
+        all.extend(aggregates(&third));
+        for group in [street, other, third] {
+            record_with(0.0, &mut all, &strings(&group), 0);
+        }
+
+        let tl = |mac: &str| Some(all[mac].1.clone());
+        let request = vec![
+            tl(street[0]),
+            tl(street[1]),
+            tl(street[2]),
+            tl(other[0]),
+            None,
+        ];
+        let flagged = inconsistent(&request);
+        assert_eq!(flagged, HashSet::from([other[0].to_string()]));
+
+        // too few access points to compare with
+        let request = vec![tl(street[0]), tl(other[0])];
+        assert!(inconsistent(&request).is_empty());
+
+        // no majority to compare with
+        let request = vec![tl(street[0]), tl(other[0]), tl(third[0])];
+        assert!(inconsistent(&request).is_empty());
+
+        // unknown access points are not flagged
+        let new = wifi("d0:00:00:00:00:01").1;
+        let request = vec![tl(street[0]), tl(street[1]), Some(new)];
+        assert!(inconsistent(&request).is_empty());
+    }
+}
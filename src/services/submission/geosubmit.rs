@@ -13,9 +13,11 @@ use tokio_postgres::GenericClient;
 
 use crate::{
     lbs::model,
-    services::helper::custom_deserialize::{
-        date_time_utc_from_str, default_timestamp, mac_address,
+    services::helper::{
+        custom_deserialize::{date_time_utc_from_str, default_timestamp, mac_address},
+        ssid,
     },
+    tasks::{blobasaur::BAConnectionManageMessage, t38::T38ConnectionManageMessage},
 };
 
 // only the bare minimum is parsed here: it is assumed that certain data issues
@@ -96,6 +98,16 @@ pub struct Wifi {
     pub extra: Value,
 }
 
+impl ssid::AccessPoint for Wifi {
+    fn mac(&self) -> &str {
+        &self.mac_address
+    }
+
+    fn ssid(&self) -> Option<&str> {
+        self.ssid.as_deref()
+    }
+}
+
 /// Serde representation to deserialize a bluetooth beacon in a report
 #[derive(Deserialize, Serialize, Debug)]
 #[serde(rename_all = "camelCase")]
@@ -138,6 +150,8 @@ pub struct Position {
 pub async fn service(
     data: web::Json<Submission>,
     pool_tp: web::Data<deadpool_postgres::Pool>,
+    tx_t38_conn: web::Data<flume::Sender<T38ConnectionManageMessage>>,
+    tx_ba_conn: web::Data<flume::Sender<BAConnectionManageMessage>>,
     req: HttpRequest,
 ) -> actix_web::Result<impl Responder> {
     let data = data.into_inner();
@@ -154,7 +168,7 @@ pub async fn service(
         None => None,
     };
 
-    if let Err(err) = insert(&pool_tp, ua, data, None)
+    if let Err(err) = insert(&pool_tp, &tx_t38_conn, &tx_ba_conn, ua, data, None)
         .await
         .context("Writing to database failed")
         .map_err(ErrorInternalServerError)
@@ -168,10 +182,18 @@ pub async fn service(
 /// Inserts a submission into the database.
 pub async fn insert(
     pool_tp: &deadpool_postgres::Pool,
+    tx_t38_conn: &flume::Sender<T38ConnectionManageMessage>,
+    tx_ba_conn: &flume::Sender<BAConnectionManageMessage>,
     user_agent: Option<String>,
-    submission: Submission,
+    mut submission: Submission,
     ts_now: Option<DateTime<Utc>>,
 ) -> anyhow::Result<()> {
+    let aps = submission
+        .items
+        .iter_mut()
+        .filter_map(|report| report.wifi_access_points.as_mut());
+    ssid::drop_opted_out(aps, tx_t38_conn, tx_ba_conn).await;
+
     let mapper = pool_tp.get().await?;
     let client = mapper.client();
 
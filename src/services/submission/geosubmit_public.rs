@@ -1,3 +1,5 @@
+use std::collections::{HashMap, HashSet};
+
 use actix_web::{
     HttpRequest, HttpResponse, Responder,
     error::ErrorInternalServerError,
@@ -14,15 +16,24 @@ use crate::{
     config::CONFIG,
     lbs::model::{Cell, valid_cell},
     services::{
-        helper::custom_deserialize::{
-            date_time_utc_from_str, default_timestamp_ms, mac_address, validate_rssi,
+        helper::{
+            body::{BodyFormat, decode_body},
+            custom_deserialize::{
+                date_time_utc_from_str, default_timestamp_ms, mac_address, normalize_mac,
+                rssi_or_default, validate_rssi,
+            },
+            proto,
         },
         submission::{
             geosubmit::{Position, PositionSource, Report, Submission, Wifi, insert},
+            idempotency::{KeyStore, duplicates, item_key, request_key, save_once},
             report::{Position as PositionProcess, Report as ReportProcess, Wifi as WifiProcess},
         },
     },
-    tasks::report::MessageSaveReport,
+    tasks::{
+        blobasaur::BAConnectionManageMessage, report::MessageSaveReport,
+        t38::T38ConnectionManageMessage,
+    },
 };
 
 #[derive(Deserialize, Clone, Debug)]
@@ -38,6 +49,14 @@ impl Into<Submission> for SubmissionPublic {
     }
 }
 
+impl From<proto::Submission> for SubmissionPublic {
+    fn from(s: proto::Submission) -> Self {
+        SubmissionPublic {
+            items: s.items.into_iter().map(ReportPublic::from).collect(),
+        }
+    }
+}
+
 /// Serde representation of a single report
 #[derive(Deserialize, Serialize, Clone, Debug)]
 #[serde(rename_all = "snake_case")]
@@ -53,6 +72,23 @@ pub struct ReportPublic {
     extra: Value,
 }
 
+impl From<proto::Report> for ReportPublic {
+    fn from(r: proto::Report) -> Self {
+        ReportPublic {
+            timestamp: proto::timestamp(r.timestamp),
+            device_id: r.device_id,
+            gnss: r.gnss.unwrap_or_default().into(),
+            wifi: if r.wifi.is_empty() {
+                None
+            } else {
+                Some(r.wifi.into_iter().map(WifiPublic::from).collect())
+            },
+            cell: r.cell.map(|c| c.into()),
+            extra: Value::Null,
+        }
+    }
+}
+
 impl Into<Report> for ReportPublic {
     fn into(self) -> Report {
         let wap = self.wifi.map(|vec_wp| {
@@ -114,6 +150,23 @@ struct WifiPublic {
     extra: Value,
 }
 
+impl From<proto::Wifi> for WifiPublic {
+    fn from(w: proto::Wifi) -> Self {
+        WifiPublic {
+            mac: normalize_mac(w.mac),
+            radio_type: w.radio_type,
+            age: w.age,
+            channel: w.channel.and_then(|c| u16::try_from(c).ok()),
+            frequency: w.frequency,
+            rssi: rssi_or_default(w.rssi),
+            snr: w.snr,
+            ssid: w.ssid,
+            bandwidth: w.bandwidth,
+            extra: Value::Null,
+        }
+    }
+}
+
 impl Into<Wifi> for WifiPublic {
     fn into(self) -> Wifi {
         Wifi {
@@ -158,6 +211,24 @@ pub struct PositionPublic {
     pub extra: Value,
 }
 
+impl From<proto::Position> for PositionPublic {
+    fn from(p: proto::Position) -> Self {
+        PositionPublic {
+            longitude: p.longitude,
+            latitude: p.latitude,
+            accuracy: p.accuracy,
+            altitude: p.altitude,
+            altitude_accuracy: p.altitude_accuracy,
+            age: p.age,
+            speed: p.speed,
+            bearing: p.bearing,
+            pressure: p.pressure,
+            source: proto::source(p.source),
+            extra: Value::Null,
+        }
+    }
+}
+
 impl Into<Position> for PositionPublic {
     fn into(self) -> Position {
         Position {
@@ -185,18 +256,53 @@ impl Into<PositionProcess> for PositionPublic {
             age: self.age,
             speed: self.speed,
             heading: self.bearing,
+            // set from the report in extract_from_report
+            timestamp: 0,
+            cell_location: None,
+            lbs_locations: HashMap::new(),
         }
     }
 }
 
 #[post("/report")]
 pub async fn service(
-    data: web::Json<SubmissionPublic>,
+    body: web::Bytes,
     pool_tp: web::Data<deadpool_postgres::Pool>,
     tx_report_web: web::Data<Option<flume::Sender<MessageSaveReport>>>,
+    tx_t38_conn: web::Data<flume::Sender<T38ConnectionManageMessage>>,
+    tx_ba_conn: web::Data<flume::Sender<BAConnectionManageMessage>>,
     req: HttpRequest,
 ) -> actix_web::Result<impl Responder> {
-    let sp = data.into_inner();
+    let format = match BodyFormat::from_request(&req) {
+        Some(f) => f,
+        None => {
+            return Ok(HttpResponse::UnsupportedMediaType().json(json!(
+                {
+                    "error": {
+                        "domain": "report",
+                        "reason": "invalid request",
+                        "message": "unsupported content type",
+                        "code": 415,
+                    }
+                }
+            )));
+        }
+    };
+    let mut sp = match decode_body::<SubmissionPublic, proto::Submission>(format, &body) {
+        Ok(sp) => sp,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().json(json!(
+                {
+                    "error": {
+                        "domain": "report",
+                        "reason": "invalid request",
+                        "message": format!("request deserialization error: {}", e),
+                        "code": 400,
+                    }
+                }
+            )));
+        }
+    };
 
     if let Some(rp) = sp.items.first() {
         if !valid_cell(rp.cell.as_ref()) {
@@ -213,6 +319,23 @@ pub async fn service(
         }
     }
 
+    // repeated submissions are acknowledged, but not saved and processed again
+    let tx_t38_conn = (*tx_t38_conn.into_inner()).clone();
+    let mut keys = Vec::new();
+    if CONFIG.idempotency.enabled {
+        if let Some(key) = request_key(&req) {
+            keys.push(key);
+            if duplicates(&tx_t38_conn, &keys).await[0] {
+                return Ok(HttpResponse::new(StatusCode::OK));
+            }
+        } else if CONFIG.idempotency.dedupe_items {
+            (sp.items, keys) = dedupe_items(&tx_t38_conn, sp.items).await;
+            if sp.items.is_empty() {
+                return Ok(HttpResponse::new(StatusCode::OK));
+            }
+        }
+    }
+
     let ua = match req
         .headers()
         .get(USER_AGENT)
@@ -224,29 +347,79 @@ pub async fn service(
     };
 
     if CONFIG.locator.process_report_online {
-        let m = MessageSaveReport {
-            sp: sp.clone(),
-            ua: ua.clone(),
-        };
-        let tx_report = (*tx_report_web.into_inner()).clone().unwrap();
-        if let Err(_e) = tx_report.send_async(m).await {
-            error!("unsuccessful submission of report for processing");
-        }
+        // the report is processed only after it is saved and its keys are registered:
+        // the retry of a failed save must not be processed twice
         let ts_now = default_timestamp_ms();
-        if let Err(err) = insert(&pool_tp, ua, sp.into(), Some(ts_now))
-            .await
-            .context("Writing to database failed")
-            .map_err(ErrorInternalServerError)
-        {
-            error!("save report in database: {}", err);
-        }
-    } else if let Err(err) = insert(&pool_tp, ua, sp.into(), None)
+        match save_once(
+            &tx_t38_conn,
+            &keys,
+            insert(
+                &pool_tp,
+                &tx_t38_conn,
+                &tx_ba_conn,
+                ua.clone(),
+                sp.clone().into(),
+                Some(ts_now),
+            ),
+        )
         .await
         .context("Writing to database failed")
         .map_err(ErrorInternalServerError)
+        {
+            Err(err) => {
+                error!("save report in database: {}", err);
+            }
+            Ok(()) => {
+                let m = MessageSaveReport { sp, ua };
+                let tx_report = (*tx_report_web.into_inner()).clone().unwrap();
+                if let Err(_e) = tx_report.send_async(m).await {
+                    error!("unsuccessful submission of report for processing");
+                }
+            }
+        }
+    } else if let Err(err) = save_once(
+        &tx_t38_conn,
+        &keys,
+        insert(&pool_tp, &tx_t38_conn, &tx_ba_conn, ua, sp.into(), None),
+    )
+    .await
+    .context("Writing to database failed")
+    .map_err(ErrorInternalServerError)
     {
         error!("save report in database: {}", err);
     }
 
     Ok(HttpResponse::new(StatusCode::OK))
 }
+
+// items without device_id can't be identified and are always kept,
+// returns the unique items and the keys to register once they are saved
+async fn dedupe_items<S: KeyStore>(
+    store: &S,
+    items: Vec<ReportPublic>,
+) -> (Vec<ReportPublic>, Vec<String>) {
+    let item_keys = items
+        .iter()
+        .map(|rp| {
+            rp.device_id
+                .as_ref()
+                .map(|device_id| item_key(device_id, &rp.timestamp))
+        })
+        .collect::<Vec<Option<String>>>();
+    let keys = item_keys.iter().flatten().cloned().collect::<Vec<String>>();
+    let mut registered = duplicates(store, &keys).await.into_iter();
+
+    let mut unique = Vec::with_capacity(items.len());
+    let mut unique_keys = HashSet::with_capacity(keys.len());
+    for (rp, key) in items.into_iter().zip(item_keys) {
+        if let Some(key) = key {
+            let duplicate = registered.next().unwrap_or(false);
+            // repeated within the submission too
+            if duplicate || !unique_keys.insert(key) {
+                continue;
+            }
+        }
+        unique.push(rp);
+    }
+    (unique, unique_keys.into_iter().collect())
+}
@@ -0,0 +1,156 @@
+use std::future::Future;
+
+use actix_web::HttpRequest;
+use chrono::{DateTime, Utc};
+use log::{error, warn};
+use redis::RedisError;
+
+use crate::{
+    CONFIG,
+    constants::Collection,
+    db::t38::{get_idempotency_keys, set_idempotency_keys},
+    tasks::t38::T38ConnectionManageMessage,
+};
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+// keys longer than this are rejected to keep the Tile38 collection compact
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 128;
+
+/// Client-supplied key of the request, ignored if empty or too long
+pub fn request_key(req: &HttpRequest) -> Option<String> {
+    let key = req
+        .headers()
+        .get(IDEMPOTENCY_KEY_HEADER)?
+        .to_str()
+        .ok()?
+        .trim();
+    if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LEN {
+        warn!(
+            "invalid {} header length: {}",
+            IDEMPOTENCY_KEY_HEADER,
+            key.len()
+        );
+        return None;
+    }
+    Some(format!("request:{}", key))
+}
+
+pub fn item_key(device_id: &str, timestamp: &DateTime<Utc>) -> String {
+    format!("item:{}:{}", device_id, timestamp.timestamp_millis())
+}
+
+/// Keys of the saved submissions within the dedupe window
+pub trait KeyStore {
+    /// Which of the keys are registered
+    async fn registered(&self, keys: &[String]) -> Result<Vec<bool>, RedisError>;
+
+    /// Register the keys for the dedupe window
+    async fn register(&self, keys: &[String]) -> Result<(), RedisError>;
+}
+
+impl KeyStore for flume::Sender<T38ConnectionManageMessage> {
+    async fn registered(&self, keys: &[String]) -> Result<Vec<bool>, RedisError> {
+        let collection = Collection::ReportIdempotency.as_ref();
+        get_idempotency_keys(self.clone(), collection, keys).await
+    }
+
+    async fn register(&self, keys: &[String]) -> Result<(), RedisError> {
+        let collection = Collection::ReportIdempotency.as_ref();
+        set_idempotency_keys(self.clone(), collection, keys, CONFIG.idempotency.window).await
+    }
+}
+
+/// Which of the keys belong to the submissions already saved.
+/// Tile38 errors do not block the submission: the report is saved as usual.
+pub async fn duplicates<S: KeyStore>(store: &S, keys: &[String]) -> Vec<bool> {
+    match store.registered(keys).await {
+        Err(e) => {
+            error!("check idempotency keys {:?}: {}", keys, e);
+            vec![false; keys.len()]
+        }
+        Ok(registered) => registered,
+    }
+}
+
+/// Save the submission, its keys are registered only after the save succeeded: if the save
+/// failed or the client disconnected and the handler was dropped, the retry is accepted.
+/// Concurrent retries may both be saved, a duplicate is preferred to a lost report.
+pub async fn save_once<S, F, T, E>(store: &S, keys: &[String], save: F) -> Result<T, E>
+where
+    S: KeyStore,
+    F: Future<Output = Result<T, E>>,
+{
+    let saved = save.await?;
+    if let Err(e) = store.register(keys).await {
+        error!("register idempotency keys {:?}: {}", keys, e);
+    }
+    Ok(saved)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, sync::Mutex};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct MemoryStore(Mutex<HashSet<String>>);
+
+    impl KeyStore for MemoryStore {
+        async fn registered(&self, keys: &[String]) -> Result<Vec<bool>, RedisError> {
+            let registered = self.0.lock().unwrap();
+            Ok(keys.iter().map(|key| registered.contains(key)).collect())
+        }
+
+        async fn register(&self, keys: &[String]) -> Result<(), RedisError> {
+            self.0.lock().unwrap().extend(keys.iter().cloned());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn idempotency_failed_save_retried() {
+        let store = MemoryStore::default();
+        let keys = vec!["request:5f1c2d".to_string()];
+        assert_eq!(duplicates(&store, &keys).await, vec![false]);
+
+        let failed = save_once(&store, &keys, async { Err::<(), _>("insert failed") }).await;
+        assert!(failed.is_err());
+        // the retry is accepted
+        assert_eq!(duplicates(&store, &keys).await, vec![false]);
+
+        // the client disconnected while the report was being saved
+        let mut dropped = Box::pin(save_once(
+            &store,
+            &keys,
+            futures::future::pending::<Result<(), &str>>(),
+        ));
+        assert!(futures::poll!(dropped.as_mut()).is_pending());
+        drop(dropped);
+        assert_eq!(duplicates(&store, &keys).await, vec![false]);
+
+        save_once(&store, &keys, async { Ok::<(), &str>(()) })
+            .await
+            .unwrap();
+        assert_eq!(duplicates(&store, &keys).await, vec![true]);
+    }
+
+    #[test]
+    fn idempotency_keys() {
+        let ts = DateTime::from_timestamp_millis(1764679692190).unwrap();
+        assert_eq!(
+            item_key("868172073967398", &ts),
+            "item:868172073967398:1764679692190"
+        );
+
+        let req = actix_web::test::TestRequest::default()
+            .insert_header((IDEMPOTENCY_KEY_HEADER, " 5f1c2d "))
+            .to_http_request();
+        assert_eq!(request_key(&req), Some("request:5f1c2d".to_string()));
+
+        let req = actix_web::test::TestRequest::default()
+            .insert_header((IDEMPOTENCY_KEY_HEADER, "x".repeat(200)))
+            .to_http_request();
+        assert_eq!(request_key(&req), None);
+    }
+}
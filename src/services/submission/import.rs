@@ -0,0 +1,631 @@
+//! Import of offline stumbler exports: NeoStumbler JSON, Tower Collector CSV and WiGLE CSV.
+//!
+//! Every row of the export is converted into a `geosubmit::Report` and saved as a regular
+//! submission, so the reports are processed in the same way as the ones sent online.
+
+use std::path::PathBuf;
+
+use actix_multipart::Multipart;
+use actix_web::{HttpResponse, Responder, post, web};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use clap::ValueEnum;
+use futures::StreamExt;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use strum_macros::Display;
+
+use crate::{
+    CONFIG,
+    services::{
+        helper::custom_deserialize::{date_time_utc_from_i64, normalize_mac},
+        submission::geosubmit::{
+            Bluetooth, Cell, Position, PositionSource, RadioTypeCell, Report, Submission, Wifi,
+            insert,
+        },
+    },
+    tasks::{blobasaur::BAConnectionManageMessage, t38::T38ConnectionManageMessage},
+};
+
+// number of row errors returned in the summary of a file
+const MAX_REPORTED_ERRORS: usize = 20;
+
+/// Supported export formats
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize, ValueEnum, Display)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportFormat {
+    #[strum(serialize = "neostumbler")]
+    #[serde(rename = "neostumbler")]
+    #[value(name = "neostumbler")]
+    NeoStumbler,
+    #[strum(serialize = "tower_collector")]
+    #[value(name = "tower_collector")]
+    TowerCollector,
+    #[strum(serialize = "wigle")]
+    Wigle,
+}
+
+impl ImportFormat {
+    /// Detect the format by the beginning of the file
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        let text = String::from_utf8_lossy(&data[..data.len().min(4096)]);
+        let text = text.trim_start_matches('\u{feff}').trim_start();
+        if text.starts_with('{') || text.starts_with('[') {
+            return Some(Self::NeoStumbler);
+        }
+        let first_line = text.lines().next().unwrap_or_default().to_lowercase();
+        if first_line.starts_with("wiglewifi") || first_line.starts_with("mac,ssid") {
+            return Some(Self::Wigle);
+        }
+        if first_line.contains("mcc") && first_line.contains("net_type") {
+            return Some(Self::TowerCollector);
+        }
+        None
+    }
+
+    fn user_agent(&self) -> String {
+        format!("import/{}", self)
+    }
+}
+
+/// Result of the import of a single file
+#[derive(Debug, Serialize)]
+pub struct ImportSummary {
+    pub file: String,
+    pub format: Option<ImportFormat>,
+    pub accepted: usize,
+    pub rejected: usize,
+    pub errors: Vec<String>,
+}
+
+impl ImportSummary {
+    fn new(file: &str, format: Option<ImportFormat>) -> Self {
+        Self {
+            file: file.to_string(),
+            format,
+            accepted: 0,
+            rejected: 0,
+            errors: Vec::new(),
+        }
+    }
+
+    fn reject(&mut self, row: usize, reason: impl std::fmt::Display) {
+        self.rejected += 1;
+        if self.errors.len() < MAX_REPORTED_ERRORS {
+            self.errors.push(format!("row {}: {}", row, reason));
+        }
+    }
+
+    fn fail(&mut self, reason: impl std::fmt::Display) {
+        self.errors.push(reason.to_string());
+    }
+}
+
+/// Parse the file and save accepted rows as reports
+pub async fn import_file(
+    pool_tp: &deadpool_postgres::Pool,
+    tx_t38_conn: &flume::Sender<T38ConnectionManageMessage>,
+    tx_ba_conn: &flume::Sender<BAConnectionManageMessage>,
+    file: &str,
+    data: &[u8],
+    format: Option<ImportFormat>,
+) -> ImportSummary {
+    let format = format.or_else(|| ImportFormat::detect(data));
+    let mut summary = ImportSummary::new(file, format);
+
+    let Some(format) = format else {
+        summary.fail("unknown file format");
+        return summary;
+    };
+
+    let reports = match format {
+        ImportFormat::NeoStumbler => parse_neostumbler(data, &mut summary),
+        ImportFormat::TowerCollector => parse_tower_collector(data, &mut summary),
+        ImportFormat::Wigle => parse_wigle(data, &mut summary),
+    };
+
+    let accepted = reports.len();
+    if accepted == 0 {
+        return summary;
+    }
+
+    let submission = Submission { items: reports };
+    if let Err(e) = insert(
+        pool_tp,
+        tx_t38_conn,
+        tx_ba_conn,
+        Some(format.user_agent()),
+        submission,
+        None,
+    )
+    .await
+    {
+        error!("import {}: {}", file, e);
+        summary.fail(format!("save reports in database: {}", e));
+        return summary;
+    }
+    summary.accepted = accepted;
+
+    info!(
+        "import {} ({}): accepted {}, rejected {}",
+        file, format, summary.accepted, summary.rejected
+    );
+    summary
+}
+
+// reports for (-1,-1) to (1, 1) are ignored by geosubmit::insert
+fn valid_position(lat: f64, lon: f64) -> Result<(), String> {
+    if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+        return Err(format!("invalid position ({}, {})", lat, lon));
+    }
+    if lat.abs() <= 1. && lon.abs() <= 1. {
+        return Err(format!("position near null island ({}, {})", lat, lon));
+    }
+    Ok(())
+}
+
+fn parse_time(s: &str) -> Option<DateTime<Utc>> {
+    let s = s.trim();
+    if let Ok(n) = s.parse::<i64>() {
+        return date_time_utc_from_i64(n).ok();
+    }
+    if let Ok(dt) = DateTime::parse_from_rfc3339(s) {
+        return Some(dt.to_utc());
+    }
+    NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S")
+        .ok()
+        .map(|ndt| ndt.and_utc())
+}
+
+fn position(lat: f64, lon: f64, accuracy: Option<f64>, altitude: Option<f64>) -> Position {
+    Position {
+        latitude: lat,
+        longitude: lon,
+        accuracy,
+        altitude,
+        altitude_accuracy: None,
+        age: None,
+        speed: None,
+        heading: None,
+        pressure: None,
+        source: Some(PositionSource::Gps),
+        extra: Value::Null,
+    }
+}
+
+fn report(timestamp: DateTime<Utc>, position: Position) -> Report {
+    Report {
+        timestamp,
+        device_id: None,
+        position,
+        cell_towers: None,
+        cell: None,
+        wifi_access_points: None,
+        bluetooth_beacons: None,
+        extra: Value::Null,
+    }
+}
+
+/// NeoStumbler export in the geosubmit format: `{"items": [...]}` or an array of reports
+fn parse_neostumbler(data: &[u8], summary: &mut ImportSummary) -> Vec<Report> {
+    let items = match serde_json::from_slice::<Value>(data) {
+        Err(e) => {
+            summary.fail(format!("invalid JSON: {}", e));
+            return vec![];
+        }
+        Ok(Value::Array(items)) => items,
+        Ok(Value::Object(mut obj)) => match obj.remove("items") {
+            Some(Value::Array(items)) => items,
+            _ => {
+                summary.fail("items array not found");
+                return vec![];
+            }
+        },
+        Ok(_) => {
+            summary.fail("unsupported JSON document");
+            return vec![];
+        }
+    };
+
+    let mut reports = Vec::with_capacity(items.len());
+    for (i, item) in items.into_iter().enumerate() {
+        match serde_json::from_value::<Report>(item) {
+            Err(e) => summary.reject(i + 1, e),
+            Ok(r) => match valid_position(r.position.latitude, r.position.longitude) {
+                Err(e) => summary.reject(i + 1, e),
+                Ok(_) => reports.push(r),
+            },
+        }
+    }
+    reports
+}
+
+/// Row of the Tower Collector CSV export
+#[derive(Debug, Deserialize)]
+struct TowerCollectorRecord {
+    mcc: u16,
+    mnc: u16,
+    lac: Option<u32>,
+    cell_id: Option<u64>,
+    psc: Option<u16>,
+    asu: Option<i32>,
+    dbm: Option<f64>,
+    ta: Option<f64>,
+    lat: f64,
+    lon: f64,
+    accuracy: Option<f64>,
+    altitude: Option<f64>,
+    measured_at: String,
+    net_type: String,
+    neighboring: Option<String>,
+}
+
+fn radio_type_tower_collector(net_type: &str) -> Option<RadioTypeCell> {
+    match net_type.to_uppercase().as_str() {
+        "GSM" | "GPRS" | "EDGE" => Some(RadioTypeCell::Gsm),
+        "UMTS" | "WCDMA" | "HSPA" | "HSDPA" | "HSUPA" | "HSPAP" => Some(RadioTypeCell::Umts),
+        "LTE" => Some(RadioTypeCell::Lte),
+        "NR" => Some(RadioTypeCell::Nr),
+        _ => None,
+    }
+}
+
+fn parse_tower_collector(data: &[u8], summary: &mut ImportSummary) -> Vec<Report> {
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(data);
+
+    let mut reports = Vec::new();
+    for (i, result) in reader.deserialize::<TowerCollectorRecord>().enumerate() {
+        // header is the first line
+        let row = i + 2;
+        let record = match result {
+            Err(e) => {
+                summary.reject(row, e);
+                continue;
+            }
+            Ok(r) => r,
+        };
+        if let Err(e) = valid_position(record.lat, record.lon) {
+            summary.reject(row, e);
+            continue;
+        }
+        let Some(timestamp) = parse_time(&record.measured_at) else {
+            summary.reject(row, format!("invalid measured_at: {}", record.measured_at));
+            continue;
+        };
+        let Some(radio_type) = radio_type_tower_collector(&record.net_type) else {
+            summary.reject(row, format!("unsupported net_type: {}", record.net_type));
+            continue;
+        };
+        let neighboring = record
+            .neighboring
+            .as_deref()
+            .is_some_and(|n| n == "1" || n.eq_ignore_ascii_case("true"));
+
+        let cell = Cell {
+            radio_type,
+            mobile_country_code: record.mcc,
+            mobile_network_code: record.mnc,
+            location_area_code: record.lac,
+            cell_id: record.cell_id,
+            age: None,
+            asu: record.asu,
+            arfcn: None,
+            primary_scrambling_code: record.psc,
+            serving: Some(if neighboring { 0 } else { 1 }),
+            signal_strength: record.dbm,
+            timing_advance: record.ta,
+            extra: Value::Null,
+        };
+
+        let mut r = report(
+            timestamp,
+            position(record.lat, record.lon, record.accuracy, record.altitude),
+        );
+        r.cell_towers = Some(vec![cell]);
+        reports.push(r);
+    }
+    reports
+}
+
+/// Row of the WiGLE CSV export
+#[derive(Debug, Deserialize)]
+struct WigleRecord {
+    #[serde(rename = "MAC")]
+    mac: String,
+    #[serde(rename = "SSID")]
+    ssid: Option<String>,
+    #[serde(rename = "FirstSeen")]
+    first_seen: String,
+    #[serde(rename = "Channel")]
+    channel: Option<i64>,
+    #[serde(rename = "Frequency", default)]
+    frequency: Option<f64>,
+    #[serde(rename = "RSSI")]
+    rssi: Option<f64>,
+    #[serde(rename = "CurrentLatitude")]
+    lat: f64,
+    #[serde(rename = "CurrentLongitude")]
+    lon: f64,
+    #[serde(rename = "AltitudeMeters")]
+    altitude: Option<f64>,
+    #[serde(rename = "AccuracyMeters")]
+    accuracy: Option<f64>,
+    #[serde(rename = "Type")]
+    record_type: String,
+}
+
+// cell identifier in WiGLE is written as "MCCMNC_LAC_CID"
+fn parse_wigle_cell(mac: &str, radio_type: RadioTypeCell) -> Option<Cell> {
+    let mut parts = mac.split('_');
+    let operator = parts.next()?;
+    let lac = parts.next()?.parse::<u32>().ok()?;
+    let cid = parts.next()?.parse::<u64>().ok()?;
+    if operator.len() < 5 || !operator.is_ascii() {
+        return None;
+    }
+    let mcc = operator[..3].parse::<u16>().ok()?;
+    let mnc = operator[3..].parse::<u16>().ok()?;
+
+    Some(Cell {
+        radio_type,
+        mobile_country_code: mcc,
+        mobile_network_code: mnc,
+        location_area_code: Some(lac),
+        cell_id: Some(cid),
+        age: None,
+        asu: None,
+        arfcn: None,
+        primary_scrambling_code: None,
+        serving: None,
+        signal_strength: None,
+        timing_advance: None,
+        extra: Value::Null,
+    })
+}
+
+fn parse_wigle(data: &[u8], summary: &mut ImportSummary) -> Vec<Report> {
+    // skip the pre-header line: WigleWifi-1.4,appRelease=...,model=...
+    let mut offset = 0;
+    let text_start = String::from_utf8_lossy(&data[..data.len().min(64)]).to_lowercase();
+    if text_start
+        .trim_start_matches('\u{feff}')
+        .starts_with("wiglewifi")
+    {
+        offset = data
+            .iter()
+            .position(|&b| b == b'\n')
+            .map(|p| p + 1)
+            .unwrap_or(data.len());
+    }
+
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(&data[offset..]);
+
+    let mut reports = Vec::new();
+    for (i, result) in reader.deserialize::<WigleRecord>().enumerate() {
+        // pre-header and header are the first lines
+        let row = if offset > 0 { i + 3 } else { i + 2 };
+        let record = match result {
+            Err(e) => {
+                summary.reject(row, e);
+                continue;
+            }
+            Ok(r) => r,
+        };
+        if let Err(e) = valid_position(record.lat, record.lon) {
+            summary.reject(row, e);
+            continue;
+        }
+        let Some(timestamp) = parse_time(&record.first_seen) else {
+            summary.reject(row, format!("invalid FirstSeen: {}", record.first_seen));
+            continue;
+        };
+
+        let mut r = report(
+            timestamp,
+            position(record.lat, record.lon, record.accuracy, record.altitude),
+        );
+        let record_type = record.record_type.to_uppercase();
+        match record_type.as_str() {
+            "WIFI" => {
+                r.wifi_access_points = Some(vec![Wifi {
+                    mac_address: normalize_mac(record.mac.to_lowercase()),
+                    radio_type: None,
+                    age: None,
+                    channel: record.channel.and_then(|c| u16::try_from(c).ok()),
+                    frequency: record.frequency,
+                    signal_strength: record.rssi,
+                    signal_to_noise_ratio: None,
+                    ssid: record.ssid.filter(|s| !s.is_empty()),
+                    extra: Value::Null,
+                }]);
+            }
+            "BT" | "BLE" => {
+                r.bluetooth_beacons = Some(vec![Bluetooth {
+                    mac_address: record.mac.to_lowercase(),
+                    name: record.ssid.filter(|s| !s.is_empty()),
+                    age: None,
+                    signal_strength: record.rssi,
+                    extra: Value::Null,
+                }]);
+            }
+            "GSM" | "WCDMA" | "LTE" | "NR" => {
+                let radio_type = match record_type.as_str() {
+                    "GSM" => RadioTypeCell::Gsm,
+                    "WCDMA" => RadioTypeCell::Umts,
+                    "LTE" => RadioTypeCell::Lte,
+                    _ => RadioTypeCell::Nr,
+                };
+                match parse_wigle_cell(&record.mac, radio_type) {
+                    None => {
+                        summary.reject(row, format!("invalid cell identifier: {}", record.mac));
+                        continue;
+                    }
+                    Some(mut cell) => {
+                        cell.signal_strength = record.rssi;
+                        r.cell_towers = Some(vec![cell]);
+                    }
+                }
+            }
+            _ => {
+                summary.reject(row, format!("unsupported type: {}", record.record_type));
+                continue;
+            }
+        }
+        reports.push(r);
+    }
+    reports
+}
+
+#[derive(Deserialize)]
+pub struct ImportParams {
+    /// force the format of all uploaded files, detected by content otherwise
+    pub format: Option<ImportFormat>,
+}
+
+/// Upload of stumbler exports as multipart/form-data, one file per field
+#[post("/import")]
+pub async fn service(
+    mut payload: Multipart,
+    params: web::Query<ImportParams>,
+    pool_tp: web::Data<deadpool_postgres::Pool>,
+    tx_t38_conn: web::Data<flume::Sender<T38ConnectionManageMessage>>,
+    tx_ba_conn: web::Data<flume::Sender<BAConnectionManageMessage>>,
+) -> actix_web::Result<impl Responder> {
+    let limit = CONFIG.server.max_payload_mb * 1024 * 1024;
+    let mut summaries = Vec::new();
+
+    while let Some(field) = payload.next().await {
+        let mut field = field?;
+        let file = field
+            .content_disposition()
+            .and_then(|cd| cd.get_filename().or_else(|| cd.get_name()))
+            .unwrap_or("unnamed")
+            .to_string();
+
+        let mut data = web::BytesMut::new();
+        while let Some(chunk) = field.next().await {
+            let chunk = chunk?;
+            if data.len() + chunk.len() > limit {
+                return Ok(HttpResponse::PayloadTooLarge().json(json!(
+                    {
+                        "error": {
+                            "domain": "import",
+                            "reason": "invalid request",
+                            "message": format!("file {} exceeds {} MB", file, CONFIG.server.max_payload_mb),
+                            "code": 413,
+                        }
+                    }
+                )));
+            }
+            data.extend_from_slice(&chunk);
+        }
+
+        summaries.push(
+            import_file(
+                &pool_tp,
+                &tx_t38_conn,
+                &tx_ba_conn,
+                &file,
+                &data,
+                params.format,
+            )
+            .await,
+        );
+    }
+
+    Ok(HttpResponse::Ok().json(json!({ "files": summaries })))
+}
+
+/// Import files from the command line
+pub async fn run(
+    pool_tp: deadpool_postgres::Pool,
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    tx_ba_conn: flume::Sender<BAConnectionManageMessage>,
+    format: Option<ImportFormat>,
+    files: Vec<PathBuf>,
+) -> anyhow::Result<()> {
+    for path in files {
+        let file = path.display().to_string();
+        let summary = match std::fs::read(&path) {
+            Err(e) => {
+                let mut summary = ImportSummary::new(&file, format);
+                summary.fail(format!("read file: {}", e));
+                summary
+            }
+            Ok(data) => {
+                import_file(&pool_tp, &tx_t38_conn, &tx_ba_conn, &file, &data, format).await
+            }
+        };
+        println!("{}", serde_json::to_string(&summary)?);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_format() {
+        assert_eq!(
+            ImportFormat::detect(b"{\"items\": []}"),
+            Some(ImportFormat::NeoStumbler)
+        );
+        assert_eq!(
+            ImportFormat::detect(b"WigleWifi-1.4,appRelease=2.26,model=Pixel\nMAC,SSID\n"),
+            Some(ImportFormat::Wigle)
+        );
+        assert_eq!(
+            ImportFormat::detect(b"mcc,mnc,lac,cell_id,lat,lon,measured_at,net_type\n"),
+            Some(ImportFormat::TowerCollector)
+        );
+        assert_eq!(ImportFormat::detect(b"id;name\n"), None);
+    }
+
+    #[test]
+    fn parse_tower_collector_rows() {
+        let data = b"mcc,mnc,lac,cell_id,psc,asu,dbm,ta,lat,lon,accuracy,speed,bearing,altitude,measured_at,net_type,neighboring
+250,2,5016,40944044,,30,-67,1,56.011208,37.476509,5,,,150,2025-12-02 12:48:12,LTE,0
+250,2,5016,40944045,,,,,0.5,0.5,5,,,,2025-12-02 12:48:12,LTE,1
+250,2,5016,40944046,,,,,56.011208,37.476509,5,,,,2025-12-02 12:48:12,CDMA,0
+";
+        let mut summary = ImportSummary::new("tc.csv", Some(ImportFormat::TowerCollector));
+        let reports = parse_tower_collector(data, &mut summary);
+
+        assert_eq!(reports.len(), 1);
+        assert_eq!(summary.rejected, 2);
+        let cell = &reports[0].cell_towers.as_ref().unwrap()[0];
+        assert_eq!(cell.cell_id, Some(40944044));
+        assert_eq!(cell.serving, Some(1));
+        assert_eq!(cell.signal_strength, Some(-67.0));
+    }
+
+    #[test]
+    fn parse_wigle_rows() {
+        let data = b"WigleWifi-1.4,appRelease=2.26,model=Pixel,release=14,device=Pixel,display=,board=,brand=google
+MAC,SSID,AuthMode,FirstSeen,Channel,RSSI,CurrentLatitude,CurrentLongitude,AltitudeMeters,AccuracyMeters,Type
+5C:A6:E6:69:E5:EC,home,[WPA2-PSK-CCMP][ESS],2025-12-02 12:48:12,6,-81,56.011208,37.476509,150,5,WIFI
+250020_5016_40944044,,LTE,2025-12-02 12:48:12,0,-67,56.011208,37.476509,150,5,LTE
+00:11:22:33:44:55,,Misc,2025-12-02 12:48:12,0,-70,56.011208,37.476509,150,5,CDMA
+";
+        let mut summary = ImportSummary::new("wigle.csv", Some(ImportFormat::Wigle));
+        let reports = parse_wigle(data, &mut summary);
+
+        assert_eq!(reports.len(), 2);
+        assert_eq!(summary.rejected, 1);
+        assert_eq!(summary.errors, vec!["row 5: unsupported type: CDMA"]);
+
+        let wifi = &reports[0].wifi_access_points.as_ref().unwrap()[0];
+        assert_eq!(wifi.mac_address, "5c:a6:e6:69:e5:ec");
+        let cell = &reports[1].cell_towers.as_ref().unwrap()[0];
+        assert_eq!(cell.mobile_country_code, 250);
+        assert_eq!(cell.mobile_network_code, 20);
+        assert_eq!(cell.cell_id, Some(40944044));
+    }
+}
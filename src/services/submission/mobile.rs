@@ -0,0 +1,334 @@
+//! Classification of mobile access points: hotspots of phones, buses, taxis and scooters.
+//!
+//! The result is saved in `TransmitterLocation::mobile`, such access points are neither
+//! aggregated nor used for locate. A single bad GNSS fix or cell location must not exclude a
+//! fixed access point: it takes `mobile_observations` contradicting observations to mark the
+//! access point as mobile and as many consistent ones to clear the mark.
+
+use once_cell::sync::Lazy;
+
+use crate::{
+    CONFIG,
+    db::{
+        model::Transmitter,
+        pg::transmitter::{MobileReason, ObservedAt, TransmitterLocation},
+    },
+    services::submission::process::Observation,
+};
+
+static MOBILE_PARAMS: Lazy<MobileParams> = Lazy::new(MobileParams::from_config);
+
+#[derive(Debug, Clone)]
+pub struct MobileParams {
+    /// lowercase SSID substrings
+    pub ssid_patterns: Vec<String>,
+    /// milliseconds
+    pub window: i64,
+    /// meters
+    pub distance: f64,
+    /// meters
+    pub cell_distance: f64,
+    /// contradicting observations to consider the access point mobile
+    pub observations: u32,
+}
+
+impl MobileParams {
+    pub fn from_config() -> Self {
+        Self {
+            ssid_patterns: CONFIG
+                .locator
+                .mobile_ssid_patterns
+                .iter()
+                .map(|p| p.to_lowercase())
+                .collect(),
+            window: CONFIG.locator.mobile_window as i64 * 1000,
+            distance: CONFIG.locator.mobile_distance,
+            cell_distance: CONFIG.locator.mobile_cell_distance,
+            observations: CONFIG.locator.mobile_observations,
+        }
+    }
+
+    pub fn is_mobile_ssid(&self, ssid: &str) -> bool {
+        let ssid = ssid.to_lowercase();
+        self.ssid_patterns.iter().any(|p| ssid.contains(p))
+    }
+}
+
+/// Observation of the access point compared with the previous one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Evidence {
+    /// far from the previous position or cell within the window
+    Contradicting(MobileReason),
+    /// close to the previous position and cell within the window
+    Consistent,
+    /// nothing to compare with within the window
+    Unknown,
+}
+
+pub fn classify(tl: &mut TransmitterLocation, transmitter: &Transmitter, o: &Observation) {
+    classify_with(&MOBILE_PARAMS, tl, transmitter, o)
+}
+
+/// Update the mobile mark of the access point by the new observation
+pub fn classify_with(
+    params: &MobileParams,
+    tl: &mut TransmitterLocation,
+    transmitter: &Transmitter,
+    o: &Observation,
+) {
+    if !matches!(transmitter, Transmitter::Wifi { .. }) {
+        return;
+    }
+
+    match transmitter.ssid() {
+        Some(ssid) if params.is_mobile_ssid(ssid) => {
+            tl.mobile = Some(MobileReason::Ssid);
+            return;
+        }
+        // the hotspot is renamed, the movements decide
+        Some(_) if tl.mobile == Some(MobileReason::Ssid) => tl.mobile = None,
+        _ => {}
+    }
+
+    match evidence_with(params, tl, o) {
+        Evidence::Contradicting(reason) => {
+            tl.mobility = (tl.mobility + 1).min(params.observations);
+            if tl.mobility >= params.observations && tl.mobile.is_none() {
+                tl.mobile = Some(reason);
+            }
+        }
+        Evidence::Consistent => {
+            tl.mobility = tl.mobility.saturating_sub(1);
+            if tl.mobility == 0 && tl.mobile != Some(MobileReason::Ssid) {
+                tl.mobile = None;
+            }
+        }
+        Evidence::Unknown => {}
+    }
+}
+
+/// Check the new observation against the previous position and cell within the window
+pub fn evidence_with(params: &MobileParams, tl: &TransmitterLocation, o: &Observation) -> Evidence {
+    let mut evidence = Evidence::Unknown;
+
+    if let Some(last) = tl.last_observation.as_ref() {
+        match contradicts(params, last, &position(o), params.distance) {
+            Some(true) => return Evidence::Contradicting(MobileReason::Gnss),
+            Some(false) => evidence = Evidence::Consistent,
+            None => {}
+        }
+    }
+    if let Some(cell) = cell(o)
+        && let Some(last_cell) = tl.last_cell.as_ref()
+    {
+        match contradicts(params, last_cell, &cell, params.cell_distance) {
+            Some(true) => return Evidence::Contradicting(MobileReason::Cell),
+            Some(false) => evidence = Evidence::Consistent,
+            None => {}
+        }
+    }
+
+    evidence
+}
+
+/// Whether the observation is further than `distance` from the previous one, `None` outside of
+/// the window or at the same time, such observations are not independent
+fn contradicts(
+    params: &MobileParams,
+    last: &ObservedAt,
+    observed: &ObservedAt,
+    distance: f64,
+) -> Option<bool> {
+    let elapsed = (observed.timestamp - last.timestamp).abs();
+    if elapsed == 0 || elapsed > params.window {
+        return None;
+    }
+    Some(observed.distance(last) > distance)
+}
+
+fn position(o: &Observation) -> ObservedAt {
+    ObservedAt {
+        lat: o.lat,
+        lon: o.lon,
+        timestamp: o.timestamp,
+    }
+}
+
+fn cell(o: &Observation) -> Option<ObservedAt> {
+    let (lat, lon) = o.cell?;
+    Some(ObservedAt {
+        lat,
+        lon,
+        timestamp: o.timestamp,
+    })
+}
+
+pub fn track(tl: &mut TransmitterLocation, o: &Observation) {
+    track_with(&MOBILE_PARAMS, tl, o)
+}
+
+/// Remember the observation for the next classification. The contradicting observation doesn't
+/// replace the previous one, otherwise a single bad fix would contradict the next observation too.
+pub fn track_with(params: &MobileParams, tl: &mut TransmitterLocation, o: &Observation) {
+    let replaces = |last: Option<&ObservedAt>, observed: &ObservedAt, distance: f64| {
+        last.is_none_or(|l| {
+            observed.timestamp >= l.timestamp
+                && contradicts(params, l, observed, distance) != Some(true)
+        })
+    };
+
+    let observed = position(o);
+    if replaces(tl.last_observation.as_ref(), &observed, params.distance) {
+        tl.last_observation = Some(observed);
+    }
+    if let Some(cell) = cell(o)
+        && replaces(tl.last_cell.as_ref(), &cell, params.cell_distance)
+    {
+        tl.last_cell = Some(cell);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: i64 = 60_000;
+
+    fn params() -> MobileParams {
+        MobileParams {
+            ssid_patterns: vec!["androidap".to_string(), "iphone".to_string()],
+            window: 60 * MINUTE,
+            distance: 2000.0,
+            cell_distance: 30000.0,
+            observations: 3,
+        }
+    }
+
+    fn wifi(ssid: &str) -> Transmitter {
+        Transmitter::Wifi {
+            mac: "aa:bb:cc:dd:ee:ff".to_string(),
+            ssid: Some(ssid.to_string()),
+            signal_strength: None,
+            age: None,
+        }
+    }
+
+    fn observation(lat: f64, lon: f64, timestamp: i64, cell: Option<(f64, f64)>) -> Observation {
+        Observation {
+            lat,
+            lon,
+            accuracy: 10.0,
+            weight: 1.0,
+            rssi: -70.0,
+            timestamp,
+            cell,
+            lbs: None,
+        }
+    }
+
+    /// Classify the observation and remember it, as the processing of the reports does
+    fn observe(p: &MobileParams, tl: &mut TransmitterLocation, o: &Observation) {
+        classify_with(p, tl, &wifi("home"), o);
+        track_with(p, tl, o);
+    }
+
+    #[test]
+    fn mobile_by_ssid() {
+        let p = params();
+        let o = observation(55.75, 37.62, 0, None);
+        let mut tl = TransmitterLocation::fixture();
+
+        classify_with(&p, &mut tl, &wifi("home"), &o);
+        assert_eq!(tl.mobile, None);
+        classify_with(&p, &mut tl, &wifi("iPhone (Anna)"), &o);
+        assert_eq!(tl.mobile, Some(MobileReason::Ssid));
+
+        // the hotspot is renamed
+        classify_with(&p, &mut tl, &wifi("home"), &o);
+        assert_eq!(tl.mobile, None);
+    }
+
+    #[test]
+    fn mobile_by_gnss() {
+        let p = params();
+        let mut tl = TransmitterLocation::fixture();
+        observe(&p, &mut tl, &observation(55.75, 37.62, 0, None));
+
+        // ~5.5 km every 10 minutes
+        observe(&p, &mut tl, &observation(55.80, 37.62, 10 * MINUTE, None));
+        observe(&p, &mut tl, &observation(55.85, 37.62, 20 * MINUTE, None));
+        assert_eq!(tl.mobile, None);
+        observe(&p, &mut tl, &observation(55.90, 37.62, 30 * MINUTE, None));
+        assert_eq!(tl.mobile, Some(MobileReason::Gnss));
+
+        // the same distance a week later is a relocation
+        let mut tl = TransmitterLocation::fixture();
+        track_with(&p, &mut tl, &observation(55.75, 37.62, 0, None));
+        let o = observation(55.80, 37.62, 7 * 24 * 60 * MINUTE, None);
+        assert_eq!(evidence_with(&p, &tl, &o), Evidence::Unknown);
+    }
+
+    #[test]
+    fn mobile_by_single_fix() {
+        let p = params();
+        let mut tl = TransmitterLocation::fixture();
+        observe(&p, &mut tl, &observation(55.75, 37.62, 0, None));
+
+        // every third fix is bad, each is outweighed by the next consistent observation
+        for i in 1..=10 {
+            let lat = if i % 3 == 0 { 55.80 } else { 55.75 };
+            observe(&p, &mut tl, &observation(lat, 37.62, i * MINUTE, None));
+            assert!(tl.mobility <= 1);
+        }
+        assert_eq!(tl.mobile, None);
+    }
+
+    #[test]
+    fn mobile_by_cell() {
+        let p = params();
+        let mut tl = TransmitterLocation::fixture();
+        observe(
+            &p,
+            &mut tl,
+            &observation(55.75, 37.62, 0, Some((55.76, 37.63))),
+        );
+
+        // GNSS is the same, but the serving cell is ~110 km away a day later
+        let o = observation(55.75, 37.62, 24 * 60 * MINUTE, Some((56.75, 37.63)));
+        assert_eq!(evidence_with(&p, &tl, &o), Evidence::Unknown);
+
+        // and within the window
+        for (i, lat) in [56.75, 56.80, 56.85].into_iter().enumerate() {
+            let o = observation(
+                55.75,
+                37.62,
+                (i as i64 + 1) * 10 * MINUTE,
+                Some((lat, 37.63)),
+            );
+            assert_eq!(
+                evidence_with(&p, &tl, &o),
+                Evidence::Contradicting(MobileReason::Cell)
+            );
+            observe(&p, &mut tl, &o);
+        }
+        assert_eq!(tl.mobile, Some(MobileReason::Cell));
+    }
+
+    #[test]
+    fn mobile_cleared() {
+        let p = params();
+        let mut tl = TransmitterLocation::fixture();
+        // marked before the evidence was counted
+        tl.mobile = Some(MobileReason::Gnss);
+        tl.mobility = p.observations;
+        observe(&p, &mut tl, &observation(55.75, 37.62, 0, None));
+
+        for i in 1..p.observations as i64 {
+            observe(&p, &mut tl, &observation(55.75, 37.62, i * MINUTE, None));
+            assert_eq!(tl.mobile, Some(MobileReason::Gnss));
+        }
+        observe(&p, &mut tl, &observation(55.75, 37.62, 10 * MINUTE, None));
+        assert_eq!(tl.mobile, None);
+        assert_eq!(tl.mobility, 0);
+    }
+}
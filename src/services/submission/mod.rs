@@ -1,5 +1,13 @@
 pub mod cell;
+pub mod cell_wifi;
+pub mod cooccurrence;
 pub mod geosubmit;
 pub mod geosubmit_public;
+pub mod idempotency;
+pub mod import;
+pub mod mobile;
 pub mod process;
 pub mod report;
+pub mod reprocess;
+pub mod trust;
+pub mod virtual_ap;
@@ -21,7 +21,11 @@ use crate::{
     },
 };
 
-use super::report::extract;
+use super::{
+    cooccurrence, mobile,
+    report::{Position, extract},
+    trust, virtual_ap,
+};
 
 const DB_ERROR: &str = "db error";
 
@@ -105,84 +109,14 @@ pub async fn run(
             continue;
         };
 
+        let virtual_groups = virtual_ap::report_groups(&transmitters);
+        let macs = cooccurrence::report_macs(&transmitters);
         for transmitter in transmitters {
-            // If we can't get the signal strength, assume a low value
-            // to prevent accuracy from being overestimated.
-            // It also implies lower weight, so it can quickly be
-            // improved by other reports with more data
-            let rssi = transmitter.signal_strength().unwrap_or(DEFAULT_RSSI);
-
-            let distance_since_scan;
-            let lat;
-            let lon;
-            if let Some(speed) = pos.speed
-                && let Some(wifi_age) = transmitter.age()
-                && let Some(pos_age) = pos.age
-            {
-                distance_since_scan = speed * (wifi_age as f64 - pos_age as f64) / 1000.0;
-
-                // "Reversed dead reckoning": guess where the transmitter was
-                // scanned based on heading and distance since last scan
-                // Neostumbler reduced metadata feature impact this feature
-                // as speed is rounded to 2 m/s and heading to 30° (which
-                // means +/-15° of error, with +/- 7.5° on average)
-                // Here are values for a 80 km/h speed with 1 second age
-                // difference
-                // cos(15°) * 22.22 m = 5.75 m error at most
-                // cos(7.5°) * 22.22 m = 2.90 m on average
-                // This algorithm is still useful with this error as without
-                // it, the data point would be located even further away
-                // (22.22 m in the given example)
-                if let Some(heading) = pos.heading {
-                    let transmitter_scan_pos = Rhumb::destination(
-                        Point::new(pos.latitude, pos.longitude),
-                        heading,
-                        -distance_since_scan,
-                    );
-                    (lat, lon) = transmitter_scan_pos.x_y();
-                } else {
-                    lat = pos.latitude;
-                    lon = pos.longitude;
-                }
-            } else {
-                distance_since_scan = 0.0;
-                lat = pos.latitude;
-                lon = pos.longitude;
-            };
-
-            // Based on https://codeberg.org/Locator/Locator/issues/31#issuecomment-3098830
-            let distance_from_transmitter =
-                10_f64.powf((BASE_RSSI - rssi) / (10.0 * SIGNAL_DROP_COEFFICIENT));
-            let signal_weight = 10_f64.powf(rssi / (10.0 * SIGNAL_DROP_COEFFICIENT));
-
-            // The formula for age was found by quick trial and error. This
-            // one seems fine. Let's take an average of 1 second between
-            // wifi and pos age.
-            // 1 m/s (3.6 km/h, by foot) = 0.91
-            // 8.33 m/s (30 km/h, slow car zone in France) = 0.46
-            // 13.88 m/s (50 km/h, fast car speed in city) = 0.28
-            // 22.22 m/s (80 km/h, rural car speed) = 0.13
-            // 30.55 m/s (110 km/h, fast car road) = 0.06
-            // 36.11 m/s (130 km/h, fastest car roads) = 0.04
-            // When no data is available, this will be computed as if the
-            // report was done without moving (giving it an higher than
-            // average weight).
-            let age_weight = 10_f64.powf(-distance_since_scan.abs() / 25.0);
-
-            // Same, found through trial and error
-            // 1m = 0.79
-            // 5m = 0.31
-            // 10m = 0.1
-            // 20m = 0.01
-            let gnss_accuracy_weight = 10_f64.powf(-pos.accuracy.unwrap_or(10.0) / 10.0);
-
-            let weight = signal_weight * age_weight * gnss_accuracy_weight;
-            let accuracy = distance_from_transmitter + pos.accuracy.unwrap_or_default();
-
+            let o = observation(&pos, &transmitter);
             let transmitter_name = transmitter.to_string();
 
             if let Some((_, tl)) = modified.get_mut(&transmitter_name) {
-                tl.update(lat, lon, accuracy, weight, rssi);
+                update_location(tl, &transmitter, &o);
             } else if let Some(mut tl) = match transmitter.lookup(tx_t38_conn.clone()).await {
                 Err(_) => {
                     // skip the cell and ble
@@ -190,25 +124,15 @@ pub async fn run(
                 }
                 Ok(tl) => tl,
             } {
-                tl.update(lat, lon, accuracy, weight, rssi);
+                update_location(&mut tl, &transmitter, &o);
                 modified.insert(transmitter.to_string(), (transmitter, tl));
             } else {
-                modified.insert(
-                    transmitter.to_string(),
-                    (
-                        transmitter,
-                        TransmitterLocation::new(
-                            &transmitter_name,
-                            lat,
-                            lon,
-                            accuracy,
-                            weight,
-                            rssi,
-                        ),
-                    ),
-                );
+                let tl = new_location(&transmitter_name, &transmitter, &o);
+                modified.insert(transmitter.to_string(), (transmitter, tl));
             }
         }
+        virtual_ap::merge(&mut modified, &virtual_groups);
+        cooccurrence::record(&mut modified, &macs, pos.timestamp);
 
         let pos = LatLng::new(pos.latitude, pos.longitude)?;
         let h3 = pos.to_cell(Resolution::try_from(CONFIG.locator.h3_resolution)?);
@@ -242,11 +166,7 @@ pub async fn run(
                 .await?;
             }
 
-            Transmitter::Wifi {
-                mac: _,
-                signal_strength: _,
-                age: _,
-            } => {
+            Transmitter::Wifi { .. } => {
                 tl.measurements = None;
                 let collection = crate::constants::Collection::Wifi.as_ref();
                 crate::db::t38::set_wifi_one(tx_t38_conn.clone(), collection, &tl).await?;
@@ -283,6 +203,155 @@ pub async fn run(
     Ok(())
 }
 
+/// Estimated position of the transmitter and the weight of a single observation
+#[derive(Debug, Clone, Copy)]
+pub struct Observation {
+    pub lat: f64,
+    pub lon: f64,
+    pub accuracy: f64,
+    pub weight: f64,
+    pub rssi: f64,
+    /// measurement time, milliseconds
+    pub timestamp: i64,
+    /// location of the serving cell (lat, lon)
+    pub cell: Option<(f64, f64)>,
+    /// location of the access point by LBS (lat, lon)
+    pub lbs: Option<(f64, f64)>,
+}
+
+/// Create the aggregate of the transmitter from the first observation
+pub fn new_location(name: &str, transmitter: &Transmitter, o: &Observation) -> TransmitterLocation {
+    let mut tl = TransmitterLocation::new(
+        name,
+        o.lat,
+        o.lon,
+        o.accuracy,
+        o.weight,
+        o.rssi,
+        o.timestamp,
+    );
+    mobile::classify(&mut tl, transmitter, o);
+    mobile::track(&mut tl, o);
+    if tl.mobile.is_none() {
+        trust::assess(&mut tl, o);
+    }
+    tl
+}
+
+/// Add the observation to the aggregate and assess its trust, mobile transmitters keep the last known location
+pub fn update_location(tl: &mut TransmitterLocation, transmitter: &Transmitter, o: &Observation) {
+    let was_mobile = tl.mobile;
+    mobile::classify(tl, transmitter, o);
+    match (was_mobile, tl.mobile) {
+        (None, Some(reason)) => {
+            info!("transmitter {} is classified as mobile: {}", tl.mac, reason)
+        }
+        (Some(_), None) => info!("transmitter {} is no longer classified as mobile", tl.mac),
+        _ => {}
+    }
+    mobile::track(tl, o);
+    tl.seen(o.timestamp);
+
+    if tl.mobile.is_none() {
+        tl.update(
+            transmitter,
+            o.lat,
+            o.lon,
+            o.accuracy,
+            o.weight,
+            o.rssi,
+            o.timestamp,
+        );
+        trust::assess(tl, o);
+    }
+}
+
+pub fn observation(pos: &Position, transmitter: &Transmitter) -> Observation {
+    // If we can't get the signal strength, assume a low value
+    // to prevent accuracy from being overestimated.
+    // It also implies lower weight, so it can quickly be
+    // improved by other reports with more data
+    let rssi = transmitter.signal_strength().unwrap_or(DEFAULT_RSSI);
+
+    let distance_since_scan;
+    let lat;
+    let lon;
+    if let Some(speed) = pos.speed
+        && let Some(wifi_age) = transmitter.age()
+        && let Some(pos_age) = pos.age
+    {
+        distance_since_scan = speed * (wifi_age as f64 - pos_age as f64) / 1000.0;
+
+        // "Reversed dead reckoning": guess where the transmitter was
+        // scanned based on heading and distance since last scan
+        // Neostumbler reduced metadata feature impact this feature
+        // as speed is rounded to 2 m/s and heading to 30° (which
+        // means +/-15° of error, with +/- 7.5° on average)
+        // Here are values for a 80 km/h speed with 1 second age
+        // difference
+        // cos(15°) * 22.22 m = 5.75 m error at most
+        // cos(7.5°) * 22.22 m = 2.90 m on average
+        // This algorithm is still useful with this error as without
+        // it, the data point would be located even further away
+        // (22.22 m in the given example)
+        if let Some(heading) = pos.heading {
+            let transmitter_scan_pos = Rhumb::destination(
+                Point::new(pos.latitude, pos.longitude),
+                heading,
+                -distance_since_scan,
+            );
+            (lat, lon) = transmitter_scan_pos.x_y();
+        } else {
+            lat = pos.latitude;
+            lon = pos.longitude;
+        }
+    } else {
+        distance_since_scan = 0.0;
+        lat = pos.latitude;
+        lon = pos.longitude;
+    };
+
+    // Based on https://codeberg.org/Locator/Locator/issues/31#issuecomment-3098830
+    let distance_from_transmitter =
+        10_f64.powf((BASE_RSSI - rssi) / (10.0 * SIGNAL_DROP_COEFFICIENT));
+    let signal_weight = 10_f64.powf(rssi / (10.0 * SIGNAL_DROP_COEFFICIENT));
+
+    // The formula for age was found by quick trial and error. This
+    // one seems fine. Let's take an average of 1 second between
+    // wifi and pos age.
+    // 1 m/s (3.6 km/h, by foot) = 0.91
+    // 8.33 m/s (30 km/h, slow car zone in France) = 0.46
+    // 13.88 m/s (50 km/h, fast car speed in city) = 0.28
+    // 22.22 m/s (80 km/h, rural car speed) = 0.13
+    // 30.55 m/s (110 km/h, fast car road) = 0.06
+    // 36.11 m/s (130 km/h, fastest car roads) = 0.04
+    // When no data is available, this will be computed as if the
+    // report was done without moving (giving it an higher than
+    // average weight).
+    let age_weight = 10_f64.powf(-distance_since_scan.abs() / 25.0);
+
+    // Same, found through trial and error
+    // 1m = 0.79
+    // 5m = 0.31
+    // 10m = 0.1
+    // 20m = 0.01
+    let gnss_accuracy_weight = 10_f64.powf(-pos.accuracy.unwrap_or(10.0) / 10.0);
+
+    let weight = signal_weight * age_weight * gnss_accuracy_weight;
+    let accuracy = distance_from_transmitter + pos.accuracy.unwrap_or_default();
+
+    Observation {
+        lat,
+        lon,
+        accuracy,
+        weight,
+        rssi,
+        timestamp: pos.timestamp,
+        cell: pos.cell_location,
+        lbs: pos.lbs_locations.get(&transmitter.to_string()).copied(),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::constants::SIGNAL_DROP_COEFFICIENT;
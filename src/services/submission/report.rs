@@ -14,12 +14,16 @@ use crate::{
     CONFIG,
     constants::{
         Collection, DEFAULT_RSSI, GPS_VALID_DISTANCE_BY_CELL, GPS_VALID_DISTANCE_BY_WIFI,
-        MAX_DISTANCE_REPORT_LBS, WIFI_SSID_IGNORED,
+        MAX_DISTANCE_REPORT_LBS,
     },
     db::{
         blobasaur::set_ba_lbs_yandex_wifi_one,
         model::{CellRadio, Transmitter},
-        pg::{get_reports_by_range_id, get_required_reports, transmitter::TransmitterLocation},
+        pg::{
+            get_required_reports,
+            query::{self, DEFAULT_SEARCH_LIMIT, ReportCursor, ReportFilter},
+            transmitter::TransmitterLocation,
+        },
         t38::{
             fget_wifi_many_from_pipeline, set_yandex_lbs_wifi_one,
             track::{
@@ -30,21 +34,25 @@ use crate::{
     error::ApiError,
     lbs::{
         altergeo::altergeo_lbs_request,
+        enrichment,
         http_client::HttpClient,
-        model::{self, create_cell_measurement},
-        yandex::{
-            cell::yandex_lbs_request_by_individual_cell,
-            wifi::{
-                WifiMeasurement, YandexLbsResponse, YandexLocation, YandexPoint,
-                yandex_lbs_request_by_individual_wifi,
-            },
-        },
+        model::{self, CellMeasurement, create_cell_measurement},
+        provider::{self, LbsContext, Priority},
+        yandex::wifi::{WifiMeasurement, YandexLbsResponse, YandexLocation, YandexPoint},
     },
     services::{
-        helper::{self, macaddr::MacAddr},
-        locate::dbscan::{Point, Proximity, distance_factor_cell},
+        helper::{
+            self,
+            macaddr::MacAddr,
+            oui,
+            ssid::{self, SsidVerdict},
+        },
+        locate::{
+            cell_geometry::{self, CellConstraint},
+            dbscan::{Point, Proximity, distance_factor_cell},
+        },
         rate_limiter::RateLimitersApp,
-        submission::process::run,
+        submission::{cell_wifi, cooccurrence::report_macs, process::run},
     },
     tasks::{
         blobasaur::BAConnectionManageMessage, t38::T38ConnectionManageMessage,
@@ -68,7 +76,7 @@ pub struct Report {
     pub bluetooth_beacons: Option<Vec<Bluetooth>>,
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Deserialize, Debug, Default, Clone)]
 pub struct GeoFence {
     pub lat_min: f64,
     pub lat_max: f64,
@@ -82,6 +90,35 @@ impl GeoFence {
     }
 }
 
+// command line representation: lat_min,lat_max,lon_min,lon_max
+impl FromStr for GeoFence {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<f64>())
+            .collect::<Result<Vec<f64>, _>>()
+            .map_err(|e| format!("invalid geofence '{}': {}", s, e))?;
+        if values.len() != 4 {
+            return Err(format!(
+                "invalid geofence '{}': expected lat_min,lat_max,lon_min,lon_max",
+                s
+            ));
+        }
+        let gf = GeoFence {
+            lat_min: values[0],
+            lat_max: values[1],
+            lon_min: values[2],
+            lon_max: values[3],
+        };
+        if !gf.validate() {
+            return Err(format!("invalid geofence '{}': min is greater than max", s));
+        }
+        Ok(gf)
+    }
+}
+
 impl Display for GeoFence {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -161,63 +198,86 @@ pub async fn extract_report(
     }
 }
 
-// scan reports by condition
-#[post("/scan_reports")]
-pub async fn scan_reports(
-    data: web::Json<RequredReport>,
+#[derive(Deserialize)]
+pub struct SearchReport {
+    #[serde(flatten)]
+    pub filter: ReportFilter,
+    /// cursor returned in the `X-Next-Cursor` header of the previous page
+    pub cursor: Option<String>,
+    pub limit: Option<u32>,
+}
+
+// search reports by filters, reports are returned as NDJSON
+#[post("/search_reports")]
+pub async fn search_reports(
+    data: web::Json<SearchReport>,
     pool_tp: web::Data<deadpool_postgres::Pool>,
     _req: HttpRequest,
 ) -> actix_web::Result<impl Responder> {
     let sr = data.into_inner();
-    let mapper = pool_tp
+
+    let cursor = match sr.cursor.as_deref().map(|c| c.parse::<ReportCursor>()) {
+        None => None,
+        Some(Ok(c)) => Some(c),
+        Some(Err(e)) => {
+            return Ok(HttpResponse::BadRequest().json(json!(
+                {
+                    "error": {
+                        "domain": "report",
+                        "reason": "Invalid cursor",
+                        "message": e.to_string(),
+                        "code": 400,
+                    }
+                }
+            )));
+        }
+    };
+
+    let mut mapper = pool_tp
         .get()
         .await
         .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
-    let client = mapper.client();
+    let transaction = mapper
+        .build_transaction()
+        .read_only(true)
+        .start()
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
 
-    match get_reports_by_range_id(client, &sr.ids).await {
-        Err(e) => Ok(HttpResponse::new(StatusCode::BAD_REQUEST)),
-        Ok(reports) => {
-            let mut rs = Vec::with_capacity(reports.len());
-            reports.into_iter().for_each(|r| {
-                if let Ok(r) = serde_json::from_slice::<'_, Report>(&r.raw) {
-                    rs.push(r);
-                }
-            });
+    let (records, next_cursor) = query::search_reports(
+        &transaction,
+        &sr.filter,
+        cursor.as_ref(),
+        sr.limit.unwrap_or(DEFAULT_SEARCH_LIMIT),
+    )
+    .await
+    .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
 
-            let mut c = 0;
-            rs.iter().for_each(|r| {
-                // invalid rxLev
-                /*
-                    if let Some(cell) = r.cell.as_ref()
-                        && let Some(gsm) = cell.gsm.as_ref()
-                    {
-                        if !gsm.is_empty() {
-                            if gsm[0].rxlev >= 0.0 {
-                                c += 1;
-                            }
-                        }
-                    }
-                */
-
-                // invalid RSSI
-                if let Some(waps) = r.wifi_access_points.as_ref() {
-                    waps.iter().for_each(|wap| {
-                        if let Some(rssi) = wap.signal_strength
-                            && rssi >= 0.0
-                        {
-                            c += 1;
-                        }
-                    });
-                }
-            });
+    transaction
+        .commit()
+        .await
+        .map_err(|e| ApiError::DatabaseError(e.to_string()))?;
 
-            Ok(HttpResponse::Ok().json(json!({
-                    // "count_reports_where_rxlev_greater_than_0": c
-                    "count_wifi_where_rssi_greater_than_0": c
-            })))
-        }
+    let lines = records.into_iter().map(|r| {
+        let report = serde_json::from_slice::<serde_json::Value>(&r.raw).unwrap_or_default();
+        let mut line = serde_json::to_vec(&json!({
+            "id": r.id,
+            "submitted_at": r.submitted_at,
+            "processed_at": r.processed_at,
+            "processing_error": r.processing_error,
+            "user_agent": r.user_agent,
+            "report": report,
+        }))?;
+        line.push(b'\n');
+        Ok::<_, actix_web::Error>(web::Bytes::from(line))
+    });
+
+    let mut response = HttpResponse::Ok();
+    response.content_type("application/x-ndjson");
+    if let Some(c) = next_cursor {
+        response.insert_header(("X-Next-Cursor", c.to_string()));
     }
+    Ok(response.streaming(futures::stream::iter(lines)))
 }
 
 pub fn timestamp_from_utc_str<'de, D>(deserializer: D) -> Result<i64, D::Error>
@@ -244,6 +304,15 @@ pub struct Position {
     pub age: Option<i32>,
     pub accuracy: Option<f64>,
     pub heading: Option<f64>,
+    /// measurement time of the report, milliseconds
+    #[serde(skip)]
+    pub timestamp: i64,
+    /// location of the serving cell by LBS (lat, lon)
+    #[serde(skip)]
+    pub cell_location: Option<(f64, f64)>,
+    /// locations of the access points by LBS (lat, lon), keyed by MAC address
+    #[serde(skip)]
+    pub lbs_locations: HashMap<String, (f64, f64)>,
 }
 
 /// Serde representation to deserialize a cell tower in a report
@@ -344,6 +413,16 @@ pub struct Wifi {
     pub signal_strength: Option<f64>,
 }
 
+impl ssid::AccessPoint for Wifi {
+    fn mac(&self) -> &str {
+        &self.mac_address
+    }
+
+    fn ssid(&self) -> Option<&str> {
+        self.ssid.as_deref()
+    }
+}
+
 impl Wifi {
     fn signal_strength(&self) -> Option<f64> {
         if let Some(signal_strength) = self.signal_strength {
@@ -352,15 +431,8 @@ impl Wifi {
         None
     }
 
-    async fn should_be_ignored(
-        &self,
-        report: &Report,
-        yandex_lbs_responses: &HashMap<String, Option<YandexLbsResponse>>,
-        yandex_client: HttpClient,
-        tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
-        tx_ba_conn: flume::Sender<BAConnectionManageMessage>,
-        ylrs_cell_opt: Option<&HashMap<String, Option<YandexLbsResponse>>>,
-    ) -> bool {
+    /// Rules of ignoring that do not require LBS requests
+    fn is_ignored_locally(&self) -> bool {
         if CONFIG.locator.laa_filter {
             // check mac address (LAA)
             if let Ok(m) = MacAddr::from_str(&self.mac_address) {
@@ -371,12 +443,22 @@ impl Wifi {
             }
         }
 
-        // reject based on ssid
-        if let Some(ssid) = self.ssid.as_ref()
-            && WIFI_SSID_IGNORED
-                .iter()
-                .any(|ssid_ignored| ssid.to_lowercase().contains(ssid_ignored))
-        {
+        // reject based on ssid and vendor
+        ssid::verdict(self.ssid.as_deref()) != SsidVerdict::Accept
+            || oui::is_excluded(&self.mac_address)
+    }
+
+    async fn should_be_ignored(
+        &self,
+        report: &Report,
+        yandex_lbs_responses: &HashMap<String, Option<YandexLbsResponse>>,
+        yandex_client: HttpClient,
+        tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+        tx_ba_conn: flume::Sender<BAConnectionManageMessage>,
+        ylrs_cell_opt: Option<&HashMap<String, Option<YandexLbsResponse>>>,
+        constraints: &[CellConstraint],
+    ) -> bool {
+        if self.is_ignored_locally() {
             return true;
         }
 
@@ -389,6 +471,7 @@ impl Wifi {
         let ignore_by_cell = is_ignore_by_cell(
             yandex_lbs_responses,
             ylrs_cell_opt,
+            constraints,
             report,
             p_origin,
             &self.mac_address,
@@ -557,6 +640,19 @@ pub async fn extract_from_report(
     tx_yandex_api: flume::Sender<YandexApiMessage>,
     rl_app: RateLimitersApp,
 ) -> Result<(Position, Vec<Transmitter>), ApiError> {
+    report.position.timestamp = report.timestamp;
+
+    // Timing Advance and sectors are lost with the cell measurements
+    let geometries = report
+        .cell
+        .as_ref()
+        .map(cell_geometry::geometries)
+        .unwrap_or_default();
+    let cms = report
+        .cell
+        .as_ref()
+        .map(create_cell_measurement)
+        .unwrap_or_default();
     let ylrs_cell = match extract_cell(
         report.cell.take(),
         tx_t38_conn.clone(),
@@ -573,10 +669,13 @@ pub async fn extract_from_report(
         }
         Ok(ylrs) => ylrs,
     };
+    report.position.cell_location = serving_cell_location(&cms, &ylrs_cell);
+    let constraints = cell_geometry::constraints(&geometries, &ylrs_cell);
 
     let mut transmitters = Vec::new();
 
-    if let Some(wifi_vec) = report.wifi_access_points.take() {
+    if let Some(mut wifi_vec) = report.wifi_access_points.take() {
+        ssid::drop_opted_out([&mut wifi_vec], &tx_t38_conn, &tx_ba_conn).await;
         // let mut macs = Vec::new();
         let mut wms = Vec::with_capacity(wifi_vec.len());
         wifi_vec.iter().for_each(|m| {
@@ -592,25 +691,45 @@ pub async fn extract_from_report(
             lon: report.position.longitude,
         };
 
-        let yandex_lbs_responses = match yandex_lbs_request_by_individual_wifi(
-            tx_t38_conn.clone(),
-            tx_ba_conn.clone(),
-            &wms,
-            yandex_client.clone(),
-            tx_yandex_api,
-            rl_app,
-        )
-        .await
-        {
-            Err(e) => {
-                error!("Yandex LBS request by individual access points: {e}");
-                return Err(e);
-            }
-            Ok(map) => {
-                // when validating input data, we do not perform Yandex outlier's analysis
-                map
+        let yandex_lbs_responses = if CONFIG.enrichment.enabled {
+            // the unknown access points are requested in the background
+            enrichment::request_wifi(tx_t38_conn.clone(), &wms).await
+        } else {
+            match provider::request_wifi(
+                &LbsContext::new(
+                    tx_t38_conn.clone(),
+                    tx_ba_conn.clone(),
+                    yandex_client.clone(),
+                    tx_yandex_api,
+                    rl_app,
+                )
+                .with_priority(Priority::Enrichment),
+                &wms,
+            )
+            .await
+            {
+                Err(e) => {
+                    error!("Yandex LBS request by individual access points: {e}");
+                    return Err(e);
+                }
+                Ok(map) => {
+                    // when validating input data, we do not perform Yandex outlier's analysis
+                    map
+                }
             }
         };
+        // used to assess the trust of our locations
+        report.position.lbs_locations = yandex_lbs_responses
+            .iter()
+            .filter_map(|(mac, ylr)| {
+                ylr.as_ref().map(|ylr| {
+                    (
+                        mac.clone(),
+                        (ylr.location.point.lat, ylr.location.point.lon),
+                    )
+                })
+            })
+            .collect();
 
         let mut wifi_valid = vec![];
         for wifi in wifi_vec {
@@ -623,6 +742,7 @@ pub async fn extract_from_report(
                     tx_t38_conn.clone(),
                     tx_ba_conn.clone(),
                     Some(&ylrs_cell),
+                    &constraints,
                 )
                 .await
             {
@@ -638,11 +758,21 @@ pub async fn extract_from_report(
             // register all networks including hidden
             transmitters.push(Transmitter::Wifi {
                 mac: wifi.mac_address.clone(),
+                ssid: wifi.ssid.clone(),
                 signal_strength: wifi.signal_strength(),
                 age: wifi.age.map(Into::into),
             });
         }
 
+        // the serving cell is located by the positions of the valid access points
+        cell_wifi::record(
+            tx_t38_conn.clone(),
+            ylrs_cell.keys(),
+            &report.position,
+            &report_macs(&transmitters),
+        )
+        .await;
+
         // save only valid points in the track
         if let Err(e) =
             process_wifi_track(wifi_valid, tx_t38_conn, &report, &yandex_lbs_responses).await
@@ -657,22 +787,23 @@ pub async fn extract_from_report(
 async fn is_ignore_by_cell(
     yandex_lbs_responses: &HashMap<String, Option<YandexLbsResponse>>,
     ylrs_cell_opt: Option<&HashMap<String, Option<YandexLbsResponse>>>,
+    constraints: &[CellConstraint],
     report: &Report,
     p_origin: Point,
     mac: &str,
     tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
 ) -> Option<bool> {
     let collection = Collection::LbsYandexWifi.as_ref();
+    // the position is outside the Timing Advance ring or the sector of the serving cell
+    if !cell_geometry::satisfies(constraints, p_origin.lat, p_origin.lon, 0.0) {
+        return Some(true);
+    }
     if let Some(ylrs_cell) = ylrs_cell_opt {
         let mut distance_cell_point = None;
         let dfc = distance_factor_cell(ylrs_cell);
 
-        if let Some(Some(ylr_cell)) = ylrs_cell.values().next() {
-            let p_cell = Point {
-                id: 2,
-                lat: ylr_cell.location.point.lat,
-                lon: ylr_cell.location.point.lon,
-            };
+        if let Some((lat, lon)) = report.position.cell_location {
+            let p_cell = Point { id: 2, lat, lon };
             distance_cell_point = Some(p_cell.distance(&p_origin));
         }
 
@@ -725,32 +856,37 @@ async fn is_gps_valid_relative_wifi(
     Ok(valid_gps)
 }
 
+/// Location of the serving cell: the located cell with the strongest signal, the lowest code on
+/// a tie, so that the same report always gives the same cell
+pub fn serving_cell_location(
+    cms: &[CellMeasurement],
+    ylrs_cell: &HashMap<String, Option<YandexLbsResponse>>,
+) -> Option<(f64, f64)> {
+    cms.iter()
+        .filter_map(|cm| {
+            let code = cm.code();
+            let ylr = ylrs_cell.get(&code)?.as_ref()?;
+            Some((cm.signal_strength, code, ylr))
+        })
+        .max_by(|(s1, c1, _), (s2, c2, _)| s1.total_cmp(s2).then_with(|| c2.cmp(c1)))
+        .map(|(_, _, ylr)| (ylr.location.point.lat, ylr.location.point.lon))
+}
+
 pub fn is_gps_valid_relative_cell(
-    ylrs_cell_opt: Option<&HashMap<String, Option<YandexLbsResponse>>>,
+    cell_location: Option<(f64, f64)>,
+    constraints: &[CellConstraint],
     p_origin: Point,
 ) -> Option<bool> {
-    if let Some(ylrs_cell) = ylrs_cell_opt {
-        let mut distance_cell_point = None;
-
-        if let Some(Some(ylr_cell)) = ylrs_cell.values().next() {
-            let p_cell = Point {
-                id: 2,
-                lat: ylr_cell.location.point.lat,
-                lon: ylr_cell.location.point.lon,
-            };
-            distance_cell_point = Some(p_cell.distance(&p_origin));
-        }
-
-        if let Some(d) = distance_cell_point {
-            if d <= GPS_VALID_DISTANCE_BY_CELL {
-                // GPS is valid
-                return Some(true);
-            } else {
-                return Some(false);
-            }
-        }
+    let (lat, lon) = cell_location?;
+    let p_cell = Point { id: 2, lat, lon };
+    if p_cell.distance(&p_origin) <= GPS_VALID_DISTANCE_BY_CELL
+        && cell_geometry::satisfies(constraints, p_origin.lat, p_origin.lon, 0.0)
+    {
+        // GPS is valid
+        Some(true)
+    } else {
+        Some(false)
     }
-    None
 }
 
 pub async fn extract_cell(
@@ -765,15 +901,24 @@ pub async fn extract_cell(
     if let Some(cell) = cell_opt {
         cms = create_cell_measurement(&cell);
     }
-    yandex_lbs_request_by_individual_cell(
-        tx_t38_conn,
-        tx_ba_conn,
-        cms,
-        yandex_client,
-        tx_yandex_api,
-        rl_app,
-    )
-    .await
+    let ylrs = if CONFIG.enrichment.enabled {
+        // the unknown cells are requested in the background
+        Ok(enrichment::request_cell(tx_t38_conn.clone(), &cms).await)
+    } else {
+        provider::request_cell(
+            &LbsContext::new(
+                tx_t38_conn.clone(),
+                tx_ba_conn,
+                yandex_client,
+                tx_yandex_api,
+                rl_app,
+            )
+            .with_priority(Priority::Enrichment),
+            &cms,
+        )
+        .await
+    };
+    cell_wifi::complete(tx_t38_conn, &cms, ylrs).await
 }
 
 async fn process_wifi_track(
@@ -845,17 +990,8 @@ async fn process_wifi_track(
     Ok(())
 }
 
-/// Extract the position and the submitted transmitters from the raw data
-pub async fn _extract_all_transmitter_types(
-    raw: &[u8],
-    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
-    tx_ba_conn: flume::Sender<BAConnectionManageMessage>,
-    yandex_client: HttpClient,
-    tx_yandex_api: flume::Sender<YandexApiMessage>,
-    rl_app: RateLimitersApp,
-) -> Result<(Position, Vec<Transmitter>), ApiError> {
-    let report: Report = serde_json::from_slice(raw)?;
-
+/// Cell towers of the report suitable for the aggregation
+fn cell_transmitters(report: &Report) -> Vec<Transmitter> {
     let mut transmitters = Vec::new();
 
     for cell in report.cell_towers.as_ref().unwrap_or(&vec![]) {
@@ -890,6 +1026,62 @@ pub async fn _extract_all_transmitter_types(
         })
     }
 
+    transmitters
+}
+
+/// Report prepared for reprocessing
+pub struct OfflineReport {
+    pub position: Position,
+    pub transmitters: Vec<Transmitter>,
+    /// access points with `_nomap` or `_optout` in the SSID
+    pub opted_out: Vec<String>,
+}
+
+/// Extract the position and the submitted transmitters without LBS requests.
+/// Used for reprocessing the reports that have already passed the validation.
+pub fn extract_offline(raw: &[u8]) -> Result<OfflineReport, ApiError> {
+    let mut report: Report = serde_json::from_slice(raw)?;
+    report.position.timestamp = report.timestamp;
+
+    let mut transmitters = cell_transmitters(&report);
+    let mut opted_out = Vec::new();
+
+    for wifi in report.wifi_access_points.as_ref().unwrap_or(&vec![]) {
+        if ssid::is_opted_out(wifi.ssid.as_deref()) {
+            opted_out.push(wifi.mac_address.clone());
+            continue;
+        }
+        if wifi.is_ignored_locally() || should_be_ignored(&report.position, wifi.age) {
+            continue;
+        }
+        transmitters.push(Transmitter::Wifi {
+            mac: wifi.mac_address.clone(),
+            ssid: wifi.ssid.clone(),
+            signal_strength: wifi.signal_strength(),
+            age: wifi.age.map(Into::into),
+        });
+    }
+
+    Ok(OfflineReport {
+        position: report.position,
+        transmitters,
+        opted_out,
+    })
+}
+
+/// Extract the position and the submitted transmitters from the raw data
+pub async fn _extract_all_transmitter_types(
+    raw: &[u8],
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    tx_ba_conn: flume::Sender<BAConnectionManageMessage>,
+    yandex_client: HttpClient,
+    tx_yandex_api: flume::Sender<YandexApiMessage>,
+    rl_app: RateLimitersApp,
+) -> Result<(Position, Vec<Transmitter>), ApiError> {
+    let report: Report = serde_json::from_slice(raw)?;
+
+    let mut transmitters = cell_transmitters(&report);
+
     if let Some(wifi_vec) = &report.wifi_access_points {
         let mut wms = Vec::with_capacity(wifi_vec.len());
         wifi_vec.iter().for_each(|m| {
@@ -900,13 +1092,16 @@ pub async fn _extract_all_transmitter_types(
         });
 
         let mut yandex_lbs_responses = HashMap::new();
-        match yandex_lbs_request_by_individual_wifi(
-            tx_t38_conn.clone(),
-            tx_ba_conn.clone(),
+        match provider::request_wifi(
+            &LbsContext::new(
+                tx_t38_conn.clone(),
+                tx_ba_conn.clone(),
+                yandex_client.clone(),
+                tx_yandex_api,
+                rl_app,
+            )
+            .with_priority(Priority::Enrichment),
             &wms,
-            yandex_client.clone(),
-            tx_yandex_api,
-            rl_app,
         )
         .await
         {
@@ -932,6 +1127,7 @@ pub async fn _extract_all_transmitter_types(
                     tx_t38_conn.clone(),
                     tx_ba_conn.clone(),
                     None,
+                    &[],
                 )
                 .await
             {
@@ -943,6 +1139,7 @@ pub async fn _extract_all_transmitter_types(
             // register all networks including hidden
             transmitters.push(Transmitter::Wifi {
                 mac: wifi.mac_address.clone(),
+                ssid: wifi.ssid.clone(),
                 signal_strength: wifi.signal_strength(),
                 age: wifi.age.map(Into::into),
             });
@@ -1061,4 +1258,44 @@ mod tests {
 
         // TODO: Test 5G/NR
     }
+
+    #[test]
+    fn serving_cell_is_strongest() {
+        let cell = |cid: u64, signal_strength: f64| CellMeasurement {
+            radio_type: "lte".to_string(),
+            mcc: 250,
+            mnc: 1,
+            lac: 7700,
+            cid,
+            signal_strength,
+        };
+        let located = |lat: f64| {
+            Some(YandexLbsResponse {
+                location: YandexLocation {
+                    point: YandexPoint { lat, lon: 37.62 },
+                    accuracy: 500.0,
+                },
+            })
+        };
+        let cms = vec![
+            cell(1, -100.0),
+            cell(2, -80.0),
+            cell(3, -70.0),
+            cell(4, -80.0),
+        ];
+        let mut ylrs_cell = HashMap::from([
+            (cms[0].code(), located(55.1)),
+            (cms[1].code(), located(55.2)),
+            // the strongest cell is not located
+            (cms[2].code(), None),
+            (cms[3].code(), located(55.4)),
+        ]);
+        // the lowest code of the equally strong cells
+        assert_eq!(serving_cell_location(&cms, &ylrs_cell), Some((55.2, 37.62)));
+
+        ylrs_cell.remove(&cms[1].code());
+        ylrs_cell.remove(&cms[3].code());
+        assert_eq!(serving_cell_location(&cms, &ylrs_cell), Some((55.1, 37.62)));
+        assert_eq!(serving_cell_location(&cms, &HashMap::new()), None);
+    }
 }
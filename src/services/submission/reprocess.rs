@@ -0,0 +1,398 @@
+//! Rebuild of the transmitter aggregates from the retained reports.
+//!
+//! Wi-Fi aggregates are written into the shadow Tile38 collection. After the confirmation the
+//! other access points are copied from the live collection into the shadow one, which replaces
+//! the live collection by `RENAME`. Cells are not rebuilt: the report processing does not
+//! aggregate them either.
+//!
+//! The rebuilt aggregates replace the live ones entirely: an access point observed in the range
+//! keeps only the observations from the range, the earlier ones are lost. The observations of the
+//! rebuilt access points processed after the replay are lost as well, the report processing
+//! should be stopped while reprocessing.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    io::{self, BufRead, Write},
+};
+
+use anyhow::Result;
+use chrono::NaiveDate;
+use clap::Args;
+use geo::{Distance, Haversine, Point};
+use log::{error, info};
+use serde::Serialize;
+use tokio_postgres::GenericClient;
+
+use crate::{
+    CONFIG,
+    constants::Collection,
+    db::{
+        blobasaur::set_ba_wifi_one,
+        model::Transmitter,
+        pg::{
+            get_processed_reports_by_partition, get_report_attached_partitions,
+            transmitter::TransmitterLocation,
+        },
+        t38::{
+            drop_collection, fget_wifi_many_from_pipeline, rename_collection, scan::scan_ids_page,
+            set_wifi_many,
+        },
+    },
+    services::{
+        helper::ssid,
+        submission::{
+            cooccurrence::{record, report_macs},
+            process::{Observation, new_location, observation, update_location},
+            report::{GeoFence, extract_offline},
+            virtual_ap::{merge, report_groups},
+        },
+    },
+    tasks::{blobasaur::BAConnectionManageMessage, t38::T38ConnectionManageMessage},
+};
+
+// number of reports read from the partition at a time
+const REPORTS_PAGE_SIZE: u32 = 10_000;
+// number of access points written to Tile38 in one pipeline
+const T38_BATCH_SIZE: usize = 1_000;
+// number of the most moved access points shown in the summary
+const MAX_MOVED_SHOWN: usize = 20;
+
+#[derive(Debug, Args)]
+pub struct ReprocessArgs {
+    /// first day of the reports, inclusive (YYYY-MM-DD)
+    #[arg(long)]
+    pub from: NaiveDate,
+    /// last day of the reports, inclusive (YYYY-MM-DD)
+    #[arg(long)]
+    pub to: NaiveDate,
+    /// reprocess only the reports inside lat_min,lat_max,lon_min,lon_max
+    #[arg(long, allow_hyphen_values = true)]
+    pub geofence: Option<GeoFence>,
+    /// access points shifted further than this distance are reported as moved, meters
+    #[arg(long, default_value_t = 100.0)]
+    pub moved: f64,
+    /// swap the shadow collection without confirmation
+    #[arg(long)]
+    pub yes: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MovedTransmitter {
+    pub mac: String,
+    pub shift: f64,
+}
+
+/// Difference between the rebuilt and the live Wi-Fi aggregates
+#[derive(Debug, Default, Serialize)]
+pub struct WifiDiff {
+    pub rebuilt: usize,
+    pub new: usize,
+    pub moved: usize,
+    pub max_shift: f64,
+    pub most_moved: Vec<MovedTransmitter>,
+}
+
+impl WifiDiff {
+    fn add(
+        &mut self,
+        rebuilt: &TransmitterLocation,
+        live: Option<&TransmitterLocation>,
+        threshold: f64,
+    ) {
+        self.rebuilt += 1;
+        let Some(live) = live else {
+            self.new += 1;
+            return;
+        };
+
+        let shift = Haversine::distance(
+            Point::new(live.lon, live.lat),
+            Point::new(rebuilt.lon, rebuilt.lat),
+        );
+        if shift > self.max_shift {
+            self.max_shift = shift;
+        }
+        if shift > threshold {
+            self.moved += 1;
+            self.most_moved.push(MovedTransmitter {
+                mac: rebuilt.mac.clone(),
+                shift,
+            });
+            self.most_moved.sort_by(|a, b| b.shift.total_cmp(&a.shift));
+            self.most_moved.truncate(MAX_MOVED_SHOWN);
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ReprocessSummary {
+    pub partitions: Vec<String>,
+    pub reports: usize,
+    pub rejected_reports: usize,
+    pub wifi: WifiDiff,
+    pub opted_out: usize,
+    pub shadow_collection: String,
+}
+
+fn aggregate(
+    aggregates: &mut BTreeMap<String, (Transmitter, TransmitterLocation)>,
+    transmitter: Transmitter,
+    o: Observation,
+) {
+    let name = transmitter.to_string();
+    if let Some((_, tl)) = aggregates.get_mut(&name) {
+        update_location(tl, &transmitter, &o);
+    } else {
+        let tl = new_location(&name, &transmitter, &o);
+        aggregates.insert(name, (transmitter, tl));
+    }
+}
+
+fn confirm(summary: &ReprocessSummary) -> Result<bool> {
+    print!(
+        "Replace collection '{}' by '{}'? [y/N] ",
+        Collection::Wifi.as_ref(),
+        summary.shadow_collection
+    );
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Copy the access points of the live collection which are neither rebuilt nor opted out
+async fn copy_live(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    live: &str,
+    shadow: &str,
+    rebuilt: &HashSet<&str>,
+    opted_out: &HashSet<String>,
+) -> Result<usize> {
+    let mut copied = 0;
+    let mut cursor = 0;
+    loop {
+        let (next, ids) =
+            scan_ids_page(tx_t38_conn.clone(), live, cursor, T38_BATCH_SIZE as u64).await?;
+        let macs = ids
+            .iter()
+            .map(String::as_str)
+            .filter(|mac| !rebuilt.contains(mac) && !opted_out.contains(*mac))
+            .collect::<Vec<&str>>();
+        if !macs.is_empty() {
+            let tls = fget_wifi_many_from_pipeline::<TransmitterLocation>(
+                tx_t38_conn.clone(),
+                live,
+                &macs,
+            )
+            .await?
+            .into_iter()
+            .flatten()
+            .collect::<Vec<TransmitterLocation>>();
+            copied += tls.len();
+            set_wifi_many(tx_t38_conn.clone(), shadow, &tls).await?;
+        }
+        if next == 0 {
+            break;
+        }
+        cursor = next;
+    }
+    Ok(copied)
+}
+
+/// Rebuild the aggregates from the retained reports and swap them in after the confirmation
+pub async fn run(
+    pool_tp: deadpool_postgres::Pool,
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    tx_ba_conn: flume::Sender<BAConnectionManageMessage>,
+    args: ReprocessArgs,
+) -> Result<()> {
+    if args.from > args.to {
+        return Err(anyhow::anyhow!(
+            "invalid range: {} is after {}",
+            args.from,
+            args.to
+        ));
+    }
+
+    let live = Collection::Wifi.as_ref();
+    let shadow = Collection::WifiShadow.as_ref();
+    let mut summary = ReprocessSummary {
+        shadow_collection: shadow.to_string(),
+        ..Default::default()
+    };
+
+    let mut manager = pool_tp.get().await?;
+    let transaction = manager.build_transaction().read_only(true).start().await?;
+    let mut partitions = get_report_attached_partitions(&transaction)
+        .await?
+        .into_iter()
+        .filter(|ap| {
+            ap.date()
+                .map(|d| (args.from..=args.to).contains(&d.date_naive()))
+                .unwrap_or(false)
+        })
+        .map(|ap| ap.child)
+        .collect::<Vec<String>>();
+    transaction.commit().await?;
+    partitions.sort();
+
+    // 1. replay the reports
+    let mut aggregates: BTreeMap<String, (Transmitter, TransmitterLocation)> = BTreeMap::new();
+    let mut opted_out: HashSet<String> = HashSet::new();
+    let client = manager.client();
+    for partition in &partitions {
+        let mut after_id = 0;
+        loop {
+            let reports = get_processed_reports_by_partition(
+                client,
+                partition,
+                args.geofence.as_ref(),
+                after_id,
+                REPORTS_PAGE_SIZE,
+            )
+            .await?;
+            let Some(last) = reports.last() else {
+                break;
+            };
+            after_id = last.id;
+
+            for report in reports {
+                let offline = match extract_offline(&report.raw) {
+                    Err(e) => {
+                        error!("reprocess report id {} in {}: {}", report.id, partition, e);
+                        summary.rejected_reports += 1;
+                        continue;
+                    }
+                    Ok(x) => x,
+                };
+                summary.reports += 1;
+                opted_out.extend(offline.opted_out);
+                let virtual_groups = report_groups(&offline.transmitters);
+                let macs = report_macs(&offline.transmitters);
+                let timestamp = offline.position.timestamp;
+                // only the access points are rebuilt
+                for transmitter in offline.transmitters {
+                    if !matches!(transmitter, Transmitter::Wifi { .. }) {
+                        continue;
+                    }
+                    let o = observation(&offline.position, &transmitter);
+                    aggregate(&mut aggregates, transmitter, o);
+                }
+                merge(&mut aggregates, &virtual_groups);
+                record(&mut aggregates, &macs, timestamp);
+            }
+        }
+        info!(
+            "reprocessed partition {}: {} reports",
+            partition, summary.reports
+        );
+    }
+    summary.partitions = partitions;
+    // opted-out access points are neither rebuilt nor copied from the live collection
+    aggregates.retain(|name, _| !opted_out.contains(name));
+    summary.opted_out = opted_out.len();
+
+    let wifi = aggregates
+        .into_values()
+        .map(|(_, mut tl)| {
+            tl.measurements = None;
+            tl
+        })
+        .collect::<Vec<TransmitterLocation>>();
+
+    // 2. shadow collection = rebuilt access points
+    drop_collection(tx_t38_conn.clone(), shadow).await?;
+    for chunk in wifi.chunks(T38_BATCH_SIZE) {
+        let macs = chunk
+            .iter()
+            .map(|tl| tl.mac.as_str())
+            .collect::<Vec<&str>>();
+        let existing =
+            fget_wifi_many_from_pipeline::<TransmitterLocation>(tx_t38_conn.clone(), live, &macs)
+                .await?;
+        for (i, tl) in chunk.iter().enumerate() {
+            let live_tl = existing.get(i).and_then(Option::as_ref);
+            summary.wifi.add(tl, live_tl, args.moved);
+        }
+        set_wifi_many(tx_t38_conn.clone(), shadow, chunk).await?;
+    }
+
+    println!("{}", serde_json::to_string_pretty(&summary)?);
+
+    // 3. swap
+    if !args.yes && !confirm(&summary)? {
+        println!(
+            "Collection '{}' with the rebuilt access points is kept for inspection, nothing is changed",
+            shadow
+        );
+        return Ok(());
+    }
+
+    // the live collection is copied right before the swap, so that the updates made while the
+    // summary was inspected are kept
+    let rebuilt = wifi
+        .iter()
+        .map(|tl| tl.mac.as_str())
+        .collect::<HashSet<&str>>();
+    let copied = copy_live(tx_t38_conn.clone(), live, shadow, &rebuilt, &opted_out).await?;
+    info!("copied {} access points from '{}'", copied, live);
+
+    rename_collection(tx_t38_conn.clone(), shadow, live).await?;
+
+    if CONFIG.blobasaur.enabled {
+        let namespace = Collection::BaWifi.as_ref();
+        for tl in &wifi {
+            if let Err(e) = set_ba_wifi_one(tx_ba_conn.clone(), namespace, tl).await {
+                error!(
+                    "save reprocessed access point {} in blobasaur: {}",
+                    tl.mac, e
+                );
+            }
+        }
+    }
+    for mac in &opted_out {
+        ssid::purge(&tx_t38_conn, &tx_ba_conn, mac).await;
+    }
+
+    info!(
+        "Reprocessing finished: {} access points replaced",
+        summary.wifi.rebuilt
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wifi_diff() {
+        let mut diff = WifiDiff::default();
+        let live = TransmitterLocation::fixture();
+
+        // ~11 m to the north
+        let near = TransmitterLocation::fixture_at(55.7501, 37.62);
+        diff.add(&near, Some(&live), 100.0);
+        assert_eq!(diff.moved, 0);
+
+        // ~1.1 km to the north
+        let far = TransmitterLocation::fixture_at(55.76, 37.62);
+        diff.add(&far, Some(&live), 100.0);
+        diff.add(&far, None, 100.0);
+
+        assert_eq!(diff.rebuilt, 3);
+        assert_eq!(diff.new, 1);
+        assert_eq!(diff.moved, 1);
+        assert!((diff.max_shift - 1112.0).abs() < 5.0);
+        assert_eq!(diff.most_moved[0].mac, "aa:bb:cc:dd:ee:01");
+    }
+
+    #[test]
+    fn parse_geofence() {
+        let gf = "55.5,56.1,37.1,38.0".parse::<GeoFence>().unwrap();
+        assert_eq!(gf.lat_max, 56.1);
+        assert_eq!(gf.lon_min, 37.1);
+        assert!("56.1,55.5,37.1,38.0".parse::<GeoFence>().is_err());
+        assert!("55.5,56.1,37.1".parse::<GeoFence>().is_err());
+    }
+}
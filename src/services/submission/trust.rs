@@ -0,0 +1,169 @@
+//! Trust of the transmitter location: number of observations, spatial spread, age and agreement
+//! with the LBS location of the access point and the serving cell.
+//!
+//! The agreement is assessed during report processing and saved in `TransmitterLocation::trust`,
+//! so repeatedly contradicting access points fade out in locate without LBS requests.
+
+use geo::{Distance, Haversine, Point};
+use once_cell::sync::Lazy;
+
+use crate::{
+    CONFIG,
+    constants::{TRUST_MIN_CHECKS, TRUST_MIN_WEIGHT, TRUST_OBSERVATIONS},
+    db::pg::transmitter::{TransmitterLocation, Trust},
+    services::{helper::decay, submission::process::Observation},
+};
+
+static TRUST_PARAMS: Lazy<TrustParams> = Lazy::new(TrustParams::from_config);
+
+#[derive(Debug, Clone, Copy)]
+pub struct TrustParams {
+    /// spread of the observations is compared with this radius, meters
+    pub radius: f64,
+    /// the trust halves every `half_life` days since the last observation, 0 disables the decay
+    pub half_life: f64,
+    /// maximum distance to the LBS location of the access point, meters
+    pub max_distance_lbs: f64,
+    /// maximum distance to the serving cell, meters
+    pub max_distance_cell: f64,
+}
+
+impl TrustParams {
+    pub fn from_config() -> Self {
+        Self {
+            radius: CONFIG.locator.radius_wifi_detection,
+            half_life: CONFIG.locator.aggregation_half_life,
+            max_distance_lbs: CONFIG.locator.max_distance_in_cluster,
+            max_distance_cell: CONFIG.locator.max_distance_cell,
+        }
+    }
+}
+
+pub fn assess(tl: &mut TransmitterLocation, o: &Observation) {
+    assess_with(&TRUST_PARAMS, tl, o);
+}
+
+/// Compare the aggregated location with the reference locations of the observation
+pub fn assess_with(params: &TrustParams, tl: &mut TransmitterLocation, o: &Observation) {
+    let location = Point::new(tl.lon, tl.lat);
+    let far = |reference: Option<(f64, f64)>, max_distance: f64| {
+        reference
+            .map(|(lat, lon)| Haversine::distance(location, Point::new(lon, lat)) > max_distance)
+    };
+    let checks = [
+        far(o.lbs, params.max_distance_lbs),
+        far(o.cell, params.max_distance_cell),
+    ];
+
+    let mut trust = tl.trust.unwrap_or_default();
+    for contradicts in checks.into_iter().flatten() {
+        if contradicts {
+            trust.contradictions = trust.contradictions.saturating_add(1);
+        } else {
+            trust.agreements = trust.agreements.saturating_add(1);
+        }
+    }
+    trust.score = score(params, tl, &trust);
+    tl.trust = Some(trust);
+}
+
+fn score(params: &TrustParams, tl: &TransmitterLocation, trust: &Trust) -> f64 {
+    let n = tl.observation_count as f64;
+    let count_factor = 0.5 + 0.5 * n / (n + TRUST_OBSERVATIONS);
+
+    let (min, max) = tl.points();
+    let spread = Haversine::distance(min, max);
+    let spread_factor = (1.0 - spread / (4.0 * params.radius)).clamp(0.5, 1.0);
+
+    let agreement_factor = (trust.agreements as f64 + 1.0)
+        / (trust.agreements as f64 + trust.contradictions as f64 + 1.0);
+
+    count_factor * spread_factor * agreement_factor
+}
+
+/// Multiplicative weight of the transmitter in locate, `now` in milliseconds
+pub fn weight(tl: &TransmitterLocation, now: i64) -> f64 {
+    weight_with(&TRUST_PARAMS, tl, now)
+}
+
+pub fn weight_with(params: &TrustParams, tl: &TransmitterLocation, now: i64) -> f64 {
+    // aggregates saved before the trust assessment are not penalized
+    let score = tl.trust.map(|t| t.score).unwrap_or(1.0);
+
+    let age_factor = tl.updated_at.map_or(1.0, |updated_at| {
+        decay::factor(decay::half_life(params.half_life), now - updated_at)
+    });
+
+    (score * age_factor).max(TRUST_MIN_WEIGHT)
+}
+
+/// Decision on the outlier by the saved agreement, `None` if there were too few checks
+pub fn is_contradicting(tl: &TransmitterLocation) -> Option<bool> {
+    let trust = tl.trust?;
+    if trust.agreements + trust.contradictions < TRUST_MIN_CHECKS {
+        return None;
+    }
+    Some(trust.contradictions > trust.agreements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::helper::decay::DAY;
+
+    const PARAMS: TrustParams = TrustParams {
+        radius: 150.0,
+        half_life: 30.0,
+        max_distance_lbs: 250.0,
+        max_distance_cell: 600.0,
+    };
+
+    fn observation(lbs: Option<(f64, f64)>, cell: Option<(f64, f64)>) -> Observation {
+        Observation {
+            lat: 55.75,
+            lon: 37.62,
+            accuracy: 20.0,
+            weight: 1.0,
+            rssi: -70.0,
+            timestamp: 0,
+            cell,
+            lbs,
+        }
+    }
+
+    #[test]
+    fn trust_agreement() {
+        let mut tl = TransmitterLocation::fixture();
+        assert_eq!(weight_with(&PARAMS, &tl, 0), 1.0);
+
+        // LBS location ~110 m away, cell ~300 m away
+        assess_with(
+            &PARAMS,
+            &mut tl,
+            &observation(Some((55.751, 37.62)), Some((55.7527, 37.62))),
+        );
+        let trust = tl.trust.unwrap();
+        assert_eq!(trust.agreements, 2);
+        assert_eq!(trust.contradictions, 0);
+        let agreed = weight_with(&PARAMS, &tl, 0);
+        assert!((agreed - 0.625).abs() < 1e-9);
+        assert_eq!(is_contradicting(&tl), None);
+
+        // LBS location ~1.1 km away
+        for _ in 0..3 {
+            assess_with(&PARAMS, &mut tl, &observation(Some((55.76, 37.62)), None));
+        }
+        assert_eq!(tl.trust.unwrap().contradictions, 3);
+        assert_eq!(is_contradicting(&tl), Some(true));
+        assert!(weight_with(&PARAMS, &tl, 0) < agreed);
+    }
+
+    #[test]
+    fn trust_age() {
+        let mut tl = TransmitterLocation::fixture();
+        assess_with(&PARAMS, &mut tl, &observation(None, None));
+        let fresh = weight_with(&PARAMS, &tl, 0);
+        assert!((weight_with(&PARAMS, &tl, 30 * DAY) - fresh / 2.0).abs() < 1e-9);
+        assert_eq!(weight_with(&PARAMS, &tl, 3650 * DAY), TRUST_MIN_WEIGHT);
+    }
+}
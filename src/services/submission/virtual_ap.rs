@@ -0,0 +1,227 @@
+//! Virtual access points: routers broadcast several BSSIDs (guest SSID, 2.4/5 GHz bands)
+//! differing in the low bits of the MAC address.
+//!
+//! BSSIDs with the same upper bits that are observed together with correlated RSSI are grouped
+//! into one physical access point. New BSSIDs take over the aggregate of their siblings, and in
+//! locate the group weighs as much as its strongest BSSID.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+};
+
+use crate::{
+    constants::{DEFAULT_RSSI, VIRTUAL_AP_MASK, VIRTUAL_AP_RSSI_DELTA},
+    db::{model::Transmitter, pg::transmitter::TransmitterLocation},
+    services::helper::macaddr::MacAddr,
+};
+
+/// Identifier of the physical access point: the MAC address with the low bits masked
+pub fn group_key(mac: &str) -> Option<String> {
+    let mut m = MacAddr::from_str(mac).ok()?;
+    m.5 &= VIRTUAL_AP_MASK;
+    Some(m.to_string())
+}
+
+/// BSSID observed in one scan
+#[derive(Debug, Clone)]
+pub struct Member<'a> {
+    pub mac: &'a str,
+    pub rssi: f64,
+    /// group saved in the aggregate
+    pub group: Option<&'a str>,
+    pub weight: f64,
+}
+
+/// Split the BSSIDs of one scan into physical access points, returns the indices of the members.
+/// A BSSID joins the strongest BSSID with the same key if their RSSI are correlated
+/// or both were grouped before.
+pub fn clusters(members: &[Member<'_>]) -> Vec<Vec<usize>> {
+    let mut by_key: HashMap<String, Vec<usize>> = HashMap::new();
+    for (i, m) in members.iter().enumerate() {
+        if let Some(key) = group_key(m.mac) {
+            by_key.entry(key).or_default().push(i);
+        }
+    }
+
+    let mut clusters = Vec::new();
+    for (_, mut indices) in by_key {
+        indices.sort_by(|a, b| members[*b].rssi.total_cmp(&members[*a].rssi));
+        while !indices.is_empty() {
+            let head = &members[indices[0]];
+            let (cluster, rest): (Vec<usize>, Vec<usize>) = indices.iter().partition(|&&i| {
+                let m = &members[i];
+                (head.rssi - m.rssi).abs() <= VIRTUAL_AP_RSSI_DELTA
+                    || (head.group.is_some() && head.group == m.group)
+            });
+            clusters.push(cluster);
+            indices = rest;
+        }
+    }
+    clusters
+}
+
+/// Scale the weights so each physical access point weighs as much as its strongest BSSID
+pub fn cap_weights(members: &mut [Member<'_>]) {
+    for cluster in clusters(members) {
+        if cluster.len() < 2 {
+            continue;
+        }
+        let total: f64 = cluster.iter().map(|&i| members[i].weight).sum();
+        let max = cluster
+            .iter()
+            .map(|&i| members[i].weight)
+            .fold(0.0, f64::max);
+        if total > 0.0 {
+            for &i in &cluster {
+                members[i].weight *= max / total;
+            }
+        }
+    }
+}
+
+/// Virtual access points of the report: MAC address to the group key
+pub fn report_groups(transmitters: &[Transmitter]) -> HashMap<String, String> {
+    let members = transmitters
+        .iter()
+        .filter_map(|t| match t {
+            Transmitter::Wifi {
+                mac,
+                signal_strength,
+                ..
+            } => Some(Member {
+                mac,
+                rssi: signal_strength.unwrap_or(DEFAULT_RSSI),
+                group: None,
+                weight: 1.0,
+            }),
+            _ => None,
+        })
+        .collect::<Vec<Member>>();
+
+    let mut groups = HashMap::new();
+    for cluster in clusters(&members) {
+        if cluster.len() < 2 {
+            continue;
+        }
+        if let Some(key) = group_key(members[cluster[0]].mac) {
+            for i in cluster {
+                groups.insert(members[i].mac.to_string(), key.clone());
+            }
+        }
+    }
+    groups
+}
+
+/// Save the groups in the aggregates, new BSSIDs take over the aggregate of the most observed sibling
+pub fn merge(
+    aggregates: &mut BTreeMap<String, (Transmitter, TransmitterLocation)>,
+    groups: &HashMap<String, String>,
+) {
+    let mut siblings: HashMap<&str, Vec<&str>> = HashMap::new();
+    for (mac, key) in groups {
+        siblings.entry(key).or_default().push(mac);
+    }
+
+    for macs in siblings.values() {
+        let best = macs
+            .iter()
+            .filter_map(|mac| aggregates.get(*mac).map(|(_, tl)| tl))
+            .filter(|tl| !tl.is_mobile())
+            .max_by_key(|tl| tl.observation_count)
+            .cloned();
+
+        for mac in macs {
+            let Some((_, tl)) = aggregates.get_mut(*mac) else {
+                continue;
+            };
+            tl.virtual_group = groups.get(*mac).cloned();
+            if let Some(best) = best.as_ref()
+                && tl.observation_count <= 1
+                && best.observation_count > 1
+            {
+                tl.adopt(best);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(mac: &str, rssi: f64, weight: f64) -> Member<'_> {
+        Member {
+            mac,
+            rssi,
+            group: None,
+            weight,
+        }
+    }
+
+    #[test]
+    fn virtual_ap_group_key() {
+        assert_eq!(
+            group_key("a0:b1:c2:d3:e4:f5").as_deref(),
+            Some("a0:b1:c2:d3:e4:f0")
+        );
+        assert_eq!(
+            group_key("a0:b1:c2:d3:e4:f5"),
+            group_key("a0:b1:c2:d3:e4:fa")
+        );
+        assert_ne!(
+            group_key("a0:b1:c2:d3:e4:f5"),
+            group_key("a0:b1:c2:d3:e4:e5")
+        );
+    }
+
+    #[test]
+    fn virtual_ap_cap_weights() {
+        let mut members = vec![
+            member("a0:b1:c2:d3:e4:f1", -60.0, 3.0),
+            member("a0:b1:c2:d3:e4:f2", -62.0, 2.0),
+            member("a0:b1:c2:d3:e4:f3", -63.0, 1.0),
+            // same key, but not correlated
+            member("a0:b1:c2:d3:e4:f4", -90.0, 0.5),
+            member("10:20:30:40:50:60", -70.0, 1.0),
+        ];
+        cap_weights(&mut members);
+
+        let router: f64 = members[..3].iter().map(|m| m.weight).sum();
+        assert!((router - 3.0).abs() < 1e-9);
+        assert_eq!(members[3].weight, 0.5);
+        assert_eq!(members[4].weight, 1.0);
+    }
+
+    #[test]
+    fn virtual_ap_merge() {
+        let wifi = |mac: &str, rssi: f64| Transmitter::Wifi {
+            mac: mac.to_string(),
+            ssid: None,
+            signal_strength: Some(rssi),
+            age: None,
+        };
+        let transmitters = vec![
+            wifi("a0:b1:c2:d3:e4:f1", -60.0),
+            wifi("a0:b1:c2:d3:e4:f2", -65.0),
+            wifi("10:20:30:40:50:60", -70.0),
+        ];
+        let groups = report_groups(&transmitters);
+        assert_eq!(groups.len(), 2);
+
+        let mut known =
+            TransmitterLocation::new("a0:b1:c2:d3:e4:f1", 55.75, 37.62, 20.0, 1.0, -60.0, 0);
+        known.seen(0);
+        let new = TransmitterLocation::new("a0:b1:c2:d3:e4:f2", 55.76, 37.63, 20.0, 1.0, -65.0, 0);
+
+        let mut aggregates = BTreeMap::new();
+        aggregates.insert(known.mac.clone(), (transmitters[0].clone(), known));
+        aggregates.insert(new.mac.clone(), (transmitters[1].clone(), new));
+        merge(&mut aggregates, &groups);
+
+        let (_, merged) = &aggregates["a0:b1:c2:d3:e4:f2"];
+        assert_eq!(merged.lat, 55.75);
+        assert_eq!(merged.lon, 37.62);
+        assert_eq!(merged.virtual_group.as_deref(), Some("a0:b1:c2:d3:e4:f0"));
+    }
+}
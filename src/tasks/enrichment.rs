@@ -0,0 +1,58 @@
+//! Background lookups of the queued access points and cells of the reports.
+
+use chrono::Timelike;
+use log::{error, info};
+use tokio::task::JoinHandle;
+use tokio_schedule::Job;
+
+use crate::{
+    CONFIG,
+    lbs::{
+        enrichment::{drain_cell, drain_wifi, priority},
+        http_client::HttpClient,
+        provider::LbsContext,
+    },
+    services::rate_limiter::RateLimitersApp,
+    tasks::{
+        blobasaur::BAConnectionManageMessage, t38::T38ConnectionManageMessage,
+        yandex::YandexApiMessage,
+    },
+};
+
+pub fn enrichment_task(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    tx_ba_conn: flume::Sender<BAConnectionManageMessage>,
+    yandex_client: HttpClient,
+    tx_yandex_api: flume::Sender<YandexApiMessage>,
+    rl_app: RateLimitersApp,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        tokio_schedule::every(CONFIG.enrichment.interval)
+            .seconds()
+            .perform(|| async {
+                let hour = chrono::Utc::now()
+                    .with_timezone(&chrono_tz::Europe::Moscow)
+                    .hour();
+                let ctx = LbsContext::new(
+                    tx_t38_conn.clone(),
+                    tx_ba_conn.clone(),
+                    yandex_client.clone(),
+                    tx_yandex_api.clone(),
+                    rl_app.clone(),
+                )
+                .with_priority(priority(hour));
+
+                match drain_wifi(&ctx, CONFIG.enrichment.batch).await {
+                    Err(e) => error!("enrich queued access points: {}", e),
+                    Ok(0) => {}
+                    Ok(count) => info!("Enriched access points: {}", count),
+                }
+                match drain_cell(&ctx, CONFIG.enrichment.batch).await {
+                    Err(e) => error!("enrich queued cells: {}", e),
+                    Ok(0) => {}
+                    Ok(count) => info!("Enriched cells: {}", count),
+                }
+            })
+            .await;
+    })
+}
@@ -1,5 +1,9 @@
 pub mod blobasaur;
+pub mod enrichment;
 pub mod graphhopper;
 pub mod report;
+pub mod retention;
+pub mod revalidation;
+pub mod ssid;
 pub mod t38;
 pub mod yandex;
@@ -27,10 +27,12 @@ use crate::{
         },
         rate_limiter::RateLimitersApp,
         submission::{
-            self,
+            self, cooccurrence,
             geosubmit::{Report, insert},
             geosubmit_public::SubmissionPublic,
+            process::{new_location, observation, update_location},
             report::{Report as ReportProcess, extract_from_report},
+            virtual_ap,
         },
     },
     tasks::{
@@ -99,56 +101,14 @@ pub fn online_process_report_task(
                         let mut modified: BTreeMap<String, (Transmitter, TransmitterLocation)> =
                             BTreeMap::new();
 
+                        let virtual_groups = virtual_ap::report_groups(&transmitters);
+                        let macs = cooccurrence::report_macs(&transmitters);
                         for transmitter in transmitters {
-                            let rssi = transmitter.signal_strength().unwrap_or(DEFAULT_RSSI);
-
-                            let distance_since_scan;
-                            let lat;
-                            let lon;
-                            if let Some(speed) = pos.speed
-                                && let Some(wifi_age) = transmitter.age()
-                                && let Some(pos_age) = pos.age
-                            {
-                                distance_since_scan =
-                                    speed * (wifi_age as f64 - pos_age as f64) / 1000.0;
-
-                                if let Some(heading) = pos.heading {
-                                    let transmitter_scan_pos = Rhumb::destination(
-                                        Point::new(pos.latitude, pos.longitude),
-                                        heading,
-                                        -distance_since_scan,
-                                    );
-                                    (lat, lon) = transmitter_scan_pos.x_y();
-                                } else {
-                                    lat = pos.latitude;
-                                    lon = pos.longitude;
-                                }
-                            } else {
-                                distance_since_scan = 0.0;
-                                lat = pos.latitude;
-                                lon = pos.longitude;
-                            };
-
-                            let distance_from_transmitter =
-                                10_f64.powf((BASE_RSSI - rssi) / (10.0 * SIGNAL_DROP_COEFFICIENT));
-
-                            let signal_weight =
-                                10_f64.powf(rssi / (10.0 * SIGNAL_DROP_COEFFICIENT));
-
-                            let age_weight = 10_f64.powf(-distance_since_scan.abs() / 25.0);
-
-                            let gnss_accuracy_weight =
-                                10_f64.powf(-pos.accuracy.unwrap_or(10.0) / 10.0);
-
-                            let weight = signal_weight * age_weight * gnss_accuracy_weight;
-
-                            let accuracy =
-                                distance_from_transmitter + pos.accuracy.unwrap_or_default();
-
+                            let o = observation(&pos, &transmitter);
                             let transmitter_name = transmitter.to_string();
 
                             if let Some((_, tl)) = modified.get_mut(&transmitter_name) {
-                                tl.update(lat, lon, accuracy, weight, rssi);
+                                update_location(tl, &transmitter, &o);
                             } else if let Some(mut tl) =
                                 match transmitter.lookup(tx_t38_conn.clone()).await {
                                     Err(_) => {
@@ -158,35 +118,21 @@ pub fn online_process_report_task(
                                     Ok(tl) => tl,
                                 }
                             {
-                                tl.update(lat, lon, accuracy, weight, rssi);
+                                update_location(&mut tl, &transmitter, &o);
                                 modified.insert(transmitter.to_string(), (transmitter, tl));
                             } else {
-                                modified.insert(
-                                    transmitter.to_string(),
-                                    (
-                                        transmitter,
-                                        TransmitterLocation::new(
-                                            &transmitter_name,
-                                            lat,
-                                            lon,
-                                            accuracy,
-                                            weight,
-                                            rssi,
-                                        ),
-                                    ),
-                                );
+                                let tl = new_location(&transmitter_name, &transmitter, &o);
+                                modified.insert(transmitter.to_string(), (transmitter, tl));
                             }
                         }
+                        virtual_ap::merge(&mut modified, &virtual_groups);
+                        cooccurrence::record(&mut modified, &macs, pos.timestamp);
 
                         for (_tr_name, (transmitter, mut tl)) in modified {
                             match transmitter {
                                 Transmitter::Cell { .. } => {}
                                 Transmitter::Bluetooth { .. } => {}
-                                Transmitter::Wifi {
-                                    mac: _,
-                                    signal_strength: _,
-                                    age: _,
-                                } => {
+                                Transmitter::Wifi { .. } => {
                                     tl.measurements = None;
                                     let collection = crate::constants::Collection::Wifi.as_ref();
                                     if let Err(e) = crate::db::t38::set_wifi_one(
@@ -0,0 +1,221 @@
+//! Expiry of the transmitters not seen within the retention period.
+//!
+//! Wi-Fi access points are expired in the Tile38 `wifi` collection (and Blobasaur),
+//! cells and bluetooth beacons in PostgreSQL. Transmitters saved before the tracking of
+//! observations have no time of the last observation and are kept until observed.
+
+use anyhow::Result;
+use chrono::{Duration, Utc};
+use log::{error, info};
+use tokio::task::JoinHandle;
+use tokio_schedule::Job;
+
+use crate::{
+    CONFIG,
+    config::{RetentionAction, RetentionPolicy},
+    constants::Collection,
+    db::{
+        blobasaur::{del_ba_wifi_one, set_ba_wifi_one},
+        pg::{
+            demote_stale_transmitters, remove_stale_transmitters, transmitter::TransmitterLocation,
+        },
+        t38::{
+            del_wifi_one, fget_wifi_many_from_pipeline, get_wifi_one, scan::scan_ids_page,
+            set_wifi_one,
+        },
+    },
+    services::helper::decay::DAY,
+    tasks::{blobasaur::BAConnectionManageMessage, t38::T38ConnectionManageMessage},
+};
+
+// number of access points read from Tile38 at a time
+const SCAN_PAGE_SIZE: u64 = 1_000;
+
+pub fn retention_task(
+    pool_tp: deadpool_postgres::Pool,
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    tx_ba_conn: flume::Sender<BAConnectionManageMessage>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        tokio_schedule::every(1)
+            .day()
+            .at(5, 0, 0)
+            .perform(|| async {
+                let retention = &CONFIG.retention;
+                match expire_wifi(tx_t38_conn.clone(), tx_ba_conn.clone(), retention.wifi).await {
+                    Err(err) => error!("expire wifi: {}", err),
+                    Ok(count) => info!("Expired wifi ({}): {}", retention.wifi.action, count),
+                }
+                for (table, policy) in
+                    [("cell", retention.cell), ("bluetooth", retention.bluetooth)]
+                {
+                    match expire_table(pool_tp.clone(), table, policy).await {
+                        Err(err) => error!("expire {}: {}", table, err),
+                        Ok(count) => info!("Expired {} ({}): {}", table, policy.action, count),
+                    }
+                }
+            })
+            .await;
+    })
+}
+
+/// Cutoff time of the policy, milliseconds
+fn cutoff(policy: RetentionPolicy, now: i64) -> i64 {
+    now - policy.days as i64 * DAY
+}
+
+/// Access points saved before the tracking of observations have no time of the last observation
+/// and are kept until observed
+fn is_expired(tl: &TransmitterLocation, cutoff: i64) -> bool {
+    tl.last_seen
+        .or(tl.updated_at)
+        .is_some_and(|last_seen| last_seen < cutoff)
+}
+
+/// Whether the access point is expired by the action, the demoted access points are removed as well
+fn is_selected(tl: &TransmitterLocation, action: RetentionAction, cutoff: i64) -> bool {
+    is_expired(tl, cutoff) && (action == RetentionAction::Remove || !tl.stale)
+}
+
+/// Expire the access point read right before the change, so that the observations saved by the
+/// report processing during the scan are not overwritten
+async fn expire_wifi_one(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    tx_ba_conn: flume::Sender<BAConnectionManageMessage>,
+    policy: RetentionPolicy,
+    cutoff: i64,
+    mac: &str,
+) -> Result<bool> {
+    let collection = Collection::Wifi.as_ref();
+    let namespace = Collection::BaWifi.as_ref();
+    let mut tl = match get_wifi_one(tx_t38_conn.clone(), collection, mac).await? {
+        Some(tl) if is_selected(&tl, policy.action, cutoff) => tl,
+        // removed or seen again in the meantime
+        _ => return Ok(false),
+    };
+    match policy.action {
+        RetentionAction::Remove => {
+            del_wifi_one(tx_t38_conn, collection, mac).await?;
+            if CONFIG.blobasaur.enabled {
+                del_ba_wifi_one(tx_ba_conn, namespace, mac).await?;
+            }
+        }
+        RetentionAction::Demote => {
+            tl.stale = true;
+            set_wifi_one(tx_t38_conn, collection, &tl).await?;
+            if CONFIG.blobasaur.enabled {
+                set_ba_wifi_one(tx_ba_conn, namespace, &tl).await?;
+            }
+        }
+    }
+    Ok(true)
+}
+
+async fn expire_wifi(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    tx_ba_conn: flume::Sender<BAConnectionManageMessage>,
+    policy: RetentionPolicy,
+) -> Result<usize> {
+    let collection = Collection::Wifi.as_ref();
+    let cutoff = cutoff(policy, Utc::now().timestamp_millis());
+
+    // the deletion shifts the offset-based cursor, the access points are expired after the scan
+    let mut expired = Vec::new();
+    let mut cursor = 0;
+    loop {
+        let (next, ids) =
+            scan_ids_page(tx_t38_conn.clone(), collection, cursor, SCAN_PAGE_SIZE).await?;
+        if !ids.is_empty() {
+            let macs = ids.iter().map(String::as_str).collect::<Vec<&str>>();
+            let tls = fget_wifi_many_from_pipeline::<TransmitterLocation>(
+                tx_t38_conn.clone(),
+                collection,
+                &macs,
+            )
+            .await?;
+            expired.extend(
+                tls.into_iter()
+                    .flatten()
+                    .filter(|tl| is_selected(tl, policy.action, cutoff))
+                    .map(|tl| tl.mac),
+            );
+        }
+        if next == 0 {
+            break;
+        }
+        cursor = next;
+    }
+
+    let mut count = 0;
+    for mac in &expired {
+        if expire_wifi_one(tx_t38_conn.clone(), tx_ba_conn.clone(), policy, cutoff, mac).await? {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Cells and bluetooth beacons saved before the tracking of observations have no time of the
+/// last observation and are kept until observed
+async fn expire_table(
+    pool_tp: deadpool_postgres::Pool,
+    table: &str,
+    policy: RetentionPolicy,
+) -> Result<u64> {
+    let cutoff = Utc::now() - Duration::days(policy.days as i64);
+    let manager = pool_tp.get().await?;
+    let client = manager.client();
+    match policy.action {
+        RetentionAction::Remove => remove_stale_transmitters(client, table, cutoff).await,
+        RetentionAction::Demote => demote_stale_transmitters(client, table, cutoff).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retention_expired() {
+        let policy = RetentionPolicy {
+            days: 365,
+            action: RetentionAction::Demote,
+        };
+        let now = 1000 * DAY;
+        let cutoff = cutoff(policy, now);
+
+        let mut tl = TransmitterLocation::fixture();
+        assert!(is_expired(&tl, cutoff));
+
+        tl.seen(900 * DAY);
+        assert!(!is_expired(&tl, cutoff));
+
+        // saved before the tracking of observations
+        tl.last_seen = None;
+        tl.updated_at = None;
+        assert!(!is_expired(&tl, cutoff));
+    }
+
+    #[test]
+    fn retention_select() {
+        let cutoff = 635 * DAY;
+        let fixture = |last_seen: Option<i64>, stale: bool| TransmitterLocation {
+            last_seen,
+            updated_at: None,
+            stale,
+            ..TransmitterLocation::fixture()
+        };
+
+        let legacy = fixture(None, false);
+        let seen = fixture(Some(900 * DAY), false);
+        let expired = fixture(Some(100 * DAY), false);
+        let demoted = fixture(Some(100 * DAY), true);
+        for action in [RetentionAction::Demote, RetentionAction::Remove] {
+            assert!(!is_selected(&legacy, action, cutoff));
+            assert!(!is_selected(&seen, action, cutoff));
+            assert!(is_selected(&expired, action, cutoff));
+        }
+        assert!(!is_selected(&demoted, RetentionAction::Demote, cutoff));
+        assert!(is_selected(&demoted, RetentionAction::Remove, cutoff));
+    }
+}
@@ -0,0 +1,55 @@
+//! Background revalidation of the cached Yandex LBS positions expiring soon.
+
+use log::{error, info};
+use tokio::task::JoinHandle;
+use tokio_schedule::Job;
+
+use crate::{
+    CONFIG,
+    lbs::{
+        cache::{revalidate_cell, revalidate_wifi},
+        http_client::HttpClient,
+        provider::{LbsContext, Priority},
+    },
+    services::rate_limiter::RateLimitersApp,
+    tasks::{
+        blobasaur::BAConnectionManageMessage, t38::T38ConnectionManageMessage,
+        yandex::YandexApiMessage,
+    },
+};
+
+pub fn revalidation_task(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    tx_ba_conn: flume::Sender<BAConnectionManageMessage>,
+    yandex_client: HttpClient,
+    tx_yandex_api: flume::Sender<YandexApiMessage>,
+    rl_app: RateLimitersApp,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        tokio_schedule::every(CONFIG.lbs_cache.revalidate_interval)
+            .seconds()
+            .perform(|| async {
+                // only the spare quota is spent
+                let ctx = LbsContext::new(
+                    tx_t38_conn.clone(),
+                    tx_ba_conn.clone(),
+                    yandex_client.clone(),
+                    tx_yandex_api.clone(),
+                    rl_app.clone(),
+                )
+                .with_priority(Priority::Enrichment);
+
+                match revalidate_wifi(&ctx).await {
+                    Err(e) => error!("revalidate cached access points: {}", e),
+                    Ok(0) => {}
+                    Ok(count) => info!("Revalidated access points: {}", count),
+                }
+                match revalidate_cell(&ctx).await {
+                    Err(e) => error!("revalidate cached cells: {}", e),
+                    Ok(0) => {}
+                    Ok(count) => info!("Revalidated cells: {}", count),
+                }
+            })
+            .await;
+    })
+}
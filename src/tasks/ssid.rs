@@ -0,0 +1,29 @@
+//! Daily sweep of the opted-out access points from the cell index and the co-occurrence graph.
+
+use log::{error, info};
+use tokio::task::JoinHandle;
+use tokio_schedule::Job;
+
+use crate::{
+    services::helper::ssid::sweep,
+    tasks::{blobasaur::BAConnectionManageMessage, t38::T38ConnectionManageMessage},
+};
+
+pub fn ssid_sweep_task(
+    tx_t38_conn: flume::Sender<T38ConnectionManageMessage>,
+    tx_ba_conn: flume::Sender<BAConnectionManageMessage>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        tokio_schedule::every(1)
+            .day()
+            .at(4, 0, 0)
+            .perform(|| async {
+                match sweep(tx_t38_conn.clone(), tx_ba_conn.clone()).await {
+                    Err(e) => error!("sweep opted-out access points: {}", e),
+                    Ok(0) => {}
+                    Ok(count) => info!("Swept opted-out access points: {} entries", count),
+                }
+            })
+            .await;
+    })
+}
@@ -36,9 +36,18 @@ pub struct ValidApiKey {
 }
 
 pub enum YandexApiMessage {
-    GetApiKey { tx: oneshot::Sender<Option<ApiKey>> },
-    InvalidApiKey { invalid_api_key: InvalidApiKey },
-    ValidApiKey { valid_api_key: ValidApiKey },
+    GetApiKey {
+        tx: oneshot::Sender<Option<ApiKey>>,
+    },
+    GetActiveKeys {
+        tx: oneshot::Sender<Vec<YandexApiKey>>,
+    },
+    InvalidApiKey {
+        invalid_api_key: InvalidApiKey,
+    },
+    ValidApiKey {
+        valid_api_key: ValidApiKey,
+    },
 }
 
 pub fn yandex_api_task(
@@ -179,6 +188,14 @@ pub fn yandex_api_task(
                         );
                     }
                 }
+                YandexApiMessage::GetActiveKeys { tx } => {
+                    if tx
+                        .send(active_api_keys.values().cloned().collect())
+                        .is_err()
+                    {
+                        error!("send active Yandex api keys");
+                    }
+                }
                 YandexApiMessage::InvalidApiKey { invalid_api_key } => {
                     // deactivate the key blocked by Yandex
                     if let Some(api_key) = active_api_keys.remove(&invalid_api_key.i) {